@@ -0,0 +1,118 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index};
+
+// Derives clustered's ShaderBytesInfo, IntoShaderBytes and FromShaderBytes for a struct whose fields all implement them,
+// laid out like the equivalent WGSL struct (see https://www.w3.org/TR/WGSL/#structure-member-layout):
+// every field starts at the next multiple of its own alignment, the struct aligns to its most aligned field,
+// and its size is rounded up to that alignment. Padding bytes are written as whatever is already in the buffer
+// (zero when going through ShaderBytes::serialise_from_slice) and ignored when reading.
+// NOTE: The generated code refers to the traits as ::clustered::shader_bytes::*, so the clustered crate has to be a dependency
+#[proc_macro_derive(ShaderBytes)]
+pub fn derive_shader_bytes(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return syn::Error::new_spanned(
+                &input.ident,
+                "ShaderBytes can only be derived for structs, WGSL has no enums or unions",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+    if fields.is_empty() {
+        return syn::Error::new_spanned(
+            &input.ident,
+            "ShaderBytes can't be derived for a struct without fields, WGSL structs need at least one member",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let field_types = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+    // self.<member> for named fields, self.<index> for tuple structs
+    let members = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(i);
+                quote!(#index)
+            }
+        })
+        .collect::<Vec<_>>();
+    // What each field is read into in from_shader_bytes before the struct is put together
+    let locals = (0..fields.len())
+        .map(|i| format_ident!("field_{}", i))
+        .collect::<Vec<_>>();
+    let constructor = match fields {
+        Fields::Named(_) => quote!(Self { #(#members: #locals),* }),
+        _ => quote!(Self(#(#locals),*)),
+    };
+
+    let shader_bytes = quote!(::clustered::shader_bytes);
+    quote! {
+        impl #impl_generics #shader_bytes::ShaderBytesInfo for #name #ty_generics #where_clause {
+            fn shader_bytes_size() -> usize {
+                let mut offset = 0usize;
+                #(
+                    offset = offset.next_multiple_of(
+                        <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_align(),
+                    ) + <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_size();
+                )*
+                offset.next_multiple_of(<Self as #shader_bytes::ShaderBytesInfo>::shader_bytes_align())
+            }
+
+            fn shader_bytes_align() -> usize {
+                let mut align = 1usize;
+                #(
+                    align = align.max(<#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_align());
+                )*
+                align
+            }
+        }
+
+        unsafe impl #impl_generics #shader_bytes::IntoShaderBytes for #name #ty_generics #where_clause {
+            fn to_shader_bytes(&self, res: &mut [u8]) {
+                let mut offset = 0usize;
+                #(
+                    offset = offset.next_multiple_of(
+                        <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_align(),
+                    );
+                    let field_size = <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_size();
+                    #shader_bytes::IntoShaderBytes::to_shader_bytes(
+                        &self.#members,
+                        &mut res[offset..offset + field_size],
+                    );
+                    offset += field_size;
+                )*
+                let _ = offset;
+            }
+        }
+
+        unsafe impl #impl_generics #shader_bytes::FromShaderBytes for #name #ty_generics #where_clause {
+            fn from_shader_bytes(buf: &[u8]) -> Self {
+                let mut offset = 0usize;
+                #(
+                    offset = offset.next_multiple_of(
+                        <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_align(),
+                    );
+                    let field_size = <#field_types as #shader_bytes::ShaderBytesInfo>::shader_bytes_size();
+                    let #locals = <#field_types as #shader_bytes::FromShaderBytes>::from_shader_bytes(
+                        &buf[offset..offset + field_size],
+                    );
+                    offset += field_size;
+                )*
+                let _ = offset;
+                #constructor
+            }
+        }
+    }
+    .into()
+}
@@ -17,6 +17,7 @@ var<storage, read_write> out_data: Info;
 var<uniform> goff: u32;
 
 @compute
+// The sorting binary replaces this with the workgroup length it dispatches with
 @workgroup_size(1)
 fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     let actual_id = gid.x+goff;
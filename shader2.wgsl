@@ -4,10 +4,14 @@ var v_in_data: texture_2d<f32>;
 @group(0) @binding(1)
 var v_out_data: texture_storage_2d<rgba8unorm, write>;
 
+// Origin of this tile of the dispatch, in workgroups
+@group(0) @binding(2)
+var<uniform> origin: vec3<u32>;
+
 
 // Gaussian blur
 @compute
-@workgroup_size(1)
+@workgroup_size(8, 8)
 fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
     var conv_kern: array<array<f32, 5>, 5> = array<array<f32, 5>, 5>(
     array<f32, 5>(1.0/273.0, 4.0/273.0, 7.0/273.0, 4.0/273.0, 1.0/273.0),
@@ -22,17 +26,19 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
 
 
     );
+    let pos = gid.xy + origin.xy * vec2<u32>(8u, 8u);
     let size = textureDimensions(v_in_data);
     let width = size.x;
     let height = size.y;
+    if (pos.x >= width || pos.y >= height) { return; }
     var sum = vec4<f32>(0, 0, 0, 0);
     for(var dx: i32 = -2; dx <= 2; dx++){
         for(var dy: i32 = -2; dy <= 2; dy++){
             let pos_kern = vec2<i32>(2+dx, 2+dy);
-            let pos_img = vec2<i32>(dx+i32(gid.x), dy+i32(gid.y));
+            let pos_img = vec2<i32>(dx+i32(pos.x), dy+i32(pos.y));
             var img_val: vec4<f32> = vec4<f32>(0, 0, 0, 0); // Pad with zero in case we go outside the image
-            if !(gid.x < 2 || gid.y < 2 || gid.x > width-2 || gid.y > height-2) { 
-                img_val = textureLoad(v_in_data, vec2(pos_img.x, pos_img.y), 0);
+            if (pos_img.x >= 0 && pos_img.y >= 0 && pos_img.x < i32(width) && pos_img.y < i32(height)) {
+                img_val = textureLoad(v_in_data, pos_img, 0);
             }
             if(img_val.w == 0.0){ // Fully transparent colors get mapped to transparent black
                 img_val = vec4<f32>(0, 0, 0, 0);
@@ -41,5 +47,5 @@ fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
             sum += img_val*kern_val;
         }
     }
-    textureStore(v_out_data, vec2(gid.x, gid.y), sum);
-}
\ No newline at end of file
+    textureStore(v_out_data, pos, sum);
+}
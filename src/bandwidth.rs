@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use wgpu::{BufferDescriptor, BufferUsages, Device, Queue};
+
+// Big enough that per-submission overhead doesn't dominate, capped by the device's max_buffer_size
+const BANDWIDTH_TEST_NBYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferBandwidth {
+    pub upload_mb_per_s: f64,
+    pub download_mb_per_s: f64,
+}
+
+impl std::fmt::Display for TransferBandwidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upload {:.1} MB/s, download {:.1} MB/s",
+            self.upload_mb_per_s, self.download_mb_per_s
+        )
+    }
+}
+
+fn mb_per_s(nbytes: u64, took: Duration) -> f64 {
+    nbytes as f64 / 1_000_000.0 / took.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+// Times a host to device write (queue.write_buffer) and a device to host readback (copy + map) of the same buffer,
+// to tell whether a workload is bound by transfers rather than compute
+// NOTE: Allocates a buffer of up to 64MiB (plus a readback buffer of the same size) for the duration of the measurement
+pub async fn measure_transfer_bandwidth(
+    device: &Device,
+    queue: &Queue,
+) -> Result<TransferBandwidth, wgpu::BufferAsyncError> {
+    let nbytes = BANDWIDTH_TEST_NBYTES.min(device.limits().max_buffer_size);
+    let buf = device.create_buffer(&BufferDescriptor {
+        label: Some("Bandwidth test buffer"),
+        size: nbytes,
+        usage: BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let data = vec![0xA5u8; usize::try_from(nbytes).unwrap()];
+
+    // Don't count work someone else left on the queue
+    crate::wait_for_submitted_work(device, queue).await;
+    let time_before_upload = Instant::now();
+    queue.write_buffer(&buf, 0, &data);
+    queue.submit([]);
+    crate::wait_for_submitted_work(device, queue).await;
+    let upload_took = time_before_upload.elapsed();
+
+    let time_before_download = Instant::now();
+    crate::read_back_buffer(device, queue, &buf, BufferUsages::empty()).await?;
+    let download_took = time_before_download.elapsed();
+
+    Ok(TransferBandwidth {
+        upload_mb_per_s: mb_per_s(nbytes, upload_took),
+        download_mb_per_s: mb_per_s(nbytes, download_took),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_measure_transfer_bandwidth() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let bandwidth = measure_transfer_bandwidth(&device, &queue).await.unwrap();
+        assert!(bandwidth.upload_mb_per_s.is_finite() && bandwidth.upload_mb_per_s > 0.0);
+        assert!(bandwidth.download_mb_per_s.is_finite() && bandwidth.download_mb_per_s > 0.0);
+    }
+}
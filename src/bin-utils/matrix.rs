@@ -1,12 +1,56 @@
 use core::fmt::Debug;
 use core::ops::{Index, IndexMut};
 
+use clustered::shader_bytes::{FromShaderBytes, ShaderBytes};
+
 pub trait Matrix {
     fn nrows(&self) -> usize;
     fn ncols(&self) -> usize;
     fn index_to_offset(&self, index: (usize, usize)) -> usize;
 }
 
+// NOTE: This file is included by several binaries and not all of them read results back, hence the allow(dead_code)s
+
+// Wraps elements that are already laid out in the matrix type's majorness
+#[allow(dead_code)]
+pub trait FromFlatData<MatrixElem>: Sized {
+    fn from_flat_data(nrows: u32, ncols: u32, data: Vec<MatrixElem>) -> Self;
+}
+
+// For results that arrive as raw bytes (e.g. from telefork), every shader element (which can itself be a whole
+// small matrix, like a mat4x4) becomes one MatrixElem
+#[allow(dead_code)]
+pub fn matrix_from_shader_bytes<MatrixElem, Order>(raw_data: &[u8], nrows: u32, ncols: u32) -> Order
+where
+    MatrixElem: FromShaderBytes,
+    Order: FromFlatData<MatrixElem>,
+{
+    let data = ShaderBytes::deserialise_to_vec::<MatrixElem>(raw_data).unwrap();
+    assert!(
+        data.len() == usize::try_from(nrows * ncols).unwrap(),
+        "Got {} elements for a {nrows}x{ncols} matrix!",
+        data.len()
+    );
+    Order::from_flat_data(nrows, ncols, data)
+}
+
+#[allow(dead_code)]
+pub async fn read_buffer_to_matrix<MatrixElem, Order>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buf: &wgpu::Buffer,
+    nrows: u32,
+    ncols: u32,
+) -> Result<Order, wgpu::BufferAsyncError>
+where
+    MatrixElem: FromShaderBytes,
+    Order: FromFlatData<MatrixElem>,
+{
+    let raw_data =
+        clustered::read_back_buffer(device, queue, buf, wgpu::BufferUsages::empty()).await?;
+    Ok(matrix_from_shader_bytes(&raw_data, nrows, ncols))
+}
+
 #[macro_export]
 macro_rules! matrix_impl {
     ($struct_name:ident) => {
@@ -65,6 +109,12 @@ impl<MatrixElem> Matrix for ColMajorMatrix<MatrixElem> {
 }
 matrix_impl!(ColMajorMatrix);
 
+impl<MatrixElem> FromFlatData<MatrixElem> for ColMajorMatrix<MatrixElem> {
+    fn from_flat_data(nrows: u32, ncols: u32, data: Vec<MatrixElem>) -> Self {
+        Self { ncols, nrows, data }
+    }
+}
+
 impl<MatrixElem> ColMajorMatrix<MatrixElem> {
     pub fn new(nrows: u32, ncols: u32) -> Self
     where
@@ -118,6 +168,12 @@ impl<MatrixElem> Matrix for RowMajorMatrix<MatrixElem> {
 }
 matrix_impl!(RowMajorMatrix);
 
+impl<MatrixElem> FromFlatData<MatrixElem> for RowMajorMatrix<MatrixElem> {
+    fn from_flat_data(nrows: u32, ncols: u32, data: Vec<MatrixElem>) -> Self {
+        Self { ncols, nrows, data }
+    }
+}
+
 impl<MatrixElem> RowMajorMatrix<MatrixElem> {
     pub fn new(nrows: u32, ncols: u32) -> Self
     where
@@ -0,0 +1,119 @@
+use std::{net::SocketAddr, time::Duration};
+
+use clustered::networking::{
+    read_buf_compressed, read_buf_limited, write_buf, write_buf_compressed, DEFAULT_MAX_BUF_LEN,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+// About the size of a big matrix-multiply-bigelems result
+const RESULT_NBYTES: usize = 256 * 1024 * 1024;
+// The simulated slow link, roughly gigabit ethernet
+const THROTTLED_BYTES_PER_SEC: f64 = 125.0 * 1000.0 * 1000.0;
+const RELAY_CHUNK_NBYTES: usize = 64 * 1024;
+
+// Forwards every connection made to the returned address on to `to`, at no more than bytes_per_sec
+// NOTE: Only forwards from the connecting side to `to`, which is all the benchmark sends
+async fn spawn_throttled_relay(to: SocketAddr, bytes_per_sec: f64) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            let mut outgoing = TcpStream::connect(to).await.unwrap();
+            tokio::spawn(async move {
+                let mut chunk = vec![0u8; RELAY_CHUNK_NBYTES];
+                let time_start = Instant::now();
+                let mut nbytes_relayed = 0;
+                loop {
+                    let nbytes = match incoming.read(&mut chunk).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(nbytes) => nbytes,
+                    };
+                    if outgoing.write_all(&chunk[..nbytes]).await.is_err() {
+                        break;
+                    }
+                    nbytes_relayed += nbytes;
+                    // Hold off until the link would have gotten this far
+                    tokio::time::sleep_until(
+                        time_start + Duration::from_secs_f64(nbytes_relayed as f64 / bytes_per_sec),
+                    )
+                    .await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+// Time from starting to send (compression included) until the receiver has the whole result
+async fn time_transfer(
+    listener: &TcpListener,
+    connect_to: SocketAddr,
+    result: &[u8],
+    compressed: bool,
+) -> Duration {
+    let time_start = Instant::now();
+    let (received, ()) = tokio::join!(
+        async {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            if compressed {
+                read_buf_compressed(&mut connection, DEFAULT_MAX_BUF_LEN).await
+            } else {
+                read_buf_limited(&mut connection, DEFAULT_MAX_BUF_LEN).await
+            }
+            .unwrap()
+        },
+        async {
+            let mut connection = TcpStream::connect(connect_to).await.unwrap();
+            if compressed {
+                write_buf_compressed(&mut connection, result).await
+            } else {
+                write_buf(&mut connection, result).await
+            }
+            .unwrap();
+        }
+    );
+    let time_taken = time_start.elapsed();
+    assert!(received == result);
+    time_taken
+}
+
+#[tokio::main]
+async fn main() {
+    clustered::logging::init();
+    // Like the output of a matrix multiply with small integer inputs, lots of repeated values
+    let structured = (0..RESULT_NBYTES / core::mem::size_of::<f32>())
+        .flat_map(|i| ((i % 4096) as f32 * 0.5).to_le_bytes())
+        .collect::<Vec<u8>>();
+    let mut rng = StdRng::seed_from_u64(1337);
+    let random = (0..RESULT_NBYTES / core::mem::size_of::<f32>())
+        .flat_map(|_| rng.gen::<f32>().to_le_bytes())
+        .collect::<Vec<u8>>();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let direct_addr = listener.local_addr().unwrap();
+    let throttled_addr = spawn_throttled_relay(direct_addr, THROTTLED_BYTES_PER_SEC).await;
+
+    println!(
+        "Sending {}MB results, throttled link is {}MB/s",
+        RESULT_NBYTES / (1024 * 1024),
+        THROTTLED_BYTES_PER_SEC / (1000.0 * 1000.0)
+    );
+    for (link, addr) in [("localhost", direct_addr), ("throttled", throttled_addr)] {
+        for (data_kind, result) in [("structured", &structured), ("random", &random)] {
+            for compressed in [false, true] {
+                let time_taken = time_transfer(&listener, addr, result, compressed).await;
+                println!(
+                    "{link}, {data_kind} f32s, {}: {:?}ms",
+                    if compressed { "compressed" } else { "raw" },
+                    time_taken.as_millis()
+                );
+            }
+        }
+    }
+}
@@ -1,15 +1,15 @@
 use std::{borrow::Cow, fs::OpenOptions, io::Read, time::Instant};
 
-use clustered::{shader_bytes::ShaderBytes, wgpu_map_helper, RunShaderParams};
+use clustered::{test_support::assert_results_close, OutputReadback};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use wgpu::{
-    Backends, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, DeviceDescriptor, Features,
-    InstanceDescriptor, InstanceFlags, RequestAdapterOptions, ShaderModuleDescriptor,
+    Backends, DeviceDescriptor, Features, InstanceDescriptor, InstanceFlags, RequestAdapterOptions,
+    ShaderModuleDescriptor,
 };
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    clustered::logging::init();
     let instance = wgpu::Instance::new(InstanceDescriptor {
         backends: Backends::all(),
         flags: InstanceFlags::empty(),
@@ -28,8 +28,10 @@ async fn main() {
     let (device, queue) = adapter
         .request_device(
             &DeviceDescriptor {
+                // MAPPABLE_PRIMARY_BUFFERS if there is such a thing, to compare both ways of reading back the output
                 required_features: Features::BUFFER_BINDING_ARRAY
-                    | Features::STORAGE_RESOURCE_BINDING_ARRAY,
+                    | Features::STORAGE_RESOURCE_BINDING_ARRAY
+                    | (adapter.features() & Features::MAPPABLE_PRIMARY_BUFFERS),
                 ..Default::default()
             },
             None,
@@ -51,25 +53,18 @@ async fn main() {
 
     let mut rng = StdRng::seed_from_u64(2);
 
-    let mut benchmark_data_total = [0u128; 2];
-    let mut benchmark_data_min = [u128::MAX; 2];
-    let mut benchmark_data_max = [0u128; 2];
+    let direct_map = clustered::supports_direct_output_mapping(&device);
+    if !direct_map {
+        println!("No MAPPABLE_PRIMARY_BUFFERS, only benchmarking copying the output back!");
+    }
+
+    // GPU (copying the output into a readback buffer), CPU, GPU (mapping the output directly)
+    let mut benchmark_data_total = [0u128; 3];
+    let mut benchmark_data_min = [u128::MAX; 3];
+    let mut benchmark_data_max = [0u128; 3];
 
     let n_elem = 128 * 1024 * 1024 / 4 / 8;
     let n_iter = 100;
-    let in_buf = device.create_buffer(&BufferDescriptor {
-        label: None,
-        size: (n_elem * f32::shader_bytes_size()).try_into().unwrap(),
-        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-    use clustered::shader_bytes::ShaderBytesInfo;
-    let mut out_buf = device.create_buffer(&BufferDescriptor {
-        label: None,
-        size: (n_elem * f32::shader_bytes_size()).try_into().unwrap(),
-        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
-        mapped_at_creation: false,
-    });
 
     for _ in 0..n_iter {
         let input_data = (0..n_elem)
@@ -77,40 +72,17 @@ async fn main() {
             .collect::<Vec<_>>();
 
         let before_gpu = Instant::now();
-        queue.write_buffer(
-            &in_buf,
-            0,
-            ShaderBytes::serialise_from_slice(&input_data).get_data(),
-        );
-
-        clustered::run_shader(RunShaderParams {
-            device: &device,
-            queue: &queue,
-            in_buf: &in_buf,
-            out_buf: &mut out_buf,
-            workgroup_len: 32,
-            n_workgroups: usize::div_ceil(input_data.len(), 32),
-            program: &cs_module,
-            entry_point: "main",
-        })
+        let gpu_res: Vec<f32> = clustered::compute(
+            &device,
+            &queue,
+            &cs_module,
+            "main",
+            &input_data,
+            input_data.len(),
+            32,
+        )
+        .await
         .unwrap();
-
-        let transfer_buf = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: out_buf.size(),
-            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor::default());
-        encoder.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
-        queue.submit([encoder.finish()].into_iter());
-
-        let transfer_buf_view = transfer_buf.slice(..);
-        wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf_view)
-            .await
-            .unwrap();
-        let gpu_res: Vec<f32> =
-            ShaderBytes::deserialise_to_iterator(&transfer_buf_view.get_mapped_range()).collect();
         let gpu_time = (Instant::now() - before_gpu).as_millis();
 
         benchmark_data_total[0] += gpu_time;
@@ -120,6 +92,28 @@ async fn main() {
         // Cleanup resources on the gpu side
         device.poll(wgpu::Maintain::wait()).panic_on_timeout();
 
+        if direct_map {
+            let before_gpu = Instant::now();
+            let gpu_direct_res: Vec<f32> = clustered::compute_with_readback(
+                &device,
+                &queue,
+                &cs_module,
+                "main",
+                &input_data,
+                input_data.len(),
+                32,
+                OutputReadback::DirectMap,
+            )
+            .await
+            .unwrap();
+            let gpu_direct_time = (Instant::now() - before_gpu).as_millis();
+            benchmark_data_total[2] += gpu_direct_time;
+            benchmark_data_min[2] = u128::min(benchmark_data_min[2], gpu_direct_time);
+            benchmark_data_max[2] = u128::max(benchmark_data_max[2], gpu_direct_time);
+            assert_eq!(gpu_direct_res, gpu_res);
+            device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+        }
+
         use rayon::prelude::*;
         let before_cpu = Instant::now();
         let cpu_res: Vec<f32> = input_data
@@ -136,15 +130,7 @@ async fn main() {
         benchmark_data_total[1] += cpu_time;
         benchmark_data_min[1] = u128::min(benchmark_data_min[1], cpu_time);
         benchmark_data_max[1] = u128::max(benchmark_data_max[1], cpu_time);
-        for (i, (gpu_elem, cpu_elem)) in gpu_res.iter().zip(cpu_res.iter()).enumerate() {
-            if (gpu_elem - cpu_elem).abs() > 0.0001 {
-                println!("Mismatch at {}!", i);
-                println!("GPU said: {}!", gpu_elem);
-                println!("CPU said: {}!", cpu_elem);
-                println!("Input was: {:?}", input_data[i]);
-                assert_eq!(gpu_elem, cpu_elem);
-            }
-        }
+        assert_results_close(&gpu_res, &cpu_res, 0.0001);
     }
 
     let avg_cpu = benchmark_data_total[1] as f64 / n_iter as f64;
@@ -163,4 +149,18 @@ async fn main() {
         avg_gpu - benchmark_data_min[0] as f64
     );
     println!("GPU is ~{:.2}x faster!", avg_cpu / avg_gpu);
+
+    if direct_map {
+        let avg_gpu_direct = benchmark_data_total[2] as f64 / n_iter as f64;
+        println!(
+            "GPU time (mapping the output directly): {:.2}ms +{:.2} or -{:.2}",
+            avg_gpu_direct,
+            benchmark_data_max[2] as f64 - avg_gpu_direct,
+            avg_gpu_direct - benchmark_data_min[2] as f64
+        );
+        println!(
+            "Mapping the output directly is ~{:.2}x as fast as copying it!",
+            avg_gpu / avg_gpu_direct
+        );
+    }
 }
@@ -1,20 +1,24 @@
 #[path = "../bin-utils/matrix.rs"]
 mod matrix;
 use matrix::*;
-use tokio::net::TcpStream;
 
 use std::{
     borrow::Cow,
     fmt::Debug,
     fs::OpenOptions,
     io::{Read, Write},
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     ops::{Index, IndexMut},
     str::FromStr,
     time::Instant,
 };
 
-use clustered::serialisable_program::SerialisableProgram;
+use clustered::{
+    compression::ResultCompression,
+    serialisable_program::SerialisableProgram,
+    shader_bytes::{FromShaderBytes, ShaderBytesInfo},
+    telefork::TeleforkClient,
+};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
 #[derive(Clone, Default)]
@@ -55,6 +59,33 @@ impl<MatrixElem> ColMajorMat4x4<MatrixElem> {
 }
 matrix_impl!(ColMajorMat4x4);
 
+// Same layout as a wgsl mat4x4<f32>, which is column major too
+impl ShaderBytesInfo for ColMajorMat4x4<f32> {
+    fn shader_bytes_size() -> usize {
+        core::mem::size_of::<f32>() * 4 * 4
+    }
+
+    fn shader_bytes_align() -> usize {
+        16
+    }
+}
+
+unsafe impl FromShaderBytes for ColMajorMat4x4<f32> {
+    fn from_shader_bytes(buf: &[u8]) -> Self {
+        let mut res = Self {
+            data: [0f32; 4 * 4],
+        };
+        for (elem, raw_elem) in res
+            .data
+            .iter_mut()
+            .zip(buf.chunks_exact(core::mem::size_of::<f32>()))
+        {
+            *elem = f32::from_shader_bytes(raw_elem);
+        }
+        res
+    }
+}
+
 struct InData<'a> {
     matrix1_ncols: u32,
     matrix1_nrows: u32,
@@ -144,8 +175,8 @@ async fn main() {
         out_mat_nrows * 4
     );
 
-    let mut telefork_server_stream =
-        TcpStream::connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337))
+    let mut telefork_client =
+        TeleforkClient::connect(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337)))
             .await
             .unwrap();
 
@@ -161,48 +192,23 @@ async fn main() {
         entry_point: "main".to_owned(),
         n_workgroups: usize::div_ceil(usize::try_from(out_mat_ncols * out_mat_nrows).unwrap(), 32),
         workgroup_size: 32,
+        extra_in_data: Vec::new(),
+        extra_out_data_nbytes: Vec::new(),
     };
-    let serialised_program = serde_json::to_string(&program_capsule).unwrap();
-    // let mut program_file = OpenOptions::new()
-    //     .create(true)
-    //     .truncate(true)
-    //     .write(true)
-    //     .open("program-capsule.json")
-    //     .unwrap();
-    // program_file
-    //     .write_all(serialised_program.as_bytes())
+    // program_capsule
+    //     .save_to_file("program-capsule.json")
+    //     .await
     //     .unwrap();
-    // drop(program_file);
 
-    clustered::networking::write_buf(&mut telefork_server_stream, serialised_program.as_bytes())
-        .await
-        .unwrap();
-
-    let raw_res = clustered::networking::read_buf(&mut telefork_server_stream)
+    let program_res = telefork_client
+        .submit(&program_capsule, ResultCompression::Auto)
         .await
         .unwrap();
+    println!("Server took {}s to run it!", program_res.elapsed_secs);
 
     assert!(out_matrix_type == 1);
-    let res = ColMajorMatrix::<ColMajorMat4x4<f32>> {
-        nrows: out_mat_nrows,
-        ncols: out_mat_ncols,
-        data: raw_res
-            .chunks_exact(core::mem::size_of::<f32>() * 4 * 4)
-            .map(|raw_elem| {
-                let mut res_elem = ColMajorMat4x4 {
-                    data: [0f32; 4 * 4],
-                };
-                for (i, val) in raw_elem
-                    .chunks_exact(core::mem::size_of::<f32>())
-                    .map(|value_bytes| f32::from_le_bytes(value_bytes.try_into().unwrap()))
-                    .enumerate()
-                {
-                    res_elem.data[i] = val;
-                }
-                res_elem
-            })
-            .collect::<Vec<ColMajorMat4x4<f32>>>(),
-    };
+    let res: ColMajorMatrix<ColMajorMat4x4<f32>> =
+        matrix_from_shader_bytes(&program_res.out_data, out_mat_nrows, out_mat_ncols);
     let time_end = Instant::now();
     assert!(res.data.len() == usize::try_from(out_mat_nrows * out_mat_ncols).unwrap());
     println!("Took {}s!", (time_end - time_start).as_secs_f64());
@@ -4,12 +4,12 @@ use matrix::*;
 
 use std::{borrow::Cow, fs::OpenOptions, io::Read, time::Instant};
 
-use clustered::{shader_bytes::ShaderBytes, wgpu_map_helper, RunShaderParams};
+use clustered::{RunShaderParams, ShaderBindings};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, DeviceDescriptor, Features,
-    InstanceDescriptor, RequestAdapterOptions, ShaderModuleDescriptor,
+    BufferDescriptor, BufferUsages, DeviceDescriptor, Features, InstanceDescriptor,
+    RequestAdapterOptions, ShaderModuleDescriptor,
 };
 
 struct InData<'a> {
@@ -147,37 +147,25 @@ async fn main() {
         queue: &queue,
         program: &cs_module,
         entry_point: "main",
+        debug_fill_output: false,
+        strict_binding_sizes: true,
+        params_buf: None,
         in_buf: &in_buf,
         out_buf: &mut out_buf,
         n_workgroups: usize::div_ceil(usize::try_from(out_mat_ncols * out_mat_nrows).unwrap(), 32)
             * 32, /* 32 chunks per element */
         workgroup_len: 32,
-    });
-
-    let transfer_buf = device.create_buffer(&BufferDescriptor {
-        label: None,
-        size: out_buf.size(),
-        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-        mapped_at_creation: false,
-    });
-    assert!(out_buf.size() == transfer_buf.size());
-
-    let mut enc = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    enc.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
-    queue.submit([enc.finish()].into_iter());
-
-    let transfer_view = transfer_buf.slice(..);
-    wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_view)
-        .await
-        .unwrap();
+        bindings: ShaderBindings::default(),
+        dispatch_dims: None,
+    })
+    .await
+    .unwrap();
 
     assert!(out_matrix_type == 2);
-    let res = RowMajorMatrix {
-        nrows: out_mat_nrows,
-        ncols: out_mat_ncols,
-        data: ShaderBytes::deserialise_to_iterator(&transfer_view.get_mapped_range())
-            .collect::<Vec<f32>>(),
-    };
+    let res: RowMajorMatrix<f32> =
+        read_buffer_to_matrix(&device, &queue, &out_buf, out_mat_nrows, out_mat_ncols)
+            .await
+            .unwrap();
     let time_end = Instant::now();
     assert!(res.data.len() == usize::try_from(out_mat_nrows * out_mat_ncols).unwrap());
     // println!("{:?}", res);
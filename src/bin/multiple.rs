@@ -1,6 +1,9 @@
 use std::{borrow::Cow, time::Instant};
 
-use clustered::{shader_bytes::ShaderBytes, wgpu_map_helper, RunShaderParams};
+use clustered::{
+    executor::GpuExecutor, serialisable_program::SerialisableProgram, shader_bytes::ShaderBytes,
+    wgpu_map_helper, RunShaderParams, ShaderBindings,
+};
 use futures::future::join_all;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use wgpu::{
@@ -11,7 +14,7 @@ use wgpu::{
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    clustered::logging::init();
     const SHDR: &str = r#"
     @group(0)
     @binding(0)
@@ -100,7 +103,13 @@ async fn main() {
                 n_workgroups: usize::div_ceil(inv.len(), 32),
                 program: &sh_module,
                 entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: true,
+                params_buf: None,
+                bindings: ShaderBindings::default(),
+                dispatch_dims: None,
             })
+            .await
             .unwrap();
             let transfer_buf = device.create_buffer(&BufferDescriptor {
                 label: None,
@@ -113,10 +122,10 @@ async fn main() {
             enc.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
             queue.submit([enc.finish()].into_iter());
 
-            let transfer_buf_view = transfer_buf.slice(..);
-            wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf_view)
-                .await
-                .unwrap();
+            let transfer_buf_view =
+                wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf, ..)
+                    .await
+                    .unwrap();
             let x = ShaderBytes::deserialise_to_iterator(&transfer_buf_view.get_mapped_range())
                 .collect::<Vec<u32>>();
             x
@@ -160,7 +169,13 @@ async fn main() {
                 n_workgroups: usize::div_ceil(inv.len(), 32),
                 program: &sh_module,
                 entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: true,
+                params_buf: None,
+                bindings: ShaderBindings::default(),
+                dispatch_dims: None,
             })
+            .await
             .unwrap();
             let transfer_buf = device.create_buffer(&BufferDescriptor {
                 label: None,
@@ -173,10 +188,10 @@ async fn main() {
             enc.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
             queue.submit([enc.finish()].into_iter());
 
-            let transfer_buf_view = transfer_buf.slice(..);
-            wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf_view)
-                .await
-                .unwrap();
+            let transfer_buf_view =
+                wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf, ..)
+                    .await
+                    .unwrap();
             let x = ShaderBytes::deserialise_to_iterator(&transfer_buf_view.get_mapped_range())
                 .collect::<Vec<u32>>();
             x
@@ -187,4 +202,39 @@ async fn main() {
     let time_seq = (Instant::now() - time_before_seq).as_millis();
     println!("Sequential run_shader: {:?}ms", time_seq);
     assert_eq!(seq_result, par_result);
+
+    // The same work again, but through a GpuExecutor, which (unlike the loops above) reuses the compiled module,
+    // pipeline and buffers between runs instead of creating them for every one
+    let executor = GpuExecutor::new(
+        adapter,
+        Features::STORAGE_RESOURCE_BINDING_ARRAY | Features::BUFFER_BINDING_ARRAY,
+        Limits::default(),
+    )
+    .await
+    .unwrap();
+    let mut executor_result = Vec::<Vec<u32>>::new();
+    let time_before_executor = Instant::now();
+    for _ in 0..100 {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut inv = Vec::<u32>::new();
+        inv.resize_with(n_elements, || rng.gen_range(0..=1000));
+        let res = executor
+            .execute(&SerialisableProgram {
+                in_data: ShaderBytes::serialise_from_slice(&inv).get_data().to_vec(),
+                out_data_nbytes: n_elements * core::mem::size_of::<u32>(),
+                program: SHDR.to_owned(),
+                entry_point: "main".to_owned(),
+                n_workgroups: usize::div_ceil(n_elements, 32),
+                workgroup_size: 32,
+                extra_in_data: Vec::new(),
+                extra_out_data_nbytes: Vec::new(),
+            })
+            .await
+            .unwrap();
+        executor_result.push(ShaderBytes::deserialise_to_iterator(&res).collect());
+    }
+
+    let time_executor = (Instant::now() - time_before_executor).as_millis();
+    println!("Sequential GpuExecutor: {:?}ms", time_executor);
+    assert_eq!(executor_result, seq_result);
 }
@@ -1,96 +1,482 @@
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     io::{self, ErrorKind},
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use clustered::serialisable_program::SerialisableProgram;
+use clustered::{
+    config::{PeerConfig, SocketOptions, StealBalancing, StressConfig},
+    executor::GpuExecutor,
+    protocol::{PeerMessage, TrackerCommand, MAGIC_PEER2PEER_SEQUENCE, MAGIC_TRACKER_SEQUENCE},
+    serialisable_program::SerialisableProgram,
+    shader_bytes::ShaderBytes,
+    verification::{CpuReference, ShadowChecker},
+};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tokio::{
-    fs::OpenOptions,
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::{Mutex, RwLock, Semaphore},
-    time::{sleep, Instant},
+    sync::{watch, Mutex, OwnedSemaphorePermit, RwLock, Semaphore},
+    task::JoinHandle,
+    time::{sleep, Instant, MissedTickBehavior},
 };
+use tracing::{error, info, warn, Instrument};
 use uuid::Uuid;
-use wgpu::{DeviceDescriptor, InstanceDescriptor, RequestAdapterOptions};
-
-const MAGIC_PEER2PEER_SEQUENCE: &str = "Clustered peer2peer, yay!";
-const MAGIC_TRACKER_SEQUENCE: &str = "Clustered tracker!";
+use wgpu::{InstanceDescriptor, RequestAdapterOptions};
 
-const MINIMUM_TASKS_BEFORE_START_STEALING_TRESH: usize = 5; // We won't steal if we have more than this number of tasks
-const NO_STEAL_TRESHOLD: usize = 1; // No stealing will be allowed if we have less than this number of tasks
+// Range the steal loop sleeps for when there are no other peers, randomised so peers desynchronise
+const EMPTY_PEER_LIST_BACKOFF_MILLIS: std::ops::RangeInclusive<u64> = 50..=150;
+// Wait between attempts to reconnect to the tracker, doubled after every failed attempt up to the max
+const TRACKER_RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const TRACKER_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+// How long a single attempt to reconnect to the tracker (connecting plus the handshake) gets before it's retried
+const TRACKER_RECONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long connecting to another peer (connecting plus sending the magic) gets, so an unreachable peer can't hold anything up for long
+const PEER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long telling a single peer to drop a cancelled task gets, see broadcast_cancel
+const CANCEL_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+// How often sending acks and results to a peer is tried before they're dropped, and the wait between tries, see send_returns
+const RETURN_SEND_ATTEMPTS: u32 = 3;
+const RETURN_RETRY_DELAY: Duration = Duration::from_secs(1);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 struct Task {
-    return_addr: SocketAddrV4, // Where to return result
+    return_addr: SocketAddr, // Where to return result
     program: SerialisableProgram,
     id: u128,
+    // Milliseconds since the unix epoch after which the result is useless to the submitter
+    // NOTE: This is compared against the worker's clock, so peers' clocks need to be roughly in sync
+    deadline_unix_millis: Option<u64>,
+}
+
+// Everything about a task except its program, sent as json in front of the program's binary capsule
+#[derive(Serialize, Deserialize)]
+struct TaskHeader {
+    return_addr: SocketAddr,
+    id: u128,
+    #[serde(default)]
+    deadline_unix_millis: Option<u64>,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+impl Task {
+    // Tasks are sent between peers as: header json length (u64 LE), header json, program in binary capsule form
+    // so in_data isn't base64 inflated on the wire
+    fn to_wire(&self) -> io::Result<Vec<u8>> {
+        let serialised_header = clustered::networking::to_versioned_json(&TaskHeader {
+            return_addr: self.return_addr,
+            id: self.id,
+            deadline_unix_millis: self.deadline_unix_millis,
+        })
+        .map_err(|err| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{err}\nWhile serialising task header"),
+            )
+        })?;
+        let mut res = (serialised_header.len() as u64).to_le_bytes().to_vec();
+        res.extend(serialised_header);
+        res.extend(self.program.to_binary()?);
+        Ok(res)
+    }
+
+    fn from_wire(data: &[u8]) -> io::Result<Self> {
+        let (raw_header_nbytes, rest) = data
+            .split_first_chunk::<{ core::mem::size_of::<u64>() }>()
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Task is too short to hold its header",
+                )
+            })?;
+        let header_nbytes = usize::try_from(u64::from_le_bytes(*raw_header_nbytes))
+            .ok()
+            .filter(|nbytes| *nbytes <= rest.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Task header length is out of bounds",
+                )
+            })?;
+        let (raw_header, raw_program) = rest.split_at(header_nbytes);
+        let header: TaskHeader =
+            clustered::networking::from_versioned_json(raw_header).map_err(|err| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{err}\nWhile deserialising task header"),
+                )
+            })?;
+        Ok(Self {
+            return_addr: header.return_addr,
+            program: SerialisableProgram::from_binary(raw_program)?,
+            id: header.id,
+            deadline_unix_millis: header.deadline_unix_millis,
+        })
+    }
+
+    fn is_past_deadline(&self) -> bool {
+        self.deadline_unix_millis
+            .is_some_and(|deadline| unix_millis(SystemTime::now()) >= deadline)
+    }
+}
+
+// Why a task came back without a result
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TaskError {
+    DeadlineExceeded,
+    // Holds the error of the peer that tried to run it
+    ExecutionFailed(String),
+    Cancelled,
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::DeadlineExceeded => {
+                write!(f, "Task wasn't run because its deadline had already passed")
+            }
+            TaskError::ExecutionFailed(err) => {
+                write!(f, "Task failed to run, even on the cpu fallback: {err}")
+            }
+            TaskError::Cancelled => write!(f, "Task was cancelled before it ran"),
+        }
+    }
+}
+
+type TaskResult = Result<Vec<u8>, TaskError>;
+
+// Every returned result is preceded by one of these
+const RESULT_STATUS_OK: u8 = 0;
+const RESULT_STATUS_DEADLINE_EXCEEDED: u8 = 1;
+// Followed by the error message, as a utf-8 buf
+const RESULT_STATUS_EXECUTION_FAILED: u8 = 2;
+const RESULT_STATUS_CANCELLED: u8 = 3;
+
+// What a peer reports about a task in its queue, deliberately leaves out the program source and data
+#[derive(Debug, Serialize, Deserialize)]
+struct TaskSummary {
+    id: u128,
+    return_addr: SocketAddr,
+    entry_point: String,
+    n_workgroups: usize,
+    in_data_nbytes: usize,
+    out_data_nbytes: usize,
+}
+
+impl From<&Task> for TaskSummary {
+    fn from(task: &Task) -> Self {
+        Self {
+            id: task.id,
+            return_addr: task.return_addr,
+            entry_point: task.program.entry_point.clone(),
+            n_workgroups: task.program.n_workgroups,
+            in_data_nbytes: task.program.in_data.len(),
+            out_data_nbytes: task.program.out_data_nbytes,
+        }
+    }
 }
 
 type TaskQueueType = Arc<Mutex<Vec<Task>>>;
-type BufferRegistryType = Arc<RwLock<HashMap<Uuid, Vec<u8>>>>;
+
+fn queued_cost(task_queue: &[Task]) -> u64 {
+    task_queue
+        .iter()
+        .map(|task| task.program.estimated_cost())
+        .fold(0, u64::saturating_add)
+}
+
+// Whether we have little enough work queued that we should go steal some more
+fn should_steal(task_queue: &[Task], config: &PeerConfig) -> bool {
+    match config.steal_balancing {
+        StealBalancing::TaskCount => {
+            task_queue.len() <= config.minimum_tasks_before_start_stealing_tresh
+        }
+        StealBalancing::EstimatedCost => {
+            queued_cost(task_queue) <= config.minimum_cost_before_start_stealing_tresh
+        }
+    }
+}
+
+// Whether we have too little work queued to give any of it away
+fn should_refuse_steal(task_queue: &[Task], config: &PeerConfig) -> bool {
+    match config.steal_balancing {
+        StealBalancing::TaskCount => task_queue.len() <= config.no_steal_treshold,
+        StealBalancing::EstimatedCost => queued_cost(task_queue) <= config.no_steal_cost_treshold,
+    }
+}
+
+// What we hand a stealer in one round trip: about half of our queue (by task count or by estimated cost,
+// depending on steal_balancing) but at least one task, or nothing if should_refuse_steal
+// They come from the front of the queue, i.e. the oldest tasks, as our runner works through it from the back
+fn take_tasks_for_stealer(task_queue: &mut Vec<Task>, config: &PeerConfig) -> Vec<Task> {
+    if should_refuse_steal(task_queue, config) {
+        return Vec::new();
+    }
+    let n_to_give = match config.steal_balancing {
+        StealBalancing::TaskCount => task_queue.len() / 2,
+        StealBalancing::EstimatedCost => {
+            let half_cost = queued_cost(task_queue) / 2;
+            let mut given_cost = 0u64;
+            task_queue
+                .iter()
+                .take_while(|task| {
+                    given_cost = given_cost.saturating_add(task.program.estimated_cost());
+                    given_cost <= half_cost
+                })
+                .count()
+        }
+    };
+    task_queue.drain(..n_to_give.max(1)).collect()
+}
+type BufferRegistryType = Arc<RwLock<HashMap<Uuid, TaskResult>>>;
 type NotifierRegistryType = Arc<RwLock<HashMap<Uuid, Arc<Semaphore>>>>;
+// Our tasks that some peer (possibly us) has acknowledged it started running, and where that peer listens
+type StartedRegistryType = Arc<RwLock<HashMap<Uuid, SocketAddr>>>;
 
-async fn connect_to_other_peer(other_peer_addr: SocketAddr) -> io::Result<TcpStream> {
-    let mut other_peer_connection = TcpStream::connect(other_peer_addr).await.map_err(|err| {
-        io::Error::new(
-            err.kind(),
-            format!("{err}\nWhile connecting to other peer: {other_peer_addr}"),
+// How often a paused runner with nothing queued checks whether it has been resumed
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Lets a peer be drained (e.g. before a driver update) without leaving the cluster:
+// while paused it keeps running what's already in its queue, but doesn't steal new tasks
+// and doesn't hand any out to stealers, so once the queue is empty it just idles, still registered with the tracker
+// NOTE: Tasks submitted locally are still queued while paused, and run once that queue is reached
+// Only loopback and PeerConfig::admin_addrs can pause or resume us
+#[derive(Clone, Default)]
+struct PeerControl {
+    paused: Arc<AtomicBool>,
+}
+
+impl PeerControl {
+    fn pause(&self) {
+        if !self.paused.swap(true, Ordering::AcqRel) {
+            warn!("Paused, finishing queued tasks but not taking on new ones!");
+        }
+    }
+
+    fn resume(&self) {
+        if self.paused.swap(false, Ordering::AcqRel) {
+            warn!("Resumed, taking on new tasks again!");
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+}
+
+// Whether whoever connected from addr may pause and resume us, see PeerControl
+fn is_admin(addr: SocketAddr, config: &PeerConfig) -> bool {
+    let ip = addr.ip().to_canonical();
+    ip.is_loopback() || config.admin_addrs.contains(&ip)
+}
+
+async fn connect_to_other_peer(
+    other_peer_addr: SocketAddr,
+    socket_options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    tokio::time::timeout(PEER_CONNECT_TIMEOUT, async {
+        let mut other_peer_connection =
+            clustered::networking::connect(other_peer_addr, socket_options)
+                .await
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("{err}\nWhile connecting to other peer: {other_peer_addr}"),
+                    )
+                })?;
+
+        clustered::networking::write_buf(
+            &mut other_peer_connection,
+            MAGIC_PEER2PEER_SEQUENCE.as_bytes(),
         )
-    })?;
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending magic sequence to other peer: {other_peer_addr}"),
+            )
+        })?;
+
+        Ok(other_peer_connection)
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("Connecting to other peer: {other_peer_addr} timed out after {PEER_CONNECT_TIMEOUT:?}"),
+        ))
+    })
+}
 
-    clustered::networking::write_buf(
+async fn query_task_queue(
+    other_peer_addr: SocketAddr,
+    socket_options: &SocketOptions,
+) -> io::Result<Vec<TaskSummary>> {
+    let mut other_peer_connection = connect_to_other_peer(other_peer_addr, socket_options).await?;
+    PeerMessage::SummariseQueue
+        .write(&mut other_peer_connection)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending message id to other peer: {other_peer_addr}"),
+            )
+        })?;
+    let serialised_summary = clustered::networking::read_buf_limited(
         &mut other_peer_connection,
-        MAGIC_PEER2PEER_SEQUENCE.as_bytes(),
+        clustered::networking::MAX_CONTROL_MESSAGE_LEN,
     )
     .await
     .map_err(|err| {
         io::Error::new(
             err.kind(),
-            format!("{err}\nWhile sending magic sequence to other peer: {other_peer_addr}"),
+            format!("{err}\nWhile receiving task queue summary from peer: {other_peer_addr}"),
         )
     })?;
+    clustered::networking::from_versioned_json(&serialised_summary).map_err(|err| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{err}\nWhile parsing task queue summary from peer: {other_peer_addr}"),
+        )
+    })
+}
+
+async fn set_peer_paused(
+    other_peer_addr: SocketAddr,
+    paused: bool,
+    socket_options: &SocketOptions,
+) -> io::Result<()> {
+    let mut other_peer_connection = connect_to_other_peer(other_peer_addr, socket_options).await?;
+    other_peer_connection
+        .write_all(&[PeerMessage::SetPaused.to_u8(), u8::from(paused)])
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending pause/resume message to peer: {other_peer_addr}"),
+            )
+        })?;
+    other_peer_connection.flush().await
+}
 
-    Ok(other_peer_connection)
+async fn send_cancel(
+    other_peer_addr: SocketAddr,
+    task_id: Uuid,
+    socket_options: &SocketOptions,
+) -> io::Result<()> {
+    let mut other_peer_connection = connect_to_other_peer(other_peer_addr, socket_options).await?;
+    let mut frame = [0u8; 17];
+    frame[0] = PeerMessage::CancelTask.to_u8();
+    frame[1..].copy_from_slice(&task_id.as_u128().to_be_bytes());
+    other_peer_connection
+        .write_all(&frame)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending cancel message to peer: {other_peer_addr}"),
+            )
+        })?;
+    other_peer_connection.flush().await
 }
 
-async fn connect_to_tracker(tracker_addr: SocketAddr) -> io::Result<(Ipv4Addr, u16, TcpStream)> {
-    let mut tracker_connection = TcpStream::connect(tracker_addr).await.map_err(|err| {
-        io::Error::new(
-            err.kind(),
-            format!("{err}\nWhile connecting to tracker: {tracker_addr}"),
-        )
-    })?;
+// We don't know which peer ended up with a stolen task (it may have been stolen again since), so every peer gets told
+// NOTE: A task that's being transferred by a steal right now misses the message, it then just runs
+async fn broadcast_cancel(
+    tracker_connection: &Mutex<TcpStream>,
+    task_id: Uuid,
+    socket_options: &SocketOptions,
+) -> io::Result<()> {
+    let peer_list = get_peer_list(tracker_connection).await?;
+    // All at once, so a few unreachable peers don't hold up telling the rest
+    let mut sends = peer_list
+        .into_iter()
+        .map(|other_peer| async move {
+            tokio::time::timeout(
+                CANCEL_SEND_TIMEOUT,
+                send_cancel(other_peer.0, task_id, socket_options),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "Telling peer: {} to cancel timed out after {CANCEL_SEND_TIMEOUT:?}",
+                        other_peer.0
+                    ),
+                ))
+            })
+        })
+        .collect::<FuturesUnordered<_>>();
+    while let Some(res) = sends.next().await {
+        if let Err(err) = res {
+            if !clustered::networking::was_connection_severed(err.kind())
+                && err.kind() != ErrorKind::ConnectionRefused
+            {
+                warn!("{err}\nWhile cancelling task {task_id}");
+            }
+        }
+    }
+    Ok(())
+}
 
-    let tracker_magic = clustered::networking::read_buf(&mut tracker_connection)
+async fn connect_to_tracker(
+    tracker_addr: SocketAddr,
+    socket_options: &SocketOptions,
+) -> io::Result<(IpAddr, u16, TcpStream)> {
+    let mut tracker_connection = clustered::networking::connect(tracker_addr, socket_options)
         .await
         .map_err(|err| {
             io::Error::new(
                 err.kind(),
-                format!("{err}\nWhile receiving magic sequence from tracker: {tracker_addr}"),
+                format!("{err}\nWhile connecting to tracker: {tracker_addr}"),
             )
         })?;
 
-    if tracker_magic != MAGIC_TRACKER_SEQUENCE.as_bytes() {
+    let tracker_magic = clustered::networking::read_buf_limited(
+        &mut tracker_connection,
+        clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+    )
+    .await
+    .map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile receiving magic sequence from tracker: {tracker_addr}"),
+        )
+    })?;
+
+    if !clustered::networking::constant_time_eq(&tracker_magic, MAGIC_TRACKER_SEQUENCE.as_bytes()) {
         return Err(io::Error::new(
-            ErrorKind::Other,
+            ErrorKind::InvalidData,
             format!(
-                "Bad magic {:?} received from tracker: {tracker_addr}!",
-                String::from_utf8(tracker_magic)
+                "Bad magic {} received from tracker: {tracker_addr}!",
+                clustered::networking::describe_bad_magic(&tracker_magic)
             ),
         ));
     }
 
-    let our_ip = Ipv4Addr::from_bits(tracker_connection.read_u32().await.map_err(|err| {
-        io::Error::new(
-            err.kind(),
-            format!("{err}\nWhile receiving ip address from tracker: {tracker_addr}"),
-        )
-    })?);
+    let our_ip = clustered::networking::read_ip_addr(&mut tracker_connection)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile receiving ip address from tracker: {tracker_addr}"),
+            )
+        })?;
 
     let peer2peer_port = tracker_connection.read_u16().await.map_err(|err| {
         io::Error::new(
@@ -102,124 +488,707 @@ async fn connect_to_tracker(tracker_addr: SocketAddr) -> io::Result<(Ipv4Addr, u
     Ok((our_ip, peer2peer_port, tracker_connection))
 }
 
-async fn return_data(
-    data: Vec<u8>,
-    return_addr: SocketAddrV4,
-    task_id: Uuid,
-    output_buffer_registry: BufferRegistryType,
-    notifier_registry: NotifierRegistryType,
+// The tracker never sends anything we didn't ask for, so if there's already something to read
+// (an eof, an error, or leftovers of an answer nobody read) the connection is either dead or out of sync
+fn is_tracker_connection_dead(tracker_connection: &TcpStream) -> bool {
+    let mut probe = [0u8; 1];
+    tracker_connection.peek(&mut probe).now_or_never().is_some()
+}
+
+// Where other peers reach us, and so where the results of our tasks come back to
+// Only changes if the tracker registers us at another port when we reconnect to it, see reconnect_to_tracker
+type OurAddrType = Arc<watch::Sender<SocketAddr>>;
+
+// Only one reconnect at a time, so the tracker doesn't get a registration from each of them
+static TRACKER_RECONNECT_LOCK: Mutex<()> = Mutex::const_new(());
+
+// Replaces a dropped tracker connection with a new one, retrying with capped exponential backoff until that works or we shut down
+// The connection itself stays usable (failing fast, since it's dead) while that goes on, it's only locked to swap the new one in
+// While the tracker still holds our old registration (it hasn't noticed the old connection is gone) it registers us at another port,
+// then that's where we move to, see OurAddrType, we keep listening at the old address for the results still headed there
+// NOTE: Whoever notices the drop first reconnects, everyone else waits for that and then finds the new connection working
+async fn reconnect_to_tracker(
+    tracker_connection: &Mutex<TcpStream>,
+    tracker_addr: SocketAddr,
+    socket_options: &SocketOptions,
+    our_addr: &watch::Sender<SocketAddr>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    // We could test if the return_addr is ourselves, but it's easier to just search for the uuid in our registry
-    // and if we have it then the return_addr is ourselves otherwise it's someone else and we need to connect to them.
-    let mut buf_registry_write_lock = output_buffer_registry.write().await;
-    if let Some(local_buf) = buf_registry_write_lock.get_mut(&task_id) {
-        *local_buf = data;
-        drop(buf_registry_write_lock);
-        if let Some(notifier) = notifier_registry.read().await.get(&task_id) {
-            notifier.add_permits(Semaphore::MAX_PERMITS);
+    let _reconnecting = TRACKER_RECONNECT_LOCK.lock().await;
+    if !is_tracker_connection_dead(&*tracker_connection.lock().await) {
+        return;
+    }
+    warn!("Lost connection to tracker: {tracker_addr}, reconnecting...");
+    let mut backoff = TRACKER_RECONNECT_INITIAL_BACKOFF;
+    loop {
+        if *shutdown.borrow() {
+            warn!("Shutting down, giving up on reconnecting to tracker: {tracker_addr}!");
+            return;
         }
-    } else {
-        drop(buf_registry_write_lock);
-        let mut other_peer_connection =
-            match connect_to_other_peer(SocketAddr::V4(return_addr)).await {
-                Ok(val) => val,
-                Err(err) => {
-                    if !clustered::networking::was_connection_severed(err.kind()) {
-                        println!("Error:");
-                        println!("{err}");
-                        println!("While returning data to other peer: {return_addr}");
+        match tokio::time::timeout(
+            TRACKER_RECONNECT_ATTEMPT_TIMEOUT,
+            connect_to_tracker(tracker_addr, socket_options),
+        )
+        .await
+        {
+            Ok(Ok((our_ip, peer2peer_port, new_connection))) => {
+                *tracker_connection.lock().await = new_connection;
+                if let Some(features) = OUR_FEATURES.get() {
+                    if let Err(err) = advertise_features(tracker_connection, *features).await {
+                        warn!("{err}\nWhile reconnecting to tracker: {tracker_addr}");
                     }
-                    return;
                 }
-            };
+                let new_addr = SocketAddr::new(our_ip, peer2peer_port);
+                let old_addr = our_addr.send_replace(new_addr);
+                if new_addr != old_addr {
+                    warn!("Tracker registered us as {new_addr:?} instead of {old_addr:?}, it probably still holds our old registration, moving there!");
+                }
+                info!("Reconnected to tracker: {tracker_addr}!");
+                return;
+            }
+            Ok(Err(err)) => {
+                warn!("{err}\nWhile reconnecting to tracker, retrying in {backoff:?}...")
+            }
+            Err(_) => warn!(
+                "Reconnecting to tracker: {tracker_addr} timed out after {TRACKER_RECONNECT_ATTEMPT_TIMEOUT:?}, retrying in {backoff:?}..."
+            ),
+        }
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = shutdown.wait_for(|shutting_down| *shutting_down) => {}
+        }
+        backoff = (backoff * 2).min(TRACKER_RECONNECT_MAX_BACKOFF);
+    }
+}
 
-        // Message id 2 is "return result" for peers
-        if let Err(err) = other_peer_connection.write_u8(2).await {
-            println!("Error: {err}");
-            println!("While sending message id to other peer: {return_addr}");
-            println!("While returning data to other peer: {return_addr}");
-            return;
-        };
+// Acks and results headed for other peers that haven't been sent yet, a return_addr having an entry means a sender is already working on it
+type ReturnOutboxType = Arc<Mutex<HashMap<SocketAddr, PendingReturns>>>;
 
-        if let Err(err) = other_peer_connection.write_u128(task_id.as_u128()).await {
-            println!("Error: {err}");
-            println!("While sending task uuid to other peer: {return_addr}");
-            println!("While returning data to other peer: {return_addr}");
-            return;
-        }
+#[derive(Default)]
+struct PendingReturns {
+    // Tasks we started running, with the address we were at when we did, see ResultReturner::ack_started
+    acks: Vec<(Uuid, SocketAddr)>,
+    results: Vec<(Uuid, TaskResult)>,
+}
 
-        if let Err(err) = clustered::networking::write_buf(&mut other_peer_connection, &data).await
-        {
-            println!("Error: {err}");
-            println!("While sending return data to other peer: {return_addr}");
-            println!("While returning data to other peer: {return_addr}");
-        }
+impl PendingReturns {
+    fn is_empty(&self) -> bool {
+        self.acks.is_empty() && self.results.is_empty()
     }
 }
 
-async fn consume_task(
-    task: Task,
+#[derive(Clone)]
+struct ResultReturner {
     output_buffer_registry: BufferRegistryType,
     notifier_registry: NotifierRegistryType,
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
+    started_registry: StartedRegistryType,
+    return_outbox: ReturnOutboxType,
+    socket_options: SocketOptions,
+    // Sent along with acks so the submitter can tell whether we're still around
+    our_addr: OurAddrType,
+}
+
+impl ResultReturner {
+    // Lets the submitter know its task is being worked on
+    // The ack goes out over the same connection as the results headed that way, ahead of them, see send_returns
+    async fn ack_started(&self, return_addr: SocketAddr, task_id: Uuid) {
+        let our_addr = *self.our_addr.borrow();
+        {
+            // Held while marking it started, so ClusterClient::remove_task can't clean up in between and leave the entry behind
+            let buf_registry_read_lock = self.output_buffer_registry.read().await;
+            if buf_registry_read_lock.contains_key(&task_id) {
+                self.started_registry
+                    .write()
+                    .await
+                    .insert(task_id, our_addr);
+                return;
+            }
+        }
+        self.queue_return(return_addr, |pending| {
+            pending.acks.push((task_id, our_addr))
+        })
+        .await;
+    }
+
+    // Adds to what's headed for return_addr, starting a sender for it if there isn't one already
+    // Whatever's added while a batch is being sent gets picked up by that sender,
+    // so many acks and results for one peer go over a single connection instead of one each
+    async fn queue_return(&self, return_addr: SocketAddr, add: impl FnOnce(&mut PendingReturns)) {
+        let mut return_outbox_lock = self.return_outbox.lock().await;
+        match return_outbox_lock.entry(return_addr) {
+            Entry::Occupied(mut pending) => add(pending.get_mut()),
+            Entry::Vacant(vacant) => {
+                add(vacant.insert(PendingReturns::default()));
+                tokio::spawn(send_returns(
+                    return_addr,
+                    self.return_outbox.clone(),
+                    self.socket_options,
+                ));
+            }
+        }
+    }
+
+    async fn return_data(&self, result: TaskResult, return_addr: SocketAddr, task_id: Uuid) {
+        // We could test if the return_addr is ourselves, but it's easier to just search for the uuid in our registry
+        // and if we have it then the return_addr is ourselves otherwise it's someone else and we need to connect to them.
+        let mut buf_registry_write_lock = self.output_buffer_registry.write().await;
+        if let Some(local_buf) = buf_registry_write_lock.get_mut(&task_id) {
+            info!("Result delivered locally!");
+            *local_buf = result;
+            drop(buf_registry_write_lock);
+            if let Some(notifier) = self.notifier_registry.read().await.get(&task_id) {
+                notifier.add_permits(Semaphore::MAX_PERMITS);
+            }
+        } else {
+            drop(buf_registry_write_lock);
+            self.queue_return(return_addr, |pending| {
+                pending.results.push((task_id, result))
+            })
+            .await;
+        }
+    }
+}
+
+async fn send_ack(
+    other_peer_connection: &mut TcpStream,
+    task_id: Uuid,
+    our_addr: SocketAddr,
+) -> io::Result<()> {
+    PeerMessage::TaskStarted
+        .write(other_peer_connection)
+        .await?;
+    other_peer_connection.write_u128(task_id.as_u128()).await?;
+    clustered::networking::write_ip_addr(other_peer_connection, our_addr.ip()).await?;
+    other_peer_connection.write_u16(our_addr.port()).await
+}
+
+// Sends everything in return_outbox for return_addr until there's nothing left, then removes its entry
+// A batch that fails to send goes back in front of whatever was queued since and is tried again over a new connection,
+// after RETURN_SEND_ATTEMPTS failed tries what's left is dropped (and logged), its submitters will time out waiting for it
+async fn send_returns(
+    return_addr: SocketAddr,
+    return_outbox: ReturnOutboxType,
+    socket_options: SocketOptions,
 ) {
-    println!("Info: Consuming task!");
-    let task_uuid = Uuid::from_u128(task.id);
-    let Some(result) = task.program.run(device, queue).await else {
-        println!("Error: Failed to run task, discarding it!");
+    for attempt in 1..=RETURN_SEND_ATTEMPTS {
+        match send_pending_returns(return_addr, &return_outbox, &socket_options).await {
+            Ok(()) => return,
+            Err(err) => {
+                warn!("{err}\nWhile returning data to other peer: {return_addr} (attempt {attempt} of {RETURN_SEND_ATTEMPTS})");
+            }
+        }
+        if attempt < RETURN_SEND_ATTEMPTS {
+            sleep(RETURN_RETRY_DELAY).await;
+        }
+    }
+
+    let Some(dropped) = return_outbox.lock().await.remove(&return_addr) else {
         return;
     };
-    tokio::spawn(return_data(
-        result,
-        task.return_addr,
-        task_uuid,
-        output_buffer_registry,
-        notifier_registry,
-    ));
+    for (task_id, _) in &dropped.acks {
+        clustered::logging::task_span(*task_id).in_scope(|| {
+            error!("Couldn't acknowledge task to its submitter: {return_addr}, dropping the ack!")
+        });
+    }
+    for (task_id, _) in &dropped.results {
+        clustered::logging::task_span(*task_id).in_scope(|| {
+            error!("Couldn't return result to its submitter: {return_addr}, dropping it!")
+        });
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PeerAddr(SocketAddrV4);
-
-async fn steal_task(
-    task_queue: TaskQueueType,
-    tracker_connection: Arc<Mutex<TcpStream>>,
+// One connection's worth of send_returns, on an error the batch being sent is back in the outbox
+async fn send_pending_returns(
+    return_addr: SocketAddr,
+    return_outbox: &ReturnOutboxType,
+    socket_options: &SocketOptions,
 ) -> io::Result<()> {
-    let peer_list = {
-        let mut tracker_connection_lock = tracker_connection.lock().await;
+    let mut other_peer_connection = connect_to_other_peer(return_addr, socket_options).await?;
 
-        // Message id 1 is "get peer list" for tracker
-        tracker_connection_lock.write_u8(1).await.map_err(|err| {
-            io::Error::new(
-                err.kind(),
-                format!(
-                    "{err}\nWhile sending message id to tracker\nWhile attempting to steal tasks"
-                ),
-            )
-        })?;
+    loop {
+        let batch = {
+            let mut return_outbox_lock = return_outbox.lock().await;
+            let Some(pending) = return_outbox_lock.get_mut(&return_addr) else {
+                return Ok(());
+            };
+            if pending.is_empty() {
+                return_outbox_lock.remove(&return_addr);
+                return Ok(());
+            }
+            core::mem::take(pending)
+        };
+
+        if let Err(err) = send_batch(&mut other_peer_connection, &batch).await {
+            // NOTE: Part of it may have gotten through, resending an ack or result the submitter already has is harmless
+            let mut return_outbox_lock = return_outbox.lock().await;
+            let pending = return_outbox_lock.entry(return_addr).or_default();
+            let queued_since = core::mem::replace(pending, batch);
+            pending.acks.extend(queued_since.acks);
+            pending.results.extend(queued_since.results);
+            return Err(err);
+        }
+        for (task_id, _) in &batch.results {
+            clustered::logging::task_span(*task_id)
+                .in_scope(|| info!("Returned result to its submitter: {return_addr}"));
+        }
+    }
+}
 
-        let raw_peer_list = clustered::networking::read_buf(&mut tracker_connection_lock)
+async fn send_batch(
+    other_peer_connection: &mut TcpStream,
+    batch: &PendingReturns,
+) -> io::Result<()> {
+    // Acks first, a result overtaking its task's ack would leave the submitter thinking it's still running
+    for (task_id, our_addr) in &batch.acks {
+        send_ack(other_peer_connection, *task_id, *our_addr)
             .await
             .map_err(|err| {
                 io::Error::new(
                     err.kind(),
-                    format!("{err}\nWhile receiving peer list from tracker\nWhile attempting to steal tasks"),
+                    format!("{err}\nWhile acknowledging task {task_id}"),
                 )
             })?;
-
-        serde_json::from_slice::<Vec<PeerAddr>>(&raw_peer_list)
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err}\nWhile deserialising peer list received from tracker\nWhile attempting to steal tasks")))?
-    };
-
+    }
+    if batch.results.is_empty() {
+        return Ok(());
+    }
+    send_return_batch(other_peer_connection, &batch.results)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{err}\nWhile returning {} task results",
+                    batch.results.len()
+                ),
+            )
+        })
+}
+
+async fn send_return_batch(
+    other_peer_connection: &mut TcpStream,
+    batch: &[(Uuid, TaskResult)],
+) -> io::Result<()> {
+    PeerMessage::ReturnResults
+        .write(other_peer_connection)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending message id to other peer"),
+            )
+        })?;
+    other_peer_connection
+        .write_u64(batch.len() as u64)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending result count to other peer"),
+            )
+        })?;
+    for (task_id, result) in batch {
+        other_peer_connection
+            .write_u128(task_id.as_u128())
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{err}\nWhile sending task uuid to other peer"),
+                )
+            })?;
+        let status = match result {
+            Ok(_) => RESULT_STATUS_OK,
+            Err(TaskError::DeadlineExceeded) => RESULT_STATUS_DEADLINE_EXCEEDED,
+            Err(TaskError::ExecutionFailed(_)) => RESULT_STATUS_EXECUTION_FAILED,
+            Err(TaskError::Cancelled) => RESULT_STATUS_CANCELLED,
+        };
+        other_peer_connection
+            .write_u8(status)
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{err}\nWhile sending result status to other peer"),
+                )
+            })?;
+        match result {
+            Ok(data) => {
+                clustered::networking::write_buf(other_peer_connection, data)
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!("{err}\nWhile sending return data to other peer"),
+                        )
+                    })?;
+            }
+            Err(TaskError::ExecutionFailed(message)) => {
+                clustered::networking::write_buf(other_peer_connection, message.as_bytes())
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!("{err}\nWhile sending execution error to other peer"),
+                        )
+                    })?;
+            }
+            Err(_) => {}
+        }
+    }
+    Ok(())
+}
+
+async fn consume_task(
+    task: Task,
+    result_returner: ResultReturner,
+    executor: &GpuExecutor,
+    shadow_checker: Arc<ShadowChecker>,
+    completed_tasks: Arc<AtomicU64>,
+) {
+    info!("Consuming task!");
+    let task_uuid = Uuid::from_u128(task.id);
+    if task.is_past_deadline() {
+        // The submitter can't use the result anymore, so don't waste gpu time on it
+        warn!("Task is past its deadline, skipping it!");
+        tokio::spawn(
+            async move {
+                result_returner
+                    .return_data(
+                        Err(TaskError::DeadlineExceeded),
+                        task.return_addr,
+                        task_uuid,
+                    )
+                    .await
+            }
+            .in_current_span(),
+        );
+        return;
+    }
+    // The program came from another peer, so don't trust its sizes, and the cpu fallback wouldn't fare any better with it
+    if let Err(err) = task.program.validate(&executor.limits()) {
+        error!("Task's program is invalid ({err}), returning the error to its submitter!");
+        tokio::spawn(
+            async move {
+                result_returner
+                    .return_data(
+                        Err(TaskError::ExecutionFailed(err.to_string())),
+                        task.return_addr,
+                        task_uuid,
+                    )
+                    .await
+            }
+            .in_current_span(),
+        );
+        return;
+    }
+    result_returner
+        .ack_started(task.return_addr, task_uuid)
+        .await;
+    let result = match executor.execute(&task.program).await {
+        Ok(val) => val,
+        // Only the gpu itself failing is worth the cpu fallback, a program the gpu rejected would just fail there too, only slower
+        Err(err) if !err.is_gpu_failure() && !executor.is_lost() => {
+            error!("Failed to run task ({err}), returning the error to its submitter!");
+            tokio::spawn(
+                async move {
+                    result_returner
+                        .return_data(
+                            Err(TaskError::ExecutionFailed(err.to_string())),
+                            task.return_addr,
+                            task_uuid,
+                        )
+                        .await
+                }
+                .in_current_span(),
+            );
+            return;
+        }
+        Err(err) => {
+            // Better to produce the result slowly than to never produce it
+            warn!("Failed to run task on the gpu ({err}), falling back to running it on the cpu, this will be slow!");
+            match task.program.run_on_cpu_fallback().await {
+                Ok(val) => val,
+                Err(err) => {
+                    error!("Failed to run task, even on the cpu fallback ({err}), returning the error to its submitter!");
+                    tokio::spawn(
+                        async move {
+                            result_returner
+                                .return_data(
+                                    Err(TaskError::ExecutionFailed(err.to_string())),
+                                    task.return_addr,
+                                    task_uuid,
+                                )
+                                .await
+                        }
+                        .in_current_span(),
+                    );
+                    return;
+                }
+            }
+        }
+    };
+
+    completed_tasks.fetch_add(1, Ordering::Relaxed);
+    info!("Finished running task!");
+
+    // Spot check the result on the cpu while it's being returned, to catch a gpu that silently miscomputes
+    if shadow_checker.is_enabled() {
+        let result = result.clone();
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.entered();
+            let Some(report) = shadow_checker.check(&task.program, &result) else {
+                return;
+            };
+            if !report.passed() {
+                error!(
+                    "Shadow cpu check of task found {} mismatching elements out of {} checked (first: {}), the gpu might be miscomputing!",
+                    report.mismatching_elems.len(),
+                    report.n_checked,
+                    report.mismatching_elems[0]
+                );
+            }
+        });
+    }
+
+    tokio::spawn(
+        async move {
+            result_returner
+                .return_data(Ok(result), task.return_addr, task_uuid)
+                .await
+        }
+        .in_current_span(),
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerAddr(SocketAddr);
+
+async fn report_bad_peer(
+    tracker_connection: Arc<Mutex<TcpStream>>,
+    bad_peer: SocketAddr,
+) -> io::Result<()> {
+    let serialised_peer =
+        clustered::networking::to_versioned_json(&PeerAddr(bad_peer)).map_err(|err| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{err}\nWhile serialising bad peer: {bad_peer:?}"),
+            )
+        })?;
+
+    let mut tracker_connection_lock = tracker_connection.lock().await;
+
+    TrackerCommand::ReportBadPeer
+        .write(&mut *tracker_connection_lock)
+        .await
+        .map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile sending message id to tracker\nWhile reporting bad peer: {bad_peer:?}"),
+        )
+    })?;
+
+    clustered::networking::write_buf(&mut *tracker_connection_lock, &serialised_peer)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending bad peer to tracker\nWhile reporting bad peer: {bad_peer:?}"),
+            )
+        })
+}
+
+async fn report_completed_tasks(
+    tracker_connection: &Mutex<TcpStream>,
+    completed_tasks: u64,
+) -> io::Result<()> {
+    let mut tracker_connection_lock = tracker_connection.lock().await;
+
+    TrackerCommand::ReportCompletedTasks
+        .write(&mut *tracker_connection_lock)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "{err}\nWhile sending message id to tracker\nWhile reporting completed tasks"
+                ),
+            )
+        })?;
+
+    tracker_connection_lock
+        .write_u64(completed_tasks)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending completed task count to tracker\nWhile reporting completed tasks"),
+            )
+        })
+}
+
+// What our runner's gpu device can do, set once the runner has it, see advertise_features
+static OUR_FEATURES: std::sync::OnceLock<wgpu::Features> = std::sync::OnceLock::new();
+
+// Tells the tracker what our gpu can do, so submitters can tell whether anyone in the cluster can run their tasks
+// NOTE: The tracker forgets it when we disconnect, so reconnect_to_tracker advertises it again
+async fn advertise_features(
+    tracker_connection: &Mutex<TcpStream>,
+    features: wgpu::Features,
+) -> io::Result<()> {
+    let mut tracker_connection_lock = tracker_connection.lock().await;
+
+    TrackerCommand::AdvertiseFeatures
+        .write(&mut *tracker_connection_lock)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending message id to tracker\nWhile advertising features"),
+            )
+        })?;
+
+    tracker_connection_lock
+        .write_u64(features.bits())
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending features to tracker\nWhile advertising features"),
+            )
+        })
+}
+
+// Periodically tells the tracker how many tasks we've completed in total, so it can work out the cluster's throughput
+async fn stats_reporter(
+    tracker_connection: Arc<Mutex<TcpStream>>,
+    config: Arc<PeerConfig>,
+    our_addr: OurAddrType,
+    completed_tasks: Arc<AtomicU64>,
+    interval: Duration,
+    shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        sleep(interval).await;
+        if let Err(err) =
+            report_completed_tasks(&tracker_connection, completed_tasks.load(Ordering::Relaxed))
+                .await
+        {
+            if clustered::networking::was_connection_severed(err.kind()) {
+                reconnect_to_tracker(
+                    &tracker_connection,
+                    config.tracker_addr,
+                    &config.socket_options,
+                    &our_addr,
+                    shutdown.clone(),
+                )
+                .await;
+            } else {
+                error!("{err}");
+            }
+        }
+    }
+}
+
+// Every other peer the tracker currently knows about (minus blacklisted ones)
+async fn get_peer_list(tracker_connection: &Mutex<TcpStream>) -> io::Result<Vec<PeerAddr>> {
+    let mut tracker_connection_lock = tracker_connection.lock().await;
+
+    TrackerCommand::ListPeers
+        .write(&mut *tracker_connection_lock)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending message id to tracker"),
+            )
+        })?;
+
+    let raw_peer_list = clustered::networking::read_buf_limited(
+        &mut *tracker_connection_lock,
+        clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+    )
+    .await
+    .map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile receiving peer list from tracker"),
+        )
+    })?;
+
+    clustered::networking::from_versioned_json::<Vec<PeerAddr>>(&raw_peer_list).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{err}\nWhile deserialising peer list received from tracker"),
+        )
+    })
+}
+
+// The features of every peer in the cluster (minus blacklisted ones, plus us), None for peers that haven't advertised any yet
+async fn get_peer_features(
+    tracker_connection: &Mutex<TcpStream>,
+) -> io::Result<Vec<Option<wgpu::Features>>> {
+    let mut tracker_connection_lock = tracker_connection.lock().await;
+
+    TrackerCommand::ListPeerFeatures
+        .write(&mut *tracker_connection_lock)
+        .await
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile sending message id to tracker"),
+            )
+        })?;
+
+    let raw_peer_features = clustered::networking::read_buf_limited(
+        &mut *tracker_connection_lock,
+        clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+    )
+    .await
+    .map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile receiving peer features from tracker"),
+        )
+    })?;
+
+    clustered::networking::from_versioned_json::<Vec<Option<u64>>>(&raw_peer_features)
+        .map(|peer_features| {
+            peer_features
+                .into_iter()
+                .map(|bits| bits.map(wgpu::Features::from_bits_truncate))
+                .collect()
+        })
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{err}\nWhile deserialising peer features received from tracker"),
+            )
+        })
+}
+
+async fn steal_task(
+    task_queue: TaskQueueType,
+    tracker_connection: Arc<Mutex<TcpStream>>,
+    socket_options: &SocketOptions,
+) -> io::Result<()> {
+    let peer_list = get_peer_list(&tracker_connection).await.map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile attempting to steal tasks"),
+        )
+    })?;
+
     if peer_list.is_empty() {
-        // Prevent a hot loop
-        sleep(Duration::from_millis(100)).await;
+        // Prevent a hot loop, jittered so peers started together don't all ask the tracker again in lockstep
+        let backoff_millis = rand::thread_rng().gen_range(EMPTY_PEER_LIST_BACKOFF_MILLIS);
+        sleep(Duration::from_millis(backoff_millis)).await;
     }
 
     for other_peer in peer_list {
         let mut other_peer_connection =
-            match connect_to_other_peer(SocketAddr::V4(other_peer.0)).await {
+            match connect_to_other_peer(other_peer.0, socket_options).await {
                 Ok(val) => val,
                 Err(err) => {
                     // Connection refused might happen if the peer disconnects after we have gotten the peer list from the tracker
@@ -227,10 +1196,8 @@ async fn steal_task(
                     if !clustered::networking::was_connection_severed(err.kind())
                         && err.kind() != ErrorKind::ConnectionRefused
                     {
-                        println!("Notice:");
-                        println!("{err}");
-                        println!(
-                            "While attempting to steal task from other peer: {:?}",
+                        warn!(
+                            "{err}\nWhile attempting to steal task from other peer: {:?}",
                             other_peer.0
                         );
                     }
@@ -238,55 +1205,84 @@ async fn steal_task(
                 }
             };
 
-        // Message id 1 is "steal task" for peers
-        if let Err(err) = other_peer_connection.write_u8(1).await {
+        if let Err(err) = PeerMessage::StealTask
+            .write(&mut other_peer_connection)
+            .await
+        {
             if !clustered::networking::was_connection_severed(err.kind()) {
-                println!("Notice:");
-                println!("{err}");
-                println!("While sending message id to other peer: {:?}", other_peer.0);
-                println!(
-                    "While attempting to steal task from other peer: {:?}",
-                    other_peer.0
+                warn!(
+                    "{err}\nWhile sending message id to other peer: {:?}\nWhile attempting to steal task from other peer: {:?}",
+                    other_peer.0, other_peer.0
                 );
             }
             continue;
         };
 
-        let raw_res = match clustered::networking::read_buf(&mut other_peer_connection).await {
+        let n_tasks = match other_peer_connection.read_u64().await {
             Ok(val) => val,
             Err(err) => {
                 if !clustered::networking::was_connection_severed(err.kind()) {
-                    println!("Notice:");
-                    println!("{err}");
-                    println!("While receiveing task from other peer: {:?}", other_peer.0);
-                    println!(
-                        "While attempting to steal task from other peer: {:?}",
-                        other_peer.0
+                    warn!(
+                        "{err}\nWhile receiveing task count from other peer: {:?}\nWhile attempting to steal task from other peer: {:?}",
+                        other_peer.0, other_peer.0
                     );
                 }
                 continue;
             }
         };
 
-        drop(other_peer_connection);
+        let mut stolen_tasks = Vec::new();
+        for _ in 0..n_tasks {
+            let raw_task = match clustered::networking::read_buf_limited(
+                &mut other_peer_connection,
+                clustered::networking::DEFAULT_MAX_BUF_LEN,
+            )
+            .await
+            {
+                Ok(val) => val,
+                Err(err) => {
+                    if !clustered::networking::was_connection_severed(err.kind()) {
+                        warn!(
+                            "{err}\nWhile receiveing task from other peer: {:?}\nWhile attempting to steal task from other peer: {:?}",
+                            other_peer.0, other_peer.0
+                        );
+                    }
+                    break;
+                }
+            };
 
-        let res: Option<Task> = match serde_json::from_slice(&raw_res) {
-            Ok(val) => val,
-            Err(err) => {
-                println!("Notice:");
-                println!("{err}");
-                println!("While deserialising task received from other peer {other_peer:?}!");
-                println!(
-                    "While attempting to steal task from other peer: {:?}",
-                    other_peer.0
-                );
-                continue;
+            match Task::from_wire(&raw_task) {
+                Ok(tsk) => stolen_tasks.push(tsk),
+                Err(err) => {
+                    warn!(
+                        "{err}\nWhile deserialising task received from other peer {other_peer:?}!\nWhile attempting to steal task from other peer: {:?}",
+                        other_peer.0
+                    );
+                    // Sending us garbage is misbehaving, let the tracker know so it can stop handing this peer out
+                    if let Err(err) =
+                        report_bad_peer(tracker_connection.clone(), other_peer.0).await
+                    {
+                        warn!("{err}");
+                    }
+                    break;
+                }
             }
-        };
+        }
 
-        if let Some(tsk) = res {
-            println!("Info: Just stole a task, from: {:?}!", other_peer.0);
-            task_queue.lock().await.push(tsk);
+        drop(other_peer_connection);
+
+        // Whatever arrived intact is ours to run now, the other peer already took it out of its queue
+        if !stolen_tasks.is_empty() {
+            info!(
+                "Just stole {} task(s), from: {:?}!",
+                stolen_tasks.len(),
+                other_peer.0
+            );
+            for tsk in &stolen_tasks {
+                clustered::logging::task_span(Uuid::from_u128(tsk.id))
+                    .in_scope(|| info!("Stolen from: {:?}", other_peer.0));
+            }
+            task_queue.lock().await.extend(stolen_tasks);
             break;
         }
     }
@@ -295,9 +1291,14 @@ async fn steal_task(
 
 async fn runner(
     task_queue: TaskQueueType,
-    output_buffer_registry: BufferRegistryType,
-    notifier_registry: NotifierRegistryType,
+    result_returner: ResultReturner,
     tracker_connection: Arc<Mutex<TcpStream>>,
+    config: Arc<PeerConfig>,
+    shadow_checker: Arc<ShadowChecker>,
+    completed_tasks: Arc<AtomicU64>,
+    peer_control: PeerControl,
+    our_addr: OurAddrType,
+    shutdown: watch::Receiver<bool>,
 ) {
     let instance = wgpu::Instance::new(InstanceDescriptor::default());
     let adapter = instance
@@ -308,60 +1309,105 @@ async fn runner(
         })
         .await
         .expect("Should be able to acquire adapter!");
-    println!("Runner is using {:?}", adapter.get_info());
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::BUFFER_BINDING_ARRAY
-                    | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY,
-                required_limits: wgpu::Limits::default(),
-                memory_hints: wgpu::MemoryHints::default(),
-            },
-            None,
-        )
-        .await
-        .expect("Should be able to get handle on device!");
+    info!("Runner is using {:?}", adapter.get_info());
+    let executor = GpuExecutor::new(
+        adapter,
+        wgpu::Features::BUFFER_BINDING_ARRAY | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY,
+        wgpu::Limits::default(),
+    )
+    .await
+    .expect("Should be able to get handle on device!");
+    let gpu = executor.gpu();
+    let features = *OUR_FEATURES.get_or_init(|| gpu.device().features());
+    if let Err(err) = advertise_features(&tracker_connection, features).await {
+        warn!("{err}\nWhile starting the runner");
+    }
+    match clustered::bandwidth::measure_transfer_bandwidth(gpu.device(), gpu.queue()).await {
+        Ok(bandwidth) => info!("Runner transfer bandwidth: {bandwidth}"),
+        Err(err) => warn!("Couldn't measure transfer bandwidth: {err}"),
+    }
 
     async fn steal_task_wrapper(
         task_queue: TaskQueueType,
         tracker_connection: Arc<Mutex<TcpStream>>,
+        config: Arc<PeerConfig>,
+        our_addr: OurAddrType,
+        shutdown: watch::Receiver<bool>,
     ) {
-        if let Err(err) = steal_task(task_queue, tracker_connection).await {
+        if let Err(err) = steal_task(
+            task_queue,
+            tracker_connection.clone(),
+            &config.socket_options,
+        )
+        .await
+        {
             if clustered::networking::was_connection_severed(err.kind()) {
-                println!("FATAL: Lost connection to tracker!");
+                reconnect_to_tracker(
+                    &tracker_connection,
+                    config.tracker_addr,
+                    &config.socket_options,
+                    &our_addr,
+                    shutdown,
+                )
+                .await;
             } else {
-                println!("Error:");
-                println!("{err}");
+                error!("{err}");
             }
         }
     }
 
     loop {
+        // Otherwise every task from here on would fail on the lost device
+        if let Err(err) = executor.recover_if_lost().await {
+            error!("Couldn't recreate lost gpu device, retrying in a second, error was: {err}");
+            sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        // Once shutting down we finish what's queued, but like when paused don't take on anything new
+        let shutting_down = *shutdown.borrow();
         let mut task_queue_guard = task_queue.lock().await;
-        let mut task_queue_len = task_queue_guard.len();
         if let Some(tsk) = task_queue_guard.pop() {
+            let low_on_work = should_steal(&task_queue_guard, &config);
             drop(task_queue_guard);
-            task_queue_len -= 1;
-            if task_queue_len <= MINIMUM_TASKS_BEFORE_START_STEALING_TRESH {
+            if low_on_work && !peer_control.is_paused() && !shutting_down {
                 tokio::spawn(steal_task_wrapper(
                     task_queue.clone(),
                     tracker_connection.clone(),
+                    config.clone(),
+                    our_addr.clone(),
+                    shutdown.clone(),
                 ));
             }
+            let span = clustered::logging::task_span(Uuid::from_u128(tsk.id));
             consume_task(
                 tsk,
-                output_buffer_registry.clone(),
-                notifier_registry.clone(),
-                &device,
-                &queue,
+                result_returner.clone(),
+                &executor,
+                shadow_checker.clone(),
+                completed_tasks.clone(),
             )
+            .instrument(span)
             .await;
+        } else if shutting_down {
+            info!("Runner drained, stopping!");
+            return;
+        } else if peer_control.is_paused() {
+            drop(task_queue_guard);
+            // Drained, wait to be resumed
+            sleep(PAUSED_POLL_INTERVAL).await;
         } else {
             drop(task_queue_guard);
             // Queue is empty, there's no point in spawning steal_task to run concurrently as we need to wait for a task to be stolen anyways
             // This also ensures that steal_task doesn't get spammed in parallel when the queue is empty causing the equivalent of a fork bomb
-            steal_task_wrapper(task_queue.clone(), tracker_connection.clone()).await;
+            steal_task_wrapper(
+                task_queue.clone(),
+                tracker_connection.clone(),
+                config.clone(),
+                our_addr.clone(),
+                shutdown.clone(),
+            )
+            .await;
         }
     }
 }
@@ -369,23 +1415,32 @@ async fn runner(
 async fn handle_other_peer(
     mut other_stream: TcpStream,
     task_queue: TaskQueueType,
-    output_buffer_registry: BufferRegistryType,
-    notifier_registry: NotifierRegistryType,
+    result_returner: ResultReturner,
+    config: Arc<PeerConfig>,
+    peer_control: PeerControl,
 ) -> io::Result<()> {
-    let magic_sequence = String::from_utf8(
-        clustered::networking::read_buf(&mut other_stream).await?,
+    let ResultReturner {
+        output_buffer_registry,
+        notifier_registry,
+        started_registry,
+        ..
+    } = &result_returner;
+    let magic_sequence = clustered::networking::read_buf_limited(
+        &mut other_stream,
+        clustered::networking::MAX_CONTROL_MESSAGE_LEN,
     )
-    .map_err(|err| {
-        io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!("Error: {err}\nWhile parsing magic sequence"),
-        )
-    })?;
-
-    if magic_sequence != MAGIC_PEER2PEER_SEQUENCE {
+    .await?;
+    if !clustered::networking::constant_time_eq(
+        &magic_sequence,
+        MAGIC_PEER2PEER_SEQUENCE.as_bytes(),
+    ) {
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            format!("Error: Magic sequence was valid utf-8, but it's not correct. Here it is: {magic_sequence:?}"),
+            format!(
+                "Error: Bad magic {} received from peer {:?}",
+                clustered::networking::describe_bad_magic(&magic_sequence),
+                other_stream.peer_addr()
+            ),
         ));
     }
 
@@ -399,212 +1454,1999 @@ async fn handle_other_peer(
                 ),
             )
         })?;
-        match message_id {
-            1 => {
+        match PeerMessage::from_u8(message_id) {
+            Some(PeerMessage::StealTask) => {
                 // Other peer wants to steal from us
-                // TODO: We just pick at random for now
-                let mut task_queue_lock = task_queue.lock().await;
-                let response = if task_queue_lock.len() <= NO_STEAL_TRESHOLD {
-                    // We don't have enough tasks to benefit from giving to someone else
+                // Answered with the number of tasks we're giving away (u64), then every task as a buf, zero means none
+                let given_tasks = if peer_control.is_paused() {
+                    // We're being drained, queued tasks stay with us
+                    Vec::new()
+                } else {
+                    // If we don't have enough tasks to benefit from giving to someone else this gives nothing,
                     // by the time it takes to transfer the task and and receive the result we are better off just running the task ourselves
-                    None
+                    take_tasks_for_stealer(&mut *task_queue.lock().await, &config)
+                };
+
+                // Tasks we can't serialise can't be run by anyone else either, so their submitters get an error back
+                let mut sendable_tasks = Vec::new();
+                for task in given_tasks {
+                    let task_uuid = Uuid::from_u128(task.id);
+                    match task.to_wire() {
+                        Ok(serialised_task) => sendable_tasks.push((task, serialised_task)),
+                        Err(err) => {
+                            let span = clustered::logging::task_span(task_uuid);
+                            span.in_scope(|| error!("Couldn't serialise task, failing it, this is probably a bug in the serialising implementation, error was: {err}!"));
+                            result_returner
+                                .return_data(
+                                    Err(TaskError::ExecutionFailed(format!(
+                                        "Couldn't serialise task to give it to a stealer: {err}"
+                                    ))),
+                                    task.return_addr,
+                                    task_uuid,
+                                )
+                                .instrument(span)
+                                .await;
+                        }
+                    }
+                }
+
+                let mut n_sent = 0;
+                let send_res: io::Result<()> = async {
+                    other_stream
+                        .write_u64(sendable_tasks.len() as u64)
+                        .await
+                        .map_err(|err| {
+                            io::Error::new(
+                                err.kind(),
+                                format!(
+                                    "Error: {err}\n While sending task count to peer: {:?}",
+                                    other_stream.peer_addr()
+                                ),
+                            )
+                        })?;
+                    for (task, serialised_task) in &sendable_tasks {
+                        clustered::networking::write_buf(&mut other_stream, serialised_task)
+                            .await
+                            .map_err(|err| {
+                                io::Error::new(
+                                    err.kind(),
+                                    format!(
+                                        "Error: {err}\n While sending task to peer: {:?}",
+                                        other_stream.peer_addr()
+                                    ),
+                                )
+                            })?;
+                        n_sent += 1;
+                        clustered::logging::task_span(Uuid::from_u128(task.id))
+                            .in_scope(|| info!("Given to stealer: {:?}", other_stream.peer_addr()));
+                    }
+                    Ok(())
+                }
+                .await;
+                if let Err(err) = send_res {
+                    // The stealer only keeps whole tasks, so everything from the one that failed on is still ours, back to the front of the queue
+                    // NOTE: The ones written before the error are treated as sent, even though they may not have made it
+                    let unsent_tasks = sendable_tasks
+                        .into_iter()
+                        .skip(n_sent)
+                        .map(|(task, _)| task)
+                        .collect::<Vec<_>>();
+                    warn!(
+                        "Couldn't give {} task(s) to stealer: {:?}, keeping them!",
+                        unsent_tasks.len(),
+                        other_stream.peer_addr()
+                    );
+                    task_queue.lock().await.splice(0..0, unsent_tasks);
+                    return Err(err);
+                }
+            }
+            Some(PeerMessage::ReturnResults) => {
+                // Other peer wants to send us a batch of task results
+                let n_results = other_stream.read_u64().await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiveing result count from peer {:?}\nWhile handling return task result message from peer {:?}",
+                            other_stream.peer_addr(), other_stream.peer_addr()
+                        ),
+                    )
+                })?;
+
+                for _ in 0..n_results {
+                    let task_uuid = Uuid::from_u128(
+                        other_stream.read_u128().await.map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!(
+                                "Error: {err}\nWhile receiveing uuid from peer {:?}\nWhile handling return task result message from peer {:?}",
+                                other_stream.peer_addr(), other_stream.peer_addr()
+                            ),
+                        )
+                    })?
+                    );
+
+                    let status = other_stream.read_u8().await.map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!(
+                                "Error: {err}\nWhile receiveing result status from peer {:?}\nWhile handling return task result message from peer {:?}",
+                                other_stream.peer_addr(), other_stream.peer_addr()
+                            ),
+                        )
+                    })?;
+                    let result = match status {
+                        RESULT_STATUS_OK => Ok(clustered::networking::read_buf_limited(&mut other_stream, clustered::networking::DEFAULT_MAX_BUF_LEN).await.map_err(|err| {
+                            io::Error::new(
+                                err.kind(),
+                                format!(
+                                    "Error: {err}\n While receiveing buffer data from peer {:?}\nWhile handling return task result message from peer {:?}",
+                                    other_stream.peer_addr(), other_stream.peer_addr()
+                                ),
+                            )
+                        })?),
+                        RESULT_STATUS_DEADLINE_EXCEEDED => Err(TaskError::DeadlineExceeded),
+                        RESULT_STATUS_EXECUTION_FAILED => Err(TaskError::ExecutionFailed(
+                            String::from_utf8_lossy(&clustered::networking::read_buf_limited(&mut other_stream, clustered::networking::MAX_CONTROL_MESSAGE_LEN).await.map_err(|err| {
+                                io::Error::new(
+                                    err.kind(),
+                                    format!(
+                                        "Error: {err}\n While receiveing execution error from peer {:?}\nWhile handling return task result message from peer {:?}",
+                                        other_stream.peer_addr(), other_stream.peer_addr()
+                                    ),
+                                )
+                            })?).into_owned(),
+                        )),
+                        RESULT_STATUS_CANCELLED => Err(TaskError::Cancelled),
+                        _ => {
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("Error: Unknown result status({status}) received for task {task_uuid}!"),
+                            ));
+                        }
+                    };
+
+                    if let Some(buf) = output_buffer_registry.write().await.get_mut(&task_uuid) {
+                        clustered::logging::task_span(task_uuid).in_scope(|| {
+                            info!("Result arrived from: {:?}", other_stream.peer_addr())
+                        });
+                        *buf = result;
+                    } else {
+                        // We gave up on it, see ClusterJob::await_result, the rest of the batch is still good
+                        warn!(
+                            "Result for task {task_uuid} we no longer wait for arrived from: {:?}, dropping it!",
+                            other_stream.peer_addr()
+                        );
+                        continue;
+                    };
+
+                    if let Some(notifier) = notifier_registry.read().await.get(&task_uuid) {
+                        notifier.add_permits(Semaphore::MAX_PERMITS);
+                    }
+                }
+            }
+
+            Some(PeerMessage::SummariseQueue) => {
+                // Someone wants to see what's in our queue, without taking anything out of it
+                let summary = task_queue
+                    .lock()
+                    .await
+                    .iter()
+                    .map(TaskSummary::from)
+                    .collect::<Vec<TaskSummary>>();
+                let serialised_summary = clustered::networking::to_versioned_json(&summary)
+                    .map_err(|err| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Error: {err}\nWhile serialising task queue summary"),
+                        )
+                    })?;
+                clustered::networking::write_buf(&mut other_stream, &serialised_summary)
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!(
+                                "Error: {err}\nWhile sending task queue summary to peer: {:?}",
+                                other_stream.peer_addr()
+                            ),
+                        )
+                    })?;
+            }
+            Some(PeerMessage::TaskStarted) => {
+                // A peer started running one of our tasks
+                let task_uuid = Uuid::from_u128(other_stream.read_u128().await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiveing uuid from peer {:?}\nWhile handling task started message from peer {:?}",
+                            other_stream.peer_addr(), other_stream.peer_addr()
+                        ),
+                    )
+                })?);
+                let holder_ip = clustered::networking::read_ip_addr(&mut other_stream).await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiveing address from peer {:?}\nWhile handling task started message from peer {:?}",
+                            other_stream.peer_addr(), other_stream.peer_addr()
+                        ),
+                    )
+                })?;
+                let holder_port = other_stream.read_u16().await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiveing port from peer {:?}\nWhile handling task started message from peer {:?}",
+                            other_stream.peer_addr(), other_stream.peer_addr()
+                        ),
+                    )
+                })?;
+                let holder_addr = SocketAddr::new(holder_ip, holder_port);
+                // The task might have been cleaned up already (e.g. it was abandoned), then there's nothing left to track
+                // Held while marking it started, so ClusterClient::remove_task can't clean up in between and leave the entry behind
+                let buf_registry_read_lock = output_buffer_registry.read().await;
+                if buf_registry_read_lock.contains_key(&task_uuid) {
+                    clustered::logging::task_span(task_uuid)
+                        .in_scope(|| info!("Started running on: {holder_addr:?}"));
+                    started_registry
+                        .write()
+                        .await
+                        .insert(task_uuid, holder_addr);
+                }
+                drop(buf_registry_read_lock);
+            }
+            Some(PeerMessage::SetPaused) => {
+                // Someone (usually an admin, see --pause/--resume) wants us to stop or start taking on new work
+                let paused = other_stream.read_u8().await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiving pause flag from peer {:?}",
+                            other_stream.peer_addr()
+                        ),
+                    )
+                })?;
+                // Any peer could otherwise take us out of the cluster's work
+                if !other_stream
+                    .peer_addr()
+                    .is_ok_and(|addr| is_admin(addr, &config))
+                {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!(
+                            "Error: Peer {:?} isn't allowed to pause or resume us, only loopback and admin_addrs are",
+                            other_stream.peer_addr()
+                        ),
+                    ));
+                }
+                if paused != 0 {
+                    peer_control.pause();
                 } else {
-                    task_queue_lock.pop()
+                    peer_control.resume();
+                }
+            }
+            Some(PeerMessage::Ping) => {
+                // The tracker checking we're still alive, see tracker's heartbeat
+                PeerMessage::Ping
+                    .write(&mut other_stream)
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!(
+                                "Error: {err}\nWhile answering ping from peer {:?}",
+                                other_stream.peer_addr()
+                            ),
+                        )
+                    })?;
+            }
+            Some(PeerMessage::CancelTask) => {
+                // The submitter of a task gave up on it, see ClusterClient::cancel
+                let task_uuid = Uuid::from_u128(other_stream.read_u128().await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Error: {err}\nWhile receiveing uuid from peer {:?}\nWhile handling cancel task message from peer {:?}",
+                            other_stream.peer_addr(), other_stream.peer_addr()
+                        ),
+                    )
+                })?);
+                // Most peers won't have it, and one that already started running it just finishes it
+                // Only its submitter may cancel it, the connection comes from an ephemeral port so only the ip can be checked
+                let sender_ip = other_stream
+                    .peer_addr()
+                    .map(|addr| addr.ip().to_canonical())
+                    .ok();
+                let cancelled_task = {
+                    let mut task_queue_lock = task_queue.lock().await;
+                    match task_queue_lock
+                        .iter()
+                        .position(|task| task.id == task_uuid.as_u128())
+                    {
+                        Some(pos)
+                            if Some(task_queue_lock[pos].return_addr.ip().to_canonical())
+                                == sender_ip =>
+                        {
+                            Some(task_queue_lock.remove(pos))
+                        }
+                        Some(pos) => {
+                            warn!(
+                                "Peer {:?} tried to cancel task {task_uuid}, which was submitted by {:?}, ignoring it!",
+                                other_stream.peer_addr(),
+                                task_queue_lock[pos].return_addr
+                            );
+                            None
+                        }
+                        None => None,
+                    }
                 };
-                drop(task_queue_lock);
+                if let Some(task) = cancelled_task {
+                    let span = clustered::logging::task_span(task_uuid);
+                    span.in_scope(|| info!("Cancelled by its submitter, dropping it!"));
+                    // The submitter keeps the task's registry entries until something comes back for it
+                    let result_returner = result_returner.clone();
+                    tokio::spawn(
+                        async move {
+                            result_returner
+                                .return_data(Err(TaskError::Cancelled), task.return_addr, task_uuid)
+                                .await
+                        }
+                        .instrument(span),
+                    );
+                }
+            }
+            None => {
+                warn!(
+                    "Unknown message id({:?}) received from peer({:?})!",
+                    message_id,
+                    other_stream.peer_addr()
+                )
+            }
+        }
+    }
+}
+
+// The submitting side of a peer, puts tasks in our own queue (from where we or other peers pick them up)
+// and waits for the results to come back through the registries
+#[derive(Clone)]
+struct ClusterClient {
+    // Where the results of our tasks come back to, see return_addr
+    our_addr: OurAddrType,
+    task_queue: TaskQueueType,
+    output_buffer_registry: BufferRegistryType,
+    notifier_registry: NotifierRegistryType,
+    started_registry: StartedRegistryType,
+    // To find the peers to tell when a task that has left our queue is cancelled
+    tracker_connection: Arc<Mutex<TcpStream>>,
+    socket_options: SocketOptions,
+    // One permit per task that may be outstanding at once, see PeerConfig::max_in_flight_tasks
+    // Without it every result of a big batch would sit in output_buffer_registry until consumed
+    in_flight: Arc<Semaphore>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskStatus {
+    // Nobody has started running it yet, if this lasts long there might be no peers able to take it
+    Queued,
+    Running,
+    Done,
+}
+
+// Everything that can keep a submitted job from producing its result
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClusterError {
+    // Nobody, us included, picked the task up within UNPICKED_TASK_TIMEOUT (e.g. every peer is paused or busy),
+    // so it was taken back out of the queue
+    NoPeersAvailable,
+    // The peer that picked the task up couldn't run it, not even on its cpu fallback, holds that peer's error
+    RemoteExecution(String),
+    // The job's deadline passed, either before any peer got to it or while we were still waiting for its result
+    Timeout,
+    // The job was cancelled with ClusterClient::cancel before any peer started running it
+    Cancelled,
+    // The peer running the job left the cluster (the tracker stopped hearing from it) before returning the result
+    PeerLost,
+}
+
+impl std::fmt::Display for ClusterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClusterError::NoPeersAvailable => write!(
+                f,
+                "No peer picked the job up within {}s",
+                UNPICKED_TASK_TIMEOUT.as_secs()
+            ),
+            ClusterError::RemoteExecution(err) => {
+                write!(f, "The peer running the job failed to run it: {err}")
+            }
+            ClusterError::Timeout => {
+                write!(f, "The job's deadline passed before its result arrived")
+            }
+            ClusterError::Cancelled => write!(f, "The job was cancelled"),
+            ClusterError::PeerLost => {
+                write!(
+                    f,
+                    "The peer running the job left before returning its result"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClusterError {}
+
+impl From<TaskError> for ClusterError {
+    fn from(err: TaskError) -> Self {
+        match err {
+            TaskError::DeadlineExceeded => ClusterError::Timeout,
+            TaskError::ExecutionFailed(err) => ClusterError::RemoteExecution(err),
+            TaskError::Cancelled => ClusterError::Cancelled,
+        }
+    }
+}
+
+// How long await_result waits before checking up on a task that hasn't been picked up
+const UNPICKED_TASK_NOTICE_INTERVAL: Duration = Duration::from_secs(30);
+// How long a task can sit in our queue without anyone picking it up before await_result gives up on it
+const UNPICKED_TASK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// How long the registry entries of an abandoned task are kept around for the answer of the peer holding it
+const ABANDONED_TASK_ANSWER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+impl ClusterClient {
+    // Tasks submitted before we moved (see reconnect_to_tracker) keep the old address, we still listen there too
+    fn return_addr(&self) -> SocketAddr {
+        *self.our_addr.borrow()
+    }
+
+    // Workers skip the task instead of running it once the deadline has passed
+    // Waits for a free slot if max_in_flight_tasks jobs are already outstanding, the slot is freed once
+    // await_result returns (whatever it returns) or the job is dropped
+    // NOTE: So submitting more than max_in_flight_tasks jobs before awaiting or dropping any of them waits forever,
+    // use reduce (or submit_with_callback) to interleave submitting with awaiting
+    async fn submit(
+        &self,
+        program: SerialisableProgram,
+        deadline: Option<SystemTime>,
+    ) -> ClusterJob {
+        let slot = self
+            .in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore shouldn't close!");
+        self.submit_in_slot(program, deadline, slot).await
+    }
+
+    async fn submit_in_slot(
+        &self,
+        program: SerialisableProgram,
+        deadline: Option<SystemTime>,
+        slot: OwnedSemaphorePermit,
+    ) -> ClusterJob {
+        let task_id = Uuid::now_v7();
+        // The registries have to know about the task before anyone can possibly return a result for it
+        self.output_buffer_registry
+            .write()
+            .await
+            .insert(task_id, Ok(Vec::new()));
+        self.notifier_registry
+            .write()
+            .await
+            .insert(task_id, Arc::from(Semaphore::new(0)));
+        if let Some(required) = self.features_nobody_has(&program).await {
+            clustered::logging::task_span(task_id).in_scope(|| {
+                warn!("No gpu in the cluster has the features the task needs ({required:?}), running it on our cpu fallback, this will be slow!")
+            });
+            self.run_on_cpu_fallback(task_id, program).await;
+        } else {
+            self.task_queue.lock().await.push(Task {
+                program,
+                return_addr: self.return_addr(),
+                id: task_id.as_u128(),
+                deadline_unix_millis: deadline.map(unix_millis),
+            });
+            clustered::logging::task_span(task_id).in_scope(|| info!("Submitted!"));
+        }
+        ClusterJob {
+            client: self.clone(),
+            task_id,
+            deadline,
+            _slot: slot,
+            settled: false,
+        }
+    }
+
+    // The features the program needs, if the tracker knows of no peer (us included) whose gpu has them
+    // NOTE: Peers that haven't advertised their features yet might have them, as might anyone if we can't reach the tracker
+    async fn features_nobody_has(&self, program: &SerialisableProgram) -> Option<wgpu::Features> {
+        // An invalid program is for the runner to report
+        let required = clustered::reflection::required_features(&program.program)
+            .ok()
+            .filter(|required| !required.is_empty())?;
+        match get_peer_features(&self.tracker_connection).await {
+            Ok(peer_features) => peer_features
+                .iter()
+                .all(|features| features.is_some_and(|features| !features.contains(required)))
+                .then_some(required),
+            Err(err) => {
+                warn!("{err}\nWhile checking whether any peer can run a task");
+                None
+            }
+        }
+    }
+
+    // Runs the task on our cpu fallback adapter instead of queueing it, for tasks no gpu in the cluster could run
+    async fn run_on_cpu_fallback(&self, task_id: Uuid, program: SerialisableProgram) {
+        // So it counts as running (on us), rather than as waiting for a peer to pick it up
+        self.started_registry
+            .write()
+            .await
+            .insert(task_id, self.return_addr());
+        let output_buffer_registry = self.output_buffer_registry.clone();
+        let notifier_registry = self.notifier_registry.clone();
+        tokio::spawn(
+            async move {
+                let result = program.run_on_cpu_fallback().await.map_err(|err| {
+                    error!("Failed to run task on the cpu fallback ({err})!");
+                    TaskError::ExecutionFailed(err.to_string())
+                });
+                if let Some(buf) = output_buffer_registry.write().await.get_mut(&task_id) {
+                    *buf = result;
+                }
+                if let Some(notifier) = notifier_registry.read().await.get(&task_id) {
+                    notifier.add_permits(Semaphore::MAX_PERMITS);
+                }
+            }
+            .instrument(clustered::logging::task_span(task_id)),
+        );
+    }
+
+    async fn task_status(&self, task_id: Uuid) -> TaskStatus {
+        let finished = self
+            .notifier_registry
+            .read()
+            .await
+            .get(&task_id)
+            .is_none_or(|notifier| notifier.available_permits() != 0);
+        if finished {
+            TaskStatus::Done
+        } else if self.started_registry.read().await.contains_key(&task_id) {
+            TaskStatus::Running
+        } else {
+            TaskStatus::Queued
+        }
+    }
+
+    // Whether the peer that started running the task has left the cluster, the tracker drops peers that stop answering its heartbeat
+    // NOTE: If we can't reach the tracker we can't tell, so the peer is assumed to still be around
+    async fn holder_is_gone(&self, task_id: Uuid) -> bool {
+        let Some(holder_addr) = self.started_registry.read().await.get(&task_id).copied() else {
+            return false;
+        };
+        if holder_addr == self.return_addr() {
+            return false;
+        }
+        match get_peer_list(&self.tracker_connection).await {
+            Ok(peer_list) => !peer_list.iter().any(|peer| peer.0 == holder_addr),
+            Err(err) => {
+                warn!("{err}\nWhile checking on the peer running task {task_id}");
+                false
+            }
+        }
+    }
+
+    // Takes the task back out of our queue, false if some peer (maybe us) already picked it up
+    async fn take_back(&self, task_id: Uuid) -> bool {
+        let mut task_queue_lock = self.task_queue.lock().await;
+        match task_queue_lock
+            .iter()
+            .position(|task| task.id == task_id.as_u128())
+        {
+            Some(pos) => {
+                task_queue_lock.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Makes the job's await_result return ClusterError::Cancelled, returns true if it was still in our queue
+    // Otherwise some peer stole it, that peer is told to drop it, if it hasn't started running it yet
+    // await_result then returns ClusterError::Cancelled once the peer confirms, if it has the result arrives as usual
+    async fn cancel(&self, task_id: Uuid) -> bool {
+        if !self.take_back(task_id).await {
+            if self.task_status(task_id).await == TaskStatus::Queued {
+                if let Err(err) =
+                    broadcast_cancel(&self.tracker_connection, task_id, &self.socket_options).await
+                {
+                    warn!("{err}\nWhile cancelling task {task_id}");
+                }
+            }
+            return false;
+        }
+        if let Some(buf) = self.output_buffer_registry.write().await.get_mut(&task_id) {
+            *buf = Err(TaskError::Cancelled);
+        }
+        if let Some(notifier) = self.notifier_registry.read().await.get(&task_id) {
+            notifier.add_permits(Semaphore::MAX_PERMITS);
+        }
+        clustered::logging::task_span(task_id).in_scope(|| info!("Cancelled!"));
+        true
+    }
+
+    // For a job that was dropped (e.g. because its deadline passed) without its result being consumed
+    // If some peer is still holding it the registry entries are kept until its answer arrives, since the answer
+    // is what tells us the peer is done with it, if it hasn't started running it yet it's told to drop it
+    // so the answer comes back quickly, if that peer died they're cleaned up after ABANDONED_TASK_ANSWER_TIMEOUT anyway
+    async fn abandon(&self, task_id: Uuid) {
+        if self.take_back(task_id).await {
+            let _ = self.remove_task(task_id).await;
+            return;
+        }
+        let sem = self
+            .notifier_registry
+            .read()
+            .await
+            .get(&task_id)
+            .expect("Task should have notifier!")
+            .clone();
+        if self.task_status(task_id).await == TaskStatus::Queued {
+            if let Err(err) =
+                broadcast_cancel(&self.tracker_connection, task_id, &self.socket_options).await
+            {
+                warn!("{err}\nWhile cancelling abandoned task {task_id}");
+            }
+        }
+        if tokio::time::timeout(ABANDONED_TASK_ANSWER_TIMEOUT, sem.acquire())
+            .await
+            .is_err()
+        {
+            warn!("Peer holding abandoned task {task_id} never answered, forgetting about it!");
+        }
+        let _ = self.remove_task(task_id).await;
+    }
+
+    // NOTE: Cleans up the task's registry entries, so this can only be called once per task
+    async fn remove_task(&self, task_id: Uuid) -> TaskResult {
+        let raw_res = self
+            .output_buffer_registry
+            .write()
+            .await
+            .remove(&task_id)
+            .expect("Task should have output buffer!");
+        self.notifier_registry.write().await.remove(&task_id);
+        self.started_registry.write().await.remove(&task_id);
+        raw_res
+    }
+
+    // Submits the program and calls callback with its result, on a tokio task, once it arrives
+    // The returned handle finishes after the callback has run
+    async fn submit_with_callback<F>(
+        &self,
+        program: SerialisableProgram,
+        callback: F,
+    ) -> JoinHandle<()>
+    where
+        F: FnOnce(Result<Vec<u8>, ClusterError>) + Send + 'static,
+    {
+        let job = self.submit(program, None).await;
+        tokio::spawn(async move { callback(job.await_result().await) })
+    }
+
+    // Submits all the programs and folds their results in whatever order they arrive in
+    // Submissions are interleaved with folding, so at most max_in_flight_tasks results are held at once
+    async fn reduce<T, F>(
+        &self,
+        programs: Vec<SerialisableProgram>,
+        init: T,
+        mut fold: F,
+    ) -> Result<T, ClusterError>
+    where
+        F: FnMut(T, Vec<u8>) -> T,
+    {
+        let mut programs = programs.into_iter();
+        let mut next_program = programs.next();
+        let mut pending_results = FuturesUnordered::new();
+        let mut acc = init;
+        loop {
+            tokio::select! {
+                // Only waiting for the slot is raced, so losing the race can't lose the program
+                slot = self.in_flight.clone().acquire_owned(), if next_program.is_some() => {
+                    let slot = slot.expect("Semaphore shouldn't close!");
+                    let program = next_program.take().unwrap();
+                    pending_results.push(self.submit_in_slot(program, None, slot).await.await_result());
+                    next_program = programs.next();
+                }
+                Some(partial_result) = pending_results.next() => {
+                    acc = fold(acc, partial_result?);
+                }
+                else => break,
+            }
+        }
+        Ok(acc)
+    }
+}
+
+// A piece of work submitted through ClusterClient::submit
+struct ClusterJob {
+    client: ClusterClient,
+    task_id: Uuid,
+    deadline: Option<SystemTime>,
+    // Our slot in ClusterClient::in_flight, only held, freed when the job goes away
+    _slot: OwnedSemaphorePermit,
+    // Whether the registry entries were already cleaned up, otherwise dropping the job abandons the task
+    settled: bool,
+}
+
+impl Drop for ClusterJob {
+    fn drop(&mut self) {
+        if self.settled {
+            return;
+        }
+        // The slot is freed right away, only the registry entries wait for the task to be dealt with
+        let client = self.client.clone();
+        let task_id = self.task_id;
+        tokio::spawn(async move { client.abandon(task_id).await });
+    }
+}
+
+impl ClusterJob {
+    // For ClusterClient::cancel, since await_result takes the job
+    fn id(&self) -> Uuid {
+        self.task_id
+    }
+
+    async fn await_result(mut self) -> Result<Vec<u8>, ClusterError> {
+        let span = clustered::logging::task_span(self.task_id);
+        let sem = self
+            .client
+            .notifier_registry
+            .read()
+            .await
+            .get(&self.task_id)
+            .expect("Task should have notifier!")
+            .clone();
+
+        let mut unpicked_for = Duration::ZERO;
+        loop {
+            let wait = self
+                .deadline
+                .map_or(UNPICKED_TASK_NOTICE_INTERVAL, |deadline| {
+                    deadline
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .min(UNPICKED_TASK_NOTICE_INTERVAL)
+                });
+            if let Ok(permit) = tokio::time::timeout(wait, sem.acquire()).await {
+                let _ = permit.expect("Semaphore shouldn't close!");
+                break;
+            }
+
+            if self
+                .deadline
+                .is_some_and(|deadline| SystemTime::now() >= deadline)
+            {
+                // Dropping the job abandons the task, see ClusterClient::abandon
+                warn!(parent: &span, "Deadline passed before the result arrived, giving up on it!");
+                return Err(ClusterError::Timeout);
+            }
+
+            if self.client.task_status(self.task_id).await == TaskStatus::Queued {
+                unpicked_for += wait;
+                if unpicked_for >= UNPICKED_TASK_TIMEOUT
+                    && self.client.take_back(self.task_id).await
+                {
+                    let _ = self.client.remove_task(self.task_id).await;
+                    self.settled = true;
+                    warn!(parent: &span, "No peer picked the task up, taking it back!");
+                    return Err(ClusterError::NoPeersAvailable);
+                }
+                warn!(
+                    parent: &span,
+                    "Still hasn't been picked up by any peer after {}s!",
+                    unpicked_for.as_secs()
+                );
+            } else if self.client.holder_is_gone(self.task_id).await {
+                // Its result might have just made it, right before the peer left
+                if sem.available_permits() == 0 {
+                    let _ = self.client.remove_task(self.task_id).await;
+                    self.settled = true;
+                    warn!(parent: &span, "The peer running the task left the cluster, giving up on it!");
+                    return Err(ClusterError::PeerLost);
+                }
+            }
+        }
+        let res = self.client.remove_task(self.task_id).await;
+        self.settled = true;
+        info!(parent: &span, "Done!");
+        Ok(res?)
+    }
+}
+
+const SUM_SHADER: &str = r#"
+    @group(0)
+    @binding(0)
+    var<storage, read> v_in_data: array<u32>;
+
+    @group(0)
+    @binding(1)
+    var<storage, read_write> v_out_data: array<u32>;
+
+    @group(0)
+    @binding(2)
+    var<uniform> goff: u32;
+
+    // Every invocation sums a strided slice of the input into its own output element
+    @compute
+    @workgroup_size(64)
+    fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+        let actual_id = gid.x+goff;
+        if (actual_id >= arrayLength(&v_out_data)){ return; }
+        var sum: u32 = 0u;
+        for (var i = actual_id; i < arrayLength(&v_in_data); i += arrayLength(&v_out_data)) {
+            sum += v_in_data[i];
+        }
+        v_out_data[actual_id] = sum;
+    }
+"#;
+
+// Cpu equivalent of SUM_SHADER, for the shadow checker
+struct SumReference;
+
+impl CpuReference for SumReference {
+    fn out_elem_nbytes(&self) -> usize {
+        core::mem::size_of::<u32>()
+    }
+
+    fn compute_elem(&self, program: &SerialisableProgram, elem_index: usize) -> Vec<u8> {
+        let n_partial_sums = program.out_data_nbytes / core::mem::size_of::<u32>();
+        program
+            .in_data
+            .chunks_exact(core::mem::size_of::<u32>())
+            .skip(elem_index)
+            .step_by(n_partial_sums)
+            .map(|raw_elem| u32::from_le_bytes(raw_elem.try_into().unwrap()))
+            .fold(0u32, u32::wrapping_add)
+            .to_le_bytes()
+            .to_vec()
+    }
+}
+
+// Sums data into n_partial_sums partial sums, n_partial_sums must be a multiple of SUM_SHADER's workgroup size (64)
+fn sum_program(data: &[u32], n_partial_sums: usize) -> SerialisableProgram {
+    SerialisableProgram {
+        in_data: ShaderBytes::serialise_from_slice(data)
+            .into_data()
+            .into_owned(),
+        out_data_nbytes: n_partial_sums * core::mem::size_of::<u32>(),
+        program: SUM_SHADER.to_owned(),
+        entry_point: "main".to_owned(),
+        n_workgroups: n_partial_sums / 64,
+        workgroup_size: 64,
+        extra_in_data: Vec::new(),
+        extra_out_data_nbytes: Vec::new(),
+    }
+}
+
+// NOTE: Wraps on overflow, just like the shader does
+async fn sum_over_cluster(client: &ClusterClient, data: &[u32]) -> u32 {
+    const CHUNK_LEN: usize = 1024 * 1024;
+    const N_PARTIAL_SUMS_PER_CHUNK: usize = 64 * 64;
+
+    let programs = data
+        .chunks(CHUNK_LEN)
+        .map(|chunk| sum_program(chunk, N_PARTIAL_SUMS_PER_CHUNK))
+        .collect();
+
+    client
+        .reduce(programs, 0u32, |acc, partial_result| {
+            ShaderBytes::deserialise_to_iterator::<u32>(&partial_result)
+                .fold(acc, u32::wrapping_add)
+        })
+        .await
+        .expect("Summing over the cluster failed!")
+}
 
-                let serialised_response = serde_json::to_vec(&response)
-                    .unwrap_or_else(|err| {
-                        println!("Notice: Couldn't serialise task, sending empty response instead, this is probably a bug in the serialising implementation, error was: {err}!");
-                        serde_json::to_vec(&Option::<Task>::None).unwrap()
-                    });
+// How often run_stress_load reports how it's going
+const STRESS_REPORT_INTERVAL: Duration = Duration::from_secs(5);
 
-                clustered::networking::write_buf(&mut other_stream, &serialised_response)
-                    .await
-                    .map_err(|err| {
-                        io::Error::new(
-                            err.kind(),
-                            format!(
-                                "Error: {err}\n While sending task to peer: {:?}",
-                                other_stream.peer_addr()
-                            ),
-                        )
-                    })?;
-            }
-            2 => {
-                // Other peer wants to send us a task result
-                let task_uuid = Uuid::from_u128(
-                    other_stream.read_u128().await.map_err(|err| {
-                    io::Error::new(
-                        err.kind(),
-                        format!(
-                            "Error: {err}\nWhile receiveing uuid from peer {:?}\nWhile handling return task result message from peer {:?}",
-                            other_stream.peer_addr(), other_stream.peer_addr()
-                        ),
-                    )
-                })?
-                );
+// What became of the tasks of a stress run
+#[derive(Debug, Default, PartialEq, Eq)]
+struct StressReport {
+    n_submitted: u64,
+    n_completed: u64,
+    n_wrong: u64,
+    n_timed_out: u64,
+    n_failed: u64,
+}
 
-                let data = clustered::networking::read_buf(&mut other_stream).await.map_err(|err| {
-                    io::Error::new(
-                        err.kind(),
-                        format!(
-                            "Error: {err}\n While receiveing buffer data from peer {:?}\nWhile handling return task result message from peer {:?}",
-                            other_stream.peer_addr(), other_stream.peer_addr()
-                        ),
-                    )
-                })?;
+// Submits synthetic sum tasks at stress.tasks_per_sec for stress.duration_secs, then waits for the ones still outstanding
+// The periodic reports include the registry sizes, which should stay bounded by max_in_flight_tasks (plus the timed out tasks
+// still waiting for an answer, see ClusterClient::abandon) however long this runs
+async fn run_stress_load(client: &ClusterClient, stress: &StressConfig) -> StressReport {
+    const N_PARTIAL_SUMS: usize = 64;
 
-                if let Some(buf) = output_buffer_registry.write().await.get_mut(&task_uuid) {
-                    *buf = data;
-                } else {
-                    return Err(io::Error::new(
-                        ErrorKind::InvalidData,
-                        format!("Error: Task UUID {task_uuid}, received from peer not found in our buffer registry!"),
-                    ));
-                };
+    if let Err(err) = stress.validate() {
+        error!("Invalid [stress] config ({err}), not generating any tasks!");
+        return StressReport::default();
+    }
+    let task_interval = Duration::from_secs_f64(1.0 / stress.tasks_per_sec);
+    let mut task_ticks = tokio::time::interval(task_interval);
+    // If submitting had to wait for a slot, carry on at the same rate instead of catching up in a burst
+    task_ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut report_ticks = tokio::time::interval(STRESS_REPORT_INTERVAL);
+    let stop_generating_at = Instant::now() + Duration::from_secs_f64(stress.duration_secs);
+    let mut rng = StdRng::seed_from_u64(stress.seed);
 
-                if let Some(notifier) = notifier_registry.read().await.get(&task_uuid) {
-                    notifier.add_permits(Semaphore::MAX_PERMITS);
-                }
+    // The next task to submit (and its expected sum), once there's a free slot for it
+    let mut next_task: Option<(SerialisableProgram, u32)> = None;
+    let mut pending_results = FuturesUnordered::new();
+    let (mut n_submitted, mut n_completed, mut n_wrong, mut n_timed_out, mut n_failed) =
+        (0u64, 0u64, 0u64, 0u64, 0u64);
+    loop {
+        let generating = Instant::now() < stop_generating_at;
+        if !generating && next_task.is_none() && pending_results.is_empty() {
+            break;
+        }
+        tokio::select! {
+            _ = task_ticks.tick(), if generating && next_task.is_none() => {
+                let data = (0..stress.task_nelements)
+                    .map(|_| rng.gen_range(0u32..=1000))
+                    .collect::<Vec<u32>>();
+                let expected = data.iter().copied().fold(0u32, u32::wrapping_add);
+                next_task = Some((sum_program(&data, N_PARTIAL_SUMS), expected));
             }
-
-            _ => {
-                println!(
-                    "Notice: Unknown message id({:?}) received from peer({:?})!",
-                    message_id,
-                    other_stream.peer_addr()
-                )
+            // Like in ClusterClient::reduce, only waiting for the slot is raced, so pending_results keeps being drained meanwhile
+            slot = client.in_flight.clone().acquire_owned(), if next_task.is_some() => {
+                let slot = slot.expect("Semaphore shouldn't close!");
+                let (program, expected) = next_task.take().unwrap();
+                let deadline = stress
+                    .task_deadline_secs
+                    .map(|secs| SystemTime::now() + Duration::from_secs_f64(secs));
+                let job = client.submit_in_slot(program, deadline, slot).await;
+                pending_results.push(async move { (job.await_result().await, expected) });
+                n_submitted += 1;
+            }
+            Some((res, expected)) = pending_results.next() => match res {
+                Ok(raw_res) => {
+                    let sum = ShaderBytes::deserialise_to_iterator::<u32>(&raw_res)
+                        .fold(0u32, u32::wrapping_add);
+                    if sum == expected {
+                        n_completed += 1;
+                    } else {
+                        error!("Stress task summed to {sum} instead of {expected}!");
+                        n_wrong += 1;
+                    }
+                }
+                Err(ClusterError::Timeout) => n_timed_out += 1,
+                Err(err) => {
+                    warn!("Stress task failed, error was: {err}!");
+                    n_failed += 1;
+                }
+            },
+            _ = report_ticks.tick() => {
+                info!(
+                    "Stress: {n_submitted} submitted, {n_completed} completed, {n_wrong} wrong, {n_timed_out} timed out, {n_failed} failed, {} outstanding, registries: {} outputs, {} notifiers, {} started, {} queued",
+                    pending_results.len(),
+                    client.output_buffer_registry.read().await.len(),
+                    client.notifier_registry.read().await.len(),
+                    client.started_registry.read().await.len(),
+                    client.task_queue.lock().await.len(),
+                );
             }
         }
     }
+    info!(
+        "Stress run done: {n_submitted} submitted, {n_completed} completed, {n_wrong} wrong, {n_timed_out} timed out, {n_failed} failed!"
+    );
+    StressReport {
+        n_submitted,
+        n_completed,
+        n_wrong,
+        n_timed_out,
+        n_failed,
+    }
 }
 
+// How long main waits at exit for the late results of timed out jobs, see ClusterJob::await_result
+const REGISTRY_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// How long queued tasks get to finish (and their results to be returned) after we're asked to shut down
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 #[tokio::main]
 async fn main() {
+    clustered::logging::init();
+    // Results computed here get sent to other peers, so refuse to run if we'd serialise them wrong
+    if let Err(err) = clustered::shader_bytes::layout_self_check() {
+        panic!("FATAL: This host's ShaderBytes layout doesn't match WGSL's!\n{err}");
+    }
+    // `peer --queue-summary <peer2peer addr>` just prints what that peer is holding, for debugging stuck peers
+    if std::env::args().nth(1).as_deref() == Some("--queue-summary") {
+        let other_peer_addr: SocketAddr = std::env::args()
+            .nth(2)
+            .expect("FATAL: --queue-summary needs the address of a peer!")
+            .parse()
+            .unwrap_or_else(|err| panic!("FATAL: Couldn't parse peer address!\n{err}"));
+        // These don't read the config, so the connection just gets the default socket options
+        let summary = query_task_queue(other_peer_addr, &SocketOptions::default())
+            .await
+            .unwrap_or_else(|err| panic!("FATAL:\n{err}"));
+        info!("Peer {other_peer_addr} holds {} tasks", summary.len());
+        for task in summary {
+            info!(
+                "{} ({} x{}, {} bytes in, {} bytes out) returns to {}",
+                Uuid::from_u128(task.id),
+                task.entry_point,
+                task.n_workgroups,
+                task.in_data_nbytes,
+                task.out_data_nbytes,
+                task.return_addr
+            );
+        }
+        return;
+    }
+    // `peer --pause <peer2peer addr>` / `peer --resume <peer2peer addr>` drain a peer for maintenance and bring it back
+    if let Some(flag @ ("--pause" | "--resume")) = std::env::args().nth(1).as_deref() {
+        let other_peer_addr: SocketAddr = std::env::args()
+            .nth(2)
+            .unwrap_or_else(|| panic!("FATAL: {flag} needs the address of a peer!"))
+            .parse()
+            .unwrap_or_else(|err| panic!("FATAL: Couldn't parse peer address!\n{err}"));
+        set_peer_paused(
+            other_peer_addr,
+            flag == "--pause",
+            &SocketOptions::default(),
+        )
+        .await
+        .unwrap_or_else(|err| panic!("FATAL:\n{err}"));
+        info!("Sent {flag} to peer {other_peer_addr}");
+        return;
+    }
+    let config: Arc<PeerConfig> = Arc::new(
+        clustered::config::load_from_args()
+            .await
+            .unwrap_or_else(|err| panic!("FATAL: Couldn't load config!\n{err}")),
+    );
+    // Zero would mean nothing ever gets dispatched
+    clustered::DispatchLimiter::set_global_limit(config.max_in_flight_dispatches.max(1))
+        .expect("Nothing should have been dispatched yet!");
     let (our_ip, peer2peer_port, tracker_connection) =
-        connect_to_tracker(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337)))
+        connect_to_tracker(config.tracker_addr, &config.socket_options)
             .await
             .unwrap_or_else(|err| panic!("FATAL:\n{err}"));
 
-    println!(
-        "Info: Connected to tracker: {:?}!",
+    info!(
+        "Connected to tracker: {:?}!",
         tracker_connection.peer_addr()
     );
+    let our_addr: OurAddrType = Arc::new(watch::channel(SocketAddr::new(our_ip, peer2peer_port)).0);
 
     let task_queue: TaskQueueType = Default::default();
     let output_buffer_registry: BufferRegistryType = Default::default();
     let notifier_registry: NotifierRegistryType = Default::default();
+    let started_registry: StartedRegistryType = Default::default();
+    let peer_control = PeerControl::default();
+    let result_returner = ResultReturner {
+        output_buffer_registry: output_buffer_registry.clone(),
+        notifier_registry: notifier_registry.clone(),
+        started_registry: started_registry.clone(),
+        return_outbox: Arc::new(Mutex::new(HashMap::new())),
+        socket_options: config.socket_options,
+        our_addr: our_addr.clone(),
+    };
+
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
 
     {
         // Start listening for other peers
 
         async fn handle_other_peer_wrapper(
             other_stream: TcpStream,
-            extra: (TaskQueueType, BufferRegistryType, NotifierRegistryType),
+            extra: (TaskQueueType, ResultReturner, Arc<PeerConfig>, PeerControl),
         ) {
-            if let Err(err) = handle_other_peer(other_stream, extra.0, extra.1, extra.2).await {
+            if let Err(err) =
+                handle_other_peer(other_stream, extra.0, extra.1, extra.2, extra.3).await
+            {
                 if !clustered::networking::was_connection_severed(err.kind()) {
-                    println!("{err}");
+                    error!("{err}");
                 }
             }
         }
 
-        tokio::spawn(clustered::networking::listen(
-            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, peer2peer_port)),
-            handle_other_peer_wrapper,
-            (
-                task_queue.clone(),
-                output_buffer_registry.clone(),
-                notifier_registry.clone(),
-            ),
-        ));
+        // Every address we're given gets a listener, old ones are kept since the results of tasks submitted
+        // before we moved still come back to them, see reconnect_to_tracker
+        let mut our_addr_receiver = our_addr.subscribe();
+        let extra = (
+            task_queue.clone(),
+            result_returner.clone(),
+            config.clone(),
+            peer_control.clone(),
+        );
+        let shutdown_receiver = shutdown_receiver.clone();
+        tokio::spawn(async move {
+            loop {
+                let listen_addr = *our_addr_receiver.borrow_and_update();
+                let mut shutdown_receiver = shutdown_receiver.clone();
+                tokio::spawn(clustered::networking::listen(
+                    // Other peers reach us at the address the tracker saw us at, so same family as that
+                    SocketAddr::new(
+                        match listen_addr.ip() {
+                            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+                        },
+                        listen_addr.port(),
+                    ),
+                    config.socket_options,
+                    handle_other_peer_wrapper,
+                    extra.clone(),
+                    async move {
+                        let _ = shutdown_receiver
+                            .wait_for(|shutting_down| *shutting_down)
+                            .await;
+                    },
+                ));
+                if our_addr_receiver.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
     }
 
-    tokio::spawn(runner(
+    let mut shadow_checker = ShadowChecker::new(config.shadow_check_fraction);
+    shadow_checker.register(SUM_SHADER, Arc::new(SumReference));
+
+    let tracker_connection = Arc::new(Mutex::new(tracker_connection));
+    let completed_tasks = Arc::new(AtomicU64::new(0));
+
+    let return_outbox = result_returner.return_outbox.clone();
+    let runner_handle = tokio::spawn(runner(
         task_queue.clone(),
-        output_buffer_registry.clone(),
-        notifier_registry.clone(),
-        Arc::new(Mutex::new(tracker_connection)),
+        result_returner,
+        tracker_connection.clone(),
+        config.clone(),
+        Arc::new(shadow_checker),
+        completed_tasks.clone(),
+        peer_control,
+        our_addr.clone(),
+        shutdown_receiver.clone(),
     ));
 
-    // And now do normal peer stuff, like adding tasks to the queue and waiting for the results
-    // sleep(Duration::MAX).await;
+    tokio::spawn(stats_reporter(
+        tracker_connection.clone(),
+        config.clone(),
+        our_addr.clone(),
+        completed_tasks,
+        Duration::from_secs_f64(config.stats_report_interval_secs),
+        shutdown_receiver,
+    ));
+
+    // Whatever we're doing when interrupted (ctrl-c or SIGTERM) is abandoned, but tasks other peers gave us still get finished
+    let workload = async {
+        // And now do normal peer stuff, like adding tasks to the queue and waiting for the results
+        // sleep(Duration::MAX).await;
+
+        // Having a local program capsule is optional, without one we just act as a pure worker (stealing and running other peers' tasks)
+        let test_program = match SerialisableProgram::load_from_file("program-capsule.json").await {
+            Ok(val) => {
+                info!("Program loaded!");
+                Some(val)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("No program capsule found, running as a pure worker!");
+                None
+            }
+            Err(err) => {
+                error!(
+                    "Failed to load program capsule, not submitting any tasks, error was: {err}"
+                );
+                None
+            }
+        };
+
+        let client = ClusterClient {
+            our_addr: our_addr.clone(),
+            task_queue: task_queue.clone(),
+            output_buffer_registry: output_buffer_registry.clone(),
+            notifier_registry: notifier_registry.clone(),
+            started_registry: started_registry.clone(),
+            tracker_connection: tracker_connection.clone(),
+            socket_options: config.socket_options,
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight_tasks.max(1))),
+        };
+
+        if let Some(stress) = &config.stress {
+            info!(
+                "Stress mode, generating {} tasks/s for {}s!",
+                stress.tasks_per_sec, stress.duration_secs
+            );
+            run_stress_load(&client, stress).await;
+        }
+
+        if let Some(test_program) = test_program {
+            // The same program every time, so only the first task on each peer pays for compiling it,
+            // the rest hit its GpuExecutor's caches, compare their latencies
+            let mut tq = Vec::new();
+            for i in 0..30 {
+                let time_start = Instant::now();
+                tq.push(
+                    client
+                        .submit_with_callback(test_program.clone(), move |res| {
+                            let raw_res = res.expect("Test program failed to run on the cluster!");
+                            assert!(raw_res.len() == core::mem::size_of::<f32>() * 4000 * 4000);
+                            let time_end = Instant::now();
+                            info!("Task {i} took: {}s!", (time_end - time_start).as_secs_f32());
+                        })
+                        .await,
+                );
+            }
+
+            for f in tq {
+                f.await.unwrap();
+            }
+
+            {
+                let mut rng = StdRng::seed_from_u64(4);
+                let mut to_sum = Vec::new();
+                to_sum.resize_with(16 * 1024 * 1024, || rng.gen_range(0u32..=1000));
+                let time_start = Instant::now();
+                let cluster_sum = sum_over_cluster(&client, &to_sum).await;
+                info!(
+                    "Summed {} elements over the cluster in {}s!",
+                    to_sum.len(),
+                    (Instant::now() - time_start).as_secs_f32()
+                );
+                assert_eq!(
+                    cluster_sum,
+                    to_sum.iter().copied().fold(0u32, u32::wrapping_add)
+                );
+            }
+
+            {
+                // Some peer might grab it before we get to cancel it, then it's dropped from that peer's queue,
+                // unless it's already running there, then it just runs
+                let job = client.submit(test_program.clone(), None).await;
+                if client.cancel(job.id()).await {
+                    assert_eq!(job.await_result().await, Err(ClusterError::Cancelled));
+                    info!("Cancelled a job before any peer picked it up!");
+                } else {
+                    match job.await_result().await {
+                        Ok(_) => {
+                            info!("Job was already running when cancelled, it finished anyway!")
+                        }
+                        Err(ClusterError::Cancelled) => {
+                            info!("Cancelled a job in the queue of the peer that stole it!")
+                        }
+                        Err(err) => panic!("Test program failed to run on the cluster!\n{err}"),
+                    }
+                }
+            }
+        }
+
+        while !task_queue.lock().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+            tokio::task::yield_now().await;
+        }
+
+        // Every job has been awaited by now, but ones that timed out after being stolen keep their registry entries
+        // until the peer holding them answers
+        let drained = tokio::time::timeout(REGISTRY_DRAIN_TIMEOUT, async {
+            while !output_buffer_registry.read().await.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "{} timed out task(s) never got an answer from the peer holding them!",
+                output_buffer_registry.read().await.len()
+            );
+        } else {
+            assert!(notifier_registry.read().await.is_empty());
+        }
+
+        // Other peers may still be stealing from us, so keep serving them until we're told to stop
+        info!("All our jobs are done, still serving other peers until interrupted (ctrl-c)...");
+        std::future::pending::<()>().await;
+    };
+    tokio::select! {
+        _ = workload => {}
+        _ = clustered::networking::shutdown_signal() => {}
+    }
+
+    // Stop accepting connections, let the runner finish the tasks we hold and return their results, then exit
+    shutdown_sender.send_replace(true);
+    warn!(
+        "Shutting down, waiting up to {}s for {} queued task(s) to finish and their results to be returned...",
+        SHUTDOWN_GRACE_PERIOD.as_secs(),
+        task_queue.lock().await.len()
+    );
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, async {
+        let _ = runner_handle.await;
+        while !return_outbox.lock().await.is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Grace period over, abandoning {} queued task(s) and the results still being returned to {} peer(s)!",
+            task_queue.lock().await.len(),
+            return_outbox.lock().await.len()
+        );
+    }
+    info!("Shut down!");
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn queue_of(n_tasks: usize) -> Vec<Task> {
+        (0..n_tasks)
+            .map(|i| Task {
+                return_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 8008)),
+                program: sum_program(&[1, 2, 3], 64 * (i + 1)),
+                id: i as u128,
+                deadline_unix_millis: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_take_tasks_for_stealer() {
+        let config = PeerConfig::default();
+
+        // Loaded, gives away the oldest half
+        let mut task_queue = queue_of(10);
+        let given = take_tasks_for_stealer(&mut task_queue, &config);
+        assert_eq!(
+            given.iter().map(|task| task.id).collect::<Vec<_>>(),
+            (0..5).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            task_queue.iter().map(|task| task.id).collect::<Vec<_>>(),
+            (5..10).collect::<Vec<_>>()
+        );
+
+        // Just over no_steal_treshold, still gives one
+        let mut task_queue = queue_of(config.no_steal_treshold + 1);
+        assert_eq!(take_tasks_for_stealer(&mut task_queue, &config).len(), 1);
+
+        // Near empty, gives nothing
+        for n_tasks in 0..=config.no_steal_treshold {
+            let mut task_queue = queue_of(n_tasks);
+            assert!(take_tasks_for_stealer(&mut task_queue, &config).is_empty());
+            assert_eq!(task_queue.len(), n_tasks);
+        }
+
+        // By cost, task i costs 64 * (i + 1), so half of the queue is 64 * 27.5, which the first 6 tasks (64 * 21) stay under
+        let config = PeerConfig {
+            steal_balancing: StealBalancing::EstimatedCost,
+            no_steal_cost_treshold: 0,
+            ..Default::default()
+        };
+        let mut task_queue = queue_of(10);
+        assert_eq!(take_tasks_for_stealer(&mut task_queue, &config).len(), 6);
+        assert_eq!(task_queue.len(), 4);
+    }
+
+    #[test]
+    fn test_only_admins_can_pause() {
+        let config = PeerConfig {
+            admin_addrs: vec![Ipv4Addr::new(10, 0, 0, 1).into()],
+            ..Default::default()
+        };
+        assert!(is_admin((Ipv4Addr::LOCALHOST, 1234).into(), &config));
+        assert!(is_admin((Ipv6Addr::LOCALHOST, 1234).into(), &config));
+        assert!(is_admin((Ipv4Addr::new(10, 0, 0, 1), 1234).into(), &config));
+        // The same address, as seen by a dual stack listener
+        assert!(is_admin(
+            (Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped(), 1234).into(),
+            &config
+        ));
+        assert!(!is_admin(
+            (Ipv4Addr::new(10, 0, 0, 2), 1234).into(),
+            &config
+        ));
+        assert!(!is_admin(
+            (Ipv4Addr::new(10, 0, 0, 1), 1234).into(),
+            &PeerConfig::default()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_drops_queued_task() {
+        // Stands in for the submitter, which should get told its task was cancelled
+        let submitter = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let submitter_addr = submitter.local_addr().unwrap();
+        let holder = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let holder_addr = holder.local_addr().unwrap();
+
+        let task_queue: TaskQueueType = Arc::new(Mutex::new(
+            queue_of(3)
+                .into_iter()
+                .map(|task| Task {
+                    // Task 2 is someone else's, so it isn't ours to cancel
+                    return_addr: if task.id == 2 {
+                        SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), submitter_addr.port()))
+                    } else {
+                        submitter_addr
+                    },
+                    ..task
+                })
+                .collect(),
+        ));
+        let result_returner = ResultReturner {
+            output_buffer_registry: Default::default(),
+            notifier_registry: Default::default(),
+            started_registry: Default::default(),
+            return_outbox: Default::default(),
+            socket_options: SocketOptions::default(),
+            our_addr: Arc::new(watch::channel(holder_addr).0),
+        };
+        tokio::spawn({
+            let task_queue = task_queue.clone();
+            async move {
+                // Every cancel message comes over its own connection
+                loop {
+                    let (holder_stream, _) = holder.accept().await.unwrap();
+                    let _ = handle_other_peer(
+                        holder_stream,
+                        task_queue.clone(),
+                        result_returner.clone(),
+                        Arc::new(PeerConfig::default()),
+                        PeerControl::default(),
+                    )
+                    .await;
+                }
+            }
+        });
+
+        // Not held by anyone, ignored
+        let socket_options = SocketOptions::default();
+        send_cancel(holder_addr, Uuid::from_u128(7), &socket_options)
+            .await
+            .unwrap();
+        send_cancel(holder_addr, Uuid::from_u128(2), &socket_options)
+            .await
+            .unwrap();
+        send_cancel(holder_addr, Uuid::from_u128(1), &socket_options)
+            .await
+            .unwrap();
+
+        let (mut returned, _) = submitter.accept().await.unwrap();
+        let magic = clustered::networking::read_buf_limited(
+            &mut returned,
+            clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
+        .await
+        .unwrap();
+        assert_eq!(magic, MAGIC_PEER2PEER_SEQUENCE.as_bytes());
+        assert_eq!(
+            PeerMessage::from_u8(returned.read_u8().await.unwrap()),
+            Some(PeerMessage::ReturnResults)
+        );
+        assert_eq!(returned.read_u64().await.unwrap(), 1);
+        assert_eq!(returned.read_u128().await.unwrap(), 1);
+        assert_eq!(returned.read_u8().await.unwrap(), RESULT_STATUS_CANCELLED);
+        assert_eq!(
+            task_queue
+                .lock()
+                .await
+                .iter()
+                .map(|task| task.id)
+                .collect::<Vec<_>>(),
+            [0, 2]
+        );
+    }
+
+    // A client that only ever has its tasks delivered locally, through the returned ResultReturner
+    async fn local_client(max_in_flight_tasks: usize) -> (ClusterClient, ResultReturner) {
+        let tracker = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let tracker_connection = TcpStream::connect(tracker.local_addr().unwrap())
+            .await
+            .unwrap();
+        let our_addr: OurAddrType =
+            Arc::new(watch::channel(SocketAddr::from((Ipv4Addr::LOCALHOST, 8008))).0);
+        let result_returner = ResultReturner {
+            output_buffer_registry: Default::default(),
+            notifier_registry: Default::default(),
+            started_registry: Default::default(),
+            return_outbox: Default::default(),
+            socket_options: SocketOptions::default(),
+            our_addr: our_addr.clone(),
+        };
+        let client = ClusterClient {
+            our_addr: our_addr.clone(),
+            task_queue: Default::default(),
+            output_buffer_registry: result_returner.output_buffer_registry.clone(),
+            notifier_registry: result_returner.notifier_registry.clone(),
+            started_registry: result_returner.started_registry.clone(),
+            tracker_connection: Arc::new(Mutex::new(tracker_connection)),
+            socket_options: SocketOptions::default(),
+            in_flight: Arc::new(Semaphore::new(max_in_flight_tasks)),
+        };
+        (client, result_returner)
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_tasks_are_bounded() {
+        let (client, result_returner) = local_client(2).await;
+        let program = sum_program(&[1, 2, 3], 64);
+        let job_a = client.submit(program.clone(), None).await;
+        let job_b = client.submit(program.clone(), None).await;
+
+        // Out of slots, so neither the task nor its result can take up any more memory
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            client.submit(program.clone(), None)
+        )
+        .await
+        .is_err());
+        assert_eq!(client.task_queue.lock().await.len(), 2);
+        assert_eq!(client.output_buffer_registry.read().await.len(), 2);
+
+        // Consuming a result frees its slot
+        assert!(client.take_back(job_a.id()).await);
+        result_returner
+            .return_data(Ok(vec![1, 2, 3]), client.return_addr(), job_a.id())
+            .await;
+        assert_eq!(job_a.await_result().await, Ok(vec![1, 2, 3]));
+        let job_c = tokio::time::timeout(
+            Duration::from_millis(100),
+            client.submit(program.clone(), None),
+        )
+        .await
+        .expect("Consuming a result should free its slot!");
 
-    let mut program_file = OpenOptions::new()
-        .read(true)
-        .open("program-capsule.json")
+        // So does dropping a job, whose task then goes away too
+        drop(job_b);
+        let job_d = tokio::time::timeout(
+            Duration::from_millis(100),
+            client.submit(program.clone(), None),
+        )
         .await
-        .expect("Program file should exist!");
-    let mut program_file_contents = String::new();
-    program_file
-        .read_to_string(&mut program_file_contents)
+        .expect("Dropping a job should free its slot!");
+        while client.output_buffer_registry.read().await.len() > 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            client
+                .task_queue
+                .lock()
+                .await
+                .iter()
+                .map(|task| Uuid::from_u128(task.id))
+                .collect::<Vec<_>>(),
+            [job_c.id(), job_d.id()]
+        );
+
+        // And the deadline passing, even when a peer still holds the task
+        let (client, _result_returner) = local_client(1).await;
+        let deadline = SystemTime::now() + Duration::from_millis(50);
+        let job = client.submit(program.clone(), Some(deadline)).await;
+        client.task_queue.lock().await.clear();
+        assert_eq!(job.await_result().await, Err(ClusterError::Timeout));
+        tokio::time::timeout(Duration::from_millis(100), client.submit(program, None))
+            .await
+            .expect("A timed out job should free its slot!");
+    }
+
+    #[tokio::test]
+    async fn test_acks_go_out_with_the_results() {
+        // Our own tasks are just marked as started
+        let (client, result_returner) = local_client(1).await;
+        let job = client.submit(sum_program(&[1, 2, 3], 64), None).await;
+        result_returner
+            .ack_started(client.return_addr(), job.id())
+            .await;
+        assert_eq!(
+            client.started_registry.read().await.get(&job.id()),
+            Some(&client.return_addr())
+        );
+
+        // Other peers get their acks ahead of the results, all over one connection
+        let submitter = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let submitter_addr = submitter.local_addr().unwrap();
+        let holder_addr = *result_returner.our_addr.borrow();
+        let (task_a, task_b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        result_returner.ack_started(submitter_addr, task_a).await;
+        result_returner.ack_started(submitter_addr, task_b).await;
+        result_returner
+            .return_data(Ok(vec![4, 5, 6]), submitter_addr, task_a)
+            .await;
+
+        let (mut returned, _) = submitter.accept().await.unwrap();
+        let magic = clustered::networking::read_buf_limited(
+            &mut returned,
+            clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
         .await
         .unwrap();
-    drop(program_file);
-
-    let test_program = serde_json::from_str::<SerialisableProgram>(&program_file_contents)
-        .expect("Program file should be able to be deserialised!");
-    println!("Program loaded!");
-    let mut tq = Vec::new();
-    for _ in 0..30 {
-        let time_start = Instant::now();
-        let task_id = Uuid::now_v7();
-        output_buffer_registry
-            .write()
+        assert_eq!(magic, MAGIC_PEER2PEER_SEQUENCE.as_bytes());
+        for task_id in [task_a, task_b] {
+            assert_eq!(
+                PeerMessage::from_u8(returned.read_u8().await.unwrap()),
+                Some(PeerMessage::TaskStarted)
+            );
+            assert_eq!(returned.read_u128().await.unwrap(), task_id.as_u128());
+            assert_eq!(
+                clustered::networking::read_ip_addr(&mut returned)
+                    .await
+                    .unwrap(),
+                holder_addr.ip()
+            );
+            assert_eq!(returned.read_u16().await.unwrap(), holder_addr.port());
+        }
+        assert_eq!(
+            PeerMessage::from_u8(returned.read_u8().await.unwrap()),
+            Some(PeerMessage::ReturnResults)
+        );
+        assert_eq!(returned.read_u64().await.unwrap(), 1);
+        assert_eq!(returned.read_u128().await.unwrap(), task_a.as_u128());
+        assert_eq!(returned.read_u8().await.unwrap(), RESULT_STATUS_OK);
+        assert_eq!(
+            clustered::networking::read_buf_limited(
+                &mut returned,
+                clustered::networking::MAX_CONTROL_MESSAGE_LEN
+            )
             .await
-            .insert(task_id, Vec::new());
-        notifier_registry
-            .write()
+            .unwrap(),
+            [4, 5, 6]
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), submitter.accept())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_returns_are_requeued() {
+        let (_client, result_returner) = local_client(1).await;
+        // Nothing listens there yet, so the first attempt fails
+        let submitter_addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
             .await
-            .insert(task_id, Arc::from(Semaphore::new(0)));
-        task_queue.lock().await.push(Task {
-            program: test_program.clone(),
-            return_addr: SocketAddrV4::new(our_ip, peer2peer_port),
-            id: task_id.as_u128(),
-        });
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let task_ids = [1, 2, 3].map(Uuid::from_u128);
+        result_returner
+            .return_data(Ok(vec![1]), submitter_addr, task_ids[0])
+            .await;
+        result_returner
+            .return_data(Ok(vec![2]), submitter_addr, task_ids[1])
+            .await;
+        sleep(RETURN_RETRY_DELAY / 2).await;
+        // Queued while the failed batch waits to be retried, so it goes out behind it
+        result_returner
+            .return_data(Ok(vec![3]), submitter_addr, task_ids[2])
+            .await;
+        let submitter = TcpListener::bind(submitter_addr).await.unwrap();
 
-        let buf_reg_clone = output_buffer_registry.clone();
-        let notif_reg_clone = notifier_registry.clone();
-        tq.push(tokio::spawn(async move {
-            let sem = notif_reg_clone
-                .read()
+        let (mut returned, _) = tokio::time::timeout(2 * RETURN_RETRY_DELAY, submitter.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let magic = clustered::networking::read_buf_limited(
+            &mut returned,
+            clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
+        .await
+        .unwrap();
+        assert_eq!(magic, MAGIC_PEER2PEER_SEQUENCE.as_bytes());
+        // All in one batch
+        assert_eq!(
+            PeerMessage::from_u8(returned.read_u8().await.unwrap()),
+            Some(PeerMessage::ReturnResults)
+        );
+        assert_eq!(returned.read_u64().await.unwrap(), 3);
+        for (task_id, out_data) in task_ids.iter().zip([1, 2, 3]) {
+            assert_eq!(returned.read_u128().await.unwrap(), task_id.as_u128());
+            assert_eq!(returned.read_u8().await.unwrap(), RESULT_STATUS_OK);
+            assert_eq!(
+                clustered::networking::read_buf_limited(
+                    &mut returned,
+                    clustered::networking::MAX_CONTROL_MESSAGE_LEN
+                )
                 .await
-                .get(&task_id)
-                .expect("Task should have notifier!")
-                .clone();
+                .unwrap(),
+                [out_data]
+            );
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !result_returner.return_outbox.lock().await.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stress_load() {
+        let (client, result_returner) = local_client(4).await;
+        // Stands in for the runner, summing every task's input on the cpu
+        let worker_client = client.clone();
+        let worker = tokio::spawn(async move {
+            loop {
+                let Some(task) = worker_client.task_queue.lock().await.pop() else {
+                    tokio::task::yield_now().await;
+                    continue;
+                };
+                let sum = ShaderBytes::deserialise_to_iterator::<u32>(&task.program.in_data)
+                    .fold(0u32, u32::wrapping_add);
+                // All in the first partial sum
+                let mut out_data = vec![0u8; task.program.out_data_nbytes];
+                out_data[..4].copy_from_slice(&sum.to_le_bytes());
+                result_returner
+                    .return_data(Ok(out_data), task.return_addr, Uuid::from_u128(task.id))
+                    .await;
+            }
+        });
+        let report = run_stress_load(
+            &client,
+            &StressConfig {
+                tasks_per_sec: 200.0,
+                task_nelements: 256,
+                duration_secs: 0.2,
+                ..Default::default()
+            },
+        )
+        .await;
+        worker.abort();
+        assert!(report.n_submitted > 0);
+        assert_eq!(
+            report,
+            StressReport {
+                n_submitted: report.n_submitted,
+                n_completed: report.n_submitted,
+                ..Default::default()
+            }
+        );
+        assert!(client.output_buffer_registry.read().await.is_empty());
+
+        // An invalid config is refused instead of panicking
+        let report = run_stress_load(
+            &client,
+            &StressConfig {
+                tasks_per_sec: 0.0,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(report, StressReport::default());
+    }
+
+    #[tokio::test]
+    async fn test_consume_task_falls_back_to_cpu() {
+        let adapter = clustered::test_support::default_adapter().await;
+        let executor = GpuExecutor::new(adapter, wgpu::Features::empty(), wgpu::Limits::default())
+            .await
+            .expect("Device must exist!");
+        let (client, result_returner) = local_client(2).await;
+        let shadow_checker = Arc::new(ShadowChecker::new(0.0));
+        let completed_tasks = Arc::new(AtomicU64::new(0));
+        let to_sum = (1..=1000u32).collect::<Vec<u32>>();
+        let sum_of =
+            |raw_res: Vec<u8>| ShaderBytes::deserialise_to_iterator::<u32>(&raw_res).sum::<u32>();
 
-            let _ = sem.acquire().await.expect("Semaphore shouldn't close!");
-            let buf_reg_lock = buf_reg_clone.read().await;
-            let raw_res = buf_reg_lock
-                .get(&task_id)
-                .expect("Task should have output buffer!");
-            assert!(raw_res.len() == core::mem::size_of::<f32>() * 4000 * 4000);
-            let time_end = Instant::now();
-            drop(buf_reg_lock);
+        // A program the gpu rejects isn't run again on the cpu
+        let job = client
+            .submit(
+                SerialisableProgram {
+                    entry_point: "not_main".to_owned(),
+                    ..sum_program(&to_sum, 64)
+                },
+                None,
+            )
+            .await;
+        let task = client.task_queue.lock().await.pop().unwrap();
+        consume_task(
+            task,
+            result_returner.clone(),
+            &executor,
+            shadow_checker.clone(),
+            completed_tasks.clone(),
+        )
+        .await;
+        assert!(matches!(
+            job.await_result().await,
+            Err(ClusterError::RemoteExecution(_))
+        ));
 
-            buf_reg_clone.write().await.remove(&task_id);
-            notif_reg_clone.write().await.remove(&task_id);
-            println!("Took: {}s!", (time_end - time_start).as_secs_f32());
-        }));
+        // But with the gpu gone it's still run, on the cpu
+        executor.simulate_device_loss();
+        assert!(executor.is_lost());
+        let job = client.submit(sum_program(&to_sum, 64), None).await;
+        let task = client.task_queue.lock().await.pop().unwrap();
+        consume_task(
+            task,
+            result_returner,
+            &executor,
+            shadow_checker,
+            completed_tasks.clone(),
+        )
+        .await;
+        assert_eq!(sum_of(job.await_result().await.unwrap()), 500500);
+        assert_eq!(completed_tasks.load(Ordering::Relaxed), 1);
     }
 
-    for f in tq {
-        f.await.unwrap();
+    #[tokio::test]
+    async fn test_task_nobody_can_run_falls_back_to_cpu() {
+        // Not every machine running the tests has a fallback adapter, let alone one with f64 support
+        let instance = wgpu::Instance::new(InstanceDescriptor::default());
+        let Some(fallback_adapter) = instance
+            .request_adapter(&RequestAdapterOptions {
+                compatible_surface: None,
+                force_fallback_adapter: true,
+                power_preference: wgpu::PowerPreference::None,
+            })
+            .await
+        else {
+            return;
+        };
+        if !fallback_adapter
+            .features()
+            .contains(wgpu::Features::SHADER_F64)
+        {
+            return;
+        }
+
+        let (client, _result_returner) = local_client(1).await;
+        let tracker = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (connection, accepted) = tokio::join!(
+            TcpStream::connect(tracker.local_addr().unwrap()),
+            tracker.accept()
+        );
+        *client.tracker_connection.lock().await = connection.unwrap();
+        let (mut tracker_end, _) = accepted.unwrap();
+        tokio::spawn(async move {
+            assert_eq!(
+                TrackerCommand::from_u8(tracker_end.read_u8().await.unwrap()),
+                Some(TrackerCommand::ListPeerFeatures)
+            );
+            // The only peer is us, and our gpu can't do f64
+            let peer_features =
+                clustered::networking::to_versioned_json(&[Some(wgpu::Features::empty().bits())])
+                    .unwrap();
+            clustered::networking::write_buf(&mut tracker_end, &peer_features)
+                .await
+                .unwrap();
+            tracker_end
+        });
+
+        let to_double = (1..=64u32).collect::<Vec<u32>>();
+        let program = SerialisableProgram {
+            program: r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(64)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x+goff;
+                    if (actual_id >= arrayLength(&v_out_data)){ return; }
+                    let val = f64(v_in_data[actual_id]);
+                    v_out_data[actual_id] = u32(val + val);
+                }
+            "#
+            .to_owned(),
+            ..sum_program(&to_double, 64)
+        };
+        assert_eq!(
+            clustered::reflection::required_features(&program.program),
+            Ok(wgpu::Features::SHADER_F64)
+        );
+
+        let job = client.submit(program, None).await;
+        // Nobody would ever pick it up from the queue
+        assert!(client.task_queue.lock().await.is_empty());
+        assert_eq!(
+            ShaderBytes::deserialise_to_iterator::<u32>(&job.await_result().await.unwrap())
+                .collect::<Vec<u32>>(),
+            to_double.iter().map(|val| val * 2).collect::<Vec<u32>>()
+        );
     }
 
-    while !task_queue.lock().await.is_empty() {
-        sleep(Duration::from_millis(10)).await;
-        tokio::task::yield_now().await;
+    // Does the tracker's side of the handshake, registering the peer with the given p2p port
+    async fn accept_as_tracker(listener: &TcpListener, peer2peer_port: u16) -> TcpStream {
+        let (mut connection, peer_addr) = listener.accept().await.unwrap();
+        clustered::networking::write_buf(&mut connection, MAGIC_TRACKER_SEQUENCE.as_bytes())
+            .await
+            .unwrap();
+        clustered::networking::write_ip_addr(&mut connection, peer_addr.ip())
+            .await
+            .unwrap();
+        connection.write_u16(peer2peer_port).await.unwrap();
+        connection
     }
 
-    assert!(output_buffer_registry.read().await.is_empty());
-    assert!(notifier_registry.read().await.is_empty());
-    assert!(task_queue.lock().await.is_empty());
+    #[tokio::test]
+    async fn test_rejoins_restarted_tracker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = listener.local_addr().unwrap();
+        let socket_options = SocketOptions::default();
+        let (tracker_end, connected) = tokio::join!(
+            accept_as_tracker(&listener, 8008),
+            connect_to_tracker(tracker_addr, &socket_options)
+        );
+        let (our_ip, peer2peer_port, tracker_connection) = connected.unwrap();
+        let (our_addr, _) = watch::channel(SocketAddr::new(our_ip, peer2peer_port));
+        let tracker_connection = Mutex::new(tracker_connection);
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
 
-    println!("Info(HACK: because i can't properly wait for all tasks to finish correctly yet): Press any key to exit...");
-    {
-        let mut junk_buf = Vec::new();
-        let _ = tokio::io::stdin().read(&mut junk_buf).await.unwrap();
+        // A working connection is left alone, a reconnect attempt would hang on the handshake nobody answers
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            reconnect_to_tracker(
+                &tracker_connection,
+                tracker_addr,
+                &socket_options,
+                &our_addr,
+                shutdown_receiver.clone(),
+            ),
+        )
+        .await
+        .expect("Shouldn't reconnect over a working connection!");
+
+        // Kill the tracker, then bring it back a bit later, still holding our old registration so we get another port
+        drop(tracker_end);
+        drop(listener);
+        let restarted_tracker = tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            let listener = TcpListener::bind(tracker_addr).await.unwrap();
+            let mut tracker_end = accept_as_tracker(&listener, 8009).await;
+            assert_eq!(
+                TrackerCommand::from_u8(tracker_end.read_u8().await.unwrap()),
+                Some(TrackerCommand::ListPeers)
+            );
+            let no_peers =
+                clustered::networking::to_versioned_json(&Vec::<SocketAddr>::new()).unwrap();
+            clustered::networking::write_buf(&mut tracker_end, &no_peers)
+                .await
+                .unwrap();
+            tracker_end
+        });
+
+        let err = get_peer_list(&tracker_connection).await.unwrap_err();
+        assert!(clustered::networking::was_connection_severed(err.kind()));
+        reconnect_to_tracker(
+            &tracker_connection,
+            tracker_addr,
+            &socket_options,
+            &our_addr,
+            shutdown_receiver.clone(),
+        )
+        .await;
+        assert_eq!(*our_addr.borrow(), SocketAddr::new(our_ip, 8009));
+        assert!(get_peer_list(&tracker_connection).await.unwrap().is_empty());
+
+        // With the tracker gone for good, shutting down stops the retrying
+        drop(restarted_tracker.await.unwrap());
+        let err = get_peer_list(&tracker_connection).await.unwrap_err();
+        assert!(clustered::networking::was_connection_severed(err.kind()));
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(300)).await;
+            shutdown_sender.send_replace(true);
+        });
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            reconnect_to_tracker(
+                &tracker_connection,
+                tracker_addr,
+                &socket_options,
+                &our_addr,
+                shutdown_receiver,
+            ),
+        )
+        .await
+        .expect("Shouldn't keep reconnecting once shutting down!");
     }
 }
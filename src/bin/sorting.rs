@@ -1,50 +1,51 @@
 use std::{borrow::Cow, fs::OpenOptions, io::Read, time::Instant};
 
-use clustered::{shader_bytes::ShaderBytes, wgpu_map_helper, RunShaderParams};
+use clustered::{
+    shader_bytes::ShaderBytes, test_support::assert_results_close, wgpu_map_helper,
+    RunShaderParams, ShaderBindings,
+};
 use rand::{rngs::StdRng, Rng, SeedableRng};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, DeviceDescriptor, Features, Limits,
-    RequestAdapterOptions, ShaderModuleDescriptor,
+    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Device, DeviceDescriptor, Features,
+    Limits, Queue, RequestAdapterOptions, ShaderModuleDescriptor,
 };
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
-    let adapter = instance
-        .request_adapter(&RequestAdapterOptions {
-            force_fallback_adapter: false,
-            power_preference: wgpu::PowerPreference::HighPerformance,
-            ..Default::default()
-        })
-        .await
-        .unwrap();
-    println!("Using {:?}", adapter.get_info());
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                required_features: Features::STORAGE_RESOURCE_BINDING_ARRAY
-                    | Features::BUFFER_BINDING_ARRAY,
-                required_limits: Limits::default(),
-                ..Default::default()
-            },
-            None,
-        )
-        .await
-        .unwrap();
-    let mut cs_source = String::new();
-    OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open("shader-mergesort.wgsl")
-        .unwrap()
-        .read_to_string(&mut cs_source)
-        .unwrap();
+// The sort shader declares this and gets it replaced with the workgroup length the caller dispatches with,
+// as the shader's @workgroup_size has to match the workgroup_len given to run_shader
+const SHADER_WORKGROUP_SIZE_DECL: &str = "@workgroup_size(1)";
+
+// Sorts the data with the merge sort shader, each invocation merges one pair of sorted runs
+async fn gpu_sort(
+    device: &Device,
+    queue: &Queue,
+    shader_source: &str,
+    to_sort: &[u32],
+    workgroup_len: usize,
+) -> Result<Vec<u32>, String> {
+    let limits = device.limits();
+    if workgroup_len == 0
+        || workgroup_len > usize::try_from(limits.max_compute_workgroup_size_x).unwrap()
+        || workgroup_len > usize::try_from(limits.max_compute_invocations_per_workgroup).unwrap()
+    {
+        return Err(format!(
+            "Workgroup length {workgroup_len} isn't supported by the device, it has to be between 1 and {}",
+            limits
+                .max_compute_workgroup_size_x
+                .min(limits.max_compute_invocations_per_workgroup)
+        ));
+    }
+    if !shader_source.contains(SHADER_WORKGROUP_SIZE_DECL) {
+        return Err(format!(
+            "Sort shader doesn't declare {SHADER_WORKGROUP_SIZE_DECL}, so its workgroup size can't be set"
+        ));
+    }
     let cs_module = device.create_shader_module(ShaderModuleDescriptor {
         label: Some("Compute module"),
-        source: wgpu::ShaderSource::Wgsl(Cow::from(cs_source)),
+        source: wgpu::ShaderSource::Wgsl(Cow::from(shader_source.replace(
+            SHADER_WORKGROUP_SIZE_DECL,
+            &format!("@workgroup_size({workgroup_len})"),
+        ))),
     });
 
     #[derive(Clone)]
@@ -68,18 +69,13 @@ async fn main() {
         }
     }
 
-    let mut rng = StdRng::seed_from_u64(4);
-    let mut to_sort = Vec::new();
-    to_sort.resize_with(1024 * 1024 * 16, || rng.gen_range(0u32..=u32::MAX));
-
     let mut subsize = 1;
     let shader_complete_input = Info {
         input_a_size: subsize,
         input_b_size: subsize,
-        data: to_sort.as_ref(),
+        data: to_sort,
     };
 
-    let gpu_before_time = Instant::now();
     let mut in_buf = device.create_buffer_init(&BufferInitDescriptor {
         label: None,
         contents: &shader_complete_input.to_shader_bytes_custom(),
@@ -94,19 +90,25 @@ async fn main() {
 
     let (mut a, mut b) = (&mut in_buf, &mut out_buf);
     loop {
+        // One invocation per merge, invocations past the last merge return straight away
+        let n_merges = usize::div_ceil(to_sort.len(), (subsize + subsize).try_into().unwrap());
         clustered::run_shader(RunShaderParams {
-            device: &device,
-            queue: &queue,
+            device,
+            queue,
             entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
             in_buf: a,
             out_buf: b,
-            n_workgroups: usize::div_ceil(
-                shader_complete_input.data.len(),
-                (subsize + subsize).try_into().unwrap(),
-            ),
+            n_workgroups: n_merges.div_ceil(workgroup_len),
             program: &cs_module,
-            workgroup_len: 1,
-        });
+            workgroup_len,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await
+        .map_err(|err| err.to_string())?;
         (a, b) = (b, a);
         subsize *= 2;
         if subsize >= to_sort.len().try_into().unwrap() {
@@ -124,22 +126,81 @@ async fn main() {
     enc.copy_buffer_to_buffer(a, 0, &transfer_buf, 0, a.size());
     queue.submit([enc.finish()].into_iter());
 
-    let transfer_buf_view = transfer_buf.slice((2 * core::mem::size_of::<u32>()) as u64..);
-    wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf_view)
+    let transfer_buf_view = wgpu_map_helper(
+        device,
+        wgpu::MapMode::Read,
+        &transfer_buf,
+        (2 * core::mem::size_of::<u32>()) as u64..,
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+    let res = ShaderBytes::deserialise_to_iterator::<u32>(&transfer_buf_view.get_mapped_range())
+        .collect();
+    Ok(res)
+}
+
+#[tokio::main]
+async fn main() {
+    clustered::logging::init();
+    // The first argument picks the workgroup length, one invocation per workgroup leaves most of the gpu idle
+    let workgroup_len: usize = std::env::args()
+        .nth(1)
+        .map(|arg| {
+            arg.parse()
+                .expect("FATAL: Workgroup length must be a number!")
+        })
+        .unwrap_or(64);
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .request_adapter(&RequestAdapterOptions {
+            force_fallback_adapter: false,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    println!("Using {:?}", adapter.get_info());
+    let (device, queue) = adapter
+        .request_device(
+            &DeviceDescriptor {
+                label: None,
+                required_features: Features::STORAGE_RESOURCE_BINDING_ARRAY
+                    | Features::BUFFER_BINDING_ARRAY,
+                required_limits: Limits::default(),
+                ..Default::default()
+            },
+            None,
+        )
         .await
         .unwrap();
-    let shader_output: Vec<u32> =
-        ShaderBytes::deserialise_to_iterator::<u32>(&transfer_buf_view.get_mapped_range())
-            .collect();
+    let mut cs_source = String::new();
+    OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open("shader-mergesort.wgsl")
+        .unwrap()
+        .read_to_string(&mut cs_source)
+        .unwrap();
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut to_sort = Vec::new();
+    to_sort.resize_with(1024 * 1024 * 16, || rng.gen_range(0u32..=u32::MAX));
+
+    let gpu_before_time = Instant::now();
+    let shader_output = gpu_sort(&device, &queue, &cs_source, &to_sort, workgroup_len)
+        .await
+        .unwrap_or_else(|err| panic!("FATAL: {err}"));
     let gpu_time = Instant::now() - gpu_before_time;
 
     use rayon::prelude::*;
     let cpu_before_time = Instant::now();
     to_sort.par_sort();
     let cpu_time = Instant::now() - cpu_before_time;
-    println!("GPU took: {}ms", gpu_time.as_millis());
+    println!(
+        "GPU took: {}ms (workgroup length {workgroup_len})",
+        gpu_time.as_millis()
+    );
     println!("CPU took: {}ms", cpu_time.as_millis());
     // println!("{:?}", to_sort);
     // println!("{:?}", shader_output);
-    assert!(to_sort == shader_output);
+    assert_results_close(&shader_output, &to_sort, 0.0);
 }
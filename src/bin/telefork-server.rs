@@ -1,12 +1,122 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
 
-use clustered::serialisable_program::SerialisableProgram;
+use clustered::{
+    executor::GpuExecutor, serialisable_program::ProgramMetadata, telefork::ProgramResult,
+};
 
-use tokio::{net::TcpListener, time::Instant};
-use wgpu::{DeviceDescriptor, InstanceDescriptor, RequestAdapterOptions};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::{error, info, warn, Instrument};
+use uuid::Uuid;
+use wgpu::{InstanceDescriptor, RequestAdapterOptions};
+
+// How long jobs that are already running get to finish after we're asked to shut down
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+async fn handle_connection(
+    mut connection: TcpStream,
+    executor: Arc<GpuExecutor>,
+    mut shutdown_receiver: watch::Receiver<bool>,
+) {
+    let mut first_byte = [0u8; 1];
+    // Clients can keep the connection open and send job after job over it
+    loop {
+        // Only stop while waiting for the next job, a job that has started arriving gets finished
+        tokio::select! {
+            res = connection.peek(&mut first_byte) => {
+                if !matches!(res, Ok(1..)) {
+                    break;
+                }
+            }
+            _ = shutdown_receiver.wait_for(|shutting_down| *shutting_down) => break,
+        }
+
+        // Otherwise this and every later job would fail on the lost device
+        if let Err(err) = executor.recover_if_lost().await {
+            error!("{err}\nWhile recreating lost gpu device");
+        }
+        // in_data is streamed straight into a gpu buffer, so we never hold the whole capsule in memory
+        // Its sizes are validated against the device's limits first, so a malformed program is just an error
+        let gpu = executor.gpu();
+        let (program_metadata, in_buf) = match ProgramMetadata::read_streamed(
+            &mut connection,
+            gpu.device(),
+            gpu.queue(),
+        )
+        .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                if !clustered::networking::was_connection_severed(err.kind()) {
+                    error!(
+                        "{err}\nWhile receiving program from: {:?}",
+                        connection.peer_addr()
+                    );
+                }
+                // e.g. a program from a newer version of clustered, the client should know why it's being hung up on
+                // The rest of the program may still be on its way, so the connection can't be used for another job
+                if err.kind() == std::io::ErrorKind::InvalidData {
+                    let _ = clustered::compression::write_error(&mut connection, &err.to_string())
+                        .await;
+                }
+                break;
+            }
+        };
+        // Jobs don't carry an id over this protocol, so each one gets a fresh one just for the logs
+        let span = clustered::logging::task_span(Uuid::now_v7());
+        info!(parent: &span, "Received program from: {:?}!", connection.peer_addr());
+        let time_before = Instant::now();
+        let res = match executor
+            .execute_with_in_buf(&program_metadata, &in_buf)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                error!(parent: &span, "{err}\nWhile running program from: {:?}", connection.peer_addr());
+                // The whole program has been read, so the connection is still usable for the next job
+                if let Err(err) =
+                    clustered::compression::write_error(&mut connection, &err.to_string()).await
+                {
+                    error!(parent: &span, "{err}\nWhile sending error to: {:?}", connection.peer_addr());
+                    break;
+                }
+                continue;
+            }
+        };
+        let time_after = Instant::now();
+        let elapsed_secs = (time_after - time_before).as_secs_f32();
+        info!(parent: &span, "Took: {:?}s!", elapsed_secs);
+        info!(parent: &span, "Sending result...");
+        let program_res = ProgramResult {
+            elapsed_secs,
+            out_data: res,
+        };
+        if let Err(err) = program_res
+            .write(
+                &mut connection,
+                program_metadata.result_compression,
+                program_metadata.format_version,
+            )
+            .await
+        {
+            error!(parent: &span, "{err}\nWhile sending result to: {:?}", connection.peer_addr());
+            break;
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    clustered::logging::init();
     let instance = wgpu::Instance::new(InstanceDescriptor::default());
     let adapter = instance
         .request_adapter(&RequestAdapterOptions {
@@ -16,42 +126,124 @@ async fn main() {
         })
         .await
         .unwrap();
-    println!("Using {:?}", adapter.get_info());
-    let (device, queue) = adapter
-        .request_device(
-            &DeviceDescriptor {
-                label: None,
-                required_features: wgpu::Features::BUFFER_BINDING_ARRAY
-                    | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY,
-                required_limits: wgpu::Limits::default(),
-                memory_hints: wgpu::MemoryHints::default(),
-            },
-            None,
+    info!("Using {:?}", adapter.get_info());
+    // Shared by every connection, so the compiled programs and buffers it keeps are too
+    let executor = Arc::new(
+        GpuExecutor::new(
+            adapter,
+            wgpu::Features::BUFFER_BINDING_ARRAY | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY,
+            wgpu::Limits::default(),
         )
         .await
-        .unwrap();
+        .unwrap(),
+    );
 
-    println!("Listening...");
+    info!("Listening...");
     let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1337))
         .await
         .unwrap();
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let shutdown = clustered::networking::shutdown_signal();
+    tokio::pin!(shutdown);
     loop {
-        let (mut connection, _) = listener.accept().await.unwrap();
-        println!("Connection from {:?} accepted!", connection.peer_addr());
-        let program_capsule: SerialisableProgram = serde_json::from_slice(
-            &clustered::networking::read_buf(&mut connection)
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (connection, _) = match accepted {
+                    Ok(val) => val,
+                    Err(err) => {
+                        error!("{err}\nWhile accepting connection");
+                        continue;
+                    }
+                };
+                info!("Connection from {:?} accepted!", connection.peer_addr());
+                connections.spawn(handle_connection(
+                    connection,
+                    executor.clone(),
+                    shutdown_receiver.clone(),
+                ));
+            }
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting, let connections finish the job they're on, then exit
+    drop(listener);
+    shutdown_sender.send_replace(true);
+    warn!(
+        "Shutting down, waiting up to {}s for {} connection(s) to finish their jobs...",
+        SHUTDOWN_GRACE_PERIOD.as_secs(),
+        connections.len()
+    );
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Grace period over, abandoning {} connection(s) with jobs still running!",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    info!("Shut down");
+}
+
+#[cfg(test)]
+mod tests {
+    use clustered::{
+        compression::ResultCompression, telefork::TeleforkClient, test_support::multiply_program,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serves_concurrent_clients() {
+        let adapter = clustered::test_support::default_adapter().await;
+        let executor = Arc::new(
+            GpuExecutor::new(adapter, wgpu::Features::empty(), wgpu::Limits::default())
                 .await
-                .unwrap(),
-        )
-        .unwrap();
-        println!("Received and deserialised program!");
-        let time_before = Instant::now();
-        let res = program_capsule.run(&device, &queue).await.unwrap();
-        let time_after = Instant::now();
-        println!("Took: {:?}s!", (time_after - time_before).as_secs_f32());
-        println!("Sending result...");
-        clustered::networking::write_buf(&mut connection, &res)
-            .await
-            .unwrap();
+                .expect("Device must exist!"),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        // Dropping the sender would count as being told to shut down
+        let (_shutdown_sender, shutdown_receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            loop {
+                let (connection, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(
+                    connection,
+                    executor.clone(),
+                    shutdown_receiver.clone(),
+                ));
+            }
+        });
+
+        // Each client runs its own program, so their jobs interleave on the shared executor
+        let client = |factor: u32| async move {
+            let mut client = TeleforkClient::connect(server_addr).await.unwrap();
+            for job_id in 0..10u32 {
+                let in_data = (0..1000u32).map(|i| i + job_id).collect::<Vec<u32>>();
+                let res = client
+                    .submit(
+                        &multiply_program(factor, &in_data),
+                        ResultCompression::Never,
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    res.out_data,
+                    in_data
+                        .iter()
+                        .flat_map(|elem| (elem * factor).to_le_bytes())
+                        .collect::<Vec<u8>>()
+                );
+            }
+        };
+        tokio::join!(client(2), client(3));
     }
 }
@@ -1,29 +1,204 @@
 use std::{
-    collections::HashSet,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
+use clustered::{
+    config::{SocketOptions, TrackerConfig},
+    protocol::{PeerMessage, TrackerCommand, MAGIC_PEER2PEER_SEQUENCE, MAGIC_TRACKER_SEQUENCE},
+};
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     sync::Mutex,
+    time::{sleep, timeout},
 };
+use tracing::{info, warn};
 
-const MAGIC_TRACKER_SEQUENCE: &str = "Clustered tracker!";
+// Every report against a peer adds to its score, the score halves every blacklist_score_half_life_secs,
+// and while it's at or above blacklist_threshold the peer isn't handed out to stealers.
+// This way a peer that had a transient issue gets back into the peer list on its own.
+// Each reporter only counts once per half life, so with the default threshold no single peer can blacklist another by itself.
+const BLACKLIST_SCORE_PER_REPORT: f64 = 1.0;
+const BLACKLIST_FORGET_SCORE: f64 = 0.01; // Entries that decayed below this are dropped
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
-struct PeerAddr(SocketAddrV4);
+struct PeerAddr(SocketAddr);
+
+struct BlacklistEntry {
+    score: f64,
+    last_update: Instant,
+    // When each peer's report against this one last counted, by the reporter's p2p address
+    reporters: HashMap<PeerAddr, Instant>,
+}
+
+impl BlacklistEntry {
+    fn decayed_score(&self, now: Instant, config: &TrackerConfig) -> f64 {
+        let half_lives =
+            (now - self.last_update).as_secs_f64() / config.blacklist_score_half_life_secs;
+        self.score * 0.5f64.powf(half_lives)
+    }
+}
+
+// Number of tasks peers reported as newly completed, and when, for the reports within the throughput window
+struct ThroughputWindow {
+    reports: VecDeque<(Instant, u64)>,
+    // Until a whole window has passed since, the reports only cover this long
+    started: Instant,
+}
+
+impl Default for ThroughputWindow {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl ThroughputWindow {
+    fn new(started: Instant) -> Self {
+        Self {
+            reports: VecDeque::new(),
+            started,
+        }
+    }
+
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while self
+            .reports
+            .front()
+            .is_some_and(|(reported_at, _)| now - *reported_at > window)
+        {
+            self.reports.pop_front();
+        }
+    }
+
+    fn record(&mut self, now: Instant, n_completed: u64, window: Duration) {
+        self.prune(now, window);
+        self.reports.push_back((now, n_completed));
+    }
+
+    fn tasks_per_sec(&mut self, now: Instant, window: Duration) -> f64 {
+        self.prune(now, window);
+        let covered = now.saturating_duration_since(self.started).min(window);
+        if covered.is_zero() {
+            return 0.0;
+        }
+        self.reports.iter().map(|(_, n)| *n).sum::<u64>() as f64 / covered.as_secs_f64()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ClusterStatus {
+    n_peers: usize,
+    tasks_per_sec: f64,
+}
+
+type PeerRegistryType = Arc<Mutex<HashSet<PeerAddr>>>;
+type BlacklistType = Arc<Mutex<HashMap<PeerAddr, BlacklistEntry>>>;
+type ThroughputType = Arc<Mutex<ThroughputWindow>>;
+// The wgpu::Features bits each peer advertised for its gpu
+type PeerFeaturesType = Arc<Mutex<HashMap<PeerAddr, u64>>>;
+
+fn is_blacklisted(
+    blacklist: &mut HashMap<PeerAddr, BlacklistEntry>,
+    peer: &PeerAddr,
+    config: &TrackerConfig,
+) -> bool {
+    let now = Instant::now();
+    blacklist.retain(|_, entry| entry.decayed_score(now, config) >= BLACKLIST_FORGET_SCORE);
+    blacklist
+        .get(peer)
+        .is_some_and(|entry| entry.decayed_score(now, config) >= config.blacklist_threshold)
+}
+
+// Returns the peer's new score, or None if the reporter's last report against it still counts (so this one doesn't)
+fn report_peer(
+    blacklist: &mut HashMap<PeerAddr, BlacklistEntry>,
+    peer: PeerAddr,
+    reporter: PeerAddr,
+    config: &TrackerConfig,
+) -> Option<f64> {
+    let now = Instant::now();
+    let report_window = Duration::from_secs_f64(config.blacklist_score_half_life_secs);
+    let entry = blacklist.entry(peer).or_insert(BlacklistEntry {
+        score: 0.0,
+        last_update: now,
+        reporters: HashMap::new(),
+    });
+    entry
+        .reporters
+        .retain(|_, reported_at| now - *reported_at < report_window);
+    if entry.reporters.contains_key(&reporter) {
+        return None;
+    }
+    entry.reporters.insert(reporter, now);
+    entry.score = entry.decayed_score(now, config) + BLACKLIST_SCORE_PER_REPORT;
+    entry.last_update = now;
+    Some(entry.score)
+}
+
+// Pings the peer through its p2p listener, i.e. at the same address stealers get handed out
+async fn ping_peer(p2p_addr: SocketAddr, socket_options: &SocketOptions) -> io::Result<()> {
+    let mut connection = clustered::networking::connect(p2p_addr, socket_options).await?;
+    clustered::networking::write_buf(&mut connection, MAGIC_PEER2PEER_SEQUENCE.as_bytes()).await?;
+    // Answered with the same id
+    PeerMessage::Ping.write(&mut connection).await?;
+    let answer = connection.read_u8().await?;
+    if PeerMessage::from_u8(answer) != Some(PeerMessage::Ping) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Answered ping with {answer}"),
+        ));
+    }
+    Ok(())
+}
 
-async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerAddr>>>) {
+// Only returns once the peer failed to answer a ping within HEARTBEAT_TIMEOUT, with why
+// A peer whose machine died without closing its connection to us would otherwise stay in the peer list forever
+async fn heartbeat(p2p_addr: SocketAddr, socket_options: &SocketOptions) -> io::Error {
+    loop {
+        sleep(clustered::networking::HEARTBEAT_INTERVAL).await;
+        match timeout(
+            clustered::networking::HEARTBEAT_TIMEOUT,
+            ping_peer(p2p_addr, socket_options),
+        )
+        .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => return err,
+            Err(_) => {
+                return io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "No answer to ping within {}s",
+                        clustered::networking::HEARTBEAT_TIMEOUT.as_secs_f32()
+                    ),
+                )
+            }
+        }
+    }
+}
+
+async fn handle_peer(
+    mut peer: TcpStream,
+    extra: (
+        PeerRegistryType,
+        BlacklistType,
+        ThroughputType,
+        PeerFeaturesType,
+        Arc<TrackerConfig>,
+    ),
+) {
+    let (peer_registry, blacklist, throughput, peer_features, config) = extra;
+    let throughput_window = Duration::from_secs_f64(config.throughput_window_secs);
     let peer_addr = match peer.peer_addr() {
-        Ok(SocketAddr::V4(val)) => val,
-        _ => {
-            println!(
-                "Notice: Peer has address {:?}. which we do not support!",
-                peer.peer_addr()
-            );
+        // A v4 peer connecting to a dual stack listener shows up as ::ffff:a.b.c.d, it's still reachable at a.b.c.d
+        Ok(val) => SocketAddr::new(val.ip().to_canonical(), val.port()),
+        Err(err) => {
+            warn!("Couldn't get address of peer, giving up on it, error was: {err:?}");
             return;
         }
     };
@@ -32,16 +207,16 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
     if let Err(err) =
         clustered::networking::write_buf(&mut peer, MAGIC_TRACKER_SEQUENCE.as_bytes()).await
     {
-        println!(
-            "Notice: Peer {peer_addr:?} connected but i can't communicate with it, giving up on it, error was: {err:?}"
+        warn!(
+            "Peer {peer_addr:?} connected but i can't communicate with it, giving up on it, error was: {err:?}"
         );
         return;
     }
 
     // Send its ip to it
-    if let Err(err) = peer.write_u32(peer_addr.ip().to_bits()).await {
-        println!(
-            "Notice: Peer {peer_addr:?} connected but i can't communicate with it, giving up on it, error was: {err:?}"
+    if let Err(err) = clustered::networking::write_ip_addr(&mut peer, peer_addr.ip()).await {
+        warn!(
+            "Peer {peer_addr:?} connected but i can't communicate with it, giving up on it, error was: {err:?}"
         );
         return;
     }
@@ -50,21 +225,24 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
     // Why not just use the same port for everybody? Because some peers may have the same ip address, so they can't both listen on the same port
     // This is realistically only the case if the same computer has multiple peers running, but it is possible.
     // So to avoid a collision this mechanism was created.
-    let mut peer2peer_port = 8008;
+    let mut peer2peer_port = config.base_peer2peer_port;
     {
         let mut registry_lock = peer_registry.lock().await;
         // Try to insert peer into registry
         loop {
             let is_unique =
-                registry_lock.insert(PeerAddr(SocketAddrV4::new(*peer_addr.ip(), peer2peer_port)));
+                registry_lock.insert(PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port)));
             if is_unique {
                 // Found good p2p port
                 break;
             }
-            peer2peer_port = match peer2peer_port.checked_add(1) {
+            peer2peer_port = match peer2peer_port
+                .checked_add(1)
+                .filter(|port| *port <= config.max_peer2peer_port)
+            {
                 Some(val) => val,
                 None => {
-                    println!("Notice: Couldn't find p2p port for this peer, there are too many other peers with the same (ip, p2p_port) pair!, how did you even do this?, giving up on {peer_addr:?}...");
+                    warn!("Couldn't find p2p port for this peer, every port from {} to {} is taken by other peers with the same ip!, giving up on {peer_addr:?}...", config.base_peer2peer_port, config.max_peer2peer_port);
                     return;
                 }
             }
@@ -76,29 +254,46 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
         assert!(peer_registry
             .lock()
             .await
-            .remove(&PeerAddr(SocketAddrV4::new(
-                *peer_addr.ip(),
-                peer2peer_port,
-            ))));
-        println!("Notice: Peer {peer_addr:?} connected but i failed to send p2p port to it, giving up on it, error was: {err}!");
+            .remove(&PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port))));
+        warn!("Peer {peer_addr:?} connected but i failed to send p2p port to it, giving up on it, error was: {err}!");
         return;
     }
 
-    println!(
-        "Info: New peer: {:?} with p2p port: {:?}!",
+    info!(
+        "New peer: {:?} with p2p port: {:?}!",
         peer_addr.ip(),
         peer2peer_port
     );
 
+    // Peers report their total completed task count, we only want to count what's new since the last report
+    let mut last_completed_tasks = 0u64;
+
+    let heartbeat = heartbeat(
+        SocketAddr::new(peer_addr.ip(), peer2peer_port),
+        &config.socket_options,
+    );
+    tokio::pin!(heartbeat);
+
     loop {
-        let command_id = match peer.read_u8().await {
+        let command_id = tokio::select! {
+            err = &mut heartbeat => {
+                warn!(
+                    "Peer {:?} with p2p port: {:?} missed its heartbeat, dropping it, error was: {err}!",
+                    peer_addr.ip(),
+                    peer2peer_port
+                );
+                break;
+            }
+            command_id = peer.read_u8() => command_id,
+        };
+        let command_id = match command_id {
             Ok(val) => val,
             Err(err) => {
                 if clustered::networking::was_connection_severed(err.kind()) {
                     break;
                 } else {
-                    println!(
-                        "Notice: Failed to receive command from peer: {:?} with p2p port: {:?}, error was: {:?}",
+                    warn!(
+                        "Failed to receive command from peer: {:?} with p2p port: {:?}, error was: {:?}",
                         peer_addr.ip(), peer2peer_port, err
                     );
                     continue;
@@ -106,23 +301,28 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
             }
         };
 
-        match command_id {
-            1 => {
-                // This is the "List peers" command
+        match TrackerCommand::from_u8(command_id) {
+            Some(TrackerCommand::ListPeers) => {
                 let mut list_copy = peer_registry.lock().await.clone();
 
                 // Remove receiving peer from list
                 // TODO: Should peers do this themselves?
-                list_copy.remove(&PeerAddr(SocketAddrV4::new(
-                    *peer_addr.ip(),
-                    peer2peer_port,
-                )));
+                list_copy.remove(&PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port)));
 
-                let serialised_response = match serde_json::to_vec(&list_copy) {
+                // Don't hand out peers that have been misbehaving
+                {
+                    let mut blacklist_lock = blacklist.lock().await;
+                    list_copy.retain(|other_peer| {
+                        !is_blacklisted(&mut blacklist_lock, other_peer, &config)
+                    });
+                }
+
+                let serialised_response = match clustered::networking::to_versioned_json(&list_copy)
+                {
                     Ok(val) => val,
                     Err(err) => {
-                        println!("Notice: Failed to serialise peer list, error was: {err:?}, sending empty response!");
-                        serde_json::to_vec(&Vec::<PeerAddr>::new()).expect("Fatal: Serialising an empty vector really shouldn't fail, this might be an issue with the serialising implementations, please open a bug report!")
+                        warn!("Failed to serialise peer list, error was: {err:?}, sending empty response!");
+                        clustered::networking::to_versioned_json(&Vec::<PeerAddr>::new()).expect("Fatal: Serialising an empty vector really shouldn't fail, this might be an issue with the serialising implementations, please open a bug report!")
                     }
                 };
 
@@ -132,14 +332,180 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
                     if clustered::networking::was_connection_severed(err.kind()) {
                         break;
                     } else {
-                        println!("Notice: Failed to send response to 'peer list' query, error was: {err:?}!");
+                        warn!("Failed to send response to 'peer list' query, error was: {err:?}!");
                         continue;
                     }
                 }
             }
 
-            _ => {
-                println!("Notice: Peer {:?}, sent us command id {:?}, but this tracker doesn't know what that command id means, so we are ignoring the request!", peer_addr, command_id);
+            Some(TrackerCommand::ReportBadPeer) => {
+                let raw_reported_peer = match clustered::networking::read_buf_limited(
+                    &mut peer,
+                    clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+                )
+                .await
+                {
+                    Ok(val) => val,
+                    Err(err) => {
+                        if clustered::networking::was_connection_severed(err.kind()) {
+                            break;
+                        } else {
+                            warn!("Failed to receive reported peer for 'report bad peer' command, error was: {err:?}!");
+                            continue;
+                        }
+                    }
+                };
+
+                let reported_peer = match clustered::networking::from_versioned_json::<PeerAddr>(
+                    &raw_reported_peer,
+                ) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        warn!("Failed to deserialise reported peer for 'report bad peer' command, error was: {err}!");
+                        continue;
+                    }
+                };
+
+                let reporter = PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port));
+                let Some(score) = report_peer(
+                    &mut *blacklist.lock().await,
+                    reported_peer,
+                    reporter,
+                    &config,
+                ) else {
+                    info!(
+                        "Peer {:?} reported peer {:?} as misbehaving again, ignoring it as its last report still counts!",
+                        peer_addr, reported_peer.0
+                    );
+                    continue;
+                };
+                info!(
+                    "Peer {:?} reported peer {:?} as misbehaving, its score is now: {:.2}{}!",
+                    peer_addr,
+                    reported_peer.0,
+                    score,
+                    if score >= config.blacklist_threshold {
+                        ", it won't be handed out to stealers until it decays"
+                    } else {
+                        ""
+                    }
+                );
+            }
+
+            Some(TrackerCommand::ReportCompletedTasks) => {
+                let completed_tasks = match peer.read_u64().await {
+                    Ok(val) => val,
+                    Err(err) => {
+                        if clustered::networking::was_connection_severed(err.kind()) {
+                            break;
+                        } else {
+                            warn!("Failed to receive completed task count for 'report completed tasks' command, error was: {err:?}!");
+                            continue;
+                        }
+                    }
+                };
+
+                let newly_completed = completed_tasks.saturating_sub(last_completed_tasks);
+                last_completed_tasks = completed_tasks;
+                throughput
+                    .lock()
+                    .await
+                    .record(Instant::now(), newly_completed, throughput_window);
+            }
+
+            Some(TrackerCommand::ClusterStatus) => {
+                let status = ClusterStatus {
+                    n_peers: peer_registry.lock().await.len(),
+                    tasks_per_sec: throughput
+                        .lock()
+                        .await
+                        .tasks_per_sec(Instant::now(), throughput_window),
+                };
+
+                let serialised_response = match clustered::networking::to_versioned_json(&status) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        warn!("Failed to serialise cluster status, error was: {err:?}, not responding!");
+                        continue;
+                    }
+                };
+
+                if let Err(err) =
+                    clustered::networking::write_buf(&mut peer, &serialised_response).await
+                {
+                    if clustered::networking::was_connection_severed(err.kind()) {
+                        break;
+                    } else {
+                        warn!("Failed to send response to 'cluster status' query, error was: {err:?}!");
+                        continue;
+                    }
+                }
+            }
+
+            Some(TrackerCommand::AdvertiseFeatures) => {
+                let features = match peer.read_u64().await {
+                    Ok(val) => val,
+                    Err(err) => {
+                        if clustered::networking::was_connection_severed(err.kind()) {
+                            break;
+                        } else {
+                            warn!("Failed to receive features for 'advertise features' command, error was: {err:?}!");
+                            continue;
+                        }
+                    }
+                };
+
+                peer_features.lock().await.insert(
+                    PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port)),
+                    features,
+                );
+            }
+
+            Some(TrackerCommand::ListPeerFeatures) => {
+                let us = PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port));
+                let mut list_copy = peer_registry.lock().await.clone();
+
+                // Blacklisted peers aren't handed out to stealers, so they won't be running anything, except for the asking one,
+                // which runs its own tasks whatever the others think of it
+                {
+                    let mut blacklist_lock = blacklist.lock().await;
+                    list_copy.retain(|other_peer| {
+                        *other_peer == us
+                            || !is_blacklisted(&mut blacklist_lock, other_peer, &config)
+                    });
+                }
+
+                let features = {
+                    let peer_features_lock = peer_features.lock().await;
+                    list_copy
+                        .iter()
+                        .map(|other_peer| peer_features_lock.get(other_peer).copied())
+                        .collect::<Vec<Option<u64>>>()
+                };
+
+                let serialised_response = match clustered::networking::to_versioned_json(&features)
+                {
+                    Ok(val) => val,
+                    Err(err) => {
+                        warn!("Failed to serialise peer features, error was: {err:?}, not responding!");
+                        continue;
+                    }
+                };
+
+                if let Err(err) =
+                    clustered::networking::write_buf(&mut peer, &serialised_response).await
+                {
+                    if clustered::networking::was_connection_severed(err.kind()) {
+                        break;
+                    } else {
+                        warn!("Failed to send response to 'list peer features' query, error was: {err:?}!");
+                        continue;
+                    }
+                }
+            }
+
+            None => {
+                warn!("Peer {:?}, sent us command id {:?}, but this tracker doesn't know what that command id means, so we are ignoring the request!", peer_addr, command_id);
                 continue;
             }
         }
@@ -149,13 +515,14 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
     assert!(peer_registry
         .lock()
         .await
-        .remove(&PeerAddr(SocketAddrV4::new(
-            *peer_addr.ip(),
-            peer2peer_port,
-        ))));
+        .remove(&PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port))));
+    peer_features
+        .lock()
+        .await
+        .remove(&PeerAddr(SocketAddr::new(peer_addr.ip(), peer2peer_port)));
 
-    println!(
-        "Info: Peer {:?}, with p2p port: {:?}, disconnected!",
+    info!(
+        "Peer {:?}, with p2p port: {:?}, disconnected!",
         peer_addr.ip(),
         peer2peer_port
     );
@@ -163,12 +530,189 @@ async fn handle_peer(mut peer: TcpStream, peer_registry: Arc<Mutex<HashSet<PeerA
 
 #[tokio::main]
 async fn main() {
-    let peer_registry = Arc::new(Mutex::from(HashSet::<PeerAddr>::new()));
-    println!("Info: Tracker online, listening...");
+    clustered::logging::init();
+    let config: TrackerConfig = clustered::config::load_from_args()
+        .await
+        .unwrap_or_else(|err| panic!("FATAL: Couldn't load config!\n{err}"));
+    if let Err(err) = config.validate() {
+        panic!("FATAL: Invalid config ({err})!");
+    }
+    let peer_registry: PeerRegistryType = Arc::new(Mutex::from(HashSet::<PeerAddr>::new()));
+    let blacklist: BlacklistType = Default::default();
+    let throughput: ThroughputType = Default::default();
+    let peer_features: PeerFeaturesType = Default::default();
+    info!("Tracker online, listening...");
     clustered::networking::listen(
-        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1337)),
+        config.listen_addr,
+        config.socket_options,
         handle_peer,
-        peer_registry,
+        (
+            peer_registry,
+            blacklist,
+            throughput,
+            peer_features,
+            Arc::new(config),
+        ),
+        clustered::networking::shutdown_signal(),
     )
     .await;
+    info!("Shutting down!");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // The tracker's side of connect_to_tracker, returns (our ip, p2p port, connection)
+    async fn register(tracker_addr: SocketAddr) -> (IpAddr, u16, TcpStream) {
+        let mut connection = TcpStream::connect(tracker_addr).await.unwrap();
+        let magic = clustered::networking::read_buf_limited(
+            &mut connection,
+            clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
+        .await
+        .unwrap();
+        assert_eq!(magic, MAGIC_TRACKER_SEQUENCE.as_bytes());
+        let our_ip = clustered::networking::read_ip_addr(&mut connection)
+            .await
+            .unwrap();
+        let peer2peer_port = connection.read_u16().await.unwrap();
+        (our_ip, peer2peer_port, connection)
+    }
+
+    #[tokio::test]
+    async fn test_ipv6_peer_is_listed() {
+        // Not every machine running the tests has ipv6 set up
+        let Ok(listener) = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await else {
+            return;
+        };
+        let tracker_addr = listener.local_addr().unwrap();
+        let config = Arc::new(TrackerConfig::default());
+        let extra = (
+            PeerRegistryType::default(),
+            BlacklistType::default(),
+            ThroughputType::default(),
+            PeerFeaturesType::default(),
+            config.clone(),
+        );
+        tokio::spawn(async move {
+            loop {
+                let (connection, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_peer(connection, extra.clone()));
+            }
+        });
+
+        let (first_ip, first_port, mut first_connection) = register(tracker_addr).await;
+        assert_eq!(first_ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(first_port, config.base_peer2peer_port);
+        // Same ip, so it gets the next port
+        let (_, second_port, mut second_connection) = register(tracker_addr).await;
+        assert_eq!(second_port, config.base_peer2peer_port + 1);
+
+        TrackerCommand::ListPeers
+            .write(&mut second_connection)
+            .await
+            .unwrap();
+        let raw_peer_list = clustered::networking::read_buf_limited(
+            &mut second_connection,
+            clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
+        .await
+        .unwrap();
+        let peer_list: Vec<PeerAddr> =
+            clustered::networking::from_versioned_json(&raw_peer_list).unwrap();
+        assert_eq!(
+            peer_list,
+            [PeerAddr(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                first_port
+            ))]
+        );
+
+        // Features are listed for both, the asking peer included, but only the first one advertised any
+        TrackerCommand::AdvertiseFeatures
+            .write(&mut first_connection)
+            .await
+            .unwrap();
+        first_connection
+            .write_u64(wgpu::Features::SHADER_F64.bits())
+            .await
+            .unwrap();
+        loop {
+            TrackerCommand::ListPeerFeatures
+                .write(&mut second_connection)
+                .await
+                .unwrap();
+            let raw_features = clustered::networking::read_buf_limited(
+                &mut second_connection,
+                clustered::networking::MAX_CONTROL_MESSAGE_LEN,
+            )
+            .await
+            .unwrap();
+            let mut features: Vec<Option<u64>> =
+                clustered::networking::from_versioned_json(&raw_features).unwrap();
+            features.sort();
+            assert_eq!(features.len(), 2);
+            // The first peer's advertisement goes over another connection, so it might not have arrived yet
+            if features[1].is_some() {
+                assert_eq!(features, [None, Some(wgpu::Features::SHADER_F64.bits())]);
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn test_reporters_count_once_per_window() {
+        let config = TrackerConfig::default();
+        let peer_at = |port| PeerAddr(SocketAddr::from(([10, 0, 0, 1], port)));
+        let reported = peer_at(8008);
+        let mut blacklist = HashMap::new();
+
+        // However often a single peer reports another, that's one report
+        assert_eq!(
+            report_peer(&mut blacklist, reported, peer_at(8009), &config),
+            Some(BLACKLIST_SCORE_PER_REPORT)
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                report_peer(&mut blacklist, reported, peer_at(8009), &config),
+                None
+            );
+        }
+        assert!(!is_blacklisted(&mut blacklist, &reported, &config));
+
+        // It takes enough different reporters (one more, as the earlier reports decayed a little since)
+        let n_reporters_needed =
+            (config.blacklist_threshold / BLACKLIST_SCORE_PER_REPORT).ceil() as u16;
+        for port in 8010..=8009 + n_reporters_needed {
+            assert!(report_peer(&mut blacklist, reported, peer_at(port), &config).is_some());
+        }
+        assert!(is_blacklisted(&mut blacklist, &reported, &config));
+    }
+
+    #[test]
+    fn test_throughput_before_a_whole_window() {
+        let window = Duration::from_secs(30);
+        let started = Instant::now();
+        let mut throughput = ThroughputWindow::new(started);
+        assert_eq!(throughput.tasks_per_sec(started, window), 0.0);
+
+        // 10 tasks in the first 5 seconds is 2 per second, not 10 per 30 seconds
+        throughput.record(started + Duration::from_secs(5), 10, window);
+        assert_eq!(
+            throughput.tasks_per_sec(started + Duration::from_secs(5), window),
+            2.0
+        );
+        // Once a whole window has passed it's averaged over the window
+        throughput.record(started + Duration::from_secs(40), 60, window);
+        assert_eq!(
+            throughput.tasks_per_sec(started + Duration::from_secs(40), window),
+            2.0
+        );
+    }
 }
@@ -0,0 +1,231 @@
+use std::io;
+#[cfg(feature = "compressed-transfers")]
+use std::io::{Read, Write};
+
+#[cfg(feature = "compressed-transfers")]
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// How the sender of a program wants its result to come back
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResultCompression {
+    #[default]
+    Never,
+    Always,
+    // Only compress if a sample of the result looks compressible
+    Auto,
+}
+
+// Results are sent as a one byte marker followed by a write_buf framed payload
+const MARKER_RAW: u8 = 0;
+const MARKER_DEFLATE: u8 = 1;
+// Instead of a result, the payload is the (utf8) message of why the program couldn't be run, see write_error
+const MARKER_ERROR: u8 = 2;
+
+// Outputs with fewer bits of entropy per byte than this are considered worth compressing
+const AUTO_COMPRESS_MAX_ENTROPY: f64 = 6.0;
+const ENTROPY_SAMPLE_NBYTES: usize = 64 * 1024;
+
+// Shannon entropy (in bits per byte) of an evenly strided sample of the data, 8 means incompressible
+pub fn estimate_entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let stride = data.len().div_ceil(ENTROPY_SAMPLE_NBYTES);
+    let mut histogram = [0usize; 256];
+    let mut n_sampled = 0;
+    for byte in data.iter().step_by(stride) {
+        histogram[usize::from(*byte)] += 1;
+        n_sampled += 1;
+    }
+    histogram
+        .iter()
+        .filter(|count| **count != 0)
+        .map(|count| {
+            let p = *count as f64 / n_sampled as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+pub fn should_compress(data: &[u8], compression: ResultCompression) -> bool {
+    match compression {
+        ResultCompression::Never => false,
+        ResultCompression::Always => true,
+        ResultCompression::Auto => estimate_entropy(data) < AUTO_COMPRESS_MAX_ENTROPY,
+    }
+}
+
+#[cfg(feature = "compressed-transfers")]
+pub fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// For data whose decompressed length is known up front, the result is preallocated
+// and it's an error (rather than more allocation) if the data doesn't decompress to exactly that
+#[cfg(feature = "compressed-transfers")]
+pub fn decompress_exact(data: &[u8], nbytes: usize) -> io::Result<Vec<u8>> {
+    let mut res = Vec::with_capacity(nbytes);
+    DeflateDecoder::new(data)
+        .take(u64::try_from(nbytes).unwrap().saturating_add(1))
+        .read_to_end(&mut res)?;
+    if res.len() != nbytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Data decompressed to {}{} bytes, but {nbytes} were announced",
+                if res.len() > nbytes { "over " } else { "" },
+                res.len().min(nbytes)
+            ),
+        ));
+    }
+    Ok(res)
+}
+
+// Without the compressed-transfers feature the result is sent raw whatever compression says, which every reader can read
+// NOTE: Compressing takes a copy of result, as it happens on tokio's blocking pool so it doesn't stall the runtime
+pub async fn write_result(
+    connection: &mut (impl AsyncWrite + Unpin),
+    result: &[u8],
+    compression: ResultCompression,
+) -> io::Result<()> {
+    #[cfg(feature = "compressed-transfers")]
+    if should_compress(result, compression) {
+        let uncompressed = result.to_vec();
+        let compressed = tokio::task::spawn_blocking(move || compress(&uncompressed))
+            .await
+            .unwrap()
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("{err}\nWhile compressing result"))
+            })?;
+        connection.write_u8(MARKER_DEFLATE).await?;
+        return crate::networking::write_buf(connection, &compressed).await;
+    }
+    #[cfg(not(feature = "compressed-transfers"))]
+    let _ = compression;
+    connection.write_u8(MARKER_RAW).await?;
+    crate::networking::write_buf(connection, result).await
+}
+
+// Sent in place of a result when the program failed to run (e.g. its WGSL doesn't compile),
+// so the submitter finds out why instead of just seeing the connection close
+pub async fn write_error(
+    connection: &mut (impl AsyncWrite + Unpin),
+    message: &str,
+) -> io::Result<()> {
+    connection.write_u8(MARKER_ERROR).await?;
+    crate::networking::write_buf(connection, message.as_bytes()).await
+}
+
+// Reads a result sent with write_result, decompressing it if the sender chose to compress it
+// An error sent with write_error comes back as an ErrorKind::Other error holding its message
+// out_data_nbytes is how big the result should be, anything announcing more is rejected before it's allocated
+pub async fn read_result(
+    connection: &mut (impl AsyncRead + Unpin),
+    out_data_nbytes: usize,
+) -> io::Result<Vec<u8>> {
+    let marker = connection.read_u8().await?;
+    let max_payload_nbytes = match marker {
+        MARKER_ERROR => crate::networking::MAX_CONTROL_MESSAGE_LEN,
+        // Deflate can make incompressible data a little bigger, but never by anywhere near this much
+        MARKER_DEFLATE => out_data_nbytes
+            .saturating_add(out_data_nbytes / 16)
+            .saturating_add(1024),
+        _ => out_data_nbytes,
+    };
+    let payload = crate::networking::read_buf_limited(connection, max_payload_nbytes).await?;
+    match marker {
+        MARKER_RAW => Ok(payload),
+        #[cfg(feature = "compressed-transfers")]
+        MARKER_DEFLATE => {
+            tokio::task::spawn_blocking(move || decompress_exact(&payload, out_data_nbytes))
+                .await
+                .unwrap()
+                .map_err(|err| {
+                    io::Error::new(err.kind(), format!("{err}\nWhile decompressing result"))
+                })
+        }
+        #[cfg(not(feature = "compressed-transfers"))]
+        MARKER_DEFLATE => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Result is compressed, but this build doesn't have the compressed-transfers feature",
+        )),
+        MARKER_ERROR => Err(io::Error::other(format!(
+            "Remote failed to run the program:\n{}",
+            String::from_utf8_lossy(&payload)
+        ))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unknown result compression marker: {marker}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_result_round_trip() {
+        let sparse_result = (0..64 * 1024u32)
+            .flat_map(|i| if i % 100 == 0 { i } else { 0 }.to_le_bytes())
+            .collect::<Vec<u8>>();
+        assert!(should_compress(&sparse_result, ResultCompression::Auto));
+
+        for compression in [
+            ResultCompression::Never,
+            ResultCompression::Always,
+            ResultCompression::Auto,
+        ] {
+            let (mut sender, mut receiver) = tokio::io::duplex(1024 * 1024);
+            write_result(&mut sender, &sparse_result, compression)
+                .await
+                .unwrap();
+            assert_eq!(
+                read_result(&mut receiver, sparse_result.len())
+                    .await
+                    .unwrap(),
+                sparse_result
+            );
+        }
+
+        // Results bigger than expected are rejected, however they were sent
+        for compression in [ResultCompression::Never, ResultCompression::Always] {
+            let (mut sender, mut receiver) = tokio::io::duplex(1024 * 1024);
+            write_result(&mut sender, &sparse_result, compression)
+                .await
+                .unwrap();
+            assert_eq!(
+                read_result(&mut receiver, sparse_result.len() - 4)
+                    .await
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_round_trip() {
+        let (mut sender, mut receiver) = tokio::io::duplex(1024);
+        write_error(&mut sender, "Shader parsing error: expected ';'")
+            .await
+            .unwrap();
+        let err = read_result(&mut receiver, 0).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(err
+            .to_string()
+            .ends_with("Shader parsing error: expected ';'"));
+    }
+
+    #[test]
+    fn test_random_data_is_not_auto_compressed() {
+        use rand::{rngs::StdRng, RngCore, SeedableRng};
+        let mut random_result = vec![0u8; 256 * 1024];
+        StdRng::seed_from_u64(5).fill_bytes(&mut random_result);
+        assert!(!should_compress(&random_result, ResultCompression::Auto));
+    }
+}
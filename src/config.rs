@@ -0,0 +1,443 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+// What the stealing thresholds of a peer are compared against
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StealBalancing {
+    // Every task counts the same
+    #[default]
+    TaskCount,
+    // Tasks count by SerialisableProgram::estimated_cost, so a few huge tasks aren't treated like a few tiny ones
+    EstimatedCost,
+}
+
+// Applied to the sockets of every connection we make or accept, see networking::connect and networking::listen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct SocketOptions {
+    // Disables Nagle's algorithm, which would otherwise hold back the small messages of the control protocol
+    // (peer list requests, steal requests, acks, ...) waiting for more data to batch them with, default: true
+    pub nodelay: bool,
+    // Size of the kernel's send buffer, unset leaves it to the OS, default: unset
+    pub send_buffer_nbytes: Option<u32>,
+    // Size of the kernel's receive buffer, unset leaves it to the OS, default: unset
+    pub recv_buffer_nbytes: Option<u32>,
+    // Whether the OS probes idle connections to notice peers that died without closing them, default: false
+    pub keepalive: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer_nbytes: None,
+            recv_buffer_nbytes: None,
+            keepalive: false,
+        }
+    }
+}
+
+// NOTE: Every field has a default, so a config file only needs to contain the knobs you actually want to change
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PeerConfig {
+    // Default: 127.0.0.1:1337
+    pub tracker_addr: SocketAddr,
+    // We won't steal if we have more than this number of tasks, default: 5
+    pub minimum_tasks_before_start_stealing_tresh: usize,
+    // No stealing will be allowed (from us) if we have less than this number of tasks, default: 1
+    pub no_steal_treshold: usize,
+    // Whether the two thresholds below are used instead of the task count ones above, default: task_count
+    pub steal_balancing: StealBalancing,
+    // Same as minimum_tasks_before_start_stealing_tresh but for the summed estimated cost of our tasks, default: 5242880
+    pub minimum_cost_before_start_stealing_tresh: u64,
+    // Same as no_steal_treshold but for the summed estimated cost of our tasks, default: 1048576
+    pub no_steal_cost_treshold: u64,
+    // Fraction of output elements of each task to spot check against a cpu reference (if we have one), 0 disables it, default: 0.0
+    pub shadow_check_fraction: f64,
+    // How often we tell the tracker how many tasks we've completed, default: 5
+    pub stats_report_interval_secs: f64,
+    // Upper bound on dispatches in flight on the gpu at once, see DispatchLimiter, default: 1024
+    pub max_in_flight_dispatches: usize,
+    // Upper bound on tasks we've submitted whose results haven't been consumed yet,
+    // submitting more waits for one of them to finish, default: 256
+    pub max_in_flight_tasks: usize,
+    // Generate synthetic tasks to load the cluster with, enabled by adding a [stress] table, default: unset
+    pub stress: Option<StressConfig>,
+    // For the connections to the tracker and other peers, set in a [socket_options] table, default: see SocketOptions
+    pub socket_options: SocketOptions,
+    // Addresses other than loopback that are allowed to pause and resume us (see `peer --pause`), default: empty
+    pub admin_addrs: Vec<IpAddr>,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            tracker_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1337)),
+            minimum_tasks_before_start_stealing_tresh: 5,
+            no_steal_treshold: 1,
+            steal_balancing: StealBalancing::TaskCount,
+            minimum_cost_before_start_stealing_tresh: 5 * 1024 * 1024,
+            no_steal_cost_treshold: 1024 * 1024,
+            shadow_check_fraction: 0.0,
+            stats_report_interval_secs: 5.0,
+            max_in_flight_dispatches: crate::DEFAULT_MAX_IN_FLIGHT_DISPATCHES,
+            max_in_flight_tasks: 256,
+            stress: None,
+            socket_options: SocketOptions::default(),
+            admin_addrs: Vec::new(),
+        }
+    }
+}
+
+// A load generator built into the peer, for exercising stealing, returning, backpressure and timeouts
+// without a real workload, every task sums task_nelements random u32s and its result is checked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct StressConfig {
+    // How many tasks are generated per second, submitting still waits for a free max_in_flight_tasks slot, default: 20
+    pub tasks_per_sec: f64,
+    // Size of every task, in u32s, default: 65536
+    pub task_nelements: usize,
+    // How long to keep generating tasks for, default: 60
+    pub duration_secs: f64,
+    // Deadline of every task relative to its submission, unset means no deadline, default: unset
+    pub task_deadline_secs: Option<f64>,
+    // The task inputs are generated from this, so runs are reproducible, default: 0
+    pub seed: u64,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            tasks_per_sec: 20.0,
+            task_nelements: 64 * 1024,
+            duration_secs: 60.0,
+            task_deadline_secs: None,
+            seed: 0,
+        }
+    }
+}
+
+impl StressConfig {
+    // The seconds all become Durations, which can't be negative, infinite or NaN,
+    // and the interval between tasks can't be zero either
+    pub fn validate(&self) -> Result<(), String> {
+        if !Duration::try_from_secs_f64(1.0 / self.tasks_per_sec)
+            .is_ok_and(|interval| !interval.is_zero())
+        {
+            return Err(format!(
+                "tasks_per_sec must be positive and finite, not {}",
+                self.tasks_per_sec
+            ));
+        }
+        if Duration::try_from_secs_f64(self.duration_secs).is_err() {
+            return Err(format!(
+                "duration_secs must be non negative and finite, not {}",
+                self.duration_secs
+            ));
+        }
+        if let Some(task_deadline_secs) = self.task_deadline_secs {
+            if Duration::try_from_secs_f64(task_deadline_secs).is_err() {
+                return Err(format!(
+                    "task_deadline_secs must be non negative and finite, not {task_deadline_secs}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct TrackerConfig {
+    // [::]:1337 accepts ipv6 peers too (as well as ipv4 ones, on dual stack systems), default: 0.0.0.0:1337
+    pub listen_addr: SocketAddr,
+    // First p2p port handed out to peers, peers with the same ip get the next free one, default: 8008
+    pub base_peer2peer_port: u16,
+    // Last p2p port handed out (inclusive), once every port up to it is taken for an ip more peers from it are turned away, default: 65535
+    pub max_peer2peer_port: u16,
+    // Peers whose misbehaviour score is at or above this aren't handed out to stealers, default: 3.0
+    pub blacklist_threshold: f64,
+    // Time it takes for a misbehaviour score to halve, default: 60
+    pub blacklist_score_half_life_secs: f64,
+    // Cluster throughput is averaged over this many of the most recent seconds, default: 30
+    pub throughput_window_secs: f64,
+    // For the connections of peers to us, set in a [socket_options] table, default: see SocketOptions
+    pub socket_options: SocketOptions,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 1337)),
+            base_peer2peer_port: 8008,
+            max_peer2peer_port: u16::MAX,
+            blacklist_threshold: 3.0,
+            blacklist_score_half_life_secs: 60.0,
+            throughput_window_secs: 30.0,
+            socket_options: SocketOptions::default(),
+        }
+    }
+}
+
+impl TrackerConfig {
+    // An inverted p2p port range would turn away every peer
+    pub fn validate(&self) -> Result<(), String> {
+        if self.base_peer2peer_port > self.max_peer2peer_port {
+            return Err(format!(
+                "base_peer2peer_port ({}) must not be above max_peer2peer_port ({})",
+                self.base_peer2peer_port, self.max_peer2peer_port
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub async fn load_from_file<Config: DeserializeOwned>(
+    path: impl AsRef<Path>,
+) -> io::Result<Config> {
+    let path = path.as_ref();
+    let config_file_contents = tokio::fs::read_to_string(path).await.map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile reading config from file: {path:?}"),
+        )
+    })?;
+    toml::from_str(&config_file_contents).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{err}\nWhile parsing config from file: {path:?}"),
+        )
+    })
+}
+
+// Parses a toml config with `key=value` overrides applied on top, e.g. `listen_addr=[::]:1337` or `socket_options.nodelay=false`
+// Values are parsed as toml, or taken as a plain string if they aren't valid toml, so addresses don't need quoting
+pub fn parse_with_overrides<Config: DeserializeOwned>(
+    contents: &str,
+    overrides: &[String],
+) -> io::Result<Config> {
+    let mut table: toml::Table = toml::from_str(contents)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    for arg in overrides {
+        let (key, raw_value) = arg.split_once('=').ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Override {arg:?} isn't of the form key=value"),
+            )
+        })?;
+        let value = toml::from_str::<toml::Table>(&format!("value = {raw_value}"))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw_value.to_owned()));
+        let mut path = key.split('.').collect::<Vec<&str>>();
+        let field = path.pop().unwrap();
+        let mut target = &mut table;
+        for part in path {
+            target = match target
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            {
+                toml::Value::Table(sub_table) => sub_table,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Override {arg:?} sets a field of {part:?}, which isn't a table"),
+                    ))
+                }
+            };
+        }
+        target.insert(field.to_owned(), value);
+    }
+    toml::Value::Table(table)
+        .try_into()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+}
+
+// Loads the config from the toml file given as the first command line argument (or uses the defaults if there isn't one),
+// then applies the `key=value` overrides given after it, see parse_with_overrides
+// NOTE: A first argument with an = in it is taken as an override, not a file, so e.g. `tracker listen_addr=[::]:1337` works
+pub async fn load_from_args<Config: DeserializeOwned>() -> io::Result<Config> {
+    let mut args = std::env::args().skip(1).peekable();
+    let (contents, source) = match args.next_if(|arg| !arg.contains('=')) {
+        Some(path) => (
+            tokio::fs::read_to_string(&path).await.map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{err}\nWhile reading config from file: {path:?}"),
+                )
+            })?,
+            format!("file: {path:?}"),
+        ),
+        None => (String::new(), "defaults".to_owned()),
+    };
+    let overrides = args.collect::<Vec<String>>();
+    parse_with_overrides(&contents, &overrides).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("{err}\nWhile parsing config from {source} with overrides: {overrides:?}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_config_uses_defaults() {
+        let config: PeerConfig = toml::from_str("minimum_tasks_before_start_stealing_tresh = 10")
+            .expect("Partial config should parse!");
+        assert_eq!(
+            config,
+            PeerConfig {
+                minimum_tasks_before_start_stealing_tresh: 10,
+                ..Default::default()
+            }
+        );
+        let config: PeerConfig = toml::from_str(r#"steal_balancing = "estimated_cost""#)
+            .expect("Partial config should parse!");
+        assert_eq!(config.steal_balancing, StealBalancing::EstimatedCost);
+        assert_eq!(config.stress, None);
+        let config: PeerConfig = toml::from_str("[stress]\ntasks_per_sec = 100.0")
+            .expect("Partial config should parse!");
+        assert_eq!(
+            config.stress,
+            Some(StressConfig {
+                tasks_per_sec: 100.0,
+                ..Default::default()
+            })
+        );
+
+        let config: TrackerConfig = toml::from_str(
+            r#"
+            listen_addr = "127.0.0.1:4242"
+            base_peer2peer_port = 9000
+            "#,
+        )
+        .expect("Partial config should parse!");
+        assert_eq!(
+            config.listen_addr,
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 4242))
+        );
+        assert_eq!(config.base_peer2peer_port, 9000);
+        assert_eq!(
+            config.blacklist_threshold,
+            TrackerConfig::default().blacklist_threshold
+        );
+
+        let config: PeerConfig = toml::from_str("[socket_options]\nsend_buffer_nbytes = 65536")
+            .expect("Partial config should parse!");
+        assert_eq!(
+            config.socket_options,
+            SocketOptions {
+                send_buffer_nbytes: Some(65536),
+                ..Default::default()
+            }
+        );
+        assert!(config.socket_options.nodelay);
+    }
+
+    #[test]
+    fn test_stress_config_validation() {
+        assert!(StressConfig::default().validate().is_ok());
+        for invalid in [
+            StressConfig {
+                tasks_per_sec: 0.0,
+                ..Default::default()
+            },
+            StressConfig {
+                tasks_per_sec: f64::INFINITY,
+                ..Default::default()
+            },
+            StressConfig {
+                tasks_per_sec: -1.0,
+                ..Default::default()
+            },
+            StressConfig {
+                duration_secs: f64::NAN,
+                ..Default::default()
+            },
+            StressConfig {
+                task_deadline_secs: Some(-5.0),
+                ..Default::default()
+            },
+        ] {
+            assert!(
+                invalid.validate().is_err(),
+                "{invalid:?} should be invalid!"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tracker_config_validation() {
+        assert!(TrackerConfig::default().validate().is_ok());
+        // A range of a single port is fine
+        assert!(TrackerConfig {
+            base_peer2peer_port: 9000,
+            max_peer2peer_port: 9000,
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+        assert!(TrackerConfig {
+            base_peer2peer_port: 9001,
+            max_peer2peer_port: 9000,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config: TrackerConfig = parse_with_overrides(
+            "base_peer2peer_port = 9000\nblacklist_threshold = 5.0",
+            &[
+                "listen_addr=[::]:4242".to_owned(),
+                "base_peer2peer_port=9100".to_owned(),
+                "max_peer2peer_port=9199".to_owned(),
+                "socket_options.nodelay=false".to_owned(),
+            ],
+        )
+        .expect("Overrides should apply!");
+        assert_eq!(
+            config,
+            TrackerConfig {
+                listen_addr: "[::]:4242".parse().unwrap(),
+                base_peer2peer_port: 9100,
+                max_peer2peer_port: 9199,
+                blacklist_threshold: 5.0,
+                socket_options: SocketOptions {
+                    nodelay: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parse_with_overrides::<PeerConfig>("", &[]).unwrap(),
+            PeerConfig::default()
+        );
+
+        for bad_override in [
+            "tracker_addr",
+            "tracker_addr=not an address",
+            "tracker_addr.port=1337",
+        ] {
+            assert!(
+                parse_with_overrides::<PeerConfig>("", &[bad_override.to_owned()]).is_err(),
+                "{bad_override:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    borrow::{Borrow, Cow},
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    Adapter, BufferUsages, CommandEncoderDescriptor, RequestDeviceError, ShaderModule,
+    ShaderModuleDescriptor,
+};
+
+use crate::{
+    compression::ResultCompression,
+    gpu_context::GpuContext,
+    serialisable_program::{ProgramMetadata, ProgramRunError, SerialisableProgram},
+    DispatchPipeline, RunShaderError,
+};
+
+// Compiled programs kept around, the least recently used one is evicted to make room for a new one
+const MAX_CACHED_MODULES: usize = 64;
+// Pipelines kept around per compiled program, evicted the same way
+const MAX_CACHED_PIPELINES_PER_MODULE: usize = 16;
+// Spare output and readback buffers kept around per buffer size
+const MAX_POOLED_BUFFERS_PER_SIZE: usize = 4;
+// Spare metadata uniforms, one is needed per program running at the same time
+const MAX_POOLED_META_BUFS: usize = 16;
+
+// (entry point, input size in bytes, output size in bytes), the bindings are sized to the buffers
+type PipelineKey = (String, u64, u64);
+
+struct CachedModule {
+    module: Arc<ShaderModule>,
+    pipelines: LruCache<PipelineKey, Arc<DispatchPipeline>>,
+}
+
+// A map that, once full, evicts the least recently used entry to make room for a new one,
+// found by a linear scan, which is plenty at the sizes used here
+struct LruCache<K, V> {
+    // Value and when it was last used
+    entries: HashMap<K, (V, u64)>,
+    capacity: usize,
+    // Bumped on every use, so it orders the uses
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Counts as a use
+    fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.clock += 1;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock));
+    }
+}
+
+// One device and queue for running program after program, plus what can be reused between runs:
+// compiled shader modules and their pipelines (workers tend to get the same kernel over and over, just with different data),
+// and the metadata, output and readback buffers. Meant to be created once per worker and shared (e.g. in an Arc)
+// by everything that runs programs.
+pub struct GpuExecutor {
+    // Swapped for a new one by recover_if_lost, every run holds on to the one it started with
+    gpu: RwLock<Arc<GpuContext>>,
+    // So concurrent recover_if_lost calls don't each create a new device
+    recovering: tokio::sync::Mutex<()>,
+    // By program source
+    modules: Mutex<LruCache<String, CachedModule>>,
+    meta_buf_pool: Mutex<Vec<wgpu::Buffer>>,
+    // By size in bytes
+    out_buf_pool: Mutex<HashMap<u64, Vec<wgpu::Buffer>>>,
+    // By size in bytes
+    readback_buf_pool: Mutex<HashMap<u64, Vec<wgpu::Buffer>>>,
+}
+
+impl GpuExecutor {
+    pub async fn new(
+        adapter: Adapter,
+        required_features: wgpu::Features,
+        required_limits: wgpu::Limits,
+    ) -> Result<Self, RequestDeviceError> {
+        Ok(Self {
+            gpu: RwLock::new(Arc::new(
+                GpuContext::new(adapter, required_features, required_limits).await?,
+            )),
+            recovering: tokio::sync::Mutex::new(()),
+            modules: Mutex::new(LruCache::new(MAX_CACHED_MODULES)),
+            meta_buf_pool: Mutex::new(Vec::new()),
+            out_buf_pool: Mutex::new(HashMap::new()),
+            readback_buf_pool: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The current device and queue, anything that has to stay on one device (e.g. the in_buf for execute_with_in_buf)
+    // should keep using the returned one, instead of calling this again after a recover_if_lost might have happened
+    pub fn gpu(&self) -> Arc<GpuContext> {
+        self.gpu.read().unwrap().clone()
+    }
+
+    pub fn limits(&self) -> wgpu::Limits {
+        self.gpu().device().limits()
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.gpu().is_lost()
+    }
+
+    // See GpuContext::simulate_device_loss
+    pub fn simulate_device_loss(&self) {
+        self.gpu().simulate_device_loss();
+    }
+
+    // See GpuContext::recover_if_lost, the caches are emptied along with the device as nothing in them works on the new one
+    // Takes &self so it works through the Arc the executor is shared in, runs still going on the lost device just fail
+    pub async fn recover_if_lost(&self) -> Result<bool, RequestDeviceError> {
+        if !self.is_lost() {
+            return Ok(false);
+        }
+        let _recovering = self.recovering.lock().await;
+        // Someone else recovered while we were waiting
+        let lost_gpu = self.gpu();
+        if !lost_gpu.is_lost() {
+            return Ok(false);
+        }
+        let recovered_gpu = lost_gpu.recreated().await?;
+        *self.gpu.write().unwrap() = Arc::new(recovered_gpu);
+        self.modules.lock().unwrap().clear();
+        self.meta_buf_pool.lock().unwrap().clear();
+        self.out_buf_pool.lock().unwrap().clear();
+        self.readback_buf_pool.lock().unwrap().clear();
+        tracing::info!("Recreated gpu device after it was lost!");
+        Ok(true)
+    }
+
+    pub async fn execute(&self, program: &SerialisableProgram) -> Result<Vec<u8>, ProgramRunError> {
+        let gpu = self.gpu();
+        // Everything done on a lost device fails anyway, some of it through wgpu's uncaptured error handler, which panics
+        if gpu.is_lost() {
+            return Err(ProgramRunError::Run(RunShaderError::DeviceLost));
+        }
+        // Rather than silently running the program without them
+        if !program.extra_in_data.is_empty() || !program.extra_out_data_nbytes.is_empty() {
+            return Err(ProgramRunError::ExtraBuffersUnsupported);
+        }
+        // Before in_data is allocated, execute_on checks the rest again
+        program
+            .validate(&gpu.device().limits())
+            .map_err(ProgramRunError::Invalid)?;
+        let in_buf = allocate(&gpu, || {
+            gpu.device().create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents: &program.in_data,
+                usage: BufferUsages::STORAGE,
+            })
+        })
+        .await?;
+        self.execute_on(&gpu, &program.metadata(ResultCompression::Never), &in_buf)
+            .await
+    }
+
+    // For programs whose input is already on the gpu, e.g. from ProgramMetadata::read_streamed on self.gpu().device()
+    pub async fn execute_with_in_buf(
+        &self,
+        metadata: &ProgramMetadata,
+        in_buf: &wgpu::Buffer,
+    ) -> Result<Vec<u8>, ProgramRunError> {
+        self.execute_on(&self.gpu(), metadata, in_buf).await
+    }
+
+    async fn execute_on(
+        &self,
+        gpu: &GpuContext,
+        metadata: &ProgramMetadata,
+        in_buf: &wgpu::Buffer,
+    ) -> Result<Vec<u8>, ProgramRunError> {
+        if gpu.is_lost() {
+            return Err(ProgramRunError::Run(RunShaderError::DeviceLost));
+        }
+        if !metadata.extra_in_data_nbytes.is_empty() || !metadata.extra_out_data_nbytes.is_empty() {
+            return Err(ProgramRunError::ExtraBuffersUnsupported);
+        }
+        // Sizes wgpu would reject (e.g. an unaligned output, which can't be copied back) go to its uncaptured error handler, which panics
+        metadata
+            .validate(&gpu.device().limits())
+            .map_err(ProgramRunError::Invalid)?;
+        // Only a warning, the shader might legitimately write less than one element per invocation
+        if let Err(warning) = metadata.check_output_size() {
+            tracing::warn!("{warning}!");
+        }
+        let module = self
+            .module(gpu, &metadata.program)
+            .await
+            .map_err(ProgramRunError::Run)?;
+        let out_nbytes = u64::try_from(metadata.out_data_nbytes).unwrap();
+        let mut out_buf = allocate(gpu, || self.take_out_buf(gpu, out_nbytes)).await?;
+        let pipeline_key = (metadata.entry_point.clone(), in_buf.size(), out_nbytes);
+        let pipeline = self
+            .modules
+            .lock()
+            .unwrap()
+            .get_mut(metadata.program.as_str())
+            .and_then(|cached| cached.pipelines.get_mut(&pipeline_key))
+            .cloned();
+        let meta_buf = self.meta_buf_pool.lock().unwrap().pop();
+
+        let (pipeline, meta_buf) = crate::run_shader_reusing(
+            crate::RunShaderParams {
+                device: gpu.device(),
+                queue: gpu.queue(),
+                in_buf,
+                out_buf: &mut out_buf,
+                workgroup_len: metadata.workgroup_size,
+                n_workgroups: metadata.n_workgroups,
+                program: &module,
+                entry_point: &metadata.entry_point,
+                debug_fill_output: false,
+                strict_binding_sizes: true,
+                params_buf: None,
+                bindings: crate::ShaderBindings::default(),
+                dispatch_dims: None,
+            },
+            pipeline,
+            meta_buf,
+        )
+        .await
+        .map_err(ProgramRunError::Run)?;
+        // If the device was lost (and maybe already recovered) during the run, none of it works on the current one
+        if !gpu.is_lost() {
+            self.cache_pipeline(&metadata.program, pipeline_key, pipeline);
+            let mut meta_bufs = self.meta_buf_pool.lock().unwrap();
+            if meta_bufs.len() < MAX_POOLED_META_BUFS {
+                meta_bufs.push(meta_buf);
+            }
+        }
+
+        let readback_buf = match take_pooled(&self.readback_buf_pool, out_nbytes) {
+            Some(buf) => buf,
+            None => {
+                allocate(gpu, || {
+                    crate::create_readback_buffer(gpu.device(), out_nbytes, BufferUsages::empty())
+                })
+                .await?
+            }
+        };
+        let res =
+            crate::read_back_buffer_through(gpu.device(), gpu.queue(), &out_buf, &readback_buf)
+                .await
+                .map_err(|err| ProgramRunError::Run(RunShaderError::ReadBack(err)))?;
+
+        if !gpu.is_lost() {
+            give_back_pooled(&self.readback_buf_pool, readback_buf);
+            give_back_pooled(&self.out_buf_pool, out_buf);
+        }
+        Ok(res)
+    }
+
+    async fn module(
+        &self,
+        gpu: &GpuContext,
+        program: &str,
+    ) -> Result<Arc<ShaderModule>, RunShaderError> {
+        if let Some(cached) = self.modules.lock().unwrap().get_mut(program) {
+            return Ok(cached.module.clone());
+        }
+        tracing::debug!("Compiling program that isn't in the cache...");
+        // The program may have come off the network, so invalid WGSL has to be an error rather than a panic
+        // The scope is popped straight away (only waiting for its result happens after), so the lock isn't held across an await
+        let (module, scope_err) =
+            crate::with_error_scope(gpu.device(), wgpu::ErrorFilter::Validation, || {
+                gpu.device().create_shader_module(ShaderModuleDescriptor {
+                    label: None,
+                    source: wgpu::ShaderSource::Wgsl(Cow::from(program)),
+                })
+            });
+        if let Some(err) = scope_err.await {
+            return Err(RunShaderError::PipelineCreation(err.to_string()));
+        }
+
+        let module = Arc::new(module);
+        if !gpu.is_lost() {
+            self.modules.lock().unwrap().insert(
+                program.to_owned(),
+                CachedModule {
+                    module: module.clone(),
+                    pipelines: LruCache::new(MAX_CACHED_PIPELINES_PER_MODULE),
+                },
+            );
+        }
+        Ok(module)
+    }
+
+    // If the module has been evicted since, so is the pipeline
+    fn cache_pipeline(&self, program: &str, key: PipelineKey, pipeline: Arc<DispatchPipeline>) {
+        if let Some(cached) = self.modules.lock().unwrap().get_mut(program) {
+            cached.pipelines.insert(key, pipeline);
+        }
+    }
+
+    // Pooled buffers still hold the previous program's output, so they're zeroed first,
+    // otherwise whatever this program doesn't write would leak another program's data
+    fn take_out_buf(&self, gpu: &GpuContext, size: u64) -> wgpu::Buffer {
+        match take_pooled(&self.out_buf_pool, size) {
+            Some(buf) => {
+                let mut enc = gpu
+                    .device()
+                    .create_command_encoder(&CommandEncoderDescriptor { label: None });
+                enc.clear_buffer(&buf, 0, None);
+                gpu.queue().submit([enc.finish()]);
+                buf
+            }
+            None => crate::create_output_buffer(gpu.device(), size, BufferUsages::COPY_DST),
+        }
+    }
+}
+
+// Running out of gpu memory shows up when allocating, captured instead of going to wgpu's uncaptured error handler, which panics
+async fn allocate<T>(gpu: &GpuContext, f: impl FnOnce() -> T) -> Result<T, ProgramRunError> {
+    let (res, scope_err) = crate::with_error_scope(gpu.device(), wgpu::ErrorFilter::OutOfMemory, f);
+    match scope_err.await {
+        Some(_) => Err(ProgramRunError::Run(RunShaderError::OutOfMemory)),
+        None => Ok(res),
+    }
+}
+
+fn take_pooled(pool: &Mutex<HashMap<u64, Vec<wgpu::Buffer>>>, size: u64) -> Option<wgpu::Buffer> {
+    pool.lock().unwrap().get_mut(&size).and_then(Vec::pop)
+}
+
+fn give_back_pooled(pool: &Mutex<HashMap<u64, Vec<wgpu::Buffer>>>, buf: wgpu::Buffer) {
+    let mut pool = pool.lock().unwrap();
+    let bufs = pool.entry(buf.size()).or_default();
+    if bufs.len() < MAX_POOLED_BUFFERS_PER_SIZE {
+        bufs.push(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialisable_program::ProgramValidationError;
+
+    #[test]
+    fn test_lru_cache_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        // Using a makes b the least recently used
+        assert_eq!(cache.get_mut("a"), Some(&mut 1));
+        cache.insert("c", 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_mut("b"), None);
+        assert_eq!(cache.get_mut("a"), Some(&mut 1));
+        assert_eq!(cache.get_mut("c"), Some(&mut 3));
+
+        // Replacing an entry doesn't evict anything
+        cache.insert("c", 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_mut("a"), Some(&mut 1));
+        assert_eq!(cache.get_mut("c"), Some(&mut 4));
+    }
+
+    #[tokio::test]
+    async fn test_execute_reuses_module_and_buffers() {
+        let adapter = crate::test_support::default_adapter().await;
+        let executor = GpuExecutor::new(adapter, wgpu::Features::empty(), wgpu::Limits::default())
+            .await
+            .expect("Device must exist!");
+
+        // Skips zero inputs, so a reused buffer that wasn't cleared would show the previous run's output there
+        let program = |in_data: Vec<u32>| SerialisableProgram {
+            in_data: in_data.iter().copied().flat_map(u32::to_le_bytes).collect(),
+            out_data_nbytes: 4000,
+            program: r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    if (v_in_data[actual_id] == 0u) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id] + 1u;
+                }
+            "#
+            .to_owned(),
+            entry_point: "main".to_owned(),
+            n_workgroups: 1000usize.div_ceil(32),
+            workgroup_size: 32,
+            extra_in_data: Vec::new(),
+            extra_out_data_nbytes: Vec::new(),
+        };
+
+        // Every element is written on the first run, only the even ones on the later ones
+        for run in 0..3u32 {
+            let in_data = (1..=1000u32)
+                .map(|i| if run == 0 || i % 2 == 0 { i } else { 0 })
+                .collect::<Vec<u32>>();
+            let res = executor.execute(&program(in_data.clone())).await.unwrap();
+            assert_eq!(
+                res,
+                in_data
+                    .iter()
+                    .map(|&i| if i == 0 { 0 } else { i + 1 })
+                    .flat_map(u32::to_le_bytes)
+                    .collect::<Vec<u8>>()
+            );
+        }
+        assert_eq!(executor.modules.lock().unwrap().len(), 1);
+        assert_eq!(
+            executor
+                .modules
+                .lock()
+                .unwrap()
+                .get_mut(program(Vec::new()).program.as_str())
+                .unwrap()
+                .pipelines
+                .len(),
+            1
+        );
+        assert_eq!(executor.meta_buf_pool.lock().unwrap().len(), 1);
+        assert_eq!(executor.out_buf_pool.lock().unwrap()[&4000].len(), 1);
+        assert_eq!(executor.readback_buf_pool.lock().unwrap()[&4000].len(), 1);
+
+        assert!(matches!(
+            executor
+                .execute(&SerialisableProgram {
+                    program: "not wgsl".to_owned(),
+                    ..program(vec![0; 1000])
+                })
+                .await,
+            Err(ProgramRunError::Run(RunShaderError::PipelineCreation(_)))
+        ));
+        assert!(matches!(
+            executor
+                .execute(&SerialisableProgram {
+                    extra_out_data_nbytes: vec![4000],
+                    ..program(vec![0; 1000])
+                })
+                .await,
+            Err(ProgramRunError::ExtraBuffersUnsupported)
+        ));
+        // Would panic when copied back if it got that far
+        assert!(matches!(
+            executor
+                .execute(&SerialisableProgram {
+                    out_data_nbytes: 4001,
+                    ..program(vec![0; 1000])
+                })
+                .await,
+            Err(ProgramRunError::Invalid(
+                ProgramValidationError::UnalignedOutput { nbytes: 4001 }
+            ))
+        ));
+        let metadata = ProgramMetadata {
+            out_data_nbytes: 4001,
+            ..program(vec![0; 1000]).metadata(ResultCompression::Never)
+        };
+        let in_buf =
+            crate::create_output_buffer(executor.gpu().device(), 4000, BufferUsages::empty());
+        assert!(matches!(
+            executor.execute_with_in_buf(&metadata, &in_buf).await,
+            Err(ProgramRunError::Invalid(
+                ProgramValidationError::UnalignedOutput { nbytes: 4001 }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recovers_through_shared_executor() {
+        let adapter = crate::test_support::default_adapter().await;
+        let executor = Arc::new(
+            GpuExecutor::new(adapter, wgpu::Features::empty(), wgpu::Limits::default())
+                .await
+                .expect("Device must exist!"),
+        );
+        let program = crate::test_support::multiply_program(2, &(0..64u32).collect::<Vec<u32>>());
+        let expected = (0..64u32)
+            .map(|i| i * 2)
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<u8>>();
+        assert_eq!(executor.execute(&program).await.unwrap(), expected);
+
+        executor.simulate_device_loss();
+        assert!(matches!(
+            executor.execute(&program).await,
+            Err(ProgramRunError::Run(RunShaderError::DeviceLost))
+        ));
+        // Concurrent recoveries only recreate the device once
+        let (first, second) = tokio::join!(executor.recover_if_lost(), executor.recover_if_lost());
+        assert_eq!(
+            [first.unwrap(), second.unwrap()]
+                .into_iter()
+                .filter(|recovered| *recovered)
+                .count(),
+            1
+        );
+        assert!(!executor.is_lost());
+        assert_eq!(executor.modules.lock().unwrap().len(), 0);
+        assert_eq!(executor.execute(&program).await.unwrap(), expected);
+    }
+}
@@ -0,0 +1,122 @@
+use wgpu::{Device, Queue};
+
+use crate::shader_bytes::FromShaderBytes;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GatherError {
+    ReadBack(wgpu::BufferAsyncError),
+    LengthMismatch { n_indices: usize, n_values: usize },
+    IndexOutOfRange { index: u32, len: usize },
+    DuplicateIndex(u32),
+}
+
+impl std::fmt::Display for GatherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatherError::ReadBack(err) => write!(f, "Reading back the buffers failed: {err}"),
+            GatherError::LengthMismatch {
+                n_indices,
+                n_values,
+            } => write!(
+                f,
+                "Got {n_indices} indices but {n_values} values, there must be exactly one index per value"
+            ),
+            GatherError::IndexOutOfRange { index, len } => write!(
+                f,
+                "Index {index} is out of range for an output of {len} elements"
+            ),
+            GatherError::DuplicateIndex(index) => {
+                write!(f, "Index {index} was given to more than one value")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GatherError {}
+
+// values[i] ends up at position indices[i], the indices must be a permutation of 0..values.len()
+pub fn order_by_indices<T>(indices: &[u32], values: Vec<T>) -> Result<Vec<T>, GatherError> {
+    if indices.len() != values.len() {
+        return Err(GatherError::LengthMismatch {
+            n_indices: indices.len(),
+            n_values: values.len(),
+        });
+    }
+    let mut res = std::iter::repeat_with(|| None)
+        .take(values.len())
+        .collect::<Vec<Option<T>>>();
+    for (&index, value) in indices.iter().zip(values) {
+        let slot = usize::try_from(index)
+            .ok()
+            .and_then(|index| res.get_mut(index))
+            .ok_or(GatherError::IndexOutOfRange {
+                index,
+                len: indices.len(),
+            })?;
+        if slot.replace(value).is_some() {
+            return Err(GatherError::DuplicateIndex(index));
+        }
+    }
+    // As many distinct in range indices as slots, so every slot got filled
+    Ok(res.into_iter().map(Option::unwrap).collect())
+}
+
+// For kernels that scatter their output as (index, value) pairs, e.g. compaction or sorting kernels:
+// reads back both buffers and puts every value at its index, giving the dense ordered result
+pub async fn read_back_gathered<T: FromShaderBytes>(
+    device: &Device,
+    queue: &Queue,
+    index_buf: &wgpu::Buffer,
+    data_buf: &wgpu::Buffer,
+) -> Result<Vec<T>, GatherError> {
+    let indices = crate::read_back_as::<u32>(device, queue, index_buf)
+        .await
+        .map_err(GatherError::ReadBack)?;
+    let values = crate::read_back_as::<T>(device, queue, data_buf)
+        .await
+        .map_err(GatherError::ReadBack)?;
+    order_by_indices(&indices, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::{
+        util::{BufferInitDescriptor, DeviceExt},
+        BufferUsages,
+    };
+
+    use super::*;
+    use crate::shader_bytes::ShaderBytes;
+
+    #[tokio::test]
+    async fn test_gather_reversed_pairs() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        // What a kernel writing element i to position n - 1 - i would leave behind
+        let indices = (0..1000u32).rev().collect::<Vec<u32>>();
+        let values = (0..1000).map(|i| i as f32 * 0.5).collect::<Vec<f32>>();
+        let create_buf = |contents: &[u8]| {
+            device.create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            })
+        };
+        let index_buf = create_buf(ShaderBytes::serialise_from_slice(&indices).get_data());
+        let data_buf = create_buf(ShaderBytes::serialise_from_slice(&values).get_data());
+
+        let gathered = read_back_gathered::<f32>(&device, &queue, &index_buf, &data_buf)
+            .await
+            .unwrap();
+        assert_eq!(gathered, values.iter().rev().copied().collect::<Vec<f32>>());
+
+        assert_eq!(
+            order_by_indices(&[0, 0, 1], vec![1, 2, 3]),
+            Err(GatherError::DuplicateIndex(0))
+        );
+        assert_eq!(
+            order_by_indices(&[0, 3, 1], vec![1, 2, 3]),
+            Err(GatherError::IndexOutOfRange { index: 3, len: 3 })
+        );
+    }
+}
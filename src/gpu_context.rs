@@ -0,0 +1,139 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use wgpu::{Adapter, Device, DeviceDescriptor, DeviceLostReason, Queue, RequestDeviceError};
+
+// A device and queue that can be rebuilt from their adapter after the device is lost (e.g. on a driver reset),
+// without this everything done on a lost device just fails forever
+pub struct GpuContext {
+    // Shared with the contexts recreated from this one
+    adapter: Arc<Adapter>,
+    required_features: wgpu::Features,
+    required_limits: wgpu::Limits,
+    device: Device,
+    queue: Queue,
+    lost: Arc<AtomicBool>,
+}
+
+impl GpuContext {
+    pub async fn new(
+        adapter: Adapter,
+        required_features: wgpu::Features,
+        required_limits: wgpu::Limits,
+    ) -> Result<Self, RequestDeviceError> {
+        let (device, queue, lost) =
+            Self::request_device(&adapter, required_features, &required_limits).await?;
+        Ok(Self {
+            adapter: Arc::new(adapter),
+            required_features,
+            required_limits,
+            device,
+            queue,
+            lost,
+        })
+    }
+
+    async fn request_device(
+        adapter: &Adapter,
+        required_features: wgpu::Features,
+        required_limits: &wgpu::Limits,
+    ) -> Result<(Device, Queue, Arc<AtomicBool>), RequestDeviceError> {
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    required_features,
+                    required_limits: required_limits.clone(),
+                    memory_hints: wgpu::MemoryHints::default(),
+                },
+                None,
+            )
+            .await?;
+        // Every device gets its own flag, so dropping a replaced device doesn't mark its replacement as lost
+        let lost = Arc::new(AtomicBool::new(false));
+        let callback_lost = lost.clone();
+        device.set_device_lost_callback(move |reason, msg| {
+            if reason != DeviceLostReason::Dropped {
+                tracing::error!("Gpu device lost ({reason:?}): {msg}");
+                callback_lost.store(true, Ordering::Release);
+            }
+        });
+        Ok((device, queue, lost))
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire)
+    }
+
+    // Replaces the device and queue with fresh ones if the device was lost, returns whether it did
+    // NOTE: Anything created on the old device (buffers, pipelines, ...) is useless afterwards
+    pub async fn recover_if_lost(&mut self) -> Result<bool, RequestDeviceError> {
+        if !self.is_lost() {
+            return Ok(false);
+        }
+        *self = self.recreated().await?;
+        tracing::info!("Recreated gpu device after it was lost!");
+        Ok(true)
+    }
+
+    // A fresh device and queue from the same adapter, with the same features and limits,
+    // for when the context is shared and can't be replaced in place, see GpuExecutor::recover_if_lost
+    pub async fn recreated(&self) -> Result<Self, RequestDeviceError> {
+        let (device, queue, lost) =
+            Self::request_device(&self.adapter, self.required_features, &self.required_limits)
+                .await?;
+        Ok(Self {
+            adapter: self.adapter.clone(),
+            required_features: self.required_features,
+            required_limits: self.required_limits.clone(),
+            device,
+            queue,
+            lost,
+        })
+    }
+
+    // For testing the recovery path, makes the device behave like it was lost
+    pub fn simulate_device_loss(&self) {
+        self.device.destroy();
+        // The lost callback only runs once the device is maintained
+        self.device.poll(wgpu::Maintain::Poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recovers_from_device_loss() {
+        let adapter = crate::test_support::default_adapter().await;
+        let mut gpu = GpuContext::new(adapter, wgpu::Features::empty(), wgpu::Limits::default())
+            .await
+            .expect("Device must exist!");
+        assert!(!gpu.is_lost());
+        assert!(!gpu.recover_if_lost().await.unwrap());
+
+        gpu.simulate_device_loss();
+        assert!(gpu.is_lost());
+        assert!(gpu.recover_if_lost().await.unwrap());
+        assert!(!gpu.is_lost());
+
+        // The new device has to actually work
+        let buf = crate::create_output_buffer(gpu.device(), 16, wgpu::BufferUsages::empty());
+        assert_eq!(
+            crate::read_back_buffer(gpu.device(), gpu.queue(), &buf, wgpu::BufferUsages::empty())
+                .await,
+            Ok(vec![0u8; 16])
+        );
+    }
+}
@@ -1,47 +1,430 @@
-use shader_bytes::IntoShaderBytes;
-use tokio::task::yield_now;
+use std::{
+    borrow::Cow,
+    future::Future,
+    ops::RangeBounds,
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use shader_bytes::{FromShaderBytes, IntoShaderBytes, ShaderBytes, WgslTypeName};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    task::yield_now,
+};
 use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
     BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor, BindGroupLayoutEntry,
     BufferDescriptor, BufferSlice, BufferUsages, CommandEncoderDescriptor, ComputePassDescriptor,
-    ComputePipelineDescriptor, Device, PipelineLayoutDescriptor, Queue, ShaderModule, ShaderStages,
+    ComputePipelineDescriptor, Device, PipelineLayoutDescriptor, Queue, ShaderModule,
+    ShaderModuleDescriptor, ShaderStages,
 };
 
+// So #[derive(ShaderBytes)], which refers to ::clustered, also works inside this crate
+extern crate self as clustered;
+
+pub mod bandwidth;
+pub mod compression;
+pub mod config;
+pub mod executor;
+pub mod gather;
+pub mod gpu_context;
+pub mod logging;
 pub mod networking;
+pub mod protocol;
+pub mod reflection;
 pub mod serialisable_program;
 pub mod shader_bytes;
+pub mod telefork;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod texture;
+pub mod tuning;
+pub mod typed_buffer;
+pub mod verification;
+
+pub use gather::read_back_gathered;
+pub use reflection::reflect_bindings;
+pub use typed_buffer::{run_shader_typed, TypedBuffer, TypedRunShaderParams};
+
+// Small enough to not be noticeable next to any real gpu work, big enough to not peg a core while waiting
+// NOTE: tokio's timer has millisecond granularity, so any non zero interval really ends up being around 1ms
+pub const DEFAULT_MAP_POLL_INTERVAL: Duration = Duration::from_micros(50);
 
 // NOTE: Device is used only for polling
-pub async fn wgpu_map_helper(
+// Maps bounds of buf and returns the mapped slice, works for either mode, for writing see also write_mapped_buffer
+// Fails (instead of tripping wgpu's validation) if buf wasn't created with required_map_usage(mode)
+pub async fn wgpu_map_helper<'a>(
+    device: &wgpu::Device,
+    mode: wgpu::MapMode,
+    buf: &'a wgpu::Buffer,
+    bounds: impl RangeBounds<wgpu::BufferAddress>,
+) -> Result<BufferSlice<'a>, wgpu::BufferAsyncError> {
+    wgpu_map_helper_with_interval(device, mode, buf, bounds, DEFAULT_MAP_POLL_INTERVAL).await
+}
+
+// Same as wgpu_map_helper but sleeps for at least poll_interval between polls,
+// a zero interval only yields between polls, for latency critical paths (at the cost of spinning)
+pub async fn wgpu_map_helper_with_interval<'a>(
     device: &wgpu::Device,
     mode: wgpu::MapMode,
+    buf: &'a wgpu::Buffer,
+    bounds: impl RangeBounds<wgpu::BufferAddress>,
+    poll_interval: Duration,
+) -> Result<BufferSlice<'a>, wgpu::BufferAsyncError> {
+    check_map_usage(mode, buf)?;
+    let buf_view = buf.slice(bounds);
+    poll_device_until(device, map_future(mode, &buf_view), poll_interval).await?;
+    Ok(buf_view)
+}
+
+// Polls the device without blocking every poll_interval (or every yield if it's zero) until fut completes,
+// for futures woken by wgpu callbacks (e.g. MapFuture), which only fire from inside a poll
+// Never waits on the whole device, so other work submitted to it (e.g. by other clients of a shared GpuExecutor) doesn't hold this up
+async fn poll_device_until<F: Future>(
+    device: &Device,
+    fut: F,
+    poll_interval: Duration,
+) -> F::Output {
+    tokio::pin!(fut);
+    loop {
+        device.poll(wgpu::Maintain::Poll);
+        tokio::select! {
+            // So a callback that fired during the poll above is seen straight away
+            biased;
+            res = &mut fut => return res,
+            _ = async {
+                if poll_interval.is_zero() {
+                    yield_now().await;
+                } else {
+                    tokio::time::sleep(poll_interval).await;
+                }
+            } => {}
+        }
+    }
+}
+
+// wgpu would report mapping a buffer without the usage as a validation error, which panics unless captured
+fn check_map_usage(mode: wgpu::MapMode, buf: &wgpu::Buffer) -> Result<(), wgpu::BufferAsyncError> {
+    if buf.usage().contains(required_map_usage(mode)) {
+        Ok(())
+    } else {
+        tracing::error!(
+            "Mapping failed, buffer needs {:?} to be mapped, but only has {:?}!",
+            required_map_usage(mode),
+            buf.usage()
+        );
+        Err(wgpu::BufferAsyncError)
+    }
+}
+
+#[derive(Default)]
+struct MapState {
+    result: Option<Result<(), wgpu::BufferAsyncError>>,
+    waker: Option<Waker>,
+}
+
+pub struct MapFuture {
+    state: Arc<Mutex<MapState>>,
+}
+
+impl Future for MapFuture {
+    type Output = Result<(), wgpu::BufferAsyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Map state mutex shouldn't be poisoned!");
+        if let Some(res) = state.result.take() {
+            Poll::Ready(res)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+// Alternative to wgpu_map_helper that doesn't poll in a loop, the device is polled once with Maintain::Wait on tokio's blocking pool instead
+// NOTE: Needs an Arc'd device because the blocking poll can outlive any borrow,
+//       if you can't provide one wgpu_map_helper is still there as a fallback.
+pub fn wgpu_map_async(
+    device: Arc<wgpu::Device>,
+    mode: wgpu::MapMode,
     buf_view: &BufferSlice<'_>,
-) -> Result<(), wgpu::BufferAsyncError> {
-    let (sender, receiver) = flume::bounded(1);
-    buf_view.map_async(mode, move |mapping_res| {
-        tokio::spawn(async move {
-            if let Err(err) = mapping_res.clone() {
-                println!("Error: Mapping failed with error: {err}!");
-            }
+) -> MapFuture {
+    let mapped = map_future(mode, buf_view);
+    // The callback only ever fires from inside a device poll, so someone has to poll,
+    // but blocking until the queue is idle means we poll exactly once instead of in a loop
+    tokio::task::spawn_blocking(move || {
+        device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+    });
+    mapped
+}
 
-            if let Err(err) = sender.try_send(mapping_res) {
-                panic!(
-                    "Error: Failed to send mapping result over flume channel, error was: {err}!"
-                );
-            }
-        });
+// Requests the mapping, the returned future is woken by the map_async callback, which only fires once the device is polled
+fn map_future(mode: wgpu::MapMode, buf_view: &BufferSlice<'_>) -> MapFuture {
+    let state = Arc::new(Mutex::new(MapState::default()));
+    let callback_state = state.clone();
+    buf_view.map_async(mode, move |mapping_res| {
+        if let Err(err) = mapping_res.clone() {
+            tracing::error!("Mapping failed with error: {err}!");
+        }
+        let mut state = callback_state
+            .lock()
+            .expect("Map state mutex shouldn't be poisoned!");
+        state.result = Some(mapping_res);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
     });
+    MapFuture { state }
+}
 
-    loop {
-        device.poll(wgpu::MaintainBase::Poll).panic_on_timeout();
-        yield_now().await;
-        if !receiver.is_empty() {
-            break;
+// Output buffers need STORAGE to be bound to the shader and COPY_SRC to be read back,
+// extra_usages are OR'd on top of those (e.g. INDIRECT if the shader writes dispatch arguments)
+pub fn create_output_buffer(
+    device: &Device,
+    size: u64,
+    extra_usages: BufferUsages,
+) -> wgpu::Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | extra_usages,
+        mapped_at_creation: false,
+    })
+}
+
+// Readback buffers need MAP_READ and COPY_DST, extra_usages are OR'd on top of those
+// NOTE: wgpu only allows MAP_READ together with anything but COPY_DST (e.g. QUERY_RESOLVE)
+//       if the device was created with Features::MAPPABLE_PRIMARY_BUFFERS
+pub fn create_readback_buffer(
+    device: &Device,
+    size: u64,
+    extra_usages: BufferUsages,
+) -> wgpu::Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST | extra_usages,
+        mapped_at_creation: false,
+    })
+}
+
+// Upload buffers need MAP_WRITE and COPY_SRC, they're filled on the cpu (see write_mapped_buffer) and then copied into
+// e.g. an input buffer, extra_usages are OR'd on top of those
+// NOTE: Same as for readback buffers, wgpu only allows MAP_WRITE together with anything but COPY_SRC
+//       if the device was created with Features::MAPPABLE_PRIMARY_BUFFERS
+pub fn create_upload_buffer(
+    device: &Device,
+    size: u64,
+    extra_usages: BufferUsages,
+) -> wgpu::Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::MAP_WRITE | BufferUsages::COPY_SRC | extra_usages,
+        mapped_at_creation: false,
+    })
+}
+
+// What a buffer has to have been created with to be mapped in this mode
+pub fn required_map_usage(mode: wgpu::MapMode) -> BufferUsages {
+    match mode {
+        wgpu::MapMode::Read => BufferUsages::MAP_READ,
+        wgpu::MapMode::Write => BufferUsages::MAP_WRITE,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteMappedError {
+    // buf wasn't created with MAP_WRITE (see create_upload_buffer), these are the usages it does have
+    NotMappableForWriting(BufferUsages),
+    // Including the padding up to wgpu::COPY_BUFFER_ALIGNMENT
+    BufferTooSmall { nbytes: u64, buf_nbytes: u64 },
+    Map(wgpu::BufferAsyncError),
+}
+
+impl std::fmt::Display for WriteMappedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteMappedError::NotMappableForWriting(usages) => write!(
+                f,
+                "Buffer needs MAP_WRITE to be mapped for writing, but only has {usages:?}"
+            ),
+            WriteMappedError::BufferTooSmall { nbytes, buf_nbytes } => write!(
+                f,
+                "Buffer of {buf_nbytes} bytes is too small to write {nbytes} bytes into"
+            ),
+            WriteMappedError::Map(err) => write!(f, "{err}"),
         }
     }
-    receiver
-        .recv_async()
+}
+
+impl std::error::Error for WriteMappedError {}
+
+// Maps the start of buf for writing, copies data into it and unmaps it again, so the gpu can use it
+// NOTE: Mappings have to be a multiple of wgpu::COPY_BUFFER_ALIGNMENT long, the padding past data is left as is
+pub async fn write_mapped_buffer(
+    device: &Device,
+    buf: &wgpu::Buffer,
+    data: &[u8],
+) -> Result<(), WriteMappedError> {
+    if !buf
+        .usage()
+        .contains(required_map_usage(wgpu::MapMode::Write))
+    {
+        return Err(WriteMappedError::NotMappableForWriting(buf.usage()));
+    }
+    let mapped_nbytes = u64::try_from(data.len())
+        .unwrap()
+        .next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT);
+    if mapped_nbytes > buf.size() {
+        return Err(WriteMappedError::BufferTooSmall {
+            nbytes: mapped_nbytes,
+            buf_nbytes: buf.size(),
+        });
+    }
+    // Empty mappings aren't allowed
+    if data.is_empty() {
+        return Ok(());
+    }
+    let buf_view = wgpu_map_helper(device, wgpu::MapMode::Write, buf, ..mapped_nbytes)
+        .await
+        .map_err(WriteMappedError::Map)?;
+    buf_view.get_mapped_range_mut()[..data.len()].copy_from_slice(data);
+    buf.unmap();
+    Ok(())
+}
+
+// Resolves once everything submitted to queue so far has finished on the gpu
+pub async fn wait_for_submitted_work(device: &Device, queue: &Queue) {
+    let (sender, receiver) = flume::bounded(1);
+    queue.on_submitted_work_done(move || {
+        let _ = sender.send(());
+    });
+    poll_device_until(device, receiver.recv_async(), DEFAULT_MAP_POLL_INTERVAL)
         .await
-        .expect("Channel should not error out when receiving mapping result!")
+        .expect("Submitted work done callback should fire before its sender is dropped!");
+}
+
+// Copies buf (which needs COPY_SRC) into a fresh readback buffer, created with extra_usages, and returns its contents
+pub async fn read_back_buffer(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+    extra_usages: BufferUsages,
+) -> Result<Vec<u8>, wgpu::BufferAsyncError> {
+    let transfer_buf = copy_to_mapped_readback_buffer(device, queue, buf, extra_usages).await?;
+    let res = transfer_buf.slice(..).get_mapped_range().to_vec();
+    Ok(res)
+}
+
+// Returns the readback buffer already mapped for reading
+async fn copy_to_mapped_readback_buffer(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+    extra_usages: BufferUsages,
+) -> Result<wgpu::Buffer, wgpu::BufferAsyncError> {
+    let transfer_buf = create_readback_buffer(device, buf.size(), extra_usages);
+    copy_and_map(device, queue, buf, &transfer_buf).await?;
+    Ok(transfer_buf)
+}
+
+// Copies buf into the start of transfer_buf and maps that part of it for reading
+async fn copy_and_map(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+    transfer_buf: &wgpu::Buffer,
+) -> Result<(), wgpu::BufferAsyncError> {
+    let mut enc = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    enc.copy_buffer_to_buffer(buf, 0, transfer_buf, 0, buf.size());
+    queue.submit([enc.finish()]);
+
+    wgpu_map_helper(device, wgpu::MapMode::Read, transfer_buf, ..buf.size()).await?;
+    Ok(())
+}
+
+// Same as read_back_buffer, but copies through transfer_buf (see create_readback_buffer, it has to be at least as big as buf)
+// instead of a fresh readback buffer, for callers that keep them around, see GpuExecutor. It's unmapped again afterwards
+pub async fn read_back_buffer_through(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+    transfer_buf: &wgpu::Buffer,
+) -> Result<Vec<u8>, wgpu::BufferAsyncError> {
+    copy_and_map(device, queue, buf, transfer_buf).await?;
+    let res = transfer_buf.slice(..buf.size()).get_mapped_range().to_vec();
+    transfer_buf.unmap();
+    Ok(res)
+}
+
+// Like read_back_as, but f gets a lazy iterator over the elements, deserialised straight out of the mapped readback buffer,
+// so e.g. summing a huge result never holds a second copy of it (neither as a Vec<u8> nor as a Vec<T>) in memory
+// The iterator borrows the mapping, which is only valid until f returns (the buffer is unmapped after), hence the closure
+pub async fn with_read_back_iter<T, R>(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+    f: impl FnOnce(shader_bytes::ShaderBytesIter<'_, T>) -> R,
+) -> Result<R, wgpu::BufferAsyncError>
+where
+    T: FromShaderBytes,
+{
+    let transfer_buf =
+        copy_to_mapped_readback_buffer(device, queue, buf, BufferUsages::empty()).await?;
+    let res = f(ShaderBytes::deserialise_to_iterator(
+        &transfer_buf.slice(..).get_mapped_range(),
+    ));
+    transfer_buf.unmap();
+    Ok(res)
+}
+
+// Debugging aid, the counterpart of reading back the output: reads back what actually ended up in any buffer
+// (e.g. the input buffer, which then needs COPY_SRC usage) and deserialises it as T,
+// so it can be compared against the data it was serialised from
+pub async fn read_back_as<T: FromShaderBytes>(
+    device: &Device,
+    queue: &Queue,
+    buf: &wgpu::Buffer,
+) -> Result<Vec<T>, wgpu::BufferAsyncError> {
+    let raw_data = read_back_buffer(device, queue, buf, BufferUsages::empty()).await?;
+    Ok(ShaderBytes::deserialise_to_iterator(&raw_data).collect())
+}
+
+// Produces something like: "binding 0: storage read-only, min_size=4; binding 2: uniform, min_size=4"
+// so a shader author can compare it against their own @group/@binding declarations
+pub fn describe_layout(entries: &[BindGroupLayoutEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let ty = match entry.ty {
+                wgpu::BindingType::Buffer { ty, .. } => match ty {
+                    wgpu::BufferBindingType::Storage { read_only: true } => {
+                        "storage read-only".to_string()
+                    }
+                    wgpu::BufferBindingType::Storage { read_only: false } => {
+                        "storage read-write".to_string()
+                    }
+                    wgpu::BufferBindingType::Uniform => "uniform".to_string(),
+                },
+                other => format!("{other:?}"),
+            };
+            let min_size = match entry.ty {
+                wgpu::BindingType::Buffer {
+                    min_binding_size: Some(size),
+                    ..
+                } => format!(", min_size={size}"),
+                _ => String::new(),
+            };
+            format!("binding {}: {ty}{min_size}", entry.binding)
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
 }
 
 pub struct RunShaderParams<'a> {
@@ -53,6 +436,335 @@ pub struct RunShaderParams<'a> {
     pub n_workgroups: usize,
     pub program: &'a ShaderModule,
     pub entry_point: &'a str,
+    // Debugging aid: fill out_buf with OUTPUT_SENTINEL_BYTE before dispatching, so find_unwritten_output
+    // can tell which parts of the output the shader never wrote, needs out_buf to have COPY_DST usage
+    pub debug_fill_output: bool,
+    // If set the in/out bindings require a min_binding_size of exactly the buffer sizes, so wgpu validates
+    // the shader's declared array sizes against the buffers up front.
+    // Unset leaves min_binding_size as None, for shaders that use runtime-sized array<T> with arrayLength
+    // and don't care about a fixed minimum size, validation then happens at draw/dispatch time instead.
+    pub strict_binding_sizes: bool,
+    // Read-only storage buffer of extra parameters bound at PARAMS_BINDING, e.g. one entry per workgroup,
+    // see create_params_buffer. As dispatches get split, the shader should index it by its *global* workgroup id,
+    // which is (gid.x + goff) / workgroup_len, not the workgroup_id builtin
+    pub params_buf: Option<&'a wgpu::Buffer>,
+    // Where the input, output and metadata buffers are bound, ShaderBindings::default() for the standard layout
+    pub bindings: ShaderBindings,
+    // Some((x, y, z)) dispatches that grid of workgroups instead of n_workgroups along X (n_workgroups is then ignored),
+    // e.g. (width, height, 1) for an image kernel. Axes over max_compute_workgroups_per_dimension get split into tiles,
+    // so the metadata binding is no longer the u32 goff but a vec3<u32>, the tile's origin *in workgroups*:
+    // @group(0) @binding(2) var<uniform> origin: vec3<u32>;
+    // and the shader's absolute workgroup id is workgroup_id + origin,
+    // i.e. its global id is global_invocation_id + origin * its @workgroup_size.
+    // None keeps the 1D dispatch, where goff is the offset along X only (in invocations, gid.x + goff)
+    pub dispatch_dims: Option<(u32, u32, u32)>,
+}
+
+// Which bind group and bindings run_shader puts its buffers at, for shaders with their own established convention
+// (e.g. storage buffers in group 1). The metadata binding still has to be a uniform holding the u32 global offset.
+// Bind groups below bind_group_index are bound as empty groups. params_buf (PARAMS_BINDING) and run_shader_counted's
+// counter (COUNTER_BINDING) go in the same group as the rest, so when they're used none of these can be 3 or 4,
+// that (or two of these being the same) is a RunShaderError::BindingCollision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShaderBindings {
+    pub bind_group_index: u32,
+    pub input_binding: u32,
+    pub output_binding: u32,
+    pub metadata_binding: u32,
+}
+
+impl Default for ShaderBindings {
+    // Group 0: input at 0, output at 1, metadata at 2
+    fn default() -> Self {
+        Self {
+            bind_group_index: 0,
+            input_binding: 0,
+            output_binding: 1,
+            metadata_binding: 2,
+        }
+    }
+}
+
+pub const PARAMS_BINDING: u32 = 3;
+
+// Like RunShaderParams, but for kernels with several separate input and/or output arrays.
+// The bindings in group 0 are numbered in order: the inputs first (read-only storage, in_bufs[i] at binding i),
+// then the outputs (read-write storage, out_bufs[i] at binding in_bufs.len() + i),
+// and the metadata uniform (the u32 global offset) right after them, at binding in_bufs.len() + out_bufs.len().
+// With one input and one output this is exactly run_shader's layout, so its shaders work unchanged.
+// NOTE: There is no params buffer here, as PARAMS_BINDING would collide with the buffers, pass it as another input instead
+pub struct RunShaderMultiParams<'a> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    pub in_bufs: &'a [&'a wgpu::Buffer],
+    pub out_bufs: &'a [&'a mut wgpu::Buffer],
+    pub workgroup_len: usize,
+    pub n_workgroups: usize,
+    pub program: &'a ShaderModule,
+    pub entry_point: &'a str,
+    // Same as in RunShaderParams, applies to every output buffer
+    pub debug_fill_output: bool,
+    pub strict_binding_sizes: bool,
+}
+
+// What prepare_dispatch needs, whichever of the run_shader variants it came from
+struct DispatchParams<'a> {
+    device: &'a Device,
+    queue: &'a Queue,
+    in_bufs: Vec<&'a wgpu::Buffer>,
+    out_bufs: Vec<&'a wgpu::Buffer>,
+    // Binding of every buffer in in_bufs, then out_bufs, then the metadata buffer
+    buffer_bindings: Vec<u32>,
+    bind_group_index: u32,
+    workgroup_len: usize,
+    n_workgroups: usize,
+    dispatch_dims: Option<(u32, u32, u32)>,
+    program: &'a ShaderModule,
+    entry_point: &'a str,
+    debug_fill_output: bool,
+    strict_binding_sizes: bool,
+    params_buf: Option<&'a wgpu::Buffer>,
+    counter_buf: Option<&'a wgpu::Buffer>,
+    // From an earlier dispatch with the same program, entry point, bindings and buffer sizes, created if None
+    pipeline: Option<Arc<DispatchPipeline>>,
+    // From an earlier dispatch with the same dispatch_dims, created if None
+    meta_buf: Option<wgpu::Buffer>,
+}
+
+// The part of a dispatch that only depends on the program and the shape of its buffers, not their contents,
+// so it can be kept around and reused by later dispatches of the same program, see run_shader_reusing
+pub struct DispatchPipeline {
+    bind_group_layout: wgpu::BindGroupLayout,
+    // Of the groups before bind_group_index
+    empty_bind_group_layout: wgpu::BindGroupLayout,
+    compute_pipeline: wgpu::ComputePipeline,
+}
+
+impl<'a> From<RunShaderParams<'a>> for DispatchParams<'a> {
+    fn from(params: RunShaderParams<'a>) -> Self {
+        let out_buf: &'a wgpu::Buffer = params.out_buf;
+        Self {
+            device: params.device,
+            queue: params.queue,
+            in_bufs: vec![params.in_buf],
+            out_bufs: vec![out_buf],
+            buffer_bindings: vec![
+                params.bindings.input_binding,
+                params.bindings.output_binding,
+                params.bindings.metadata_binding,
+            ],
+            bind_group_index: params.bindings.bind_group_index,
+            workgroup_len: params.workgroup_len,
+            n_workgroups: params.n_workgroups,
+            dispatch_dims: params.dispatch_dims,
+            program: params.program,
+            entry_point: params.entry_point,
+            debug_fill_output: params.debug_fill_output,
+            strict_binding_sizes: params.strict_binding_sizes,
+            params_buf: params.params_buf,
+            counter_buf: None,
+            pipeline: None,
+            meta_buf: None,
+        }
+    }
+}
+
+impl<'a> From<RunShaderMultiParams<'a>> for DispatchParams<'a> {
+    fn from(params: RunShaderMultiParams<'a>) -> Self {
+        Self {
+            device: params.device,
+            queue: params.queue,
+            in_bufs: params.in_bufs.to_vec(),
+            out_bufs: params.out_bufs.iter().map(|buf| &**buf).collect(),
+            buffer_bindings: (0..params.in_bufs.len() + params.out_bufs.len() + 1)
+                .map(|binding| u32::try_from(binding).unwrap())
+                .collect(),
+            bind_group_index: 0,
+            workgroup_len: params.workgroup_len,
+            n_workgroups: params.n_workgroups,
+            dispatch_dims: None,
+            program: params.program,
+            entry_point: params.entry_point,
+            debug_fill_output: params.debug_fill_output,
+            strict_binding_sizes: params.strict_binding_sizes,
+            params_buf: None,
+            counter_buf: None,
+            pipeline: None,
+            meta_buf: None,
+        }
+    }
+}
+
+// Binding of the atomic<u32> counter of run_shader_counted
+pub const COUNTER_BINDING: u32 = 4;
+
+pub fn create_params_buffer<T: IntoShaderBytes>(device: &Device, params: &[T]) -> wgpu::Buffer {
+    device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("Params buffer"),
+        contents: ShaderBytes::serialise_from_slice(params).get_data(),
+        usage: BufferUsages::STORAGE,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunShaderError {
+    ZeroWorkgroupLength,
+    // An empty dispatch is almost always a bug in the caller's size calculations, so it's an error instead of a silent no-op
+    ZeroWorkgroups,
+    // The global offset uniform is a u32, so the global id of every invocation must fit in one
+    OffsetOverflow {
+        workgroup_id: usize,
+        workgroup_len: usize,
+    },
+    SentinelFillNeedsCopyDst,
+    // wgpu can't bind zero sized buffers
+    EmptyInputBuffer,
+    EmptyOutputBuffer,
+    // wgpu's validation error for the shader module, pipeline or bind group, e.g. invalid WGSL,
+    // a missing entry point, or bindings that don't match the shader.
+    // Captured instead of going to wgpu's uncaptured error handler, which panics
+    PipelineCreation(String),
+    // Only from the helpers that also read the output back, the dispatch went fine but mapping the output failed
+    ReadBack(wgpu::BufferAsyncError),
+    // Only from SerialisableProgram::run_on_cpu_fallback
+    NoFallbackDevice,
+    // tuning::tune_workgroup_size was given no workgroup sizes to pick from
+    NoTuningCandidates,
+    // Only from texture::run_texture_shader, see texture::SUPPORTED_TEXTURE_FORMATS
+    UnsupportedTextureFormat(wgpu::TextureFormat),
+    // Only from texture::run_texture_shader, the output texture has to be the size of the input one, as (width, height)
+    TextureSizeMismatch {
+        in_size: (u32, u32),
+        out_size: (u32, u32),
+    },
+    // Only from GpuExecutor, allocating the program's buffers failed
+    OutOfMemory,
+    // Only from GpuExecutor, its device was lost and hasn't been recovered yet, see GpuExecutor::recover_if_lost
+    DeviceLost,
+    // Two buffers were to be bound at the same binding, see ShaderBindings
+    BindingCollision(u32),
+}
+
+impl std::fmt::Display for RunShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunShaderError::ZeroWorkgroupLength => {
+                write!(f, "Your workgroups must have a size of at least 1")
+            }
+            RunShaderError::ZeroWorkgroups => {
+                write!(f, "You must dispatch at least 1 workgroup")
+            }
+            RunShaderError::OffsetOverflow {
+                workgroup_id,
+                workgroup_len,
+            } => write!(
+                f,
+                "Global ids of workgroup {workgroup_id} (with workgroups of length {workgroup_len}) don't fit in a u32, the dispatch is too large"
+            ),
+            RunShaderError::SentinelFillNeedsCopyDst => write!(
+                f,
+                "Filling the output buffer with the sentinel needs the output buffer to have COPY_DST usage"
+            ),
+            RunShaderError::EmptyInputBuffer => write!(f, "The input buffer must not be empty"),
+            RunShaderError::EmptyOutputBuffer => {
+                write!(f, "The output buffer must not be empty")
+            }
+            RunShaderError::PipelineCreation(err) => {
+                write!(f, "Creating the compute pipeline failed:\n{err}")
+            }
+            RunShaderError::ReadBack(err) => write!(f, "Reading back the output failed: {err}"),
+            RunShaderError::NoFallbackDevice => {
+                write!(f, "No fallback (cpu) adapter or device is available")
+            }
+            RunShaderError::NoTuningCandidates => {
+                write!(f, "No candidate workgroup sizes were given to tune between")
+            }
+            RunShaderError::UnsupportedTextureFormat(format) => write!(
+                f,
+                "Texture format {format:?} isn't supported, only {:?} are",
+                texture::SUPPORTED_TEXTURE_FORMATS
+            ),
+            RunShaderError::TextureSizeMismatch { in_size, out_size } => write!(
+                f,
+                "Output texture is {}x{}, but the input texture is {}x{}",
+                out_size.0, out_size.1, in_size.0, in_size.1
+            ),
+            RunShaderError::OutOfMemory => write!(f, "The gpu ran out of memory"),
+            RunShaderError::DeviceLost => write!(f, "The gpu device was lost"),
+            RunShaderError::BindingCollision(binding) => {
+                write!(f, "More than one buffer is bound at binding {binding}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RunShaderError {}
+
+pub const OUTPUT_SENTINEL_BYTE: u8 = 0xCD;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnwrittenOutput {
+    pub nbytes: usize,
+    pub first_offset: usize,
+}
+
+impl std::fmt::Display for UnwrittenOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} output bytes never written (first one at byte offset {})",
+            self.nbytes, self.first_offset
+        )
+    }
+}
+
+// For outputs of a run_shader call with debug_fill_output set, reports how much of the output still holds the sentinel
+// NOTE: Works on whole u32s so a single byte that happens to be 0xCD isn't reported,
+//       but an output element that legitimately is 0xCDCDCDCD will still be a false positive
+pub fn find_unwritten_output(out_data: &[u8]) -> Result<(), UnwrittenOutput> {
+    let mut unwritten = out_data
+        .chunks(core::mem::size_of::<u32>())
+        .enumerate()
+        .filter(|(_, word)| word.iter().all(|byte| *byte == OUTPUT_SENTINEL_BYTE))
+        .map(|(word_id, word)| (word_id * core::mem::size_of::<u32>(), word.len()));
+    let Some((first_offset, first_nbytes)) = unwritten.next() else {
+        return Ok(());
+    };
+    Err(UnwrittenOutput {
+        nbytes: first_nbytes + unwritten.map(|(_, nbytes)| nbytes).sum::<usize>(),
+        first_offset,
+    })
+}
+
+fn fill_with_sentinel(queue: &Queue, buf: &wgpu::Buffer) {
+    const FILL_CHUNK_NBYTES: u64 = 16 * 1024 * 1024;
+    let sentinel_chunk = vec![OUTPUT_SENTINEL_BYTE; FILL_CHUNK_NBYTES.min(buf.size()) as usize];
+    for offset in (0..buf.size()).step_by(FILL_CHUNK_NBYTES as usize) {
+        let chunk_nbytes = FILL_CHUNK_NBYTES.min(buf.size() - offset) as usize;
+        queue.write_buffer(buf, offset, &sentinel_chunk[..chunk_nbytes]);
+    }
+}
+
+fn global_offset(workgroup_id: usize, workgroup_len: usize) -> Result<u32, RunShaderError> {
+    workgroup_id
+        .checked_mul(workgroup_len)
+        .and_then(|offset| u32::try_from(offset).ok())
+        .ok_or(RunShaderError::OffsetOverflow {
+            workgroup_id,
+            workgroup_len,
+        })
+}
+
+// The last workgroup's offset fitting isn't enough, the global id of its last invocation has to fit too
+fn check_global_ids_fit(n_workgroups: usize, workgroup_len: usize) -> Result<(), RunShaderError> {
+    n_workgroups
+        .checked_mul(workgroup_len)
+        .and_then(|n_invocations| u32::try_from(n_invocations.saturating_sub(1)).ok())
+        .map(|_| ())
+        .ok_or(RunShaderError::OffsetOverflow {
+            workgroup_id: n_workgroups.saturating_sub(1),
+            workgroup_len,
+        })
 }
 
 /* IDEA: This could maybe benefit from interning literally everything but the data
@@ -69,68 +781,330 @@ pub struct RunShaderParams<'a> {
    NOTE:    Total number of calls = number of workgroups * workgroup len
 */
 
-// TODO: Experiment with Features::MAPPABLE_PRIMARY_BUFFERS for extra performance
+// What the layout helpers below make every binding visible to, unless told otherwise
+pub const DEFAULT_BINDING_VISIBILITY: ShaderStages = ShaderStages::COMPUTE;
+
+// The bindings every run_shader shader uses: read-only storage input at 0, read-write storage output at 1,
+// and the uniform metadata (the global offset) at 2. None as a size leaves min_binding_size unset for that binding.
+pub fn standard_bind_group_layout_entries(
+    in_size: Option<wgpu::BufferSize>,
+    out_size: Option<wgpu::BufferSize>,
+    meta_size: Option<wgpu::BufferSize>,
+) -> Vec<BindGroupLayoutEntry> {
+    multi_bind_group_layout_entries(&[in_size], &[out_size], meta_size)
+}
+
+// The bindings of a run_shader_multi shader, see RunShaderMultiParams for the numbering
+pub fn multi_bind_group_layout_entries(
+    in_sizes: &[Option<wgpu::BufferSize>],
+    out_sizes: &[Option<wgpu::BufferSize>],
+    meta_size: Option<wgpu::BufferSize>,
+) -> Vec<BindGroupLayoutEntry> {
+    let with_default_visibility = |sizes: &[Option<wgpu::BufferSize>]| {
+        sizes
+            .iter()
+            .map(|size| (*size, DEFAULT_BINDING_VISIBILITY))
+            .collect::<Vec<_>>()
+    };
+    multi_bind_group_layout_entries_with_visibility(
+        &with_default_visibility(in_sizes),
+        &with_default_visibility(out_sizes),
+        (meta_size, DEFAULT_BINDING_VISIBILITY),
+    )
+}
+
+// Like multi_bind_group_layout_entries, but every binding comes with the stages it's visible to,
+// e.g. for layouts shared between a compute and a render pipeline
+pub fn multi_bind_group_layout_entries_with_visibility(
+    ins: &[(Option<wgpu::BufferSize>, ShaderStages)],
+    outs: &[(Option<wgpu::BufferSize>, ShaderStages)],
+    meta: (Option<wgpu::BufferSize>, ShaderStages),
+) -> Vec<BindGroupLayoutEntry> {
+    let bindings = (0..ins.len() + outs.len() + 1)
+        .map(|binding| u32::try_from(binding).unwrap())
+        .collect::<Vec<_>>();
+    let visibility = ins
+        .iter()
+        .chain(outs)
+        .chain([&meta])
+        .map(|(_, visibility)| *visibility)
+        .collect::<Vec<_>>();
+    let sizes = |bindings: &[(Option<wgpu::BufferSize>, ShaderStages)]| {
+        bindings.iter().map(|(size, _)| *size).collect::<Vec<_>>()
+    };
+    bind_group_layout_entries(&bindings, &visibility, &sizes(ins), &sizes(outs), meta.0)
+}
+
+// The inputs, outputs and metadata uniform, in that order, at the given bindings and with the given visibility
+fn bind_group_layout_entries(
+    bindings: &[u32],
+    visibility: &[ShaderStages],
+    in_sizes: &[Option<wgpu::BufferSize>],
+    out_sizes: &[Option<wgpu::BufferSize>],
+    meta_size: Option<wgpu::BufferSize>,
+) -> Vec<BindGroupLayoutEntry> {
+    let buffer_entry = |binding, visibility, ty, min_binding_size| BindGroupLayoutEntry {
+        binding,
+        count: None,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size,
+        },
+    };
+    let in_entries = in_sizes
+        .iter()
+        .map(|size| (wgpu::BufferBindingType::Storage { read_only: true }, *size));
+    let out_entries = out_sizes
+        .iter()
+        .map(|size| (wgpu::BufferBindingType::Storage { read_only: false }, *size));
+    in_entries
+        .chain(out_entries)
+        .chain([(wgpu::BufferBindingType::Uniform, meta_size)])
+        .zip(bindings.iter().zip(visibility))
+        .map(|((ty, size), (binding, visibility))| buffer_entry(*binding, *visibility, ty, size))
+        .collect()
+}
+
+// The same layout run_shader builds for bind group 0 (minus the optional params binding),
+// for custom dispatch code that wants to stay compatible with run_shader's shaders
+pub fn standard_bind_group_layout(
+    device: &Device,
+    in_size: Option<wgpu::BufferSize>,
+    out_size: Option<wgpu::BufferSize>,
+    meta_size: Option<wgpu::BufferSize>,
+) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("Standard bind group layout"),
+        entries: &standard_bind_group_layout_entries(in_size, out_size, meta_size),
+    })
+}
 
-pub fn run_shader(params: RunShaderParams<'_>) -> Option<()> {
-    assert!(params.out_buf.size() != 0);
-    assert!(params.in_buf.size() != 0);
+fn prepare_dispatch<'a>(
+    params: impl Into<DispatchParams<'a>>,
+) -> Result<ShaderDispatch<'a>, RunShaderError> {
+    let mut params = params.into();
+    if params.in_bufs.is_empty() || params.in_bufs.iter().any(|buf| buf.size() == 0) {
+        return Err(RunShaderError::EmptyInputBuffer);
+    }
+    if params.out_bufs.is_empty() || params.out_bufs.iter().any(|buf| buf.size() == 0) {
+        return Err(RunShaderError::EmptyOutputBuffer);
+    }
     if params.workgroup_len == 0 {
-        println!("Your workgroups must have a size of at least 1.");
-        return None;
+        return Err(RunShaderError::ZeroWorkgroupLength);
+    }
+    // wgpu would only catch this as a layout validation error, which doesn't say which binding it was
+    let mut bindings_used = params.buffer_bindings.clone();
+    if params.params_buf.is_some() {
+        bindings_used.push(PARAMS_BINDING);
+    }
+    if params.counter_buf.is_some() {
+        bindings_used.push(COUNTER_BINDING);
+    }
+    bindings_used.sort_unstable();
+    if let Some(pair) = bindings_used.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(RunShaderError::BindingCollision(pair[0]));
     }
     let n_workgroups: usize = params.n_workgroups;
-    assert!(n_workgroups != 0);
+    match params.dispatch_dims {
+        Some((x, y, z)) => {
+            if x == 0 || y == 0 || z == 0 {
+                return Err(RunShaderError::ZeroWorkgroups);
+            }
+        }
+        None => {
+            if n_workgroups == 0 {
+                return Err(RunShaderError::ZeroWorkgroups);
+            }
+            // Check up front so we don't end up with half of the dispatch submitted
+            check_global_ids_fit(n_workgroups, params.workgroup_len)?;
+        }
+    }
 
-    let mut metadata_var = [0u8; core::mem::size_of::<u32>()];
-    let meta_buf = params.device.create_buffer(&BufferDescriptor {
-        label: Some("Metadata compute uniform buffer"),
-        size: metadata_var.len() as u64,
-        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
-        mapped_at_creation: false,
+    if params.debug_fill_output {
+        if params
+            .out_bufs
+            .iter()
+            .any(|buf| !buf.usage().contains(BufferUsages::COPY_DST))
+        {
+            return Err(RunShaderError::SentinelFillNeedsCopyDst);
+        }
+        for out_buf in params.out_bufs.iter() {
+            fill_with_sentinel(params.queue, out_buf);
+        }
+    }
+
+    let meta_buf = params.meta_buf.take().unwrap_or_else(|| {
+        params.device.create_buffer(&BufferDescriptor {
+            label: Some("Metadata compute uniform buffer"),
+            // goff: u32, or origin: vec3<u32> for dispatch_dims
+            size: match params.dispatch_dims {
+                Some(_) => 3 * core::mem::size_of::<u32>() as u64,
+                None => core::mem::size_of::<u32>() as u64,
+            },
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
     });
 
-    let bind_group_0_layout = params
+    // A program that came off the network must not be able to panic us through wgpu's uncaptured error handler
+    let ((pipeline, empty_bind_group, bind_group), scope_err) =
+        with_error_scope(params.device, wgpu::ErrorFilter::Validation, || {
+            let pipeline = match params.pipeline.take() {
+                Some(pipeline) => pipeline,
+                None => Arc::new(create_dispatch_pipeline(&params, meta_buf.size())),
+            };
+
+            // Same order as the layout entries: inputs, outputs, metadata
+            let mut bind_group_entries = params
+                .in_bufs
+                .iter()
+                .chain(params.out_bufs.iter())
+                .copied()
+                .chain([&meta_buf])
+                .zip(&params.buffer_bindings)
+                .map(|(buf, binding)| BindGroupEntry {
+                    binding: *binding,
+                    resource: buf.as_entire_binding(),
+                })
+                .collect::<Vec<_>>();
+            if let Some(params_buf) = params.params_buf {
+                bind_group_entries.push(BindGroupEntry {
+                    binding: PARAMS_BINDING,
+                    resource: params_buf.as_entire_binding(),
+                });
+            }
+            if let Some(counter_buf) = params.counter_buf {
+                bind_group_entries.push(BindGroupEntry {
+                    binding: COUNTER_BINDING,
+                    resource: counter_buf.as_entire_binding(),
+                });
+            }
+            let empty_bind_group = params.device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Empty bind group"),
+                layout: &pipeline.empty_bind_group_layout,
+                entries: &[],
+            });
+            let bind_group = params.device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Compute bind group"),
+                layout: &pipeline.bind_group_layout,
+                entries: &bind_group_entries,
+            });
+            (pipeline, empty_bind_group, bind_group)
+        });
+    if let Some(err) = futures::executor::block_on(scope_err) {
+        return Err(RunShaderError::PipelineCreation(err.to_string()));
+    }
+
+    let max_dispatch_workgroups: usize = params
+        .device
+        .limits()
+        .max_compute_workgroups_per_dimension
+        .try_into()
+        .unwrap();
+
+    // We dispatch as many workgroups per pass as possible, the last chunk gets the remainder
+    let chunks = match params.dispatch_dims {
+        Some(dims) => tiles_3d(dims, u32::try_from(max_dispatch_workgroups).unwrap())
+            .into_iter()
+            .map(|(origin, how_many)| (ChunkOffset::Origin(origin), how_many))
+            .collect(),
+        None => (0..n_workgroups)
+            .step_by(max_dispatch_workgroups)
+            .map(|workgroup_id| {
+                Ok((
+                    ChunkOffset::Global(global_offset(workgroup_id, params.workgroup_len)?),
+                    (
+                        u32::try_from(max_dispatch_workgroups.min(n_workgroups - workgroup_id))
+                            .unwrap(),
+                        1,
+                        1,
+                    ),
+                ))
+            })
+            .collect::<Result<Vec<_>, RunShaderError>>()?,
+    };
+
+    Ok(ShaderDispatch {
+        device: params.device,
+        queue: params.queue,
+        pipeline,
+        bind_group_index: params.bind_group_index,
+        bind_group,
+        empty_bind_group,
+        meta_buf,
+        chunks,
+    })
+}
+
+// Creates the layouts and the pipeline of the dispatch, any validation errors end up in the caller's error scope
+fn create_dispatch_pipeline(params: &DispatchParams<'_>, meta_nbytes: u64) -> DispatchPipeline {
+    let binding_size = |buf: &&wgpu::Buffer| {
+        params
+            .strict_binding_sizes
+            .then(|| buf.size().try_into().unwrap())
+    };
+    let mut bind_group_layout_entries = bind_group_layout_entries(
+        &params.buffer_bindings,
+        &vec![ShaderStages::COMPUTE; params.buffer_bindings.len()],
+        &params.in_bufs.iter().map(binding_size).collect::<Vec<_>>(),
+        &params.out_bufs.iter().map(binding_size).collect::<Vec<_>>(),
+        Some(meta_nbytes.try_into().unwrap()),
+    );
+    if params.params_buf.is_some() {
+        bind_group_layout_entries.push(BindGroupLayoutEntry {
+            binding: PARAMS_BINDING,
+            count: None,
+            visibility: ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+        });
+    }
+    if params.counter_buf.is_some() {
+        bind_group_layout_entries.push(BindGroupLayoutEntry {
+            binding: COUNTER_BINDING,
+            count: None,
+            visibility: ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: Some((core::mem::size_of::<u32>() as u64).try_into().unwrap()),
+            },
+        });
+    }
+    tracing::debug!(
+        "run_shader bind group {} layout: {}",
+        params.bind_group_index,
+        describe_layout(&bind_group_layout_entries)
+    );
+
+    let bind_group_layout = params
         .device
         .create_bind_group_layout(&BindGroupLayoutDescriptor {
             label: Some("Compute pipeline bind group layout"),
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    count: None,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(params.in_buf.size().try_into().unwrap()),
-                    },
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    count: None,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(params.out_buf.size().try_into().unwrap()),
-                    },
-                },
-                BindGroupLayoutEntry {
-                    binding: 2,
-                    count: None,
-                    visibility: ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: Some(meta_buf.size().try_into().unwrap()),
-                    },
-                },
-            ],
+            entries: &bind_group_layout_entries,
         });
+    // Groups before ours are left empty, but still have to be in the layout (and bound)
+    let empty_bind_group_layout =
+        params
+            .device
+            .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Empty bind group layout"),
+                entries: &[],
+            });
+    let bind_group_layouts = (0..params.bind_group_index)
+        .map(|_| &empty_bind_group_layout)
+        .chain([&bind_group_layout])
+        .collect::<Vec<_>>();
 
     let compute_pipeline_layout = params
         .device
         .create_pipeline_layout(&PipelineLayoutDescriptor {
-            bind_group_layouts: &[&bind_group_0_layout],
+            bind_group_layouts: &bind_group_layouts,
             label: Some("Compute pipeline layout"),
             push_constant_ranges: &[],
         });
@@ -146,74 +1120,513 @@ pub fn run_shader(params: RunShaderParams<'_>) -> Option<()> {
             cache: None,
         });
 
-    let bind_group_0 = params.device.create_bind_group(&BindGroupDescriptor {
-        label: Some("Bind group 0"),
-        layout: &bind_group_0_layout,
-        entries: &[
-            BindGroupEntry {
-                binding: 0,
-                resource: params.in_buf.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: params.out_buf.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: meta_buf.as_entire_binding(),
-            },
-        ],
-    });
+    DispatchPipeline {
+        bind_group_layout,
+        empty_bind_group_layout,
+        compute_pipeline,
+    }
+}
 
-    let dispatch_workgroups = |how_many| {
-        let mut encoder = params
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor { label: None });
-        {
-            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: None,
-                timestamp_writes: None,
-            });
-            cpass.set_pipeline(&compute_pipeline);
-            cpass.set_bind_group(0, &bind_group_0, &[]);
-            cpass.dispatch_workgroups(how_many, 1, 1);
-        }
+// Everything needed to submit the chunks of a run_shader call, so the async and blocking versions can share it
+struct ShaderDispatch<'a> {
+    device: &'a Device,
+    queue: &'a Queue,
+    pipeline: Arc<DispatchPipeline>,
+    bind_group_index: u32,
+    bind_group: wgpu::BindGroup,
+    // Bound at every index below bind_group_index
+    empty_bind_group: wgpu::BindGroup,
+    meta_buf: wgpu::Buffer,
+    // (where it starts, number of workgroups along x, y and z) of every dispatch
+    chunks: Vec<(ChunkOffset, (u32, u32, u32))>,
+}
 
-        params.queue.submit(Some(encoder.finish()));
-    };
+// What gets written to the metadata uniform before a chunk is dispatched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkOffset {
+    // goff, the offset of the chunk's first invocation along X
+    Global(u32),
+    // For dispatch_dims, the origin of the tile in workgroups, as a vec3<u32>
+    Origin((u32, u32, u32)),
+}
 
-    let max_dispatch_workgroups: usize = params
-        .device
-        .limits()
-        .max_compute_workgroups_per_dimension
-        .try_into()
-        .unwrap();
+pub const DEFAULT_MAX_IN_FLIGHT_DISPATCHES: usize = 1024;
+// How long to wait before trying again when all permits are held by dispatches that haven't been submitted yet
+const DISPATCH_PERMIT_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// Bounds the number of dispatches that have been submitted but haven't finished on the gpu yet,
+// a safety valve against drivers that get unstable when flooded with submissions.
+// run_shader(_blocking) goes through the process-global one, so the bound holds no matter how many tasks or devices are active.
+// NOTE: Permits are given back from wgpu's on_submitted_work_done callback, which only fires when a device is polled (or submitted to),
+//       waiting for a permit polls the waiter's device, but a permit held by another device's work is only freed once that device gets polled.
+pub struct DispatchLimiter {
+    max_in_flight: usize,
+    semaphore: Arc<Semaphore>,
+}
 
-    let remainder_workgroups = n_workgroups % max_dispatch_workgroups;
+static GLOBAL_DISPATCH_LIMITER: OnceLock<DispatchLimiter> = OnceLock::new();
 
-    // We try to dispatch as many workgroups per pass as possible and deal with the remainder afterwards
-    for workgroup_id in (0..n_workgroups - remainder_workgroups).step_by(max_dispatch_workgroups) {
-        // Tell the compute shader its absolute offset
-        // because the global offset is only global within the dispatch
-        u32::to_shader_bytes(
-            &u32::try_from(workgroup_id * params.workgroup_len).unwrap(),
-            &mut metadata_var,
-        );
-        params.queue.write_buffer(&meta_buf, 0, &metadata_var);
-        dispatch_workgroups(u32::try_from(max_dispatch_workgroups).unwrap());
-    }
+// A dispatch's share of a DispatchLimiter, held until the work it's submitted with has finished on the gpu, see submit
+// NOTE: Dropping it without submitting anything gives it back straight away
+pub struct DispatchPermit(OwnedSemaphorePermit);
 
-    // Deal with remainder
-    if remainder_workgroups != 0 {
-        u32::to_shader_bytes(
-            &u32::try_from((n_workgroups - remainder_workgroups) * params.workgroup_len).unwrap(),
-            &mut metadata_var,
-        );
-        params.queue.write_buffer(&meta_buf, 0, &metadata_var);
-        dispatch_workgroups(u32::try_from(remainder_workgroups).unwrap());
+impl DispatchPermit {
+    // Submits the command buffers, the permit is given back once they (and everything submitted before them) have finished on the gpu
+    pub fn submit(
+        self,
+        queue: &Queue,
+        command_buffers: impl IntoIterator<Item = wgpu::CommandBuffer>,
+    ) -> wgpu::SubmissionIndex {
+        let submission = queue.submit(command_buffers);
+        queue.on_submitted_work_done(move || drop(self));
+        submission
     }
+}
+
+// wgpu's error scopes are one stack per device, shared by every thread using it, so two threads checking for errors at once
+// (e.g. telefork-server running several clients' programs on one GpuExecutor) could pop each other's scopes,
+// every push and pop goes through with_error_scope, which holds this from the push until the pop
+static ERROR_SCOPE_LOCK: Mutex<()> = Mutex::new(());
 
-    Some(())
+// Runs f inside an error scope, holding ERROR_SCOPE_LOCK until the scope is popped again
+// Waiting for the scope's error (ready straight away on native) is left to the caller, so the lock isn't held across an await
+pub(crate) fn with_error_scope<T>(
+    device: &Device,
+    filter: wgpu::ErrorFilter,
+    f: impl FnOnce() -> T,
+) -> (T, impl Future<Output = Option<wgpu::Error>>) {
+    let _error_scope_guard = ERROR_SCOPE_LOCK.lock().unwrap();
+    device.push_error_scope(filter);
+    let res = f();
+    (res, device.pop_error_scope())
+}
+
+impl DispatchLimiter {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            max_in_flight,
+            semaphore: Arc::new(Semaphore::new(max_in_flight)),
+        }
+    }
+
+    // Has to be called before the first dispatch, after that the limit is fixed (and returned as the error)
+    pub fn set_global_limit(max_in_flight: usize) -> Result<(), usize> {
+        GLOBAL_DISPATCH_LIMITER
+            .set(DispatchLimiter::new(max_in_flight))
+            .map_err(|_| Self::global().max_in_flight)
+    }
+
+    pub fn global() -> &'static DispatchLimiter {
+        GLOBAL_DISPATCH_LIMITER
+            .get_or_init(|| DispatchLimiter::new(DEFAULT_MAX_IN_FLIGHT_DISPATCHES))
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    fn try_acquire(&self) -> Option<DispatchPermit> {
+        match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(DispatchPermit(permit)),
+            Err(TryAcquireError::NoPermits) => None,
+            Err(TryAcquireError::Closed) => {
+                unreachable!("Dispatch limiter semaphore is never closed!")
+            }
+        }
+    }
+
+    // Sleeps until the gpu has finished what's been submitted (which gives back the permits of those dispatches),
+    // permits held by dispatches that haven't been submitted yet (or by another device's work) have nothing to wait for
+    // on this device, so once its queue is empty those are retried after a short sleep
+    pub fn acquire_blocking(&self, device: &Device) -> DispatchPermit {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            let queue_was_empty = device.poll(wgpu::Maintain::Wait).is_queue_empty();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            // Otherwise more work was submitted while we waited, which might be holding the permits, so wait for that too
+            if queue_was_empty {
+                std::thread::sleep(DISPATCH_PERMIT_RETRY_INTERVAL);
+            }
+        }
+    }
+
+    // Same as acquire_blocking, but without holding up the runtime's other tasks while sleeping
+    pub async fn acquire(&self, device: &Device) -> DispatchPermit {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            device.poll(wgpu::Maintain::Poll);
+            tokio::time::sleep(DEFAULT_MAP_POLL_INTERVAL).await;
+        }
+    }
+}
+
+// At most this many (plus the last one) per-chunk progress lines are printed per dispatch,
+// so a dispatch split into thousands of chunks doesn't drown the log
+pub const MAX_LOGGED_CHUNKS_PER_DISPATCH: usize = 8;
+
+// Samples every n_chunks.div_ceil(max_logged)-th chunk, always including the first and the last
+fn should_log_chunk(chunk_index: usize, n_chunks: usize, max_logged: usize) -> bool {
+    let stride = n_chunks.div_ceil(max_logged.max(1));
+    chunk_index % stride == 0 || chunk_index + 1 == n_chunks
+}
+
+// Single chunk dispatches are the common case, so they aren't logged at all
+fn log_chunk_progress(chunk_index: usize, n_chunks: usize, n_workgroups: u64) {
+    if n_chunks > 1 && should_log_chunk(chunk_index, n_chunks, MAX_LOGGED_CHUNKS_PER_DISPATCH) {
+        tracing::info!(
+            "Dispatched chunk {}/{n_chunks} ({n_workgroups} workgroups)",
+            chunk_index + 1
+        );
+    }
+}
+
+fn chunk_workgroups((_, (x, y, z)): (ChunkOffset, (u32, u32, u32))) -> u64 {
+    u64::from(x) * u64::from(y) * u64::from(z)
+}
+
+impl ShaderDispatch<'_> {
+    fn dispatch_chunk(
+        &self,
+        (offset, (how_many_x, how_many_y, how_many_z)): (ChunkOffset, (u32, u32, u32)),
+        permit: DispatchPermit,
+    ) {
+        // Tell the compute shader its absolute offset
+        // because the global offset is only global within the dispatch
+        let metadata = match offset {
+            ChunkOffset::Global(goff) => vec![goff],
+            ChunkOffset::Origin((x, y, z)) => vec![x, y, z],
+        };
+        let mut metadata_var = vec![0u8; metadata.len() * core::mem::size_of::<u32>()];
+        for (value, bytes) in metadata
+            .iter()
+            .zip(metadata_var.chunks_exact_mut(core::mem::size_of::<u32>()))
+        {
+            u32::to_shader_bytes(value, bytes);
+        }
+        self.queue.write_buffer(&self.meta_buf, 0, &metadata_var);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(&self.pipeline.compute_pipeline);
+            for index in 0..self.bind_group_index {
+                cpass.set_bind_group(index, &self.empty_bind_group, &[]);
+            }
+            cpass.set_bind_group(self.bind_group_index, &self.bind_group, &[]);
+            cpass.dispatch_workgroups(how_many_x, how_many_y, how_many_z);
+        }
+
+        permit.submit(self.queue, Some(encoder.finish()));
+    }
+
+    async fn submit_all(&self) {
+        for (chunk_index, chunk) in self.chunks.iter().copied().enumerate() {
+            let permit = DispatchLimiter::global().acquire(self.device).await;
+            self.dispatch_chunk(chunk, permit);
+            log_chunk_progress(chunk_index, self.chunks.len(), chunk_workgroups(chunk));
+            yield_now().await;
+        }
+    }
+
+    fn submit_all_blocking(&self) {
+        for (chunk_index, chunk) in self.chunks.iter().copied().enumerate() {
+            let permit = DispatchLimiter::global().acquire_blocking(self.device);
+            self.dispatch_chunk(chunk, permit);
+            log_chunk_progress(chunk_index, self.chunks.len(), chunk_workgroups(chunk));
+        }
+    }
+}
+
+// Submits every chunk of the dispatch, yielding to the runtime after each one, without waiting for the gpu to finish them,
+// anything that reads out_buf afterwards (e.g. read_back_buffer) is ordered after them by the queue anyway.
+// Dropping the returned future stops any further chunks from being submitted, the pipeline,
+// bind group and metadata buffer are released with it (wgpu keeps them alive until in-flight work is done).
+// NOTE: Chunks that were already submitted can't be recalled, they will still run to completion on the gpu,
+//       so after cancelling the contents of the output buffer are unspecified.
+pub async fn run_shader(params: RunShaderParams<'_>) -> Result<(), RunShaderError> {
+    prepare_dispatch(params)?.submit_all().await;
+    Ok(())
+}
+
+// Same as run_shader, but reuses the pipeline and metadata buffer of an earlier call with the same program, entry point,
+// bindings, buffer sizes and dispatch_dims instead of creating them again, for callers that run one program over and over,
+// see GpuExecutor. None creates them, either way they're given back (once the chunks are submitted) for the next call
+// NOTE: The metadata buffer is written before every chunk, so it mustn't be shared by calls running at the same time
+pub async fn run_shader_reusing(
+    params: RunShaderParams<'_>,
+    pipeline: Option<Arc<DispatchPipeline>>,
+    meta_buf: Option<wgpu::Buffer>,
+) -> Result<(Arc<DispatchPipeline>, wgpu::Buffer), RunShaderError> {
+    let mut dispatch_params = DispatchParams::from(params);
+    dispatch_params.pipeline = pipeline;
+    dispatch_params.meta_buf = meta_buf;
+    let dispatch = prepare_dispatch(dispatch_params)?;
+    dispatch.submit_all().await;
+    Ok((dispatch.pipeline, dispatch.meta_buf))
+}
+
+// Same as run_shader, but only returns once the gpu has actually finished running the shader, e.g. for timing it
+pub async fn run_shader_to_completion(params: RunShaderParams<'_>) -> Result<(), RunShaderError> {
+    let (device, queue) = (params.device, params.queue);
+    run_shader(params).await?;
+    wait_for_submitted_work(device, queue).await;
+    Ok(())
+}
+
+// Same as run_shader, but blocks the calling thread instead of yielding while waiting for a free dispatch slot,
+// for callers outside of an async context
+pub fn run_shader_blocking(params: RunShaderParams<'_>) -> Result<(), RunShaderError> {
+    prepare_dispatch(params)?.submit_all_blocking();
+    Ok(())
+}
+
+// Same as run_shader, but with any number of input and output buffers, see RunShaderMultiParams for how they're bound
+pub async fn run_shader_multi(params: RunShaderMultiParams<'_>) -> Result<(), RunShaderError> {
+    prepare_dispatch(params)?.submit_all().await;
+    Ok(())
+}
+
+// Same as run_shader, but also binds a zeroed atomic<u32> counter at COUNTER_BINDING and returns its final value,
+// for a verifiable count of how many elements the shader actually processed (e.g. the invocations that passed the bounds check).
+// The shader has to cooperate, declaring:
+// @group(0) @binding(4) var<storage, read_write> processed: atomic<u32>;
+// and doing atomicAdd(&processed, 1u) for every element it processes
+pub async fn run_shader_counted(params: RunShaderParams<'_>) -> Result<u32, RunShaderError> {
+    let device = params.device;
+    let queue = params.queue;
+    let counter_buf = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("Processed elements counter buffer"),
+        contents: &0u32.to_le_bytes(),
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+    });
+    let mut dispatch_params = DispatchParams::from(params);
+    dispatch_params.counter_buf = Some(&counter_buf);
+    prepare_dispatch(dispatch_params)?.submit_all().await;
+
+    let counter = read_back_as::<u32>(device, queue, &counter_buf)
+        .await
+        .map_err(RunShaderError::ReadBack)?;
+    Ok(counter[0])
+}
+
+// The one call version of run_shader: uploads input, runs the shader with one invocation per output element
+// (out_len.div_ceil(workgroup_len) workgroups, the shader has to bounds check the last one) and returns the output
+pub async fn compute<In: IntoShaderBytes, Out: FromShaderBytes>(
+    device: &Device,
+    queue: &Queue,
+    program: &ShaderModule,
+    entry_point: &str,
+    input: &[In],
+    out_len: usize,
+    workgroup_len: usize,
+) -> Result<Vec<Out>, RunShaderError> {
+    compute_with_readback(
+        device,
+        queue,
+        program,
+        entry_point,
+        input,
+        out_len,
+        workgroup_len,
+        OutputReadback::Copy,
+    )
+    .await
+}
+
+// How compute gets the output off the gpu
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputReadback {
+    // Copies the output storage buffer into a separate readback buffer and maps that, works on every device
+    #[default]
+    Copy,
+    // Creates the output storage buffer mappable and maps it directly, skipping the copy and the second buffer.
+    // Needs the device to have been created with Features::MAPPABLE_PRIMARY_BUFFERS, Copy is used instead if it wasn't.
+    // NOTE: Not a guaranteed win, on a discrete gpu a mappable buffer usually lives in host visible memory,
+    //       so every write of the shader goes over the bus, which for write heavy shaders can cost more than the one big copy does.
+    //       On integrated gpus (unified memory) the copy is pure overhead, see generalised-example for comparing the two
+    DirectMap,
+}
+
+// Whether OutputReadback::DirectMap actually maps the output directly on device, rather than falling back to Copy
+pub fn supports_direct_output_mapping(device: &Device) -> bool {
+    device
+        .features()
+        .contains(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS)
+}
+
+// Same as compute, but with a choice of how the output is read back, see OutputReadback
+#[allow(clippy::too_many_arguments)]
+pub async fn compute_with_readback<In: IntoShaderBytes, Out: FromShaderBytes>(
+    device: &Device,
+    queue: &Queue,
+    program: &ShaderModule,
+    entry_point: &str,
+    input: &[In],
+    out_len: usize,
+    workgroup_len: usize,
+    readback: OutputReadback,
+) -> Result<Vec<Out>, RunShaderError> {
+    // Checked before working out the number of workgroups, which divides by it
+    if workgroup_len == 0 {
+        return Err(RunShaderError::ZeroWorkgroupLength);
+    }
+    let in_buf = TypedBuffer::from_slice(device, input, BufferUsages::empty());
+    if readback == OutputReadback::DirectMap && supports_direct_output_mapping(device) {
+        let mut out_buf = create_output_buffer(
+            device,
+            (out_len * TypedBuffer::<Out>::stride()) as u64,
+            BufferUsages::MAP_READ,
+        );
+        run_shader(RunShaderParams {
+            device,
+            queue,
+            in_buf: in_buf.buffer(),
+            out_buf: &mut out_buf,
+            workgroup_len,
+            n_workgroups: out_len.div_ceil(workgroup_len),
+            program,
+            entry_point,
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await?;
+        // Mapping waits for the dispatches writing the buffer to finish
+        let out_view = wgpu_map_helper(device, wgpu::MapMode::Read, &out_buf, ..)
+            .await
+            .map_err(RunShaderError::ReadBack)?;
+        let res = ShaderBytes::deserialise_to_iterator(&out_view.get_mapped_range()).collect();
+        out_buf.unmap();
+        return Ok(res);
+    }
+    let mut out_buf = TypedBuffer::<Out>::new_output(device, out_len, BufferUsages::empty());
+    run_shader_typed(TypedRunShaderParams {
+        device,
+        queue,
+        in_buf: &in_buf,
+        out_buf: &mut out_buf,
+        workgroup_len,
+        n_workgroups: out_len.div_ceil(workgroup_len),
+        program,
+        entry_point,
+        debug_fill_output: false,
+        strict_binding_sizes: true,
+        params_buf: None,
+        bindings: ShaderBindings::default(),
+        dispatch_dims: None,
+    })
+    .await
+}
+
+// Workgroup size of the shaders generated by gpu_map
+const GPU_MAP_WORKGROUP_LEN: usize = 64;
+
+// The standard bindings around op, which is the body of a WGSL function from x: In to Out, e.g. "return x * x;"
+fn gpu_map_program(in_type: &str, out_type: &str, op: &str) -> String {
+    format!(
+        r#"
+        @group(0) @binding(0) var<storage, read> v_in_data: array<{in_type}>;
+        @group(0) @binding(1) var<storage, read_write> v_out_data: array<{out_type}>;
+        @group(0) @binding(2) var<uniform> goff: u32;
+
+        fn op(x: {in_type}) -> {out_type} {{
+            {op}
+        }}
+
+        @compute @workgroup_size({GPU_MAP_WORKGROUP_LEN})
+        fn main(@builtin(global_invocation_id) gid: vec3<u32>) {{
+            let actual_id = gid.x + goff;
+            if (actual_id >= arrayLength(&v_out_data)) {{ return; }}
+            v_out_data[actual_id] = op(v_in_data[actual_id]);
+        }}
+        "#
+    )
+}
+
+// Applies wgsl_op to every element of input on the gpu, e.g. gpu_map(&device, &queue, "return x * x;", 0u32..1000),
+// wgsl_op is the body of a WGSL function taking the element as x, the binding boilerplate is generated around it
+// NOTE: The shader is compiled on every call, for running the same op repeatedly build a ShaderModule and use compute
+pub async fn gpu_map<In, Out>(
+    device: &Device,
+    queue: &Queue,
+    wgsl_op: &str,
+    input: impl IntoIterator<Item = In>,
+) -> Result<Vec<Out>, RunShaderError>
+where
+    In: IntoShaderBytes + WgslTypeName,
+    Out: FromShaderBytes + WgslTypeName,
+{
+    let input = input.into_iter().collect::<Vec<In>>();
+    // Mapping nothing gives nothing, instead of the empty buffer errors
+    if input.is_empty() {
+        return Ok(Vec::new());
+    }
+    // A typo in wgsl_op has to be an error rather than a panic in wgpu's uncaptured error handler
+    let (program, scope_err) = with_error_scope(device, wgpu::ErrorFilter::Validation, || {
+        device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("gpu_map"),
+            source: wgpu::ShaderSource::Wgsl(Cow::from(gpu_map_program(
+                In::WGSL_TYPE_NAME,
+                Out::WGSL_TYPE_NAME,
+                wgsl_op,
+            ))),
+        })
+    });
+    if let Some(err) = scope_err.await {
+        return Err(RunShaderError::PipelineCreation(err.to_string()));
+    }
+    compute(
+        device,
+        queue,
+        &program,
+        "main",
+        &input,
+        input.len(),
+        GPU_MAP_WORKGROUP_LEN,
+    )
+    .await
+}
+
+// Numbers of workgroups (or a position in workgroups) along x, y and z
+type WorkgroupDims = (u32, u32, u32);
+
+// ((origin x, origin y, origin z), (workgroups x, workgroups y, workgroups z)) of every tile of the grid, in workgroups,
+// only the axes bigger than max_workgroups_per_dim actually get split
+fn tiles_3d(
+    (n_workgroups_x, n_workgroups_y, n_workgroups_z): WorkgroupDims,
+    max_workgroups_per_dim: u32,
+) -> Vec<(WorkgroupDims, WorkgroupDims)> {
+    let step = usize::try_from(max_workgroups_per_dim).unwrap();
+    (0..n_workgroups_z)
+        .step_by(step)
+        .flat_map(|origin_z| {
+            (0..n_workgroups_y).step_by(step).flat_map(move |origin_y| {
+                (0..n_workgroups_x).step_by(step).map(move |origin_x| {
+                    (
+                        (origin_x, origin_y, origin_z),
+                        (
+                            max_workgroups_per_dim.min(n_workgroups_x - origin_x),
+                            max_workgroups_per_dim.min(n_workgroups_y - origin_y),
+                            max_workgroups_per_dim.min(n_workgroups_z - origin_z),
+                        ),
+                    )
+                })
+            })
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -224,23 +1637,1089 @@ mod tests {
     use shader_bytes::ShaderBytes;
     use wgpu::{
         util::{BufferInitDescriptor, DeviceExt},
-        DeviceDescriptor, Features, InstanceDescriptor, Limits, RequestAdapterOptions,
-        ShaderModuleDescriptor,
+        DeviceDescriptor, Features, Limits, ShaderModuleDescriptor,
     };
 
     use super::*;
 
+    #[test]
+    fn test_global_offset_u32_boundary() {
+        let workgroup_len = 32;
+        let last_ok_workgroup = usize::try_from(u32::MAX).unwrap() / workgroup_len;
+        assert_eq!(
+            global_offset(last_ok_workgroup, workgroup_len),
+            Ok(u32::try_from(last_ok_workgroup * workgroup_len).unwrap())
+        );
+        assert_eq!(
+            global_offset(last_ok_workgroup + 1, workgroup_len),
+            Err(RunShaderError::OffsetOverflow {
+                workgroup_id: last_ok_workgroup + 1,
+                workgroup_len
+            })
+        );
+        assert!(global_offset(usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn test_last_invocation_u32_boundary() {
+        // u32::MAX is a multiple of 3, so the last global id of max_workgroups is u32::MAX - 1
+        let workgroup_len = 3;
+        let max_workgroups = usize::try_from(u32::MAX).unwrap() / workgroup_len;
+        assert_eq!(check_global_ids_fit(max_workgroups, workgroup_len), Ok(()));
+        // One more and the last workgroup's offset (u32::MAX) still fits, but the global ids past it don't
+        assert!(global_offset(max_workgroups, workgroup_len).is_ok());
+        assert_eq!(
+            check_global_ids_fit(max_workgroups + 1, workgroup_len),
+            Err(RunShaderError::OffsetOverflow {
+                workgroup_id: max_workgroups,
+                workgroup_len
+            })
+        );
+        assert!(check_global_ids_fit(usize::MAX, 2).is_err());
+    }
+
     #[tokio::test]
-    async fn test_computation_equivalence() {
-        let instance = wgpu::Instance::new(InstanceDescriptor::default());
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions {
-                force_fallback_adapter: false,
-                power_preference: wgpu::PowerPreference::None,
-                ..Default::default()
+    async fn test_zero_workgroups_is_an_error() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from("@compute @workgroup_size(1) fn main() {}")),
+        });
+        let in_buf = create_output_buffer(&device, 4, BufferUsages::empty());
+        let mut out_buf = create_output_buffer(&device, 4, BufferUsages::empty());
+
+        assert_eq!(
+            run_shader(RunShaderParams {
+                device: &device,
+                queue: &queue,
+                in_buf: &in_buf,
+                out_buf: &mut out_buf,
+                workgroup_len: 1,
+                n_workgroups: 0,
+                program: &cs_module,
+                entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: false,
+                params_buf: None,
+                bindings: ShaderBindings::default(),
+                dispatch_dims: None,
             })
+            .await,
+            Err(RunShaderError::ZeroWorkgroups)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_back_buffer_with_extra_usages() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let out_buf = create_output_buffer(
+            &device,
+            256,
+            BufferUsages::COPY_DST | BufferUsages::INDIRECT,
+        );
+        assert!(out_buf
+            .usage()
+            .contains(BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::INDIRECT));
+
+        let data = (0..=255u8).collect::<Vec<u8>>();
+        queue.write_buffer(&out_buf, 0, &data);
+        assert_eq!(
+            read_back_buffer(&device, &queue, &out_buf, BufferUsages::empty()).await,
+            Ok(data)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_mapping_round_trips() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let upload_buf = create_upload_buffer(&device, 1004, BufferUsages::empty());
+        assert!(upload_buf
+            .usage()
+            .contains(required_map_usage(wgpu::MapMode::Write) | BufferUsages::COPY_SRC));
+        // Not a multiple of COPY_BUFFER_ALIGNMENT, so the mapping has to be padded
+        let data = (0..=255u8).cycle().take(1001).collect::<Vec<u8>>();
+        write_mapped_buffer(&device, &upload_buf, &data)
+            .await
+            .unwrap();
+        // A buffer can't be both MAP_WRITE and MAP_READ, so the second mapping is of a readback buffer it's copied into
+        let read_back = read_back_buffer(&device, &queue, &upload_buf, BufferUsages::empty())
+            .await
+            .unwrap();
+        assert_eq!(read_back[..data.len()], data);
+
+        // It was unmapped, so it can be mapped and written again
+        write_mapped_buffer(&device, &upload_buf, &[7; 8])
+            .await
+            .unwrap();
+        let read_back = read_back_buffer(&device, &queue, &upload_buf, BufferUsages::empty())
+            .await
+            .unwrap();
+        assert_eq!(read_back[..8], [7; 8]);
+        assert_eq!(read_back[8..data.len()], data[8..]);
+
+        // Misuse is an error, not a panic (or a wgpu validation error)
+        assert_eq!(
+            write_mapped_buffer(&device, &upload_buf, &[0; 1005]).await,
+            Err(WriteMappedError::BufferTooSmall {
+                nbytes: 1008,
+                buf_nbytes: 1004
+            })
+        );
+        let readback_buf = create_readback_buffer(&device, 16, BufferUsages::empty());
+        assert_eq!(
+            write_mapped_buffer(&device, &readback_buf, &[0; 4]).await,
+            Err(WriteMappedError::NotMappableForWriting(
+                readback_buf.usage()
+            ))
+        );
+        assert!(
+            wgpu_map_helper(&device, wgpu::MapMode::Write, &readback_buf, ..)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_map_with_zero_poll_interval() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        // Yields instead of sleeping between polls, the mapping still completes the same
+        let upload_buf = create_upload_buffer(&device, 16, BufferUsages::empty());
+        let buf_view = wgpu_map_helper_with_interval(
+            &device,
+            wgpu::MapMode::Write,
+            &upload_buf,
+            ..,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        buf_view.get_mapped_range_mut().copy_from_slice(&[3; 16]);
+        upload_buf.unmap();
+        let read_back = read_back_buffer(&device, &queue, &upload_buf, BufferUsages::empty())
+            .await
+            .unwrap();
+        assert_eq!(read_back, [3; 16]);
+        wait_for_submitted_work(&device, &queue).await;
+    }
+
+    #[tokio::test]
+    async fn test_with_read_back_iter_sums_lazily() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let data = (0..1024 * 1024u32).collect::<Vec<u32>>();
+        let buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &ShaderBytes::serialise_from_slice(&data).into_data(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        });
+        let (len, sum) = with_read_back_iter::<u32, _>(&device, &queue, &buf, |elems| {
+            (
+                elems.len(),
+                elems.fold(0u64, |acc, elem| acc + u64::from(elem)),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(sum, data.iter().copied().map(u64::from).sum::<u64>());
+    }
+
+    #[tokio::test]
+    async fn test_debug_fill_output_finds_unwritten_output() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[0];
+                }
+            "#,
+            )),
+        });
+
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &[0u8; 4],
+            usage: BufferUsages::STORAGE,
+        });
+        let mut out_buf = create_output_buffer(&device, 1024 * 4, BufferUsages::COPY_DST);
+
+        // Deliberately only dispatches enough invocations for half of the output
+        run_shader(RunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 32,
+            n_workgroups: 16,
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: true,
+            strict_binding_sizes: false,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await
+        .unwrap();
+
+        let out_data = read_back_buffer(&device, &queue, &out_buf, BufferUsages::empty())
+            .await
+            .unwrap();
+        let unwritten = find_unwritten_output(&out_data).unwrap_err();
+        assert_eq!(
+            unwritten,
+            UnwrittenOutput {
+                nbytes: 512 * 4,
+                first_offset: 512 * 4
+            }
+        );
+        assert_eq!(
+            unwritten.to_string(),
+            "2048 output bytes never written (first one at byte offset 2048)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dropping_run_shader_stops_dispatch() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let max_dispatch_workgroups = device.limits().max_compute_workgroups_per_dimension;
+        // Every workgroup counts itself in the output element of the chunk it was dispatched in
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(format!(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<atomic<u32>>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(1)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {{
+                    atomicAdd(&v_out_data[(gid.x + goff) / {max_dispatch_workgroups}u], 1u);
+                }}
+            "#
+            ))),
+        });
+
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &[0u8; 4],
+            usage: BufferUsages::STORAGE,
+        });
+        let mut out_buf = create_output_buffer(&device, 4 * 4, BufferUsages::empty());
+
+        {
+            let run = run_shader(RunShaderParams {
+                device: &device,
+                queue: &queue,
+                in_buf: &in_buf,
+                out_buf: &mut out_buf,
+                workgroup_len: 1,
+                n_workgroups: usize::try_from(max_dispatch_workgroups).unwrap() * 4,
+                program: &cs_module,
+                entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: false,
+                params_buf: None,
+                bindings: ShaderBindings::default(),
+                dispatch_dims: None,
+            });
+            futures::pin_mut!(run);
+            // First poll submits the first chunk and then yields
+            assert!(futures::poll!(run).is_pending());
+        }
+
+        let out_data = read_back_buffer(&device, &queue, &out_buf, BufferUsages::empty())
             .await
-            .expect("Adapter must exist!");
+            .unwrap();
+        assert_eq!(
+            ShaderBytes::deserialise_to_iterator::<u32>(&out_data).collect::<Vec<u32>>(),
+            vec![max_dispatch_workgroups, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_chunk_logging_is_bounded() {
+        let n_chunks = 1_000_000;
+        let logged = (0..n_chunks)
+            .filter(|&i| should_log_chunk(i, n_chunks, MAX_LOGGED_CHUNKS_PER_DISPATCH))
+            .collect::<Vec<_>>();
+        assert!(logged.len() <= MAX_LOGGED_CHUNKS_PER_DISPATCH + 1);
+        assert_eq!(logged.first(), Some(&0));
+        assert_eq!(logged.last(), Some(&(n_chunks - 1)));
+
+        // Small dispatches get every chunk logged
+        assert!((0..5).all(|i| should_log_chunk(i, 5, MAX_LOGGED_CHUNKS_PER_DISPATCH)));
+    }
+
+    #[test]
+    fn test_tiles_3d_cover_huge_grid() {
+        let tiles = tiles_3d((100_000, 100_000, 1), 65535);
+        assert_eq!(
+            tiles,
+            vec![
+                ((0, 0, 0), (65535, 65535, 1)),
+                ((65535, 0, 0), (34465, 65535, 1)),
+                ((0, 65535, 0), (65535, 34465, 1)),
+                ((65535, 65535, 0), (34465, 34465, 1)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_dims_splits_overflowing_axis() {
+        let (device, queue) = crate::test_support::default_device().await;
+        // Just over the limit along X, so every row takes two tiles
+        let width = device.limits().max_compute_workgroups_per_dimension + 2;
+        let height = 3u32;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(format!(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> origin: vec3<u32>;
+
+                @compute @workgroup_size(1)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {{
+                    let abs_id = gid + origin;
+                    v_out_data[abs_id.y * {width}u + abs_id.x] = abs_id.y * {width}u + abs_id.x + 1u;
+                }}
+            "#
+            ))),
+        });
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &[0u8; 4],
+            usage: BufferUsages::STORAGE,
+        });
+        let n_elems = u64::from(width) * u64::from(height);
+        let mut out_buf = create_output_buffer(&device, n_elems * 4, BufferUsages::empty());
+
+        run_shader(RunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 1,
+            n_workgroups: 0,
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: false,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: Some((width, height, 1)),
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            read_back_as::<u32>(&device, &queue, &out_buf).await,
+            Ok((1..=u32::try_from(n_elems).unwrap()).collect::<Vec<u32>>())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_dispatch_is_an_error() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id];
+                }
+            "#,
+            )),
+        });
+        let in_buf = create_output_buffer(&device, 128, BufferUsages::empty());
+        let empty_buf = create_output_buffer(&device, 0, BufferUsages::empty());
+        let mut out_buf = create_output_buffer(&device, 128, BufferUsages::empty());
+        fn params<'a>(
+            (device, queue, program): (&'a Device, &'a Queue, &'a ShaderModule),
+            in_buf: &'a wgpu::Buffer,
+            out_buf: &'a mut wgpu::Buffer,
+            entry_point: &'a str,
+        ) -> RunShaderParams<'a> {
+            RunShaderParams {
+                device,
+                queue,
+                in_buf,
+                out_buf,
+                workgroup_len: 32,
+                n_workgroups: 1,
+                program,
+                entry_point,
+                debug_fill_output: false,
+                strict_binding_sizes: true,
+                params_buf: None,
+                bindings: ShaderBindings::default(),
+                dispatch_dims: None,
+            }
+        }
+        let gpu = (&device, &queue, &cs_module);
+
+        assert_eq!(
+            run_shader(params(gpu, &empty_buf, &mut out_buf, "main")).await,
+            Err(RunShaderError::EmptyInputBuffer)
+        );
+        assert!(matches!(
+            run_shader(params(gpu, &in_buf, &mut out_buf, "not_main")).await,
+            Err(RunShaderError::PipelineCreation(_))
+        ));
+        // Nothing was left broken by the failed attempts
+        assert_eq!(
+            run_shader(params(gpu, &in_buf, &mut out_buf, "main")).await,
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_bindings() {
+        let (device, queue) = crate::test_support::default_device().await;
+        // The same kernel, once at group 0 bindings 3/4/5 and once with the standard bindings but in group 1
+        let shader = |group: u32, [input, output, meta]: [u32; 3]| {
+            device.create_shader_module(ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(Cow::from(format!(
+                    r#"
+                    @group({group}) @binding({input}) var<storage, read> v_in_data: array<u32>;
+                    @group({group}) @binding({output}) var<storage, read_write> v_out_data: array<u32>;
+                    @group({group}) @binding({meta}) var<uniform> goff: u32;
+
+                    @compute @workgroup_size(32)
+                    fn main(@builtin(global_invocation_id) gid: vec3<u32>) {{
+                        let actual_id = gid.x + goff;
+                        if (actual_id >= arrayLength(&v_out_data)) {{ return; }}
+                        v_out_data[actual_id] = v_in_data[actual_id] + 7u;
+                    }}
+                    "#
+                ))),
+            })
+        };
+
+        let input = (0..100u32).collect::<Vec<u32>>();
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: ShaderBytes::serialise_from_slice(&input).get_data(),
+            usage: BufferUsages::STORAGE,
+        });
+        let expected = Ok(input.iter().map(|i| i + 7).collect::<Vec<u32>>());
+        for bindings in [
+            ShaderBindings {
+                bind_group_index: 0,
+                input_binding: 3,
+                output_binding: 4,
+                metadata_binding: 5,
+            },
+            ShaderBindings {
+                bind_group_index: 1,
+                ..ShaderBindings::default()
+            },
+        ] {
+            let cs_module = shader(
+                bindings.bind_group_index,
+                [
+                    bindings.input_binding,
+                    bindings.output_binding,
+                    bindings.metadata_binding,
+                ],
+            );
+            let mut out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+            let params = RunShaderParams {
+                device: &device,
+                queue: &queue,
+                in_buf: &in_buf,
+                out_buf: &mut out_buf,
+                workgroup_len: 32,
+                n_workgroups: input.len().div_ceil(32),
+                program: &cs_module,
+                entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: true,
+                params_buf: None,
+                bindings,
+                dispatch_dims: None,
+            };
+            run_shader(params).await.unwrap();
+            assert_eq!(
+                read_back_as::<u32>(&device, &queue, &out_buf).await,
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_colliding_bindings_are_rejected() {
+        let (device, queue) = crate::test_support::default_device().await;
+        // Never gets as far as being used, the bindings are checked first
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from("@compute @workgroup_size(1) fn main() {}")),
+        });
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &[0; 16],
+            usage: BufferUsages::STORAGE,
+        });
+        let params_buf = create_params_buffer(&device, &[0u32]);
+        for (bindings, params_buf, colliding) in [
+            (
+                ShaderBindings {
+                    input_binding: PARAMS_BINDING,
+                    ..ShaderBindings::default()
+                },
+                Some(&params_buf),
+                PARAMS_BINDING,
+            ),
+            (
+                ShaderBindings {
+                    output_binding: 0,
+                    ..ShaderBindings::default()
+                },
+                None,
+                0,
+            ),
+        ] {
+            let mut out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+            let params = RunShaderParams {
+                device: &device,
+                queue: &queue,
+                in_buf: &in_buf,
+                out_buf: &mut out_buf,
+                workgroup_len: 1,
+                n_workgroups: 1,
+                program: &cs_module,
+                entry_point: "main",
+                debug_fill_output: false,
+                strict_binding_sizes: false,
+                params_buf,
+                bindings,
+                dispatch_dims: None,
+            };
+            assert!(matches!(
+                run_shader(params).await,
+                Err(RunShaderError::BindingCollision(binding)) if binding == colliding
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compute() {
+        // Kept around to request a second device with MAPPABLE_PRIMARY_BUFFERS further down
+        let adapter = crate::test_support::default_adapter().await;
+        let (device, queue) = adapter
+            .request_device(&DeviceDescriptor::default(), None)
+            .await
+            .expect("Device must exist!");
+        let source = r#"
+            @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+            @group(0) @binding(1) var<storage, read_write> v_out_data: array<f32>;
+            @group(0) @binding(2) var<uniform> goff: u32;
+
+            @compute @workgroup_size(64)
+            fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                let actual_id = gid.x + goff;
+                if (actual_id >= arrayLength(&v_out_data)) { return; }
+                v_out_data[actual_id] = f32(v_in_data[actual_id]) * 0.5;
+            }
+        "#;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(source)),
+        });
+
+        // Not a multiple of the workgroup size, so the last workgroup is partially idle
+        let input = (0..1000u32).collect::<Vec<u32>>();
+        let output: Vec<f32> =
+            compute(&device, &queue, &cs_module, "main", &input, input.len(), 64)
+                .await
+                .unwrap();
+        assert_eq!(
+            output,
+            input.iter().map(|&i| i as f32 * 0.5).collect::<Vec<f32>>()
+        );
+
+        // Falls back to copying on devices without MAPPABLE_PRIMARY_BUFFERS, either way the output is the same
+        let output: Vec<f32> = compute_with_readback(
+            &device,
+            &queue,
+            &cs_module,
+            "main",
+            &input,
+            input.len(),
+            64,
+            OutputReadback::DirectMap,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            output,
+            input.iter().map(|&i| i as f32 * 0.5).collect::<Vec<f32>>()
+        );
+
+        assert_eq!(
+            compute::<u32, f32>(&device, &queue, &cs_module, "main", &input, input.len(), 0).await,
+            Err(RunShaderError::ZeroWorkgroupLength)
+        );
+
+        if adapter
+            .features()
+            .contains(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS)
+        {
+            let (device, queue) = adapter
+                .request_device(
+                    &DeviceDescriptor {
+                        required_features: wgpu::Features::MAPPABLE_PRIMARY_BUFFERS,
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .await
+                .expect("Device must exist!");
+            assert!(supports_direct_output_mapping(&device));
+            let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(Cow::from(source)),
+            });
+            let output: Vec<f32> = compute_with_readback(
+                &device,
+                &queue,
+                &cs_module,
+                "main",
+                &input,
+                input.len(),
+                64,
+                OutputReadback::DirectMap,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                output,
+                input.iter().map(|&i| i as f32 * 0.5).collect::<Vec<f32>>()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gpu_map() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let squares: Vec<u32> = gpu_map(&device, &queue, "return x*x;", 0u32..1000)
+            .await
+            .unwrap();
+        assert_eq!(squares, (0u32..1000).map(|x| x * x).collect::<Vec<u32>>());
+
+        let halves: Vec<f32> = gpu_map(&device, &queue, "return f32(x) * 0.5;", [1i32, -3, 8])
+            .await
+            .unwrap();
+        assert_eq!(halves, [0.5, -1.5, 4.0]);
+
+        let empty: Vec<u32> = gpu_map(&device, &queue, "return x;", Vec::<u32>::new())
+            .await
+            .unwrap();
+        assert!(empty.is_empty());
+
+        assert!(matches!(
+            gpu_map::<u32, u32>(&device, &queue, "return y;", 0u32..10).await,
+            Err(RunShaderError::PipelineCreation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run_shader_counted() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+                @group(0) @binding(4) var<storage, read_write> processed: atomic<u32>;
+
+                @compute @workgroup_size(64)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id] + 1u;
+                    atomicAdd(&processed, 1u);
+                }
+            "#,
+            )),
+        });
+
+        // 1000 elements, but 16 workgroups of 64 is 1024 invocations
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &ShaderBytes::serialise_from_slice(&vec![0u32; 1000]).into_data(),
+            usage: BufferUsages::STORAGE,
+        });
+        let mut out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+
+        let params = RunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 64,
+            n_workgroups: 1000usize.div_ceil(64),
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        };
+        let n_processed = run_shader_counted(params).await.unwrap();
+        assert_eq!(n_processed, 1000);
+    }
+
+    #[tokio::test]
+    async fn test_run_shader_multi_buffers() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_a: array<u32>;
+                @group(0) @binding(1) var<storage, read> v_b: array<u32>;
+                @group(0) @binding(2) var<storage, read_write> v_sums: array<u32>;
+                @group(0) @binding(3) var<storage, read_write> v_products: array<u32>;
+                @group(0) @binding(4) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_sums)) { return; }
+                    v_sums[actual_id] = v_a[actual_id] + v_b[actual_id];
+                    v_products[actual_id] = v_a[actual_id] * v_b[actual_id];
+                }
+            "#,
+            )),
+        });
+
+        let a = (0..1000u32).collect::<Vec<u32>>();
+        let b = (0..1000u32).map(|i| i % 7).collect::<Vec<u32>>();
+        let create_in_buf = |data: &[u32]| {
+            device.create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents: &ShaderBytes::serialise_from_slice(data).into_data(),
+                usage: BufferUsages::STORAGE,
+            })
+        };
+        let (a_buf, b_buf) = (create_in_buf(&a), create_in_buf(&b));
+        let mut sums_buf = create_output_buffer(&device, a_buf.size(), BufferUsages::empty());
+        let mut products_buf = create_output_buffer(&device, a_buf.size(), BufferUsages::empty());
+
+        run_shader_multi(RunShaderMultiParams {
+            device: &device,
+            queue: &queue,
+            in_bufs: &[&a_buf, &b_buf],
+            out_bufs: &[&mut sums_buf, &mut products_buf],
+            workgroup_len: 32,
+            n_workgroups: 1000usize.div_ceil(32),
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            read_back_as::<u32>(&device, &queue, &sums_buf).await,
+            Ok(a.iter().zip(&b).map(|(a, b)| a + b).collect::<Vec<u32>>())
+        );
+        assert_eq!(
+            read_back_as::<u32>(&device, &queue, &products_buf).await,
+            Ok(a.iter().zip(&b).map(|(a, b)| a * b).collect::<Vec<u32>>())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_standard_layout_in_custom_dispatch() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id] + goff;
+                }
+            "#,
+            )),
+        });
+
+        let input_data = (0..256u32).collect::<Vec<u32>>();
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &ShaderBytes::serialise_from_slice(&input_data).into_data(),
+            usage: BufferUsages::STORAGE,
+        });
+        let out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+        let meta_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &7u32.to_le_bytes(),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let layout = standard_bind_group_layout(
+            &device,
+            Some(in_buf.size().try_into().unwrap()),
+            None,
+            Some(meta_buf.size().try_into().unwrap()),
+        );
+        let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+            label: None,
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[&layout],
+                push_constant_ranges: &[],
+            })),
+            module: &cs_module,
+            entry_point: "main",
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            cache: None,
+        });
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[&in_buf, &out_buf, &meta_buf]
+                .into_iter()
+                .enumerate()
+                .map(|(binding, buf)| BindGroupEntry {
+                    binding: binding as u32,
+                    resource: buf.as_entire_binding(),
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        {
+            let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            cpass.set_pipeline(&pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            // goff is 7, so the first 7 elements are never written
+            cpass.dispatch_workgroups(8, 1, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+
+        let expected = (0..256u32)
+            .map(|i| if i >= 7 { i + 7 } else { 0 })
+            .collect::<Vec<u32>>();
+        assert_eq!(
+            read_back_as::<u32>(&device, &queue, &out_buf).await,
+            Ok(expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_layout_visible_to_fragment_stage() {
+        let (device, _queue) = crate::test_support::default_device().await;
+
+        let compute_and_fragment = ShaderStages::COMPUTE | ShaderStages::FRAGMENT;
+        let entries = multi_bind_group_layout_entries_with_visibility(
+            &[(None, compute_and_fragment)],
+            &[(None, ShaderStages::COMPUTE)],
+            (None, compute_and_fragment),
+        );
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.binding, entry.visibility))
+                .collect::<Vec<_>>(),
+            [
+                (0, compute_and_fragment),
+                (1, ShaderStages::COMPUTE),
+                (2, compute_and_fragment)
+            ]
+        );
+        assert!(standard_bind_group_layout_entries(None, None, None)
+            .iter()
+            .all(|entry| entry.visibility == DEFAULT_BINDING_VISIBILITY));
+
+        let (_, scope_err) = with_error_scope(&device, wgpu::ErrorFilter::Validation, || {
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: None,
+                entries: &entries,
+            })
+        });
+        assert!(scope_err.await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_params_buffer_indexed_by_workgroup() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+                @group(0) @binding(3) var<storage, read> v_params: array<u32>;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id] * v_params[actual_id / 32u];
+                }
+            "#,
+            )),
+        });
+
+        let input_data = vec![1u32; 32 * 8];
+        let params = (0..8u32).map(|i| i * 10).collect::<Vec<u32>>();
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &ShaderBytes::serialise_from_slice(&input_data).into_data(),
+            usage: BufferUsages::STORAGE,
+        });
+        let params_buf = create_params_buffer(&device, &params);
+        let mut out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+
+        run_shader(RunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 32,
+            n_workgroups: 8,
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: Some(&params_buf),
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            read_back_as::<u32>(&device, &queue, &out_buf).await,
+            Ok(params
+                .iter()
+                .flat_map(|param| [*param; 32])
+                .collect::<Vec<u32>>())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_input_round_trips_through_gpu() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<f32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<f32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id];
+                }
+            "#,
+            )),
+        });
+
+        let input_data = (0..1024).map(|i| i as f32 * -0.5).collect::<Vec<f32>>();
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: &ShaderBytes::serialise_from_slice(&input_data).into_data(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+        });
+        let mut out_buf = create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+
+        // What we uploaded
+        assert_eq!(
+            read_back_as::<f32>(&device, &queue, &in_buf).await,
+            Ok(input_data.clone())
+        );
+
+        // What the shader saw
+        run_shader(RunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 32,
+            n_workgroups: 32,
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            read_back_as::<f32>(&device, &queue, &out_buf).await,
+            Ok(input_data)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_limiter_of_one_serialises() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let limiter = DispatchLimiter::new(1);
+        let permit = limiter.acquire(&device).await;
+        assert!(limiter.try_acquire().is_none());
+
+        // Held until the gpu is done with the submission
+        permit.submit(&queue, []);
+        assert!(limiter.try_acquire().is_none());
+        device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+        let permit = limiter.try_acquire().unwrap();
+
+        // The blocking version waits for the gpu the same way
+        permit.submit(&queue, []);
+        let permit = limiter.acquire_blocking(&device);
+        assert!(limiter.try_acquire().is_none());
+        drop(permit);
+        assert!(limiter.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_computation_equivalence() {
+        let adapter = crate::test_support::default_adapter().await;
         let (device, queue) = adapter
             .request_device(
                 &DeviceDescriptor {
@@ -303,7 +2782,7 @@ mod tests {
             usage: BufferUsages::STORAGE,
         });
 
-        run_shader::<u32>(RunShaderParams {
+        run_shader(RunShaderParams {
             device: &device,
             queue: &queue,
             in_buf: &in_buf,
@@ -312,6 +2791,11 @@ mod tests {
             n_workgroups: usize::div_ceil(input_data.len(), 32),
             program: &cs_module,
             entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
         })
         .await
         .unwrap();
@@ -327,12 +2811,11 @@ mod tests {
         encoder.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
         queue.submit([encoder.finish()].into_iter());
 
-        let transfer_buf_view = transfer_buf.slice(..);
-        wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf_view)
+        let transfer_buf_view = wgpu_map_helper(&device, wgpu::MapMode::Read, &transfer_buf, ..)
             .await
             .unwrap();
         let res: Vec<u32> =
-            ShaderBytes::deserialise_to_slice(&transfer_buf_view.get_mapped_range());
+            ShaderBytes::deserialise_to_iterator(&transfer_buf_view.get_mapped_range()).collect();
         drop(transfer_buf);
 
         // Cleanup resources on the gpu side
@@ -0,0 +1,23 @@
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+// What gets logged unless RUST_LOG says otherwise, wgpu and naga are chatty at info
+const DEFAULT_LOG_FILTER: &str = "info,wgpu_core=warn,wgpu_hal=warn,naga=warn";
+
+// Sets up the log output of the binaries, filtered by RUST_LOG (e.g. RUST_LOG=debug) if set, DEFAULT_LOG_FILTER otherwise
+// Targets are module paths, so e.g. RUST_LOG=info,clustered=warn quiets this library and RUST_LOG=warn,peer=info everything but the peer
+// NOTE: Records from the log crate (e.g. wgpu's) end up in the same output
+pub fn init() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .init();
+}
+
+// Everything that happens to a task (submitted, stolen, run, returned) is logged inside its span,
+// which prefixes the lines with task{id=<uuid>}, so grepping every node's output for the uuid gives its whole lifecycle
+pub fn task_span(task_id: Uuid) -> tracing::Span {
+    tracing::info_span!("task", id = %task_id)
+}
@@ -1,47 +1,336 @@
-use std::{future::Future, io::ErrorKind, net::SocketAddr};
+use std::{
+    future::Future,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
 };
 
-pub async fn read_buf(connection: &mut tokio::net::TcpStream) -> std::io::Result<Vec<u8>> {
+use crate::config::SocketOptions;
+
+// Cap for buffers holding program data or results
+pub const DEFAULT_MAX_BUF_LEN: usize = 1024 * 1024 * 1024;
+// Cap for everything else (magic sequences, peer lists, error messages, ...)
+pub const MAX_CONTROL_MESSAGE_LEN: usize = 1024 * 1024;
+
+// How often the tracker checks that each of its peers is still reachable at its p2p address
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// A peer that doesn't answer a heartbeat within this long is dropped from the tracker's peer list
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Buffers are sent as their total length (u64) followed by chunks of at most this many bytes,
+// each prefixed with its own length (u32), so nothing has to be read in one go and progress can be reported
+pub const BUF_CHUNK_NBYTES: usize = 4 * 1024 * 1024;
+
+// NOTE: These work on anything tokio can read from/write to, not just TcpStreams, e.g. tokio::io::duplex for tests
+// Fails with ErrorKind::InvalidData, before allocating anything, if the other end announces more than max_len bytes
+pub async fn read_buf_limited(
+    connection: &mut (impl AsyncRead + Unpin),
+    max_len: usize,
+) -> std::io::Result<Vec<u8>> {
+    read_buf_streaming(connection, max_len, |_, _| {}).await
+}
+
+// Like read_buf_limited, on_progress is called with (bytes received so far, total bytes) after every chunk
+pub async fn read_buf_streaming(
+    connection: &mut (impl AsyncRead + Unpin),
+    max_len: usize,
+    mut on_progress: impl FnMut(usize, usize),
+) -> std::io::Result<Vec<u8>> {
     let nbytes = connection.read_u64().await?;
-    let mut buf = vec![0u8; nbytes.try_into().unwrap()];
-    connection.read_exact(&mut buf).await?;
+    let nbytes = usize::try_from(nbytes)
+        .ok()
+        .filter(|&nbytes| nbytes <= max_len)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Announced buffer length of {nbytes} bytes is over the limit of {max_len} bytes"),
+            )
+        })?;
+    // Grown a chunk at a time as the data arrives, so announcing a huge buffer and then sending nothing doesn't allocate it
+    let mut buf = Vec::new();
+    while buf.len() < nbytes {
+        let offset = buf.len();
+        let chunk_nbytes = usize::try_from(connection.read_u32().await?).unwrap();
+        // An empty chunk would never finish the buffer, and write_buf never sends bigger ones
+        if chunk_nbytes == 0 || chunk_nbytes > BUF_CHUNK_NBYTES || chunk_nbytes > nbytes - offset {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Chunk of {chunk_nbytes} bytes at offset {offset} doesn't fit in a buffer of {nbytes} bytes"
+                ),
+            ));
+        }
+        buf.resize(offset + chunk_nbytes, 0);
+        connection.read_exact(&mut buf[offset..]).await?;
+        on_progress(buf.len(), nbytes);
+    }
     Ok(buf)
 }
 
-pub async fn write_buf(connection: &mut tokio::net::TcpStream, buf: &[u8]) -> std::io::Result<()> {
+pub async fn write_buf(
+    connection: &mut (impl AsyncWrite + Unpin),
+    buf: &[u8],
+) -> std::io::Result<()> {
+    write_buf_streaming(connection, buf, |_, _| {}).await
+}
+
+// Like write_buf, on_progress is called with (bytes sent so far, total bytes) after every chunk
+pub async fn write_buf_streaming(
+    connection: &mut (impl AsyncWrite + Unpin),
+    buf: &[u8],
+    mut on_progress: impl FnMut(usize, usize),
+) -> std::io::Result<()> {
     connection.write_u64(buf.len().try_into().unwrap()).await?;
-    connection.write_all(buf).await?;
+    let mut nbytes_sent = 0;
+    for chunk in buf.chunks(BUF_CHUNK_NBYTES) {
+        connection
+            .write_u32(chunk.len().try_into().unwrap())
+            .await?;
+        connection.write_all(chunk).await?;
+        nbytes_sent += chunk.len();
+        on_progress(nbytes_sent, buf.len());
+    }
     Ok(())
 }
 
-pub async fn listen<F, Fut, ExtraData>(listen_addr: SocketAddr, handler: F, extra: ExtraData)
-where
+// Deflate compressed alternative to write_buf, for big buffers that are likely to compress well (e.g. structured f32 data)
+// Sent as the compressed length (u64) and the uncompressed length (u64), so the reader can preallocate, then the compressed bytes
+// NOTE: Compressing takes a copy of buf, as it happens on tokio's blocking pool so it doesn't stall the runtime
+#[cfg(feature = "compressed-transfers")]
+pub async fn write_buf_compressed(
+    connection: &mut (impl AsyncWrite + Unpin),
+    buf: &[u8],
+) -> std::io::Result<()> {
+    let uncompressed = buf.to_vec();
+    let compressed =
+        tokio::task::spawn_blocking(move || crate::compression::compress(&uncompressed))
+            .await
+            .unwrap()
+            .map_err(|err| {
+                std::io::Error::new(err.kind(), format!("{err}\nWhile compressing buffer"))
+            })?;
+    connection
+        .write_u64(compressed.len().try_into().unwrap())
+        .await?;
+    connection.write_u64(buf.len().try_into().unwrap()).await?;
+    for chunk in compressed.chunks(BUF_CHUNK_NBYTES) {
+        connection.write_all(chunk).await?;
+    }
+    Ok(())
+}
+
+// Receives a buffer sent with write_buf_compressed, max_len caps the uncompressed length (like read_buf_limited's)
+#[cfg(feature = "compressed-transfers")]
+pub async fn read_buf_compressed(
+    connection: &mut (impl AsyncRead + Unpin),
+    max_len: usize,
+) -> std::io::Result<Vec<u8>> {
+    let compressed_nbytes = connection.read_u64().await?;
+    let nbytes = connection.read_u64().await?;
+    let nbytes = usize::try_from(nbytes)
+        .ok()
+        .filter(|&nbytes| nbytes <= max_len)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Announced buffer length of {nbytes} bytes is over the limit of {max_len} bytes"),
+            )
+        })?;
+    // Deflate can make incompressible data a little bigger, but never by anywhere near this much
+    let max_compressed_nbytes = nbytes.saturating_add(nbytes / 16).saturating_add(1024);
+    let compressed_nbytes = usize::try_from(compressed_nbytes)
+        .ok()
+        .filter(|&compressed_nbytes| compressed_nbytes <= max_compressed_nbytes)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Announced compressed length of {compressed_nbytes} bytes is too big for a buffer of {nbytes} bytes"),
+            )
+        })?;
+    let mut compressed = vec![0u8; compressed_nbytes];
+    connection.read_exact(&mut compressed).await?;
+    tokio::task::spawn_blocking(move || crate::compression::decompress_exact(&compressed, nbytes))
+        .await
+        .unwrap()
+        .map_err(|err| {
+            std::io::Error::new(err.kind(), format!("{err}\nWhile decompressing buffer"))
+        })
+}
+
+// Tagged with the family, so both fit: 4 then the 4 octets of an ipv4 address, or 6 then the 16 octets of an ipv6 one
+pub async fn write_ip_addr(
+    connection: &mut (impl AsyncWrite + Unpin),
+    addr: IpAddr,
+) -> std::io::Result<()> {
+    match addr {
+        IpAddr::V4(addr) => {
+            connection.write_u8(4).await?;
+            connection.write_all(&addr.octets()).await
+        }
+        IpAddr::V6(addr) => {
+            connection.write_u8(6).await?;
+            connection.write_all(&addr.octets()).await
+        }
+    }
+}
+
+pub async fn read_ip_addr(connection: &mut (impl AsyncRead + Unpin)) -> std::io::Result<IpAddr> {
+    match connection.read_u8().await? {
+        4 => {
+            let mut octets = [0u8; 4];
+            connection.read_exact(&mut octets).await?;
+            Ok(IpAddr::from(octets))
+        }
+        6 => {
+            let mut octets = [0u8; 16];
+            connection.read_exact(&mut octets).await?;
+            Ok(IpAddr::from(octets))
+        }
+        family => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown address family {family}"),
+        )),
+    }
+}
+
+// Schema version of the json control messages exchanged between peers and the tracker (peer lists, task headers, ...),
+// bump it whenever one of those structs changes in a way older nodes can't read
+// NOTE: Separate from the handshake, that only checks both ends speak the same protocol, not that every message body agrees
+pub const CONTROL_SCHEMA_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum VersionedMessageError {
+    TooShort,
+    UnsupportedSchemaVersion(u16),
+    Json(serde_json::Error),
+}
+
+impl std::fmt::Display for VersionedMessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionedMessageError::TooShort => {
+                write!(f, "Message is too short to hold its schema version")
+            }
+            VersionedMessageError::UnsupportedSchemaVersion(version) => write!(
+                f,
+                "Unsupported schema version {version}, this node only understands version {CONTROL_SCHEMA_VERSION}"
+            ),
+            VersionedMessageError::Json(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for VersionedMessageError {}
+
+// The envelope is: schema version (u16 LE), payload json
+pub fn to_versioned_json<T: Serialize + ?Sized>(payload: &T) -> serde_json::Result<Vec<u8>> {
+    let mut res = CONTROL_SCHEMA_VERSION.to_le_bytes().to_vec();
+    serde_json::to_writer(&mut res, payload)?;
+    Ok(res)
+}
+
+pub fn from_versioned_json<T: DeserializeOwned>(data: &[u8]) -> Result<T, VersionedMessageError> {
+    let (raw_version, payload) = data
+        .split_first_chunk::<{ core::mem::size_of::<u16>() }>()
+        .ok_or(VersionedMessageError::TooShort)?;
+    let version = u16::from_le_bytes(*raw_version);
+    if version != CONTROL_SCHEMA_VERSION {
+        return Err(VersionedMessageError::UnsupportedSchemaVersion(version));
+    }
+    serde_json::from_slice(payload).map_err(VersionedMessageError::Json)
+}
+
+// Everything except nodelay has to be set before connecting/listening to apply to the whole connection
+fn socket_with_options(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(nbytes) = options.send_buffer_nbytes {
+        socket.set_send_buffer_size(nbytes)?;
+    }
+    if let Some(nbytes) = options.recv_buffer_nbytes {
+        socket.set_recv_buffer_size(nbytes)?;
+    }
+    socket.set_keepalive(options.keepalive)?;
+    socket.set_nodelay(options.nodelay)?;
+    Ok(socket)
+}
+
+// Same as TcpStream::connect, but with the options applied to the socket
+pub async fn connect(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<TcpStream> {
+    socket_with_options(addr, options)?.connect(addr).await
+}
+
+fn bind_with_options(addr: SocketAddr, options: &SocketOptions) -> std::io::Result<TcpListener> {
+    let socket = socket_with_options(addr, options)?;
+    // Same as TcpListener::bind, so restarting doesn't have to wait for the old connections to time out
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// Completes on ctrl-c, or on SIGTERM where there is one (what service managers and container runtimes send)
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Couldn't listen for SIGTERM!");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// NOTE: Buffer sizes and keepalive are inherited by the accepted connections from the listening socket, nodelay is set on each of them
+// Stops accepting (and returns) once shutdown completes, pass std::future::pending() to listen forever,
+// connections that were already accepted are left to their handlers
+pub async fn listen<F, Fut, ExtraData>(
+    listen_addr: SocketAddr,
+    socket_options: SocketOptions,
+    handler: F,
+    extra: ExtraData,
+    shutdown: impl Future<Output = ()>,
+) where
     F: Fn(TcpStream, ExtraData) -> Fut,
     ExtraData: Clone,
     Fut: Future<Output = ()> + Send + 'static,
 {
-    let listener = match TcpListener::bind(listen_addr).await {
+    let listener = match bind_with_options(listen_addr, &socket_options) {
         Ok(val) => val,
         Err(err) => {
-            println!(
-                "Error: Unable to bind to address {:?} for listening, error was: {:?}!",
-                listen_addr, err
-            );
+            tracing::error!("{err}\nWhile binding to address {listen_addr:?} for listening");
             return;
         }
     };
 
+    tokio::pin!(shutdown);
     loop {
-        match listener.accept().await {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
             Ok((connection, _)) => {
+                if let Err(err) = connection.set_nodelay(socket_options.nodelay) {
+                    tracing::warn!("{err}\nWhile setting nodelay on a connection");
+                }
                 tokio::spawn(handler(connection, extra.clone()));
             }
             Err(err) => {
-                println!("Notice: Unable to accept a connection, error was: {err:?}!");
+                tracing::warn!("{err}\nWhile accepting a connection");
             }
         }
     }
@@ -57,3 +346,293 @@ pub fn was_connection_severed(err_kind: ErrorKind) -> bool {
             | ErrorKind::UnexpectedEof
     )
 }
+
+// Takes the same time no matter where (or whether) the inputs differ, only their lengths matter,
+// so once magic sequences double as auth tokens, timing a rejection doesn't tell how much of a guess was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+// For error messages, magic sequences from misbehaving peers aren't necessarily utf-8 (or short)
+pub fn describe_bad_magic(magic: &[u8]) -> String {
+    const MAX_SHOWN_NBYTES: usize = 64;
+    let shown = String::from_utf8_lossy(&magic[..magic.len().min(MAX_SHOWN_NBYTES)]).into_owned();
+    if magic.len() > MAX_SHOWN_NBYTES {
+        format!("{shown:?} ({} bytes in total)", magic.len())
+    } else {
+        format!("{shown:?}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ip_addr_round_trip() {
+        let addrs = [
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 42)),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ];
+        let (mut client, mut server) = tokio::io::duplex(256);
+        for addr in addrs {
+            write_ip_addr(&mut client, addr).await.unwrap();
+        }
+        for addr in addrs {
+            assert_eq!(read_ip_addr(&mut server).await.unwrap(), addr);
+        }
+
+        client.write_all(&[5, 0, 0, 0, 0]).await.unwrap();
+        assert_eq!(
+            read_ip_addr(&mut server).await.unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_rejects_future_schema_version() {
+        let peer_list = vec!["127.0.0.1:4000".to_owned(), "127.0.0.1:4001".to_owned()];
+        let mut message = to_versioned_json(&peer_list).unwrap();
+        assert_eq!(
+            from_versioned_json::<Vec<String>>(&message).unwrap(),
+            peer_list
+        );
+
+        message[..2].copy_from_slice(&(CONTROL_SCHEMA_VERSION + 1).to_le_bytes());
+        let err = from_versioned_json::<Vec<String>>(&message).unwrap_err();
+        assert!(matches!(
+            err,
+            VersionedMessageError::UnsupportedSchemaVersion(version) if version == CONTROL_SCHEMA_VERSION + 1
+        ));
+        assert!(err.to_string().starts_with("Unsupported schema version"));
+    }
+
+    #[tokio::test]
+    async fn test_read_buf_limited_rejects_huge_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = tokio::spawn(async move {
+            let mut connection = TcpStream::connect(addr).await.unwrap();
+            // 1TB, and no data after it
+            connection.write_u64(1 << 40).await.unwrap();
+            connection
+        });
+
+        let (mut connection, _) = listener.accept().await.unwrap();
+        let err = read_buf_limited(&mut connection, DEFAULT_MAX_BUF_LEN)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        drop(sender.await.unwrap());
+
+        let (mut client_end, mut server_end) = tokio::io::duplex(64);
+        write_buf(&mut client_end, b"Clustered tracker!")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_buf_limited(&mut server_end, 18).await.unwrap(),
+            b"Clustered tracker!"
+        );
+    }
+
+    #[cfg(feature = "compressed-transfers")]
+    #[tokio::test]
+    async fn test_compressed_buf_round_trip() {
+        let sparse = (0..1024 * 1024u32)
+            .flat_map(|i| if i % 100 == 0 { i } else { 0 }.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let (mut client_end, mut server_end) = tokio::io::duplex(1024 * 1024);
+        let to_send = sparse.clone();
+        let sender = tokio::spawn(async move {
+            write_buf_compressed(&mut client_end, &to_send)
+                .await
+                .unwrap();
+            write_buf_compressed(&mut client_end, &to_send)
+                .await
+                .unwrap();
+            client_end
+        });
+        let compressed_nbytes = server_end.read_u64().await.unwrap();
+        assert!(compressed_nbytes < sparse.len() as u64 / 10);
+        assert_eq!(server_end.read_u64().await.unwrap(), sparse.len() as u64);
+        let mut compressed = vec![0u8; compressed_nbytes.try_into().unwrap()];
+        server_end.read_exact(&mut compressed).await.unwrap();
+        assert_eq!(
+            crate::compression::decompress_exact(&compressed, sparse.len()).unwrap(),
+            sparse
+        );
+        assert_eq!(
+            read_buf_compressed(&mut server_end, sparse.len())
+                .await
+                .unwrap(),
+            sparse
+        );
+        drop(sender.await.unwrap());
+
+        // Lying about the uncompressed length is caught, whichever way it's off
+        assert_eq!(
+            crate::compression::decompress_exact(&compressed, sparse.len() - 1)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            crate::compression::decompress_exact(&compressed, sparse.len() + 1)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+
+        let (mut client_end, mut server_end) = tokio::io::duplex(64);
+        client_end.write_u64(16).await.unwrap();
+        client_end.write_u64(1 << 40).await.unwrap();
+        assert_eq!(
+            read_buf_compressed(&mut server_end, DEFAULT_MAX_BUF_LEN)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listen_stops_on_shutdown() {
+        // Grab a free port, listen rebinds it with reuseaddr
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (accepted_sender, mut accepted_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let listening = tokio::spawn(listen(
+            addr,
+            SocketOptions::default(),
+            |_connection, accepted_sender: tokio::sync::mpsc::UnboundedSender<()>| async move {
+                accepted_sender.send(()).unwrap();
+            },
+            accepted_sender,
+            async {
+                let _ = shutdown_receiver.await;
+            },
+        ));
+
+        // Still accepting before the shutdown
+        let mut connection = None;
+        for _ in 0..50 {
+            if let Ok(val) = TcpStream::connect(addr).await {
+                connection = Some(val);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(connection.is_some());
+        tokio::time::timeout(Duration::from_secs(5), accepted_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        shutdown_sender.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), listening)
+            .await
+            .unwrap()
+            .unwrap();
+        // The listening socket is gone with it
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_applies_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connection = connect(addr, &SocketOptions::default()).await.unwrap();
+        assert!(connection.nodelay().unwrap());
+        let connection = connect(
+            addr,
+            &SocketOptions {
+                nodelay: false,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(!connection.nodelay().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_streams_buf_in_chunks() {
+        let data = (0..2 * BUF_CHUNK_NBYTES + 5)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        let (mut client_end, mut server_end) = tokio::io::duplex(64 * 1024);
+        let to_send = data.clone();
+        let sender = tokio::spawn(async move {
+            let mut sent_progress = Vec::new();
+            write_buf_streaming(&mut client_end, &to_send, |sent, total| {
+                sent_progress.push((sent, total))
+            })
+            .await
+            .unwrap();
+            sent_progress
+        });
+
+        let mut received_progress = Vec::new();
+        let received = read_buf_streaming(&mut server_end, data.len(), |received, total| {
+            received_progress.push((received, total))
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, data);
+        let expected_progress = vec![
+            (BUF_CHUNK_NBYTES, data.len()),
+            (2 * BUF_CHUNK_NBYTES, data.len()),
+            (data.len(), data.len()),
+        ];
+        assert_eq!(received_progress, expected_progress);
+        assert_eq!(sender.await.unwrap(), expected_progress);
+
+        // A chunk running past the announced length
+        let (mut client_end, mut server_end) = tokio::io::duplex(64);
+        client_end.write_u64(4).await.unwrap();
+        client_end.write_u32(8).await.unwrap();
+        let err = read_buf_limited(&mut server_end, DEFAULT_MAX_BUF_LEN)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        // A chunk bigger than write_buf ever sends, which would otherwise be allocated before any of it arrived
+        let (mut client_end, mut server_end) = tokio::io::duplex(64);
+        client_end
+            .write_u64(DEFAULT_MAX_BUF_LEN as u64)
+            .await
+            .unwrap();
+        client_end.write_u32(u32::MAX).await.unwrap();
+        let err = read_buf_limited(&mut server_end, DEFAULT_MAX_BUF_LEN)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(
+            b"Clustered tracker!",
+            b"Clustered tracker!"
+        ));
+        assert!(!constant_time_eq(
+            b"Clustered tracker!",
+            b"Clustered tracker?"
+        ));
+        assert!(!constant_time_eq(b"Clustered tracker!", b"Clustered"));
+        assert!(!constant_time_eq(b"Clustered tracker!", &[0xff; 18]));
+        assert_eq!(describe_bad_magic(&[0xff, b'a']), "\"\u{fffd}a\"");
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+// What every connection starts with (as a buf), so talking to the wrong kind of listener is an error rather than garbage
+// NOTE: The tracker's one changed when it started sending peers their ip tagged with its family (ipv6 support),
+//       so older peers fail the handshake instead of misreading their ip
+// The peer2peer one changed when bufs started being sent in chunks (see networking::BUF_CHUNK_NBYTES),
+// so older peers fail the handshake instead of misreading every buf after it
+pub const MAGIC_TRACKER_SEQUENCE: &str = "Clustered tracker v2!";
+pub const MAGIC_PEER2PEER_SEQUENCE: &str = "Clustered peer2peer v2, yay!";
+
+// What a peer asks of the tracker over its tracker connection, every message starts with the id (u8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackerCommand {
+    // Answered with the other peers (minus blacklisted ones), as versioned json
+    ListPeers,
+    // Followed by the misbehaving peer, as versioned json
+    ReportBadPeer,
+    // Followed by the total number of tasks the peer has completed (u64)
+    ReportCompletedTasks,
+    // Answered with the ClusterStatus, as versioned json
+    ClusterStatus,
+    // Followed by the features of the peer's gpu device, as wgpu::Features bits (u64)
+    AdvertiseFeatures,
+    // Answered with the features of every (non blacklisted) peer, the asking one included, as versioned json of their bits,
+    // None for peers that haven't advertised theirs yet
+    ListPeerFeatures,
+}
+
+impl TrackerCommand {
+    pub const ALL: [Self; 6] = [
+        Self::ListPeers,
+        Self::ReportBadPeer,
+        Self::ReportCompletedTasks,
+        Self::ClusterStatus,
+        Self::AdvertiseFeatures,
+        Self::ListPeerFeatures,
+    ];
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::ListPeers => 1,
+            Self::ReportBadPeer => 2,
+            Self::ReportCompletedTasks => 3,
+            Self::ClusterStatus => 4,
+            Self::AdvertiseFeatures => 5,
+            Self::ListPeerFeatures => 6,
+        }
+    }
+
+    // None for ids this version doesn't know about
+    pub fn from_u8(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|command| command.to_u8() == id)
+    }
+
+    pub async fn write(self, connection: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        connection.write_u8(self.to_u8()).await
+    }
+}
+
+// What goes over a peer2peer connection, every message starts with the id (u8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerMessage {
+    // Answered with the number of tasks given away (u64), then every task as a buf
+    StealTask,
+    // Followed by the number of results (u64), then every result's task uuid (u128), status and data
+    ReturnResults,
+    // Answered with a summary of every queued task, as versioned json
+    SummariseQueue,
+    // Followed by the uuid (u128) of the task that started running
+    TaskStarted,
+    // Followed by 1 to pause or 0 to resume
+    SetPaused,
+    // Answered with the same id, see the tracker's heartbeat
+    Ping,
+    // Followed by the uuid (u128) of the task its submitter gave up on
+    CancelTask,
+}
+
+impl PeerMessage {
+    pub const ALL: [Self; 7] = [
+        Self::StealTask,
+        Self::ReturnResults,
+        Self::SummariseQueue,
+        Self::TaskStarted,
+        Self::SetPaused,
+        Self::Ping,
+        Self::CancelTask,
+    ];
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::StealTask => 1,
+            Self::ReturnResults => 2,
+            Self::SummariseQueue => 3,
+            Self::TaskStarted => 4,
+            Self::SetPaused => 5,
+            Self::Ping => 6,
+            Self::CancelTask => 7,
+        }
+    }
+
+    // None for ids this version doesn't know about
+    pub fn from_u8(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|message| message.to_u8() == id)
+    }
+
+    pub async fn write(self, connection: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        connection.write_u8(self.to_u8()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_message_id_round_trip() {
+        for command in TrackerCommand::ALL {
+            assert_eq!(TrackerCommand::from_u8(command.to_u8()), Some(command));
+        }
+        for message in PeerMessage::ALL {
+            assert_eq!(PeerMessage::from_u8(message.to_u8()), Some(message));
+        }
+        // Two messages sharing an id would make one of them unreachable
+        assert_eq!(
+            TrackerCommand::ALL
+                .map(TrackerCommand::to_u8)
+                .into_iter()
+                .collect::<HashSet<u8>>()
+                .len(),
+            TrackerCommand::ALL.len()
+        );
+        assert_eq!(
+            PeerMessage::ALL
+                .map(PeerMessage::to_u8)
+                .into_iter()
+                .collect::<HashSet<u8>>()
+                .len(),
+            PeerMessage::ALL.len()
+        );
+        assert_eq!(TrackerCommand::from_u8(0), None);
+        assert_eq!(PeerMessage::from_u8(u8::MAX), None);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        PeerMessage::CancelTask.write(&mut client).await.unwrap();
+        TrackerCommand::ReportBadPeer
+            .write(&mut client)
+            .await
+            .unwrap();
+        assert_eq!(
+            PeerMessage::from_u8(server.read_u8().await.unwrap()),
+            Some(PeerMessage::CancelTask)
+        );
+        assert_eq!(
+            TrackerCommand::from_u8(server.read_u8().await.unwrap()),
+            Some(TrackerCommand::ReportBadPeer)
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use naga::{
+    valid::{Capabilities, ValidationFlags, Validator},
+    AddressSpace, ArraySize, ImageClass, StorageAccess, TypeInner,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingAccess {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl From<StorageAccess> for BindingAccess {
+    fn from(access: StorageAccess) -> Self {
+        match (
+            access.contains(StorageAccess::LOAD),
+            access.contains(StorageAccess::STORE),
+        ) {
+            (true, true) => BindingAccess::ReadWrite,
+            (false, true) => BindingAccess::WriteOnly,
+            _ => BindingAccess::ReadOnly,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingKind {
+    Storage(BindingAccess),
+    Uniform,
+    Texture,
+    StorageTexture(BindingAccess),
+    Sampler,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindingInfo {
+    pub group: u32,
+    pub binding: u32,
+    pub name: Option<String>,
+    pub kind: BindingKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReflectError {
+    InvalidWgsl(String),
+    EntryPointNotFound(String),
+}
+
+impl std::fmt::Display for ReflectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReflectError::InvalidWgsl(err) => write!(f, "Shader isn't valid WGSL:\n{err}"),
+            ReflectError::EntryPointNotFound(entry_point) => {
+                write!(f, "Shader has no entry point called '{entry_point}'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReflectError {}
+
+// Lists the bindings the entry point actually uses, sorted by (group, binding)
+// NOTE: Bindings that are declared but never touched by the entry point are left out, just like wgpu's derived layouts do
+pub fn reflect_bindings(
+    wgsl_source: &str,
+    entry_point: &str,
+) -> Result<Vec<BindingInfo>, ReflectError> {
+    let module = naga::front::wgsl::parse_str(wgsl_source)
+        .map_err(|err| ReflectError::InvalidWgsl(err.emit_to_string(wgsl_source)))?;
+    let module_info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|err| ReflectError::InvalidWgsl(err.emit_to_string(wgsl_source)))?;
+    let entry_point_id = module
+        .entry_points
+        .iter()
+        .position(|ep| ep.name == entry_point)
+        .ok_or_else(|| ReflectError::EntryPointNotFound(entry_point.to_owned()))?;
+    let entry_point_info = module_info.get_entry_point(entry_point_id);
+
+    let mut bindings = module
+        .global_variables
+        .iter()
+        .filter(|(handle, _)| !entry_point_info[*handle].is_empty())
+        .filter_map(|(_, var)| {
+            let resource_binding = var.binding.as_ref()?;
+            let kind = match (var.space, &module.types[var.ty].inner) {
+                (AddressSpace::Storage { access }, _) => BindingKind::Storage(access.into()),
+                (AddressSpace::Uniform, _) => BindingKind::Uniform,
+                (
+                    AddressSpace::Handle,
+                    TypeInner::Image {
+                        class: ImageClass::Storage { access, .. },
+                        ..
+                    },
+                ) => BindingKind::StorageTexture((*access).into()),
+                (AddressSpace::Handle, TypeInner::Image { .. }) => BindingKind::Texture,
+                (AddressSpace::Handle, TypeInner::Sampler { .. }) => BindingKind::Sampler,
+                _ => return None,
+            };
+            Some(BindingInfo {
+                group: resource_binding.group,
+                binding: resource_binding.binding,
+                name: var.name.clone(),
+                kind,
+            })
+        })
+        .collect::<Vec<_>>();
+    bindings.sort_by_key(|info| (info.group, info.binding));
+    Ok(bindings)
+}
+
+// Stride in bytes of the runtime sized array run_shader's output buffer is bound to (group 0, binding 1),
+// None if the shader doesn't declare the output as one (so the element size can't be inferred)
+pub fn output_elem_stride(wgsl_source: &str) -> Result<Option<u32>, ReflectError> {
+    let module = naga::front::wgsl::parse_str(wgsl_source)
+        .map_err(|err| ReflectError::InvalidWgsl(err.emit_to_string(wgsl_source)))?;
+    let stride = module.global_variables.iter().find_map(|(_, var)| {
+        let resource_binding = var.binding.as_ref()?;
+        if (resource_binding.group, resource_binding.binding) != (0, 1) {
+            return None;
+        }
+        match module.types[var.ty].inner {
+            TypeInner::Array {
+                size: ArraySize::Dynamic,
+                stride,
+                ..
+            } => Some(stride),
+            _ => None,
+        }
+    });
+    Ok(stride)
+}
+
+// The naga capabilities a compute shader can need, with the wgpu feature that provides each
+const CAPABILITY_FEATURES: &[(Capabilities, wgpu::Features)] = &[
+    (Capabilities::PUSH_CONSTANT, wgpu::Features::PUSH_CONSTANTS),
+    (Capabilities::FLOAT64, wgpu::Features::SHADER_F64),
+    (Capabilities::SHADER_INT64, wgpu::Features::SHADER_INT64),
+    (
+        Capabilities::SHADER_INT64_ATOMIC_MIN_MAX,
+        wgpu::Features::SHADER_INT64_ATOMIC_MIN_MAX,
+    ),
+    (
+        Capabilities::SHADER_INT64_ATOMIC_ALL_OPS,
+        wgpu::Features::SHADER_INT64_ATOMIC_ALL_OPS,
+    ),
+    (Capabilities::SUBGROUP, wgpu::Features::SUBGROUP),
+    (
+        Capabilities::SUBGROUP_BARRIER,
+        wgpu::Features::SUBGROUP_BARRIER,
+    ),
+    (
+        Capabilities::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+        wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+    ),
+];
+
+// The wgpu features a device needs to run the shader, found by validating it with each capability taken away in turn
+// NOTE: Binding arrays aren't a naga capability, so those come from the bindings' types instead
+pub fn required_features(wgsl_source: &str) -> Result<wgpu::Features, ReflectError> {
+    let module = naga::front::wgsl::parse_str(wgsl_source)
+        .map_err(|err| ReflectError::InvalidWgsl(err.emit_to_string(wgsl_source)))?;
+    Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|err| ReflectError::InvalidWgsl(err.emit_to_string(wgsl_source)))?;
+
+    let mut features = CAPABILITY_FEATURES
+        .iter()
+        .filter(|(capability, _)| {
+            Validator::new(ValidationFlags::all(), Capabilities::all() - *capability)
+                .validate(&module)
+                .is_err()
+        })
+        .fold(wgpu::Features::empty(), |acc, (_, feature)| acc | *feature);
+    for (_, var) in module.global_variables.iter() {
+        let TypeInner::BindingArray { base, .. } = module.types[var.ty].inner else {
+            continue;
+        };
+        features |= match (var.space, &module.types[base].inner) {
+            (AddressSpace::Handle, TypeInner::Image { .. } | TypeInner::Sampler { .. }) => {
+                wgpu::Features::TEXTURE_BINDING_ARRAY
+            }
+            (AddressSpace::Storage { .. }, _) => {
+                wgpu::Features::BUFFER_BINDING_ARRAY
+                    | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY
+            }
+            _ => wgpu::Features::BUFFER_BINDING_ARRAY,
+        };
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reflect_mergesort_bindings() {
+        let bindings = reflect_bindings(include_str!("../shader-mergesort.wgsl"), "main").unwrap();
+        assert_eq!(
+            bindings
+                .iter()
+                .map(|info| (info.group, info.binding, info.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 0, BindingKind::Storage(BindingAccess::ReadOnly)),
+                (0, 1, BindingKind::Storage(BindingAccess::ReadWrite)),
+                (0, 2, BindingKind::Uniform),
+            ]
+        );
+        assert_eq!(bindings[0].name.as_deref(), Some("in_data"));
+    }
+
+    #[test]
+    fn test_required_features() {
+        assert_eq!(
+            required_features(include_str!("../shader-mergesort.wgsl")),
+            Ok(wgpu::Features::empty())
+        );
+        assert_eq!(
+            required_features(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: binding_array<array<u32>, 2>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<f64>;
+
+                @compute @workgroup_size(1)
+                fn main() {
+                    v_out_data[0] = f64(v_in_data[0][0]);
+                }
+                "#
+            ),
+            Ok(wgpu::Features::SHADER_F64
+                | wgpu::Features::BUFFER_BINDING_ARRAY
+                | wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY)
+        );
+    }
+}
@@ -1,74 +1,1800 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, io, path::Path};
 
-use serde::{Deserialize, Serialize};
+use crate::compression::ResultCompression;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_with::{base64::Base64, serde_as};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
-    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, ShaderModuleDescriptor,
+    BufferDescriptor, BufferUsages, DeviceDescriptor, InstanceDescriptor, RequestAdapterOptions,
+    ShaderModuleDescriptor,
 };
 
-#[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Version of the program format, written into every json capsule and every ProgramMetadata (so binary and streamed programs too),
+// programs from before it was versioned are version 1. Newer versions than this are rejected with an error saying so,
+// as peers of a cluster may be updated at different times.
+// 1: a single input and output, 2: adds extra_in_data and extra_out_data_nbytes,
+// 3: adds ProgramMetadata::in_data_compressed,
+// 4: telefork servers send how long the program took after its result, see telefork::ProgramResult
+pub const PROGRAM_FORMAT_VERSION: u32 = 4;
+// Capsules, binary programs and plain metadata have nothing newer than version 2, so they're still written as that
+// for older readers. Only write_streamed (whose receiver is a telefork server) sends the newest version,
+// so older servers reject the program instead of misreading compressed in_data or answering without the timing
+const CAPSULE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(into = "VersionedProgram", try_from = "VersionedProgram")]
 pub struct SerialisableProgram {
-    #[serde_as(as = "Base64")]
     pub in_data: Vec<u8>,
     pub out_data_nbytes: usize,
     pub program: String,
     pub entry_point: String,
     pub n_workgroups: usize,
     pub workgroup_size: usize,
+    // Inputs and outputs after the first, for kernels with several of each (e.g. a reduction emitting a result and a count),
+    // bound the way run_shader_multi binds them, see run_multi
+    // NOTE: Only run_multi and the capsule formats know about these, everything else runs the first input and output only
+    pub extra_in_data: Vec<Vec<u8>>,
+    pub extra_out_data_nbytes: Vec<usize>,
 }
 
-impl SerialisableProgram {
-    pub async fn run(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Vec<u8>> {
-        let cm = device.create_shader_module(ShaderModuleDescriptor {
+// What SerialisableProgram is actually (de)serialised as, the same fields plus the format version
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct VersionedProgram {
+    #[serde(default = "first_program_format_version")]
+    format_version: u32,
+    #[serde_as(as = "Base64")]
+    in_data: Vec<u8>,
+    out_data_nbytes: usize,
+    program: String,
+    entry_point: String,
+    n_workgroups: usize,
+    workgroup_size: usize,
+    #[serde_as(as = "Vec<Base64>")]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_in_data: Vec<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    extra_out_data_nbytes: Vec<usize>,
+}
+
+fn first_program_format_version() -> u32 {
+    1
+}
+
+fn unsupported_format_version(format_version: u32) -> String {
+    format!(
+        "Program has format version {format_version}, but only up to {PROGRAM_FORMAT_VERSION} is supported, it probably comes from a newer version of clustered"
+    )
+}
+
+// For json capsules and serialised ProgramMetadata, reports a program of a newer format as such,
+// instead of as whatever serde makes of the fields that changed
+// The json is only parsed once, the version is looked up in the parsed value before it's deserialised any further
+fn from_versioned_json<T: DeserializeOwned>(raw_json: &[u8]) -> io::Result<T> {
+    let invalid_data = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+    let json: serde_json::Value =
+        serde_json::from_slice(raw_json).map_err(|err| invalid_data(err.to_string()))?;
+    if let Some(format_version) = json
+        .get("format_version")
+        .and_then(serde_json::Value::as_u64)
+    {
+        if format_version > u64::from(PROGRAM_FORMAT_VERSION) {
+            return Err(invalid_data(unsupported_format_version(
+                u32::try_from(format_version).unwrap_or(u32::MAX),
+            )));
+        }
+    }
+    serde_json::from_value(json).map_err(|err| invalid_data(err.to_string()))
+}
+
+impl From<SerialisableProgram> for VersionedProgram {
+    fn from(program: SerialisableProgram) -> Self {
+        Self {
+            format_version: CAPSULE_FORMAT_VERSION,
+            in_data: program.in_data,
+            out_data_nbytes: program.out_data_nbytes,
+            program: program.program,
+            entry_point: program.entry_point,
+            n_workgroups: program.n_workgroups,
+            workgroup_size: program.workgroup_size,
+            extra_in_data: program.extra_in_data,
+            extra_out_data_nbytes: program.extra_out_data_nbytes,
+        }
+    }
+}
+
+impl TryFrom<VersionedProgram> for SerialisableProgram {
+    type Error = String;
+
+    fn try_from(program: VersionedProgram) -> Result<Self, Self::Error> {
+        // Anything newer might mean something we'd silently get wrong
+        if program.format_version > PROGRAM_FORMAT_VERSION {
+            return Err(unsupported_format_version(program.format_version));
+        }
+        Ok(Self {
+            in_data: program.in_data,
+            out_data_nbytes: program.out_data_nbytes,
+            program: program.program,
+            entry_point: program.entry_point,
+            n_workgroups: program.n_workgroups,
+            workgroup_size: program.workgroup_size,
+            extra_in_data: program.extra_in_data,
+            extra_out_data_nbytes: program.extra_out_data_nbytes,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramBuildError {
+    MissingField(&'static str),
+    ZeroWorkgroups,
+    ZeroWorkgroupSize,
+    EmptyInput,
+    EmptyOutput,
+    OutputNotMultipleOfElem {
+        out_data_nbytes: usize,
+        out_elem_nbytes: usize,
+    },
+    InvalidWgsl(String),
+    EntryPointNotFound(String),
+    WorkgroupSizeMismatch {
+        declared: [u32; 3],
+        workgroup_size: usize,
+    },
+}
+
+impl std::fmt::Display for ProgramBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramBuildError::MissingField(field) => {
+                write!(f, "Field '{field}' wasn't set on the program builder")
+            }
+            ProgramBuildError::ZeroWorkgroups => write!(f, "n_workgroups must be at least 1"),
+            ProgramBuildError::ZeroWorkgroupSize => write!(f, "workgroup_size must be at least 1"),
+            ProgramBuildError::EmptyInput => write!(f, "in_data must not be empty"),
+            ProgramBuildError::EmptyOutput => write!(f, "out_data_nbytes must be at least 1"),
+            ProgramBuildError::OutputNotMultipleOfElem {
+                out_data_nbytes,
+                out_elem_nbytes,
+            } => write!(
+                f,
+                "out_data_nbytes ({out_data_nbytes}) isn't a multiple of the output element size ({out_elem_nbytes})"
+            ),
+            ProgramBuildError::InvalidWgsl(err) => write!(f, "Program isn't valid WGSL:\n{err}"),
+            ProgramBuildError::EntryPointNotFound(entry_point) => write!(
+                f,
+                "Program has no compute entry point called '{entry_point}'"
+            ),
+            ProgramBuildError::WorkgroupSizeMismatch {
+                declared,
+                workgroup_size,
+            } => write!(
+                f,
+                "Entry point declares @workgroup_size({}, {}, {}), but workgroup_size is {workgroup_size} (run_shader only dispatches along x, so it must be ({workgroup_size}, 1, 1))",
+                declared[0], declared[1], declared[2]
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProgramBuildError {}
+
+// Everything that can go wrong in SerialisableProgram::run_local
+#[derive(Debug, Clone)]
+pub enum ProgramRunError {
+    // The features the program needs couldn't be worked out, because it doesn't parse or validate
+    InvalidProgram(crate::reflection::ReflectError),
+    NoAdapter,
+    UnsupportedFeatures {
+        required: wgpu::Features,
+        supported: wgpu::Features,
+    },
+    RequestDevice(wgpu::RequestDeviceError),
+    Run(crate::RunShaderError),
+    // Only from GpuExecutor, which checks programs against the device's limits before allocating anything for them
+    Invalid(ProgramValidationError),
+    // Only from GpuExecutor, which only binds in_data and the first output, see SerialisableProgram::run_multi for the rest
+    ExtraBuffersUnsupported,
+}
+
+impl std::fmt::Display for ProgramRunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramRunError::InvalidProgram(err) => write!(f, "{err}"),
+            ProgramRunError::NoAdapter => write!(f, "No gpu adapter is available"),
+            ProgramRunError::UnsupportedFeatures {
+                required,
+                supported,
+            } => write!(
+                f,
+                "Program needs the features {required:?}, but the adapter is missing {:?}",
+                *required - *supported
+            ),
+            ProgramRunError::RequestDevice(err) => write!(f, "Couldn't get a gpu device: {err}"),
+            ProgramRunError::Run(err) => write!(f, "{err}"),
+            ProgramRunError::Invalid(err) => write!(f, "Invalid program: {err}"),
+            ProgramRunError::ExtraBuffersUnsupported => write!(
+                f,
+                "Programs with extra inputs or outputs can't be run by a GpuExecutor"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProgramRunError {}
+
+impl ProgramRunError {
+    // The gpu failing rather than the program, so the program might still run elsewhere (e.g. on the cpu fallback)
+    pub fn is_gpu_failure(&self) -> bool {
+        matches!(
+            self,
+            ProgramRunError::Run(
+                crate::RunShaderError::OutOfMemory | crate::RunShaderError::DeviceLost
+            )
+        )
+    }
+}
+
+// The output buffer can't hold one element per invocation, even allowing for the last workgroup being partially idle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTooSmall {
+    pub out_data_nbytes: usize,
+    pub min_expected_nbytes: usize,
+    pub out_elem_nbytes: usize,
+}
+
+impl std::fmt::Display for OutputTooSmall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "out_data_nbytes is {}, but the dispatch looks like it writes at least {} bytes ({} byte elements, one per invocation), whole workgroups would have nothing to write to",
+            self.out_data_nbytes, self.min_expected_nbytes, self.out_elem_nbytes
+        )
+    }
+}
+
+impl std::error::Error for OutputTooSmall {}
+
+// Why a program that came off the wire can't be run safely, see SerialisableProgram::validate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramValidationError {
+    EmptyEntryPoint,
+    ZeroWorkgroups,
+    ZeroWorkgroupSize,
+    EmptyOutput,
+    WorkgroupTooLarge {
+        workgroup_size: usize,
+        max: u32,
+    },
+    // Every input and output is its own storage buffer binding
+    TooManyBuffers {
+        n_buffers: usize,
+        max: u32,
+    },
+    // Invocation ids are u32s in the shader, so the whole dispatch has to fit in one
+    TooManyInvocations {
+        n_workgroups: usize,
+        workgroup_size: usize,
+    },
+    InputTooLarge {
+        nbytes: u64,
+        max_nbytes: u64,
+    },
+    OutputTooLarge {
+        nbytes: u64,
+        max_nbytes: u64,
+    },
+    // Outputs are copied out of their buffers whole, and wgpu only copies multiples of COPY_BUFFER_ALIGNMENT
+    UnalignedOutput {
+        nbytes: usize,
+    },
+}
+
+impl std::fmt::Display for ProgramValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramValidationError::EmptyEntryPoint => write!(f, "entry_point must not be empty"),
+            ProgramValidationError::ZeroWorkgroups => write!(f, "n_workgroups must be at least 1"),
+            ProgramValidationError::ZeroWorkgroupSize => {
+                write!(f, "workgroup_size must be at least 1")
+            }
+            ProgramValidationError::EmptyOutput => write!(f, "out_data_nbytes must be at least 1"),
+            ProgramValidationError::WorkgroupTooLarge {
+                workgroup_size,
+                max,
+            } => write!(
+                f,
+                "Workgroup size is {workgroup_size}, but the device allows at most {max}"
+            ),
+            ProgramValidationError::TooManyBuffers { n_buffers, max } => write!(
+                f,
+                "Program has {n_buffers} inputs and outputs, but the device can bind at most {max} storage buffers"
+            ),
+            ProgramValidationError::TooManyInvocations {
+                n_workgroups,
+                workgroup_size,
+            } => write!(
+                f,
+                "{n_workgroups} workgroups of {workgroup_size} invocations don't fit in a u32 invocation id"
+            ),
+            ProgramValidationError::InputTooLarge { nbytes, max_nbytes } => write!(
+                f,
+                "Input is {nbytes} bytes, but the device can bind at most {max_nbytes}"
+            ),
+            ProgramValidationError::OutputTooLarge { nbytes, max_nbytes } => write!(
+                f,
+                "Output is {nbytes} bytes, but the device can bind at most {max_nbytes}"
+            ),
+            ProgramValidationError::UnalignedOutput { nbytes } => write!(
+                f,
+                "Output is {nbytes} bytes, which isn't a multiple of {} bytes",
+                wgpu::COPY_BUFFER_ALIGNMENT
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProgramValidationError {}
+
+// One reason a device can't run a program, see SerialisableProgram::compatibility
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    // Doesn't parse or validate, or (when checked against an actual device) the device rejected it
+    InvalidProgram(String),
+    MissingFeatures(wgpu::Features),
+    // The sizes or dispatch shape don't fit the device's limits (or are malformed), see SerialisableProgram::validate
+    Validation(ProgramValidationError),
+}
+
+impl std::fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Incompatibility::InvalidProgram(err) => write!(f, "Program is invalid: {err}"),
+            Incompatibility::MissingFeatures(features) => {
+                write!(f, "Device is missing the features {features:?}")
+            }
+            Incompatibility::Validation(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+// Whether a device can run a program, and if not every reason why not
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compatibility {
+    pub incompatibilities: Vec<Incompatibility>,
+}
+
+impl Compatibility {
+    pub fn is_compatible(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+}
+
+impl std::fmt::Display for Compatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_compatible() {
+            return write!(f, "Compatible");
+        }
+        write!(f, "Incompatible: ")?;
+        for (i, incompatibility) in self.incompatibilities.iter().enumerate() {
+            if i != 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{incompatibility}")?;
+        }
+        Ok(())
+    }
+}
+
+// Catches out_data_nbytes being off by some factor, assuming the usual one output element per invocation.
+// Kernels are expected to bounds check against arrayLength, so the last workgroup is allowed to be only partially used.
+// NOTE: This is a heuristic, if the element size can't be inferred (or the shader doesn't parse) nothing is reported
+fn check_output_size(
+    program: &str,
+    out_data_nbytes: usize,
+    n_workgroups: usize,
+    workgroup_size: usize,
+) -> Result<(), OutputTooSmall> {
+    let Ok(Some(out_elem_nbytes)) = crate::reflection::output_elem_stride(program) else {
+        return Ok(());
+    };
+    let out_elem_nbytes = usize::try_from(out_elem_nbytes).unwrap();
+    let min_expected_nbytes = n_workgroups
+        .saturating_sub(1)
+        .saturating_mul(workgroup_size)
+        .saturating_add(1)
+        .saturating_mul(out_elem_nbytes);
+    if out_data_nbytes < min_expected_nbytes {
+        return Err(OutputTooSmall {
+            out_data_nbytes,
+            min_expected_nbytes,
+            out_elem_nbytes,
+        });
+    }
+    Ok(())
+}
+
+// Shared by SerialisableProgram::validate and ProgramMetadata::validate, only needs the sizes so it's cheap enough
+// to run on everything received from another machine before any buffer gets allocated for it
+// in_data_nbytes and out_data_nbytes hold the size of every input and output, the first ones included
+fn validate(
+    in_data_nbytes: &[usize],
+    out_data_nbytes: &[usize],
+    entry_point: &str,
+    n_workgroups: usize,
+    workgroup_size: usize,
+    device_limits: &wgpu::Limits,
+) -> Result<(), ProgramValidationError> {
+    if entry_point.is_empty() {
+        return Err(ProgramValidationError::EmptyEntryPoint);
+    }
+    if n_workgroups == 0 {
+        return Err(ProgramValidationError::ZeroWorkgroups);
+    }
+    if workgroup_size == 0 {
+        return Err(ProgramValidationError::ZeroWorkgroupSize);
+    }
+    if out_data_nbytes.contains(&0) {
+        return Err(ProgramValidationError::EmptyOutput);
+    }
+    let n_buffers = in_data_nbytes.len() + out_data_nbytes.len();
+    if !u32::try_from(n_buffers)
+        .is_ok_and(|n_buffers| n_buffers <= device_limits.max_storage_buffers_per_shader_stage)
+    {
+        return Err(ProgramValidationError::TooManyBuffers {
+            n_buffers,
+            max: device_limits.max_storage_buffers_per_shader_stage,
+        });
+    }
+
+    // Dispatches are only along x
+    let max_workgroup_size = device_limits
+        .max_compute_workgroup_size_x
+        .min(device_limits.max_compute_invocations_per_workgroup);
+    if !u32::try_from(workgroup_size).is_ok_and(|size| size <= max_workgroup_size) {
+        return Err(ProgramValidationError::WorkgroupTooLarge {
+            workgroup_size,
+            max: max_workgroup_size,
+        });
+    }
+    if n_workgroups
+        .checked_mul(workgroup_size)
+        .and_then(|n_invocations| u32::try_from(n_invocations).ok())
+        .is_none()
+    {
+        return Err(ProgramValidationError::TooManyInvocations {
+            n_workgroups,
+            workgroup_size,
+        });
+    }
+
+    let max_nbytes =
+        u64::from(device_limits.max_storage_buffer_binding_size).min(device_limits.max_buffer_size);
+    for in_nbytes in in_data_nbytes {
+        // Input buffers get rounded up to a whole number of words
+        let in_nbytes = u64::try_from(*in_nbytes)
+            .unwrap_or(u64::MAX)
+            .checked_next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT)
+            .unwrap_or(u64::MAX);
+        if in_nbytes > max_nbytes {
+            return Err(ProgramValidationError::InputTooLarge {
+                nbytes: in_nbytes,
+                max_nbytes,
+            });
+        }
+    }
+    for &out_nbytes in out_data_nbytes {
+        let out_nbytes_u64 = u64::try_from(out_nbytes).unwrap_or(u64::MAX);
+        if out_nbytes_u64 > max_nbytes {
+            return Err(ProgramValidationError::OutputTooLarge {
+                nbytes: out_nbytes_u64,
+                max_nbytes,
+            });
+        }
+        if out_nbytes_u64 % wgpu::COPY_BUFFER_ALIGNMENT != 0 {
+            return Err(ProgramValidationError::UnalignedOutput { nbytes: out_nbytes });
+        }
+    }
+    Ok(())
+}
+
+// Builds a SerialisableProgram, checking the invariants that would otherwise only blow up once it's run (possibly on another peer)
+#[derive(Default)]
+pub struct SerialisableProgramBuilder {
+    in_data: Option<Vec<u8>>,
+    out_data_nbytes: Option<usize>,
+    out_elem_nbytes: Option<usize>,
+    program: Option<String>,
+    entry_point: Option<String>,
+    n_workgroups: Option<usize>,
+    workgroup_size: Option<usize>,
+}
+
+impl SerialisableProgramBuilder {
+    pub fn in_data(mut self, in_data: Vec<u8>) -> Self {
+        self.in_data = Some(in_data);
+        self
+    }
+
+    pub fn out_data_nbytes(mut self, out_data_nbytes: usize) -> Self {
+        self.out_data_nbytes = Some(out_data_nbytes);
+        self
+    }
+
+    // Optional, if set out_data_nbytes has to be a multiple of it
+    pub fn out_elem_nbytes(mut self, out_elem_nbytes: usize) -> Self {
+        self.out_elem_nbytes = Some(out_elem_nbytes);
+        self
+    }
+
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    pub fn entry_point(mut self, entry_point: impl Into<String>) -> Self {
+        self.entry_point = Some(entry_point.into());
+        self
+    }
+
+    pub fn n_workgroups(mut self, n_workgroups: usize) -> Self {
+        self.n_workgroups = Some(n_workgroups);
+        self
+    }
+
+    pub fn workgroup_size(mut self, workgroup_size: usize) -> Self {
+        self.workgroup_size = Some(workgroup_size);
+        self
+    }
+
+    pub fn build(self) -> Result<SerialisableProgram, ProgramBuildError> {
+        let in_data = self
+            .in_data
+            .ok_or(ProgramBuildError::MissingField("in_data"))?;
+        let out_data_nbytes = self
+            .out_data_nbytes
+            .ok_or(ProgramBuildError::MissingField("out_data_nbytes"))?;
+        let program = self
+            .program
+            .ok_or(ProgramBuildError::MissingField("program"))?;
+        let entry_point = self
+            .entry_point
+            .ok_or(ProgramBuildError::MissingField("entry_point"))?;
+        let n_workgroups = self
+            .n_workgroups
+            .ok_or(ProgramBuildError::MissingField("n_workgroups"))?;
+        let workgroup_size = self
+            .workgroup_size
+            .ok_or(ProgramBuildError::MissingField("workgroup_size"))?;
+
+        if n_workgroups == 0 {
+            return Err(ProgramBuildError::ZeroWorkgroups);
+        }
+        if workgroup_size == 0 {
+            return Err(ProgramBuildError::ZeroWorkgroupSize);
+        }
+        if in_data.is_empty() {
+            return Err(ProgramBuildError::EmptyInput);
+        }
+        if out_data_nbytes == 0 {
+            return Err(ProgramBuildError::EmptyOutput);
+        }
+        if let Some(out_elem_nbytes) = self.out_elem_nbytes {
+            if out_elem_nbytes == 0 || out_data_nbytes % out_elem_nbytes != 0 {
+                return Err(ProgramBuildError::OutputNotMultipleOfElem {
+                    out_data_nbytes,
+                    out_elem_nbytes,
+                });
+            }
+        }
+
+        let module = naga::front::wgsl::parse_str(&program)
+            .map_err(|err| ProgramBuildError::InvalidWgsl(err.emit_to_string(&program)))?;
+        let declared = module
+            .entry_points
+            .iter()
+            .find(|ep| ep.name == entry_point && ep.stage == naga::ShaderStage::Compute)
+            .ok_or_else(|| ProgramBuildError::EntryPointNotFound(entry_point.clone()))?
+            .workgroup_size;
+        if u32::try_from(workgroup_size).ok() != Some(declared[0]) || declared[1..] != [1, 1] {
+            return Err(ProgramBuildError::WorkgroupSizeMismatch {
+                declared,
+                workgroup_size,
+            });
+        }
+
+        Ok(SerialisableProgram {
+            in_data,
+            out_data_nbytes,
+            program,
+            entry_point,
+            n_workgroups,
+            workgroup_size,
+            extra_in_data: Vec::new(),
+            extra_out_data_nbytes: Vec::new(),
+        })
+    }
+}
+
+// The program may have come off the network, so invalid WGSL has to be an error rather than a panic
+async fn create_module(
+    device: &wgpu::Device,
+    program: &str,
+) -> Result<wgpu::ShaderModule, crate::RunShaderError> {
+    let (cm, scope_err) = crate::with_error_scope(device, wgpu::ErrorFilter::Validation, || {
+        device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(program)),
+        })
+    });
+    if let Some(err) = scope_err.await {
+        return Err(crate::RunShaderError::PipelineCreation(err.to_string()));
+    }
+    Ok(cm)
+}
+
+// Size of the pieces in_data is streamed in, must be a multiple of wgpu::COPY_BUFFER_ALIGNMENT
+const STREAM_CHUNK_NBYTES: usize = 16 * 1024 * 1024;
+
+// Everything in a SerialisableProgram except the input data itself.
+// Used for streaming programs over the network, where the metadata is sent as json and in_data follows it as raw bytes,
+// this way we don't need to hold a (base64 inflated) copy of the whole capsule in memory on either end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProgramMetadata {
+    // See PROGRAM_FORMAT_VERSION, read_streamed and SerialisableProgram::from_binary reject newer ones
+    #[serde(default = "first_program_format_version")]
+    pub format_version: u32,
+    pub in_data_nbytes: usize,
+    // How the receiver should send back the result, see compression::write_result
+    #[serde(default)]
+    pub result_compression: ResultCompression,
+    // Whether in_data follows the metadata deflate compressed (see networking::write_buf_compressed) instead of raw
+    // NOTE: Older receivers don't know about this and would take the compressed bytes as raw in_data,
+    //       so only opt in when sending to an up to date telefork server
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub in_data_compressed: bool,
+    pub out_data_nbytes: usize,
+    pub program: String,
+    pub entry_point: String,
+    pub n_workgroups: usize,
+    pub workgroup_size: usize,
+    // Sizes of SerialisableProgram::extra_in_data and extra_out_data_nbytes, only used by the binary format,
+    // streamed programs can't have extra inputs or outputs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_in_data_nbytes: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_out_data_nbytes: Vec<usize>,
+}
+
+impl ProgramMetadata {
+    // Receives a program sent by SerialisableProgram::write_streamed, in_data goes straight into a gpu buffer in chunks
+    pub async fn read_streamed(
+        connection: &mut (impl AsyncRead + Unpin),
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> io::Result<(Self, wgpu::Buffer)> {
+        let raw_metadata = crate::networking::read_buf_limited(
+            connection,
+            crate::networking::MAX_CONTROL_MESSAGE_LEN,
+        )
+        .await?;
+        let metadata: Self = from_versioned_json(&raw_metadata).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile deserialising streamed program metadata"),
+            )
+        })?;
+
+        // Sizes come straight off the wire, so check them before allocating anything based on them
+        metadata.validate(&device.limits()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{err}\nWhile validating streamed program metadata"),
+            )
+        })?;
+        if !metadata.extra_in_data_nbytes.is_empty() || !metadata.extra_out_data_nbytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Streamed programs can't have extra inputs or outputs",
+            ));
+        }
+
+        let in_buf = device.create_buffer(&BufferDescriptor {
             label: None,
-            source: wgpu::ShaderSource::Wgsl(Cow::from(&self.program)),
+            size: u64::try_from(metadata.in_data_nbytes)
+                .unwrap()
+                .next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
+
+        #[cfg(not(feature = "compressed-transfers"))]
+        if metadata.in_data_compressed {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Streamed program has compressed in_data, but this build doesn't have the compressed-transfers feature",
+            ));
+        }
+        #[cfg(feature = "compressed-transfers")]
+        if metadata.in_data_compressed {
+            // Has to be decompressed in one go, so unlike raw in_data it's held in memory whole
+            let mut in_data =
+                crate::networking::read_buf_compressed(connection, metadata.in_data_nbytes)
+                    .await
+                    .map_err(|err| {
+                        io::Error::new(
+                            err.kind(),
+                            format!("{err}\nWhile receiving compressed streamed in_data"),
+                        )
+                    })?;
+            // read_buf_compressed already made sure it decompressed to exactly in_data_nbytes
+            in_data.resize(
+                in_data
+                    .len()
+                    .next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize),
+                0,
+            );
+            queue.write_buffer(&in_buf, 0, &in_data);
+            queue.submit([]);
+            return Ok((metadata, in_buf));
+        }
+
+        let mut chunk = vec![0u8; STREAM_CHUNK_NBYTES.min(metadata.in_data_nbytes)];
+        let mut offset = 0;
+        while offset < metadata.in_data_nbytes {
+            let chunk_nbytes = STREAM_CHUNK_NBYTES.min(metadata.in_data_nbytes - offset);
+            chunk.resize(chunk_nbytes, 0);
+            connection.read_exact(&mut chunk).await.map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{err}\nWhile receiving streamed in_data at offset: {offset}"),
+                )
+            })?;
+            // Only the last chunk can be unaligned, pad it with zeroes, the buffer was rounded up to fit this
+            chunk.resize(
+                chunk_nbytes.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize),
+                0,
+            );
+            queue.write_buffer(&in_buf, offset.try_into().unwrap(), &chunk);
+            // Flush the staged write so staging memory doesn't pile up to the size of the whole input
+            queue.submit([]);
+            offset += chunk_nbytes;
+        }
+
+        Ok((metadata, in_buf))
+    }
+
+    pub async fn run_with_in_buf(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        in_buf: &wgpu::Buffer,
+    ) -> Result<Vec<u8>, crate::RunShaderError> {
+        let mut out_buf = crate::create_output_buffer(
+            device,
+            self.out_data_nbytes.try_into().unwrap(),
+            BufferUsages::empty(),
+        );
+
+        let cm = self.create_module(device).await?;
+        crate::run_shader(self.run_shader_params(device, queue, in_buf, &mut out_buf, &cm)).await?;
+
+        crate::read_back_buffer(device, queue, &out_buf, BufferUsages::empty())
+            .await
+            .map_err(crate::RunShaderError::ReadBack)
+    }
+
+    pub fn check_output_size(&self) -> Result<(), OutputTooSmall> {
+        check_output_size(
+            &self.program,
+            self.out_data_nbytes,
+            self.n_workgroups,
+            self.workgroup_size,
+        )
+    }
+
+    // See SerialisableProgram::validate, read_streamed already does this before allocating the input buffer
+    pub fn validate(&self, device_limits: &wgpu::Limits) -> Result<(), ProgramValidationError> {
+        validate(
+            &[&[self.in_data_nbytes][..], &self.extra_in_data_nbytes[..]].concat(),
+            &[&[self.out_data_nbytes][..], &self.extra_out_data_nbytes[..]].concat(),
+            &self.entry_point,
+            self.n_workgroups,
+            self.workgroup_size,
+            device_limits,
+        )
+    }
+
+    // Leaves the output in out_buf on the gpu, see SerialisableProgram::run_into
+    pub fn run_into_with_in_buf(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        in_buf: &wgpu::Buffer,
+        out_buf: &mut wgpu::Buffer,
+    ) -> Result<(), crate::RunShaderError> {
+        let cm = futures::executor::block_on(self.create_module(device))?;
+        crate::run_shader_blocking(self.run_shader_params(device, queue, in_buf, out_buf, &cm))
+    }
+
+    async fn create_module(
+        &self,
+        device: &wgpu::Device,
+    ) -> Result<wgpu::ShaderModule, crate::RunShaderError> {
+        // Only a warning, the shader might legitimately write less than one element per invocation
+        if let Err(warning) = self.check_output_size() {
+            tracing::warn!("{warning}!");
+        }
+        create_module(device, &self.program).await
+    }
+
+    fn run_shader_params<'a>(
+        &'a self,
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        in_buf: &'a wgpu::Buffer,
+        out_buf: &'a mut wgpu::Buffer,
+        cm: &'a wgpu::ShaderModule,
+    ) -> crate::RunShaderParams<'a> {
+        crate::RunShaderParams {
+            device,
+            queue,
+            in_buf,
+            out_buf,
+            workgroup_len: self.workgroup_size,
+            n_workgroups: self.n_workgroups,
+            program: cm,
+            entry_point: &self.entry_point,
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: crate::ShaderBindings::default(),
+            dispatch_dims: None,
+        }
+    }
+}
+
+impl SerialisableProgram {
+    pub fn builder() -> SerialisableProgramBuilder {
+        SerialisableProgramBuilder::default()
+    }
+
+    // in_data followed by extra_in_data, in binding order
+    fn all_in_data(&self) -> impl Iterator<Item = &Vec<u8>> {
+        std::iter::once(&self.in_data).chain(&self.extra_in_data)
+    }
+
+    // out_data_nbytes followed by extra_out_data_nbytes, in binding order
+    fn all_out_data_nbytes(&self) -> impl Iterator<Item = usize> + '_ {
+        std::iter::once(self.out_data_nbytes).chain(self.extra_out_data_nbytes.iter().copied())
+    }
+
+    pub async fn save_to_file(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let serialised_program = serde_json::to_vec(self).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{err}\nWhile serialising program capsule for file: {path:?}"),
+            )
+        })?;
+        tokio::fs::write(path, serialised_program)
+            .await
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{err}\nWhile writing program capsule to file: {path:?}"),
+                )
+            })
+    }
+
+    pub async fn load_from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let program_file_contents = tokio::fs::read(path).await.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile reading program capsule from file: {path:?}"),
+            )
+        })?;
+        from_versioned_json(&program_file_contents).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile deserialising program capsule from file: {path:?}"),
+            )
+        })
+    }
+
+    // See ProgramMetadata::check_output_size, this is also checked (as a warning) right before running
+    pub fn check_output_size(&self) -> Result<(), OutputTooSmall> {
+        check_output_size(
+            &self.program,
+            self.out_data_nbytes,
+            self.n_workgroups,
+            self.workgroup_size,
+        )
+    }
+
+    // Checks that the sizes and dispatch shape are sane for a device with these limits, so a malformed program
+    // (e.g. one received from another peer) is an error instead of a panic or an absurdly large allocation in run
+    // NOTE: The shader itself isn't compiled, see compatibility for that
+    pub fn validate(&self, device_limits: &wgpu::Limits) -> Result<(), ProgramValidationError> {
+        validate(
+            &self.all_in_data().map(Vec::len).collect::<Vec<_>>(),
+            &self.all_out_data_nbytes().collect::<Vec<_>>(),
+            &self.entry_point,
+            self.n_workgroups,
+            self.workgroup_size,
+            device_limits,
+        )
+    }
+
+    // Rough measure of how much gpu work running the program is, for balancing work between peers
+    // NOTE: Just the total number of invocations, the shader itself isn't looked at
+    pub fn estimated_cost(&self) -> u64 {
+        u64::try_from(self.n_workgroups.saturating_mul(self.workgroup_size)).unwrap_or(u64::MAX)
+    }
+
+    pub fn metadata(&self, result_compression: ResultCompression) -> ProgramMetadata {
+        ProgramMetadata {
+            format_version: CAPSULE_FORMAT_VERSION,
+            in_data_nbytes: self.in_data.len(),
+            result_compression,
+            in_data_compressed: false,
+            out_data_nbytes: self.out_data_nbytes,
+            program: self.program.clone(),
+            entry_point: self.entry_point.clone(),
+            n_workgroups: self.n_workgroups,
+            workgroup_size: self.workgroup_size,
+            extra_in_data_nbytes: self.extra_in_data.iter().map(Vec::len).collect(),
+            extra_out_data_nbytes: self.extra_out_data_nbytes.clone(),
+        }
+    }
+
+    // Compact alternative to the json capsule for sending programs between machines, in_data is kept as raw bytes
+    // instead of being base64 inflated, the layout is: metadata json length (u64 LE), metadata json, in_data,
+    // then every extra_in_data back to back (their lengths are in the metadata)
+    pub fn to_binary(&self) -> io::Result<Vec<u8>> {
+        let serialised_metadata = serde_json::to_vec(&self.metadata(ResultCompression::Never))
+            .map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{err}\nWhile serialising binary program metadata"),
+                )
+            })?;
+        let mut res = Vec::with_capacity(
+            core::mem::size_of::<u64>()
+                + serialised_metadata.len()
+                + self.all_in_data().map(Vec::len).sum::<usize>(),
+        );
+        res.extend((serialised_metadata.len() as u64).to_le_bytes());
+        res.extend(serialised_metadata);
+        for in_data in self.all_in_data() {
+            res.extend_from_slice(in_data);
+        }
+        Ok(res)
+    }
+
+    pub fn from_binary(data: &[u8]) -> io::Result<Self> {
+        let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let (raw_metadata_nbytes, rest) = data
+            .split_first_chunk::<{ core::mem::size_of::<u64>() }>()
+            .ok_or_else(|| {
+                invalid_data("Binary program is too short to hold its header".to_owned())
+            })?;
+        let metadata_nbytes = usize::try_from(u64::from_le_bytes(*raw_metadata_nbytes))
+            .ok()
+            .filter(|nbytes| *nbytes <= rest.len())
+            .ok_or_else(|| {
+                invalid_data("Binary program metadata length is out of bounds".to_owned())
+            })?;
+        let (raw_metadata, in_data) = rest.split_at(metadata_nbytes);
+        let metadata: ProgramMetadata = from_versioned_json(raw_metadata).map_err(|err| {
+            invalid_data(format!(
+                "{err}\nWhile deserialising binary program metadata"
+            ))
+        })?;
+        let expected_nbytes = std::iter::once(metadata.in_data_nbytes)
+            .chain(metadata.extra_in_data_nbytes.iter().copied())
+            .try_fold(0usize, usize::checked_add);
+        if expected_nbytes != Some(in_data.len()) {
+            return Err(invalid_data(format!(
+                "Binary program should have {} bytes of in_data (extra inputs included), but has {}",
+                expected_nbytes.map_or("too many".to_owned(), |nbytes| nbytes.to_string()),
+                in_data.len()
+            )));
+        }
+        let (first_in_data, mut rest) = in_data.split_at(metadata.in_data_nbytes);
+        let extra_in_data = metadata
+            .extra_in_data_nbytes
+            .iter()
+            .map(|nbytes| {
+                let (extra, after) = rest.split_at(*nbytes);
+                rest = after;
+                extra.to_vec()
+            })
+            .collect();
+        Ok(Self {
+            in_data: first_in_data.to_vec(),
+            out_data_nbytes: metadata.out_data_nbytes,
+            program: metadata.program,
+            entry_point: metadata.entry_point,
+            n_workgroups: metadata.n_workgroups,
+            workgroup_size: metadata.workgroup_size,
+            extra_in_data,
+            extra_out_data_nbytes: metadata.extra_out_data_nbytes,
+        })
+    }
+
+    // Sends the program in the streamed format, to be received with ProgramMetadata::read_streamed
+    // result_compression is passed along so the receiver knows how we want the result back,
+    // with compress_in_data in_data is sent deflate compressed, worth it for big structured inputs on slow links
+    pub async fn write_streamed(
+        &self,
+        connection: &mut (impl AsyncWrite + Unpin),
+        result_compression: ResultCompression,
+        compress_in_data: bool,
+    ) -> io::Result<()> {
+        // ProgramMetadata::read_streamed only streams in_data into a single buffer
+        if !self.extra_in_data.is_empty() || !self.extra_out_data_nbytes.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Programs with extra inputs or outputs can't be streamed, use to_binary instead",
+            ));
+        }
+        #[cfg(not(feature = "compressed-transfers"))]
+        if compress_in_data {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Compressing in_data needs the compressed-transfers feature",
+            ));
+        }
+        let metadata = ProgramMetadata {
+            format_version: PROGRAM_FORMAT_VERSION,
+            in_data_compressed: compress_in_data,
+            ..self.metadata(result_compression)
+        };
+        let serialised_metadata = serde_json::to_vec(&metadata).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{err}\nWhile serialising streamed program metadata"),
+            )
+        })?;
+        crate::networking::write_buf(connection, &serialised_metadata).await?;
+        #[cfg(feature = "compressed-transfers")]
+        if compress_in_data {
+            return crate::networking::write_buf_compressed(connection, &self.in_data).await;
+        }
+        for chunk in self.in_data.chunks(STREAM_CHUNK_NBYTES) {
+            connection.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Vec<u8>, crate::RunShaderError> {
         let in_buf = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
             contents: &self.in_data,
             usage: BufferUsages::STORAGE,
         });
+        self.metadata(ResultCompression::Never)
+            .run_with_in_buf(device, queue, &in_buf)
+            .await
+    }
 
-        let mut out_buf = device.create_buffer(&BufferDescriptor {
-            label: None,
-            size: self.out_data_nbytes.try_into().unwrap(),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
-        });
+    // Runs the program with all of its inputs and outputs, bound as run_shader_multi binds them:
+    // in_data then extra_in_data, then the first output then extra_out_data_nbytes, then the goff uniform.
+    // Returns every output in that order, the first one is what run returns
+    // NOTE: With no extras this is run_shader's standard layout, so it also runs single input/output programs
+    pub async fn run_multi(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Vec<Vec<u8>>, crate::RunShaderError> {
+        let in_bufs = self
+            .all_in_data()
+            .map(|in_data| {
+                device.create_buffer_init(&BufferInitDescriptor {
+                    label: None,
+                    contents: in_data,
+                    usage: BufferUsages::STORAGE,
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut out_bufs = self
+            .all_out_data_nbytes()
+            .map(|out_data_nbytes| {
+                crate::create_output_buffer(
+                    device,
+                    out_data_nbytes.try_into().unwrap(),
+                    BufferUsages::empty(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let cm = create_module(device, &self.program).await?;
 
-        crate::run_shader(crate::RunShaderParams {
+        crate::run_shader_multi(crate::RunShaderMultiParams {
             device,
             queue,
-            in_buf: &in_buf,
-            out_buf: &mut out_buf,
+            in_bufs: &in_bufs.iter().collect::<Vec<_>>(),
+            out_bufs: &out_bufs.iter_mut().collect::<Vec<_>>(),
             workgroup_len: self.workgroup_size,
             n_workgroups: self.n_workgroups,
             program: &cm,
             entry_point: &self.entry_point,
-        })?;
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+        })
+        .await?;
+
+        let mut res = Vec::with_capacity(out_bufs.len());
+        for out_buf in &out_bufs {
+            res.push(
+                crate::read_back_buffer(device, queue, out_buf, BufferUsages::empty())
+                    .await
+                    .map_err(crate::RunShaderError::ReadBack)?,
+            );
+        }
+        Ok(res)
+    }
 
-        let transfer_buf = device.create_buffer(&BufferDescriptor {
+    // Same as run, but the output stays on the gpu in out_buf (no readback), so more gpu work can be chained onto it
+    // out_buf should be made with crate::create_output_buffer, out_data_nbytes long,
+    // plus whatever usages the follow up work needs (e.g. STORAGE to be the in_buf of another run_shader)
+    pub fn run_into(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        out_buf: &mut wgpu::Buffer,
+    ) -> Result<(), crate::RunShaderError> {
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
             label: None,
-            size: out_buf.size(),
-            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-            mapped_at_creation: false,
+            contents: &self.in_data,
+            usage: BufferUsages::STORAGE,
         });
+        self.metadata(ResultCompression::Never)
+            .run_into_with_in_buf(device, queue, &in_buf, out_buf)
+    }
+
+    // Pre-flight check of whether a device with these features and limits could run the program, without dispatching anything
+    // or even needing the device, e.g. for a scheduler deciding which peer to send it to based on what the peers reported
+    pub fn compatibility_with(
+        &self,
+        features: wgpu::Features,
+        limits: &wgpu::Limits,
+    ) -> Compatibility {
+        let mut incompatibilities = Vec::new();
+        match crate::reflection::required_features(&self.program) {
+            Ok(required) if !features.contains(required) => {
+                incompatibilities.push(Incompatibility::MissingFeatures(required - features));
+            }
+            Ok(_) => {}
+            Err(err) => incompatibilities.push(Incompatibility::InvalidProgram(err.to_string())),
+        }
 
-        let mut enc = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-        enc.copy_buffer_to_buffer(&out_buf, 0, &transfer_buf, 0, out_buf.size());
-        queue.submit([enc.finish()].into_iter());
+        if let Err(err) = self.validate(limits) {
+            incompatibilities.push(Incompatibility::Validation(err));
+        }
+        Compatibility { incompatibilities }
+    }
 
-        let transfer_view = transfer_buf.slice(..);
-        crate::wgpu_map_helper(device, wgpu::MapMode::Read, &transfer_view)
+    // Same as compatibility_with the device's features and limits, and also has the device compile the program
+    pub fn compatibility(&self, device: &wgpu::Device) -> Compatibility {
+        let mut compatibility = self.compatibility_with(device.features(), &device.limits());
+        // Compiling it would just fail again, or on features we already know are missing
+        if !compatibility.is_compatible() {
+            return compatibility;
+        }
+        let (_, scope_err) = crate::with_error_scope(device, wgpu::ErrorFilter::Validation, || {
+            device.create_shader_module(ShaderModuleDescriptor {
+                label: None,
+                source: wgpu::ShaderSource::Wgsl(Cow::from(&self.program)),
+            })
+        });
+        if let Some(err) = futures::executor::block_on(scope_err) {
+            compatibility
+                .incompatibilities
+                .push(Incompatibility::InvalidProgram(err.to_string()));
+        }
+        compatibility
+    }
+
+    // Runs the program on this machine, no networking involved: sets up a device with the features the program needs
+    // (worked out from its shader), runs it and reads the output back, the device is dropped afterwards
+    // Mostly meant for testing capsules, e.g. SerialisableProgram::load_from_file(path).await?.run_local().await
+    pub async fn run_local(self) -> Result<Vec<u8>, ProgramRunError> {
+        let required_features = crate::reflection::required_features(&self.program)
+            .map_err(ProgramRunError::InvalidProgram)?;
+        let instance = wgpu::Instance::new(InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions::default())
             .await
-            .ok()?;
-        let res = transfer_view
-            .get_mapped_range()
-            .iter()
-            .copied()
+            .ok_or(ProgramRunError::NoAdapter)?;
+        if !adapter.features().contains(required_features) {
+            return Err(ProgramRunError::UnsupportedFeatures {
+                required: required_features,
+                supported: adapter.features(),
+            });
+        }
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    label: None,
+                    required_features,
+                    required_limits: wgpu::Limits::default(),
+                    memory_hints: wgpu::MemoryHints::default(),
+                },
+                None,
+            )
+            .await
+            .map_err(ProgramRunError::RequestDevice)?;
+        self.run(&device, &queue)
+            .await
+            .map_err(ProgramRunError::Run)
+    }
+
+    // Runs the program on wgpu's fallback adapter (a software implementation, if the platform has one)
+    // This is meant as a last resort for when the program can't be run on an actual gpu, expect it to be slow
+    pub async fn run_on_cpu_fallback(&self) -> Result<Vec<u8>, crate::RunShaderError> {
+        let instance = wgpu::Instance::new(InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions {
+                compatible_surface: None,
+                force_fallback_adapter: true,
+                power_preference: wgpu::PowerPreference::None,
+            })
+            .await
+            .ok_or(crate::RunShaderError::NoFallbackDevice)?;
+        tracing::info!("Cpu fallback is using {:?}", adapter.get_info());
+        // An invalid program fails in run, with a better error than the device would give
+        let required_features =
+            crate::reflection::required_features(&self.program).unwrap_or_default();
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    required_features,
+                    ..Default::default()
+                },
+                None,
+            )
+            .await
+            .map_err(|_| crate::RunShaderError::NoFallbackDevice)?;
+        self.run(&device, &queue).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::multiply_program;
+
+    #[tokio::test]
+    async fn test_file_round_trip() {
+        let program = multiply_program(3, &(0..256u32).collect::<Vec<u32>>());
+
+        let path = std::env::temp_dir().join(format!(
+            "clustered-test-capsule-{}.json",
+            uuid::Uuid::now_v7()
+        ));
+        program.save_to_file(&path).await.unwrap();
+        let loaded_program = SerialisableProgram::load_from_file(&path).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(loaded_program.unwrap(), program);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let program = multiply_program(3, &(0..1024 * 1024u32).collect::<Vec<u32>>());
+
+        let binary = program.to_binary().unwrap();
+        // No base64, so the overhead over in_data is just the (small) metadata
+        assert!(binary.len() < program.in_data.len() + 1024);
+        assert!(binary.len() < serde_json::to_vec(&program).unwrap().len() * 4 / 5);
+        assert_eq!(SerialisableProgram::from_binary(&binary).unwrap(), program);
+        assert!(SerialisableProgram::from_binary(&binary[..binary.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_multi_buffer_round_trip() {
+        let a = (0..1000u32).collect::<Vec<u32>>();
+        let b = (0..1000u32).map(|i| i % 7).collect::<Vec<u32>>();
+        let program = SerialisableProgram {
+            in_data: a.iter().copied().flat_map(u32::to_le_bytes).collect(),
+            out_data_nbytes: 4000,
+            program: r#"
+                @group(0) @binding(0) var<storage, read> v_a: array<u32>;
+                @group(0) @binding(1) var<storage, read> v_b: array<u32>;
+                @group(0) @binding(2) var<storage, read_write> v_sums: array<u32>;
+                @group(0) @binding(3) var<storage, read_write> v_products: array<u32>;
+                @group(0) @binding(4) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_sums)) { return; }
+                    v_sums[actual_id] = v_a[actual_id] + v_b[actual_id];
+                    v_products[actual_id] = v_a[actual_id] * v_b[actual_id];
+                }
+            "#
+            .to_owned(),
+            entry_point: "main".to_owned(),
+            n_workgroups: 1000usize.div_ceil(32),
+            workgroup_size: 32,
+            extra_in_data: vec![b.iter().copied().flat_map(u32::to_le_bytes).collect()],
+            extra_out_data_nbytes: vec![4000],
+        };
+
+        let json = serde_json::to_value(&program).unwrap();
+        assert_eq!(json["format_version"], CAPSULE_FORMAT_VERSION);
+        assert_eq!(
+            serde_json::from_value::<SerialisableProgram>(json.clone()).unwrap(),
+            program
+        );
+        let binary = program.to_binary().unwrap();
+        assert_eq!(SerialisableProgram::from_binary(&binary).unwrap(), program);
+
+        // Capsules from before the format was versioned only have a single input and output
+        let mut unversioned = json.clone();
+        let unversioned_fields = unversioned.as_object_mut().unwrap();
+        for field in ["format_version", "extra_in_data", "extra_out_data_nbytes"] {
+            unversioned_fields.remove(field);
+        }
+        let loaded = serde_json::from_value::<SerialisableProgram>(unversioned).unwrap();
+        assert!(loaded.extra_in_data.is_empty() && loaded.extra_out_data_nbytes.is_empty());
+        let mut from_the_future = json;
+        from_the_future["format_version"] = (PROGRAM_FORMAT_VERSION + 1).into();
+        assert!(serde_json::from_value::<SerialisableProgram>(from_the_future).is_err());
+
+        let (device, queue) = crate::test_support::default_device().await;
+        let outputs = program.run_multi(&device, &queue).await.unwrap();
+        let as_u32s = |data: &[u8]| {
+            data.chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect::<Vec<u32>>()
+        };
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(
+            as_u32s(&outputs[0]),
+            a.iter().zip(&b).map(|(a, b)| a + b).collect::<Vec<u32>>()
+        );
+        assert_eq!(
+            as_u32s(&outputs[1]),
+            a.iter().zip(&b).map(|(a, b)| a * b).collect::<Vec<u32>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_newer_format_version_is_rejected_clearly() {
+        let program = multiply_program(3, &[0; 4]);
+        // A newer version that changed the type of a field, which on its own would be a confusing serde error
+        let mut from_the_future = serde_json::to_value(&program).unwrap();
+        from_the_future["format_version"] = (PROGRAM_FORMAT_VERSION + 1).into();
+        from_the_future["out_data_nbytes"] = "sixteen".into();
+
+        let path = std::env::temp_dir().join(format!(
+            "clustered-test-future-capsule-{}.json",
+            uuid::Uuid::now_v7()
+        ));
+        tokio::fs::write(&path, serde_json::to_vec(&from_the_future).unwrap())
+            .await
+            .unwrap();
+        let err = SerialisableProgram::load_from_file(&path)
+            .await
+            .unwrap_err();
+        let _ = tokio::fs::remove_file(&path).await;
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with(&format!(
+            "Program has format version {}",
+            PROGRAM_FORMAT_VERSION + 1
+        )));
+
+        let mut metadata =
+            serde_json::to_value(program.metadata(ResultCompression::Never)).unwrap();
+        metadata["format_version"] = (PROGRAM_FORMAT_VERSION + 1).into();
+        let raw_metadata = serde_json::to_vec(&metadata).unwrap();
+        let mut binary = (raw_metadata.len() as u64).to_le_bytes().to_vec();
+        binary.extend(raw_metadata);
+        binary.extend(&program.in_data);
+        assert!(SerialisableProgram::from_binary(&binary)
+            .unwrap_err()
+            .to_string()
+            .starts_with("Program has format version"));
+    }
+
+    #[tokio::test]
+    async fn test_run_into_chains_without_readback() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        let program = multiply_program(3, &(0..1024u32).collect::<Vec<u32>>());
+
+        let mut intermediate_buf =
+            crate::create_output_buffer(&device, 4096, BufferUsages::empty());
+        program
+            .run_into(&device, &queue, &mut intermediate_buf)
+            .unwrap();
+
+        // Run the same kernel again, straight on the first run's output
+        let mut out_buf = crate::create_output_buffer(&device, 4096, BufferUsages::empty());
+        program
+            .metadata(ResultCompression::Never)
+            .run_into_with_in_buf(&device, &queue, &intermediate_buf, &mut out_buf)
+            .unwrap();
+
+        assert_eq!(
+            crate::read_back_as::<u32>(&device, &queue, &out_buf).await,
+            Ok((0..1024u32).map(|i| i * 9).collect::<Vec<u32>>())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_local() {
+        let program = multiply_program(3, &(0..1000u32).collect::<Vec<u32>>());
+
+        let path = std::env::temp_dir().join(format!(
+            "clustered-test-capsule-{}.json",
+            uuid::Uuid::now_v7()
+        ));
+        program.save_to_file(&path).await.unwrap();
+        let loaded_program = SerialisableProgram::load_from_file(&path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(
+            loaded_program.run_local().await.unwrap(),
+            (0..1000u32)
+                .flat_map(|i| (i * 3).to_le_bytes())
+                .collect::<Vec<u8>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_run_matches_run() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        // Odd number of bytes, so the last streamed chunk has to be padded
+        let program = SerialisableProgram {
+            in_data: (0..4001u32).map(|i| (i % 251) as u8).collect(),
+            ..multiply_program(3, &[0; 1000])
+        };
+
+        let expected = program.run(&device, &queue).await;
+        assert!(expected.is_ok());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        for compress_in_data in [false, cfg!(feature = "compressed-transfers")] {
+            let sender_program = program.clone();
+            let sender = tokio::spawn(async move {
+                let mut connection = tokio::net::TcpStream::connect(listen_addr).await.unwrap();
+                sender_program
+                    .write_streamed(&mut connection, ResultCompression::Never, compress_in_data)
+                    .await
+                    .unwrap();
+            });
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let (metadata, in_buf) =
+                ProgramMetadata::read_streamed(&mut connection, &device, &queue)
+                    .await
+                    .unwrap();
+            sender.await.unwrap();
+
+            assert_eq!(
+                metadata,
+                ProgramMetadata {
+                    format_version: PROGRAM_FORMAT_VERSION,
+                    in_data_compressed: compress_in_data,
+                    ..program.metadata(ResultCompression::Never)
+                }
+            );
+            assert_eq!(
+                metadata.run_with_in_buf(&device, &queue, &in_buf).await,
+                expected
+            );
+        }
+    }
+
+    // Client -> server -> client, over an in-memory pipe but otherwise the same way TeleforkClient talks to telefork-server:
+    // the program goes out with write_streamed, in_data is streamed into a buffer on the server's (fallback) device and the result comes back as a ProgramResult
+    #[tokio::test]
+    async fn test_telefork_round_trip() {
+        let program = multiply_program(3, &(0..1024u32).collect::<Vec<u32>>());
+
+        let (mut client_end, mut server_end) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let instance = wgpu::Instance::new(InstanceDescriptor::default());
+            let adapter = instance
+                .request_adapter(&RequestAdapterOptions {
+                    compatible_surface: None,
+                    force_fallback_adapter: true,
+                    power_preference: wgpu::PowerPreference::None,
+                })
+                .await
+                .expect("Fallback adapter must exist!");
+            let (device, queue) = adapter
+                .request_device(&DeviceDescriptor::default(), None)
+                .await
+                .expect("Device must exist!");
+
+            let (metadata, in_buf) =
+                ProgramMetadata::read_streamed(&mut server_end, &device, &queue)
+                    .await
+                    .unwrap();
+            let out_data = metadata
+                .run_with_in_buf(&device, &queue, &in_buf)
+                .await
+                .unwrap();
+            crate::telefork::ProgramResult {
+                elapsed_secs: 0.5,
+                out_data,
+            }
+            .write(
+                &mut server_end,
+                metadata.result_compression,
+                metadata.format_version,
+            )
+            .await
+            .unwrap();
+        });
+
+        program
+            .write_streamed(&mut client_end, ResultCompression::Always, false)
+            .await
+            .unwrap();
+        let res = crate::telefork::ProgramResult::read(&mut client_end, program.out_data_nbytes)
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        let expected = (0..1024u32)
+            .flat_map(|e| (e * 3).to_le_bytes())
             .collect::<Vec<u8>>();
-        Some(res)
+        assert_eq!(
+            res,
+            crate::telefork::ProgramResult {
+                elapsed_secs: 0.5,
+                out_data: expected
+            }
+        );
+    }
+
+    #[test]
+    fn test_builder_validates_program() {
+        let builder = || {
+            SerialisableProgram::builder()
+                .in_data(vec![0u8; 64])
+                .out_data_nbytes(64)
+                .out_elem_nbytes(4)
+                .program("@compute @workgroup_size(32) fn main() {}")
+                .entry_point("main")
+                .n_workgroups(1)
+        };
+
+        assert!(builder().workgroup_size(32).build().is_ok());
+        let err = builder().workgroup_size(64).build().unwrap_err();
+        assert_eq!(
+            err,
+            ProgramBuildError::WorkgroupSizeMismatch {
+                declared: [32, 1, 1],
+                workgroup_size: 64
+            }
+        );
+        assert_eq!(
+            builder()
+                .workgroup_size(32)
+                .entry_point("not_main")
+                .build()
+                .unwrap_err(),
+            ProgramBuildError::EntryPointNotFound("not_main".to_owned())
+        );
+        assert_eq!(
+            builder()
+                .workgroup_size(32)
+                .out_data_nbytes(63)
+                .build()
+                .unwrap_err(),
+            ProgramBuildError::OutputNotMultipleOfElem {
+                out_data_nbytes: 63,
+                out_elem_nbytes: 4
+            }
+        );
+        assert!(matches!(
+            builder()
+                .workgroup_size(32)
+                .program("this isn't wgsl")
+                .build(),
+            Err(ProgramBuildError::InvalidWgsl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_program_is_an_error() {
+        let (device, queue) = crate::test_support::default_device().await;
+
+        // What a malformed capsule from the network might look like, this must not panic the runner
+        let program = SerialisableProgram {
+            program: "this isn't wgsl".to_owned(),
+            ..multiply_program(3, &[0; 32])
+        };
+        assert!(matches!(
+            program.run(&device, &queue).await,
+            Err(crate::RunShaderError::PipelineCreation(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_output_size() {
+        let program = |out_data_nbytes, n_workgroups| SerialisableProgram {
+            out_data_nbytes,
+            program: r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<vec2<u32>>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = vec2(v_in_data[actual_id]);
+                }
+            "#
+            .to_owned(),
+            n_workgroups,
+            ..multiply_program(3, &[0; 1000])
+        };
+
+        // 1000 elements of 8 bytes, the last of the 32 workgroups is partially idle, that's fine
+        assert_eq!(program(8000, 32).check_output_size(), Ok(()));
+        // Sized as if the elements were u32s
+        assert_eq!(
+            program(4000, 32).check_output_size(),
+            Err(OutputTooSmall {
+                out_data_nbytes: 4000,
+                min_expected_nbytes: 7944,
+                out_elem_nbytes: 8
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let program = multiply_program(3, &[0; 1024]);
+        let limits = wgpu::Limits::default();
+        assert_eq!(program.validate(&limits), Ok(()));
+        assert_eq!(
+            program.metadata(ResultCompression::Never).validate(&limits),
+            Ok(())
+        );
+
+        let invalid = |program: SerialisableProgram| program.validate(&limits).unwrap_err();
+        assert_eq!(
+            invalid(SerialisableProgram {
+                entry_point: String::new(),
+                ..program.clone()
+            }),
+            ProgramValidationError::EmptyEntryPoint
+        );
+        assert_eq!(
+            invalid(SerialisableProgram {
+                workgroup_size: 0,
+                ..program.clone()
+            }),
+            ProgramValidationError::ZeroWorkgroupSize
+        );
+        assert_eq!(
+            invalid(SerialisableProgram {
+                workgroup_size: usize::MAX,
+                ..program.clone()
+            }),
+            ProgramValidationError::WorkgroupTooLarge {
+                workgroup_size: usize::MAX,
+                max: 256
+            }
+        );
+        assert_eq!(
+            invalid(SerialisableProgram {
+                n_workgroups: usize::MAX / 16,
+                ..program.clone()
+            }),
+            ProgramValidationError::TooManyInvocations {
+                n_workgroups: usize::MAX / 16,
+                workgroup_size: 32
+            }
+        );
+        // Reading it back would trip wgpu's validation, which panics
+        assert_eq!(
+            invalid(SerialisableProgram {
+                extra_out_data_nbytes: vec![5],
+                ..program.clone()
+            }),
+            ProgramValidationError::UnalignedOutput { nbytes: 5 }
+        );
+        // Would otherwise be allocated as is on the receiving end
+        assert_eq!(
+            invalid(SerialisableProgram {
+                out_data_nbytes: usize::MAX,
+                ..program
+            }),
+            ProgramValidationError::OutputTooLarge {
+                nbytes: u64::try_from(usize::MAX).unwrap(),
+                max_nbytes: u64::from(limits.max_storage_buffer_binding_size)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_missing_file() {
+        let path = std::env::temp_dir().join(format!(
+            "clustered-test-missing-capsule-{}.json",
+            uuid::Uuid::now_v7()
+        ));
+        let err = SerialisableProgram::load_from_file(&path)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_compatibility() {
+        let (device, _queue) = crate::test_support::default_device().await;
+        let program = multiply_program(3, &[0; 1024]);
+        assert_eq!(program.compatibility(&device), Compatibility::default());
+
+        let small_limits = wgpu::Limits {
+            max_storage_buffer_binding_size: 1024,
+            ..wgpu::Limits::default()
+        };
+        let compatibility = program.compatibility_with(wgpu::Features::empty(), &small_limits);
+        assert!(!compatibility.is_compatible());
+        assert_eq!(
+            compatibility.incompatibilities,
+            vec![Incompatibility::Validation(
+                ProgramValidationError::InputTooLarge {
+                    nbytes: 4096,
+                    max_nbytes: 1024
+                }
+            )]
+        );
+        let small_limits = wgpu::Limits {
+            max_compute_workgroup_size_x: 16,
+            ..wgpu::Limits::default()
+        };
+        assert_eq!(
+            program
+                .compatibility_with(wgpu::Features::empty(), &small_limits)
+                .incompatibilities,
+            vec![Incompatibility::Validation(
+                ProgramValidationError::WorkgroupTooLarge {
+                    workgroup_size: 32,
+                    max: 16
+                }
+            )]
+        );
+
+        let int64_program = SerialisableProgram {
+            program: program.program.replace(
+                "v_in_data[actual_id] * 3u",
+                "u32(u64(v_in_data[actual_id]) * u64(3u))",
+            ),
+            ..program.clone()
+        };
+        assert_eq!(
+            int64_program
+                .compatibility_with(wgpu::Features::empty(), &wgpu::Limits::default())
+                .incompatibilities,
+            vec![Incompatibility::MissingFeatures(
+                wgpu::Features::SHADER_INT64
+            )]
+        );
+
+        let invalid_program = SerialisableProgram {
+            program: "not wgsl".to_owned(),
+            ..program
+        };
+        assert!(matches!(
+            invalid_program.compatibility(&device).incompatibilities[..],
+            [Incompatibility::InvalidProgram(_)]
+        ));
     }
 }
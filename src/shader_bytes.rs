@@ -1,5 +1,8 @@
 use std::borrow::Cow;
 
+// #[derive(ShaderBytes)] implements the traits below for structs, with WGSL's member alignment and padding
+pub use clustered_derive::ShaderBytes;
+
 pub trait ShaderBytesInfo {
     // NOTE: By *not* taking a self we explicitly disallow dynamically sized types and unsized types
     // Because working with consistently sized types is overall better (opinion)
@@ -22,6 +25,33 @@ pub unsafe trait FromShaderBytes: ShaderBytesInfo {
     fn from_shader_bytes(buf: &[u8]) -> Self;
 }
 
+// The type's name in WGSL, for helpers that generate the shader around the element type, see crate::gpu_map
+// NOTE: Only scalars implement it, #[derive(ShaderBytes)] structs would need their WGSL declaration generated too
+pub trait WgslTypeName {
+    const WGSL_TYPE_NAME: &'static str;
+}
+
+impl WgslTypeName for u32 {
+    const WGSL_TYPE_NAME: &'static str = "u32";
+}
+
+impl WgslTypeName for i32 {
+    const WGSL_TYPE_NAME: &'static str = "i32";
+}
+
+impl WgslTypeName for f32 {
+    const WGSL_TYPE_NAME: &'static str = "f32";
+}
+
+// NOTE: Needs a device with Features::SHADER_INT64, see impl_shader_bytes_for_64_bit
+impl WgslTypeName for u64 {
+    const WGSL_TYPE_NAME: &'static str = "u64";
+}
+
+impl WgslTypeName for i64 {
+    const WGSL_TYPE_NAME: &'static str = "i64";
+}
+
 impl ShaderBytesInfo for u32 {
     fn shader_bytes_size() -> usize {
         core::mem::size_of::<Self>()
@@ -98,10 +128,270 @@ unsafe impl FromShaderBytes for f32 {
     }
 }
 
+// wgsl's u64/i64, laid out as two u32 words, the low word first, i.e. as a vec2<u32> of (low, high) would be
+// NOTE: Needs a device with Features::SHADER_INT64 (naga's Capabilities::SHADER_INT64), unlike every other type here
+macro_rules! impl_shader_bytes_for_64_bit {
+    ($int:ty) => {
+        impl ShaderBytesInfo for $int {
+            fn shader_bytes_size() -> usize {
+                core::mem::size_of::<Self>()
+            }
+            fn shader_bytes_align() -> usize {
+                core::mem::size_of::<Self>()
+            }
+        }
+
+        unsafe impl IntoShaderBytes for $int {
+            fn to_shader_bytes(&self, res: &mut [u8]) {
+                // Little endian bytes are already the low word followed by the high word, each little endian
+                res.copy_from_slice(&self.to_le_bytes());
+            }
+        }
+
+        unsafe impl FromShaderBytes for $int {
+            fn from_shader_bytes(buf: &[u8]) -> Self {
+                Self::from_le_bytes(buf.try_into().unwrap())
+            }
+        }
+    };
+}
+
+impl_shader_bytes_for_64_bit!(u64);
+impl_shader_bytes_for_64_bit!(i64);
+
+// There are no 8 or 16-bit storage types in wgsl (without extensions), so u8 and u16 don't implement the traits,
+// instead they get packed into u32s, little endian (element 0 in the lowest bits), which is what the shader's
+// extractBits(word, 8u * (i % 4u), 8u) or unpack4xU8 (and extractBits(word, 16u * (i % 2u), 16u) for u16) expect.
+// The last word is padded with zeroes, so unpacking needs the original length.
+pub fn pack_u8_slice(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|chunk| {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+pub fn unpack_u8_slice(packed: &[u32], len: usize) -> Vec<u8> {
+    packed
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take(len)
+        .collect()
+}
+
+pub fn pack_u16_slice(data: &[u16]) -> Vec<u32> {
+    data.chunks(2)
+        .map(|chunk| u32::from(chunk[0]) | chunk.get(1).map_or(0, |high| u32::from(*high) << 16))
+        .collect()
+}
+
+pub fn unpack_u16_slice(packed: &[u32], len: usize) -> Vec<u16> {
+    packed
+        .iter()
+        .flat_map(|word| [*word as u16, (word >> 16) as u16])
+        .take(len)
+        .collect()
+}
+
+// [f32; N] maps to wgsl's vecN<f32>, vec2 aligns to 8 bytes and both vec3 and vec4 align to 16,
+// so a vec3 in an array takes up 16 bytes, the last 4 being padding (zeroed when serialising, ignored when deserialising)
+macro_rules! impl_shader_bytes_for_f32_vec {
+    ($n:literal, $align:literal) => {
+        impl ShaderBytesInfo for [f32; $n] {
+            fn shader_bytes_size() -> usize {
+                core::mem::size_of::<Self>()
+            }
+            fn shader_bytes_align() -> usize {
+                $align
+            }
+        }
+
+        unsafe impl IntoShaderBytes for [f32; $n] {
+            fn to_shader_bytes(&self, res: &mut [u8]) {
+                for (elem, raw_elem) in self
+                    .iter()
+                    .zip(res.chunks_exact_mut(core::mem::size_of::<f32>()))
+                {
+                    elem.to_shader_bytes(raw_elem);
+                }
+            }
+        }
+
+        unsafe impl FromShaderBytes for [f32; $n] {
+            fn from_shader_bytes(buf: &[u8]) -> Self {
+                let mut res = [0f32; $n];
+                for (elem, raw_elem) in res
+                    .iter_mut()
+                    .zip(buf.chunks_exact(core::mem::size_of::<f32>()))
+                {
+                    *elem = f32::from_shader_bytes(raw_elem);
+                }
+                res
+            }
+        }
+    };
+}
+
+impl_shader_bytes_for_f32_vec!(2, 8);
+impl_shader_bytes_for_f32_vec!(3, 16);
+impl_shader_bytes_for_f32_vec!(4, 16);
+
+// Host side stand-in for wgsl's atomic<u32> and atomic<i32>, these have the exact same memory layout as the scalar they wrap
+// so this is purely about intent, it makes it obvious (and checkable) that a buffer is meant for an atomic-expecting shader
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(transparent)]
+pub struct Atomic<T>(pub T);
+
+// NOTE: Wgsl only allows atomics of u32 and i32
+pub trait AtomicScalar: IntoShaderBytes + FromShaderBytes {}
+impl AtomicScalar for u32 {}
+impl AtomicScalar for i32 {}
+
+impl<T: AtomicScalar> ShaderBytesInfo for Atomic<T> {
+    fn shader_bytes_size() -> usize {
+        T::shader_bytes_size()
+    }
+    fn shader_bytes_align() -> usize {
+        T::shader_bytes_align()
+    }
+}
+
+unsafe impl<T: AtomicScalar> IntoShaderBytes for Atomic<T> {
+    fn to_shader_bytes(&self, res: &mut [u8]) {
+        self.0.to_shader_bytes(res)
+    }
+}
+
+unsafe impl<T: AtomicScalar> FromShaderBytes for Atomic<T> {
+    fn from_shader_bytes(buf: &[u8]) -> Self {
+        Atomic(T::from_shader_bytes(buf))
+    }
+}
+
+// Normalised integers, e.g. colour channels: a float in [0, 1] (unorm) or [-1, 1] (snorm) stored as a narrow integer,
+// converted the same way the gpu does for normalised texture formats (see https://www.w3.org/TR/WGSL/#pack4x8unorm-builtin):
+// scaled by the integer's max, rounded to nearest and saturated, so out of range values clamp and NaN becomes 0.
+// These take up exactly their integer's size in the buffer, WGSL has no 8/16-bit storage types though,
+// so the shader reads them 4 (or 2) at a time from an array<u32> with unpack4x8unorm/unpack4x8snorm (or unpack2x16unorm/unpack2x16snorm)
+// NOTE: Storage buffer bindings need a size that's a multiple of 4 bytes, pad the slice out accordingly
+macro_rules! impl_normalised {
+    ($name:ident, $int:ty, $min:literal) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub struct $name(pub $int);
+
+        impl $name {
+            const SCALE: f32 = <$int>::MAX as f32;
+
+            pub fn from_f32(value: f32) -> Self {
+                // `as` saturates and maps NaN to 0
+                Self((value.clamp($min, 1.0) * Self::SCALE).round() as $int)
+            }
+
+            pub fn to_f32(self) -> f32 {
+                // The most negative snorm value is one step past -1, and also means -1
+                (f32::from(self.0) / Self::SCALE).max($min)
+            }
+        }
+
+        impl From<f32> for $name {
+            fn from(value: f32) -> Self {
+                Self::from_f32(value)
+            }
+        }
+
+        impl From<$name> for f32 {
+            fn from(value: $name) -> Self {
+                value.to_f32()
+            }
+        }
+
+        impl ShaderBytesInfo for $name {
+            fn shader_bytes_size() -> usize {
+                core::mem::size_of::<$int>()
+            }
+            fn shader_bytes_align() -> usize {
+                core::mem::size_of::<$int>()
+            }
+        }
+
+        unsafe impl IntoShaderBytes for $name {
+            fn to_shader_bytes(&self, res: &mut [u8]) {
+                res.copy_from_slice(&self.0.to_le_bytes());
+            }
+        }
+
+        unsafe impl FromShaderBytes for $name {
+            fn from_shader_bytes(buf: &[u8]) -> Self {
+                Self(<$int>::from_le_bytes(buf.try_into().unwrap()))
+            }
+        }
+    };
+}
+
+impl_normalised!(Unorm8, u8, 0.0);
+impl_normalised!(Snorm8, i8, -1.0);
+impl_normalised!(Unorm16, u16, 0.0);
+impl_normalised!(Snorm16, i16, -1.0);
+
 pub struct ShaderBytes<'a> {
     inner: Cow<'a, [u8]>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShaderBytesError {
+    // Trailing bytes that don't make up a whole element, the data is shorter (or padded) compared to what the shader was expected to write
+    LengthNotMultipleOfStride { len: usize, stride: usize },
+}
+
+impl std::fmt::Display for ShaderBytesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderBytesError::LengthNotMultipleOfStride { len, stride } => write!(
+                f,
+                "Got {len} bytes, which isn't a whole number of {stride} byte elements ({} bytes left over)",
+                len % stride
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ShaderBytesError {}
+
+// Lazily deserialises one element per step, straight from the bytes it borrows, nothing is collected anywhere,
+// so it can be consumed as is (summed, written out, ...) without ever holding a Vec<T> of the whole data
+pub struct ShaderBytesIter<'a, T> {
+    chunks: std::slice::ChunksExact<'a, u8>,
+    _elem: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<'a, T: FromShaderBytes> ShaderBytesIter<'a, T> {
+    // Every element takes up stride bytes, of which only the first T::shader_bytes_size() are T's
+    fn new(data: &'a [u8], stride: usize) -> Self {
+        Self {
+            chunks: data.chunks_exact(stride),
+            _elem: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T: FromShaderBytes> Iterator for ShaderBytesIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.chunks
+            .next()
+            .map(|raw_bytes| T::from_shader_bytes(&raw_bytes[..T::shader_bytes_size()]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl<T: FromShaderBytes> ExactSizeIterator for ShaderBytesIter<'_, T> {}
+
 impl<'a> ShaderBytes<'a> {
     pub fn get_data(&self) -> &[u8] {
         &self.inner
@@ -136,13 +426,320 @@ impl<'a> ShaderBytes<'a> {
         }
     }
 
-    pub fn deserialise_to_iterator<T>(data: &[u8]) -> impl Iterator<Item = T> + '_
+    // Lazy, see ShaderBytesIter, for a gpu buffer see crate::with_read_back_iter which skips the intermediate Vec<u8> too
+    // NOTE: Trailing bytes that don't make up a whole element are silently ignored,
+    //       prefer deserialise_to_vec unless the data really has to be streamed
+    pub fn deserialise_to_iterator<T>(data: &[u8]) -> ShaderBytesIter<'_, T>
     where
         T: FromShaderBytes,
     {
         let stride: usize =
             usize::next_multiple_of(T::shader_bytes_size(), T::shader_bytes_align());
-        data.chunks_exact(stride)
-            .map(|raw_bytes| T::from_shader_bytes(raw_bytes))
+        ShaderBytesIter::new(data, stride)
+    }
+
+    // Same as deserialise_to_iterator, but data has to be a whole number of elements,
+    // catching outputs that are shorter than expected or have a stride other than T's
+    pub fn deserialise_to_vec<T>(data: &[u8]) -> Result<Vec<T>, ShaderBytesError>
+    where
+        T: FromShaderBytes,
+    {
+        let stride: usize =
+            usize::next_multiple_of(T::shader_bytes_size(), T::shader_bytes_align());
+        if data.len() % stride != 0 {
+            return Err(ShaderBytesError::LengthNotMultipleOfStride {
+                len: data.len(),
+                stride,
+            });
+        }
+        Ok(Self::deserialise_to_iterator(data).collect())
+    }
+
+    // Same as deserialise_to_iterator, but every element takes up stride bytes (of which only the start is T),
+    // for outputs whose gpu layout pads each element out further than T's own stride,
+    // e.g. an array of structs with members after the one being read, or an explicitly padded struct
+    pub fn deserialise_with_stride<T>(data: &[u8], stride: usize) -> ShaderBytesIter<'_, T>
+    where
+        T: FromShaderBytes,
+    {
+        assert!(
+            stride >= T::shader_bytes_size(),
+            "Stride ({stride}) must be at least the size of the element ({})",
+            T::shader_bytes_size()
+        );
+        ShaderBytesIter::new(data, stride)
+    }
+}
+
+fn check_layout<T>(type_name: &str, value: T, expected: &[u8]) -> Result<(), String>
+where
+    T: IntoShaderBytes + FromShaderBytes + PartialEq + std::fmt::Debug,
+{
+    if T::shader_bytes_size() != expected.len() || T::shader_bytes_align() != expected.len() {
+        return Err(format!(
+            "{type_name} has size {} and align {}, but WGSL expects both to be {}",
+            T::shader_bytes_size(),
+            T::shader_bytes_align(),
+            expected.len()
+        ));
+    }
+
+    let serialised = ShaderBytes::serialise_from_slice(std::slice::from_ref(&value));
+    if serialised.get_data() != expected {
+        return Err(format!(
+            "{type_name} {value:?} serialised to {:02X?}, but WGSL's little-endian layout is {expected:02X?}",
+            serialised.get_data()
+        ));
+    }
+
+    let deserialised = T::from_shader_bytes(expected);
+    if deserialised != value {
+        return Err(format!(
+            "{type_name} bytes {expected:02X?} deserialised to {deserialised:?}, expected {value:?}"
+        ));
+    }
+    Ok(())
+}
+
+// Checks the ShaderBytes impls against hardcoded WGSL layouts, turning the endianness/representation assumptions above
+// into something that can be checked at runtime, e.g. by a peer before it accepts any work
+pub fn layout_self_check() -> Result<(), String> {
+    check_layout("u32", 0x0102_0304u32, &[0x04, 0x03, 0x02, 0x01])?;
+    check_layout("u32", u32::MAX, &[0xFF, 0xFF, 0xFF, 0xFF])?;
+    // Two's complement
+    check_layout("i32", -2i32, &[0xFE, 0xFF, 0xFF, 0xFF])?;
+    check_layout("i32", i32::MIN, &[0x00, 0x00, 0x00, 0x80])?;
+    // IEEE 754 binary32
+    check_layout("f32", 1.5f32, &[0x00, 0x00, 0xC0, 0x3F])?;
+    check_layout("f32", -0.0f32, &[0x00, 0x00, 0x00, 0x80])?;
+    check_layout(
+        "Atomic<u32>",
+        Atomic(0xDEAD_BEEFu32),
+        &[0xEF, 0xBE, 0xAD, 0xDE],
+    )?;
+    check_layout("Atomic<i32>", Atomic(-1i32), &[0xFF, 0xFF, 0xFF, 0xFF])?;
+    check_layout(
+        "u64",
+        0x0102_0304_0506_0708u64,
+        &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01],
+    )?;
+    check_layout(
+        "i64",
+        -2i64,
+        &[0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+    )?;
+    check_layout("Unorm8", Unorm8::from_f32(1.0), &[0xFF])?;
+    check_layout("Snorm16", Snorm16::from_f32(-1.0), &[0x01, 0x80])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_self_check() {
+        assert_eq!(layout_self_check(), Ok(()));
+    }
+
+    #[test]
+    fn test_f32_vec_round_trip() {
+        let vec2s = [[1.0f32, -2.0], [0.5, 1e9]];
+        let serialised = ShaderBytes::serialise_from_slice(&vec2s);
+        assert_eq!(serialised.get_data().len(), 2 * 8);
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<[f32; 2]>(serialised.get_data())
+                .eq(vec2s.into_iter())
+        );
+
+        let vec3s = [[1.0f32, 2.0, 3.0], [-4.0, 5.5, f32::MAX]];
+        let serialised = ShaderBytes::serialise_from_slice(&vec3s);
+        // Every vec3 is padded out to 16 bytes
+        assert_eq!(serialised.get_data().len(), 2 * 16);
+        assert_eq!(&serialised.get_data()[12..16], &[0u8; 4]);
+        assert_eq!(&serialised.get_data()[16..20], &(-4.0f32).to_le_bytes()[..]);
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<[f32; 3]>(serialised.get_data())
+                .eq(vec3s.into_iter())
+        );
+        // Whatever the shader left in the padding doesn't matter
+        let mut raw = serialised.get_data().to_vec();
+        raw[12..16].copy_from_slice(&[0xFF; 4]);
+        assert!(ShaderBytes::deserialise_to_iterator::<[f32; 3]>(&raw).eq(vec3s.into_iter()));
+
+        let vec4s = [[1.0f32, 2.0, 3.0, 4.0]];
+        let serialised = ShaderBytes::serialise_from_slice(&vec4s);
+        assert_eq!(serialised.get_data().len(), 16);
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<[f32; 4]>(serialised.get_data())
+                .eq(vec4s.into_iter())
+        );
+    }
+
+    #[derive(ShaderBytes, Debug, Clone, Copy, PartialEq)]
+    struct Light {
+        id: u32,
+        position: [f32; 3],
+        intensity: f32,
+    }
+
+    #[test]
+    fn test_derived_struct_layout() {
+        // id at 0, position bumped from 4 to 16 because vec3 aligns to 16, intensity right after it at 28,
+        // the size (32) is already a multiple of the struct's alignment (16)
+        assert_eq!(Light::shader_bytes_align(), 16);
+        assert_eq!(Light::shader_bytes_size(), 32);
+
+        let lights = [
+            Light {
+                id: 7,
+                position: [1.0, 2.0, 3.0],
+                intensity: 0.5,
+            },
+            Light {
+                id: u32::MAX,
+                position: [-1.0, 0.0, 1e9],
+                intensity: 2.0,
+            },
+        ];
+        let serialised = ShaderBytes::serialise_from_slice(&lights);
+        let raw = serialised.get_data();
+        assert_eq!(raw.len(), 2 * 32);
+        assert_eq!(&raw[0..4], &7u32.to_le_bytes());
+        assert_eq!(&raw[4..16], &[0u8; 12]);
+        assert_eq!(&raw[16..20], &1.0f32.to_le_bytes());
+        assert_eq!(&raw[28..32], &0.5f32.to_le_bytes());
+        assert_eq!(&raw[32..36], &u32::MAX.to_le_bytes());
+        assert!(ShaderBytes::deserialise_to_iterator::<Light>(raw).eq(lights.into_iter()));
+    }
+
+    #[test]
+    fn test_atomic_layout_matches_scalar() {
+        let plain_u32 = [0u32, 1, 0xDEAD_BEEF, u32::MAX];
+        let atomic_u32 = plain_u32.map(Atomic);
+        assert_eq!(
+            ShaderBytes::serialise_from_slice(&plain_u32).get_data(),
+            ShaderBytes::serialise_from_slice(&atomic_u32).get_data()
+        );
+
+        let plain_i32 = [0i32, -1, i32::MIN, i32::MAX];
+        let atomic_i32 = plain_i32.map(Atomic);
+        let serialised = ShaderBytes::serialise_from_slice(&atomic_i32);
+        assert_eq!(
+            ShaderBytes::serialise_from_slice(&plain_i32).get_data(),
+            serialised.get_data()
+        );
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<Atomic<i32>>(serialised.get_data())
+                .eq(atomic_i32.into_iter())
+        );
+    }
+
+    #[test]
+    fn test_deserialise_with_stride() {
+        // What a shader writing array<Slot> with struct Slot { position: vec3<f32>, flag: u32, weight: f32 } leaves behind,
+        // size 32 because the struct aligns to 16
+        let mut raw = Vec::new();
+        for i in 0..3u32 {
+            let position = [i as f32, i as f32 + 0.5, -(i as f32)];
+            raw.extend(ShaderBytes::serialise_from_slice(&[position]).get_data());
+            raw.extend(&[0xAA; 16]);
+        }
+        assert_eq!(
+            ShaderBytes::deserialise_with_stride::<[f32; 3]>(&raw, 32).collect::<Vec<_>>(),
+            vec![[0.0, 0.5, -0.0], [1.0, 1.5, -1.0], [2.0, 2.5, -2.0]]
+        );
+    }
+
+    #[test]
+    fn test_normalised_round_trip() {
+        // Within half a step of the format's precision (plus float rounding)
+        for i in 0..=1000 {
+            let value = i as f32 / 1000.0;
+            assert!((Unorm8::from_f32(value).to_f32() - value).abs() <= 0.5 / 255.0 + f32::EPSILON);
+            assert!(
+                (Unorm16::from_f32(value).to_f32() - value).abs() <= 0.5 / 65535.0 + f32::EPSILON
+            );
+            let signed = value * 2.0 - 1.0;
+            assert!(
+                (Snorm8::from_f32(signed).to_f32() - signed).abs() <= 0.5 / 127.0 + f32::EPSILON
+            );
+            assert!(
+                (Snorm16::from_f32(signed).to_f32() - signed).abs() <= 0.5 / 32767.0 + f32::EPSILON
+            );
+        }
+
+        // Saturating
+        assert_eq!(Unorm8::from_f32(1.5), Unorm8(255));
+        assert_eq!(Unorm8::from_f32(-0.5), Unorm8(0));
+        assert_eq!(Unorm8::from_f32(f32::NAN), Unorm8(0));
+        assert_eq!(Snorm8::from_f32(-2.0), Snorm8(-127));
+        assert_eq!(Snorm8(i8::MIN).to_f32(), -1.0);
+
+        // Tightly packed, the bytes of an array<u32> element that unpack4x8unorm turns into (0, 1/255, 128/255, 1)
+        let pixel = [0.0, 1.0 / 255.0, 128.0 / 255.0, 1.0].map(Unorm8::from_f32);
+        let serialised = ShaderBytes::serialise_from_slice(&pixel);
+        assert_eq!(serialised.get_data(), &[0, 1, 128, 255]);
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<Unorm8>(serialised.get_data())
+                .eq(pixel.into_iter())
+        );
+    }
+
+    #[test]
+    fn test_64_bit_word_order() {
+        let values = [0x1122_3344_5566_7788u64, u64::MAX, 1];
+        let serialised = ShaderBytes::serialise_from_slice(&values);
+        // What the shader would see reading the same buffer as array<vec2<u32>>, (low, high) for each element
+        assert_eq!(
+            ShaderBytes::deserialise_to_iterator::<u32>(serialised.get_data()).collect::<Vec<_>>(),
+            vec![0x5566_7788, 0x1122_3344, u32::MAX, u32::MAX, 1, 0]
+        );
+        assert!(
+            ShaderBytes::deserialise_to_iterator::<u64>(serialised.get_data())
+                .eq(values.into_iter())
+        );
+
+        let serialised = ShaderBytes::serialise_from_slice(&[-1i64, i64::MIN]);
+        assert_eq!(
+            ShaderBytes::deserialise_to_iterator::<u32>(serialised.get_data()).collect::<Vec<_>>(),
+            vec![u32::MAX, u32::MAX, 0, 0x8000_0000]
+        );
+    }
+
+    #[test]
+    fn test_pack_narrow_slices() {
+        let bytes = [1u8, 2, 3, 4, 5, 6];
+        let packed = pack_u8_slice(&bytes);
+        assert_eq!(packed, vec![0x0403_0201, 0x0000_0605]);
+        assert_eq!(unpack_u8_slice(&packed, bytes.len()), bytes);
+
+        let halves = [0x1111u16, 0x2222, 0xFFFF];
+        let packed = pack_u16_slice(&halves);
+        assert_eq!(packed, vec![0x2222_1111, 0x0000_FFFF]);
+        assert_eq!(unpack_u16_slice(&packed, halves.len()), halves);
+    }
+
+    #[test]
+    fn test_deserialise_to_vec_rejects_partial_elements() {
+        let vec3s = [[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]];
+        let serialised = ShaderBytes::serialise_from_slice(&vec3s);
+        assert_eq!(
+            ShaderBytes::deserialise_to_vec::<[f32; 3]>(serialised.get_data()),
+            Ok(vec3s.to_vec())
+        );
+        // Read as if vec3s were tightly packed (12 byte stride), the last element's padding is missing
+        let raw = &serialised.get_data()[..28];
+        assert_eq!(
+            ShaderBytes::deserialise_to_iterator::<[f32; 3]>(raw).count(),
+            1
+        );
+        assert_eq!(
+            ShaderBytes::deserialise_to_vec::<[f32; 3]>(raw),
+            Err(ShaderBytesError::LengthNotMultipleOfStride {
+                len: 28,
+                stride: 16
+            })
+        );
     }
 }
@@ -0,0 +1,293 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{compression::ResultCompression, serialisable_program::SerialisableProgram};
+
+// Programs sent as this format version or newer get elapsed_secs back after their result, older ones only get the result
+pub const TIMED_RESULT_FORMAT_VERSION: u32 = 4;
+
+// What telefork-server sends back for every program it ran
+// A program that couldn't be run gets a compression::write_error instead, which ProgramResult::read returns as an error
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgramResult {
+    // How long the server took to run the program, not counting receiving it or sending this back
+    pub elapsed_secs: f32,
+    pub out_data: Vec<u8>,
+}
+
+impl ProgramResult {
+    // out_data goes first, as a compression::write_result, then elapsed_secs (f32),
+    // this way the error case is still just a compression::write_error
+    // format_version is the program's, clients older than TIMED_RESULT_FORMAT_VERSION don't expect elapsed_secs
+    pub async fn write(
+        &self,
+        connection: &mut (impl AsyncWrite + Unpin),
+        compression: ResultCompression,
+        format_version: u32,
+    ) -> io::Result<()> {
+        crate::compression::write_result(connection, &self.out_data, compression).await?;
+        if format_version < TIMED_RESULT_FORMAT_VERSION {
+            return Ok(());
+        }
+        connection.write_f32(self.elapsed_secs).await
+    }
+
+    // For programs sent with write_streamed, which are always of a format version with elapsed_secs
+    // out_data_nbytes is the program's, see compression::read_result
+    pub async fn read(
+        connection: &mut (impl AsyncRead + Unpin),
+        out_data_nbytes: usize,
+    ) -> io::Result<Self> {
+        let out_data = crate::compression::read_result(connection, out_data_nbytes).await?;
+        let elapsed_secs = connection.read_f32().await.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{err}\nWhile receiving how long the program took"),
+            )
+        })?;
+        Ok(Self {
+            elapsed_secs,
+            out_data,
+        })
+    }
+}
+
+// Client side of telefork-server, keeps one connection open across submissions
+// and transparently reconnects if the server (or something in between) dropped it
+pub struct TeleforkClient {
+    server_addr: SocketAddr,
+    connection: Option<TcpStream>,
+    // See SerialisableProgram::write_streamed, off by default as older servers can't read compressed programs
+    compress_in_data: bool,
+}
+
+impl TeleforkClient {
+    // Doesn't connect until the first submission
+    pub fn new(server_addr: SocketAddr) -> Self {
+        Self {
+            server_addr,
+            connection: None,
+            compress_in_data: false,
+        }
+    }
+
+    // Whether programs' in_data gets deflate compressed on the way to the server, worth it for big structured inputs
+    #[cfg(feature = "compressed-transfers")]
+    pub fn set_compress_in_data(&mut self, compress_in_data: bool) {
+        self.compress_in_data = compress_in_data;
+    }
+
+    // Connects straight away, so the first submission doesn't pay for the handshake
+    pub async fn connect(server_addr: SocketAddr) -> io::Result<Self> {
+        let mut client = Self::new(server_addr);
+        client.connection().await?;
+        Ok(client)
+    }
+
+    async fn connection(&mut self) -> io::Result<&mut TcpStream> {
+        if self.connection.is_none() {
+            let connection = TcpStream::connect(self.server_addr).await.map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!(
+                        "{err}\nWhile connecting to telefork server: {}",
+                        self.server_addr
+                    ),
+                )
+            })?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    async fn submit_once(
+        &mut self,
+        program: &SerialisableProgram,
+        result_compression: ResultCompression,
+    ) -> io::Result<ProgramResult> {
+        let compress_in_data = self.compress_in_data;
+        let connection = self.connection().await?;
+        program
+            .write_streamed(connection, result_compression, compress_in_data)
+            .await?;
+        ProgramResult::read(connection, program.out_data_nbytes).await
+    }
+
+    // Sends the program and waits for its result, which is checked to be out_data_nbytes long
+    // NOTE: If the connection turns out to be dead the program is resent over a new one,
+    //       so the server may end up running it twice
+    pub async fn submit(
+        &mut self,
+        program: &SerialisableProgram,
+        result_compression: ResultCompression,
+    ) -> io::Result<ProgramResult> {
+        let res = self.submit_with_retry(program, result_compression).await?;
+        if res.out_data.len() != program.out_data_nbytes {
+            // Whatever went wrong, the connection can't be trusted to be in sync anymore
+            self.connection = None;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Telefork server returned {} bytes of output, but the program has {} bytes of output",
+                    res.out_data.len(),
+                    program.out_data_nbytes
+                ),
+            ));
+        }
+        Ok(res)
+    }
+
+    async fn submit_with_retry(
+        &mut self,
+        program: &SerialisableProgram,
+        result_compression: ResultCompression,
+    ) -> io::Result<ProgramResult> {
+        match self.submit_once(program, result_compression).await {
+            Ok(res) => Ok(res),
+            Err(err) if crate::networking::was_connection_severed(err.kind()) => {
+                tracing::warn!("Lost connection to telefork server ({err}), reconnecting...");
+                self.connection = None;
+                self.submit_once(program, result_compression)
+                    .await
+                    .map_err(|err| {
+                        self.connection = None;
+                        io::Error::new(
+                            err.kind(),
+                            format!("{err}\nWhile resubmitting program after reconnecting"),
+                        )
+                    })
+            }
+            Err(err) => {
+                self.connection = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::serialisable_program::ProgramMetadata;
+
+    #[tokio::test]
+    async fn test_reuses_connection_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let n_connections = Arc::new(AtomicUsize::new(0));
+
+        // Echoes in_data back, the first connection is dropped after one job to force a reconnect
+        let server_n_connections = n_connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut connection, _) = listener.accept().await.unwrap();
+                let connection_id = server_n_connections.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    while let Ok(raw_metadata) = crate::networking::read_buf_limited(
+                        &mut connection,
+                        crate::networking::MAX_CONTROL_MESSAGE_LEN,
+                    )
+                    .await
+                    {
+                        let metadata: ProgramMetadata =
+                            serde_json::from_slice(&raw_metadata).unwrap();
+                        let mut in_data = vec![0u8; metadata.in_data_nbytes];
+                        connection.read_exact(&mut in_data).await.unwrap();
+                        ProgramResult {
+                            elapsed_secs: connection_id as f32,
+                            out_data: in_data,
+                        }
+                        .write(
+                            &mut connection,
+                            metadata.result_compression,
+                            metadata.format_version,
+                        )
+                        .await
+                        .unwrap();
+                        if connection_id == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut client = TeleforkClient::connect(server_addr).await.unwrap();
+        for job_id in 0..100u32 {
+            let program = SerialisableProgram {
+                in_data: job_id.to_le_bytes().to_vec(),
+                out_data_nbytes: 4,
+                program: String::new(),
+                entry_point: "main".to_owned(),
+                n_workgroups: 1,
+                workgroup_size: 1,
+                extra_in_data: Vec::new(),
+                extra_out_data_nbytes: Vec::new(),
+            };
+            assert_eq!(
+                client
+                    .submit(&program, ResultCompression::Never)
+                    .await
+                    .unwrap(),
+                ProgramResult {
+                    elapsed_secs: if job_id == 0 { 0.0 } else { 1.0 },
+                    out_data: job_id.to_le_bytes().to_vec(),
+                }
+            );
+        }
+        assert_eq!(n_connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_older_clients_get_no_elapsed_secs() {
+        let program_res = ProgramResult {
+            elapsed_secs: 1.5,
+            out_data: vec![1, 2, 3, 4],
+        };
+        let (mut client_end, mut server_end) = tokio::io::duplex(1024);
+        program_res
+            .write(
+                &mut server_end,
+                ResultCompression::Never,
+                TIMED_RESULT_FORMAT_VERSION - 1,
+            )
+            .await
+            .unwrap();
+        program_res
+            .write(
+                &mut server_end,
+                ResultCompression::Never,
+                TIMED_RESULT_FORMAT_VERSION,
+            )
+            .await
+            .unwrap();
+        drop(server_end);
+        assert_eq!(
+            crate::compression::read_result(&mut client_end, program_res.out_data.len())
+                .await
+                .unwrap(),
+            program_res.out_data
+        );
+        assert_eq!(
+            ProgramResult::read(&mut client_end, program_res.out_data.len())
+                .await
+                .unwrap(),
+            program_res
+        );
+        assert_eq!(
+            client_end.read_u8().await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
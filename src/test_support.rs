@@ -0,0 +1,101 @@
+use std::fmt::Debug;
+
+use wgpu::{DeviceDescriptor, InstanceDescriptor, RequestAdapterOptions};
+
+use crate::serialisable_program::SerialisableProgram;
+
+// NOTE: Not just #[cfg(test)], the bins' tests and the benchmarks use these too, see the test-support feature
+
+// Whatever adapter wgpu picks by default, panics if there's none
+pub async fn default_adapter() -> wgpu::Adapter {
+    wgpu::Instance::new(InstanceDescriptor::default())
+        .request_adapter(&RequestAdapterOptions::default())
+        .await
+        .expect("Adapter must exist!")
+}
+
+// A device with default features and limits on default_adapter, panics if it can't be created
+pub async fn default_device() -> (wgpu::Device, wgpu::Queue) {
+    default_adapter()
+        .await
+        .request_device(&DeviceDescriptor::default(), None)
+        .await
+        .expect("Device must exist!")
+}
+
+// Multiplies every u32 of in_data by factor, in workgroups of 32, tests override whatever field they're about
+pub fn multiply_program(factor: u32, in_data: &[u32]) -> SerialisableProgram {
+    SerialisableProgram {
+        in_data: in_data.iter().copied().flat_map(u32::to_le_bytes).collect(),
+        out_data_nbytes: std::mem::size_of_val(in_data),
+        program: format!(
+            r#"
+            @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+            @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+            @group(0) @binding(2) var<uniform> goff: u32;
+
+            @compute @workgroup_size(32)
+            fn main(@builtin(global_invocation_id) gid: vec3<u32>) {{
+                let actual_id = gid.x + goff;
+                if (actual_id >= arrayLength(&v_out_data)) {{ return; }}
+                v_out_data[actual_id] = v_in_data[actual_id] * {factor}u;
+            }}
+        "#
+        ),
+        entry_point: "main".to_owned(),
+        n_workgroups: in_data.len().div_ceil(32),
+        workgroup_size: 32,
+        extra_in_data: Vec::new(),
+        extra_out_data_nbytes: Vec::new(),
+    }
+}
+
+// Compares gpu results against cpu reference results, elements differing by more than tolerance are mismatches
+// Panics on any mismatch, with the first mismatching element and how many mismatches there are in total
+pub fn assert_results_close<T>(gpu: &[T], cpu: &[T], tolerance: f64)
+where
+    T: Copy + Into<f64> + Debug,
+{
+    assert_eq!(
+        gpu.len(),
+        cpu.len(),
+        "GPU produced {} results, but CPU produced {}!",
+        gpu.len(),
+        cpu.len()
+    );
+
+    let mut mismatches = gpu
+        .iter()
+        .zip(cpu.iter())
+        .enumerate()
+        .map(|(i, (gpu_elem, cpu_elem))| {
+            (
+                i,
+                gpu_elem,
+                cpu_elem,
+                ((*gpu_elem).into() - (*cpu_elem).into()).abs(),
+            )
+        })
+        .filter(|(_, _, _, diff)| diff.is_nan() || *diff > tolerance);
+    let Some((first_index, first_gpu_elem, first_cpu_elem, first_diff)) = mismatches.next() else {
+        return;
+    };
+    let n_mismatches = 1 + mismatches.count();
+    panic!(
+        "Mismatch at {first_index}!\nGPU said: {first_gpu_elem:?}!\nCPU said: {first_cpu_elem:?}!\nDifference: {first_diff} (tolerance {tolerance})\n{n_mismatches} out of {} elements mismatch in total!",
+        gpu.len()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "Mismatch at 2!")]
+    fn test_reports_first_mismatch() {
+        let cpu = [1.0f32, 2.0, 3.0, 4.0];
+        assert_results_close(&[1.0f32, 2.00001, 3.0, 4.0], &cpu, 0.0001);
+        assert_results_close(&[1.0f32, 2.0, 3.5, 5.0], &cpu, 0.0001);
+    }
+}
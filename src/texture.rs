@@ -0,0 +1,379 @@
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayoutDescriptor, BindGroupLayoutEntry,
+    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, ComputePipelineDescriptor, Device,
+    Extent3d, PipelineLayoutDescriptor, Queue, ShaderModule, ShaderStages, TextureDescriptor,
+    TextureFormat, TextureUsages, TextureViewDescriptor,
+};
+
+use crate::{tiles_3d, ChunkOffset, RunShaderError, ShaderDispatch};
+
+// Both are read (textureLoad) and written (textureStore) as vec4<f32> by the shader
+pub const SUPPORTED_TEXTURE_FORMATS: [TextureFormat; 2] =
+    [TextureFormat::Rgba8Unorm, TextureFormat::Rgba32Float];
+
+pub struct RunTextureShaderParams<'a> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    // Needs TEXTURE_BINDING usage
+    pub in_texture: &'a wgpu::Texture,
+    // Needs STORAGE_BINDING usage (and COPY_SRC for read_back_texture) and the same size as in_texture, see create_output_texture
+    pub out_texture: &'a wgpu::Texture,
+    // Has to match the shader's @workgroup_size(x, y)
+    pub workgroup_size: (u32, u32),
+    pub program: &'a ShaderModule,
+    pub entry_point: &'a str,
+}
+
+// Output textures need STORAGE_BINDING to be bound to the shader and COPY_SRC to be read back, see read_back_texture
+pub fn create_output_texture(
+    device: &Device,
+    (width, height): (u32, u32),
+    format: TextureFormat,
+) -> wgpu::Texture {
+    device.create_texture(&TextureDescriptor {
+        label: Some("Output texture"),
+        size: Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+fn check_format(format: TextureFormat) -> Result<(), RunShaderError> {
+    if SUPPORTED_TEXTURE_FORMATS.contains(&format) {
+        Ok(())
+    } else {
+        Err(RunShaderError::UnsupportedTextureFormat(format))
+    }
+}
+
+/* The image processing version of run_shader, with one invocation per pixel. The shader has to declare:
+   @group(0) @binding(0) var in_texture: texture_2d<f32>;
+   @group(0) @binding(1) var out_texture: texture_storage_2d<rgba8unorm, write>; (or rgba32float, whichever out_texture is)
+   @group(0) @binding(2) var<uniform> origin: vec3<u32>;
+   Like with RunShaderParams::dispatch_dims, the grid is split into tiles if the image needs more workgroups than
+   max_compute_workgroups_per_dimension, so the shader's pixel is gid.xy + origin.xy * vec2(workgroup_size).
+   NOTE: The last row/column of workgroups overhangs the image unless its size is a multiple of workgroup_size,
+         so the shader has to bounds check against textureDimensions.
+   See read_back_texture for copying the output out afterwards
+*/
+pub async fn run_texture_shader(params: RunTextureShaderParams<'_>) -> Result<(), RunShaderError> {
+    let device = params.device;
+    check_format(params.in_texture.format())?;
+    check_format(params.out_texture.format())?;
+    if params.workgroup_size.0 == 0 || params.workgroup_size.1 == 0 {
+        return Err(RunShaderError::ZeroWorkgroupLength);
+    }
+    let (width, height) = (params.in_texture.width(), params.in_texture.height());
+    // The dispatch is sized to the input, so a smaller output would silently drop pixels
+    let out_size = (params.out_texture.width(), params.out_texture.height());
+    if out_size != (width, height) {
+        return Err(RunShaderError::TextureSizeMismatch {
+            in_size: (width, height),
+            out_size,
+        });
+    }
+    let meta_buf = device.create_buffer(&BufferDescriptor {
+        label: Some("Metadata compute uniform buffer"),
+        size: 3 * core::mem::size_of::<u32>() as u64,
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    // A program that came off the network must not be able to panic us through wgpu's uncaptured error handler
+    let ((compute_pipeline, bind_group, empty_bind_group), scope_err) =
+        crate::with_error_scope(device, wgpu::ErrorFilter::Validation, || {
+            let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("Texture compute pipeline bind group layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        count: None,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Texture {
+                            // textureLoad only, so Rgba32Float not being filterable everywhere doesn't matter
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        count: None,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: params.out_texture.format(),
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                        },
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        count: None,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: Some(meta_buf.size().try_into().unwrap()),
+                        },
+                    },
+                ],
+            });
+            let compute_pipeline_layout =
+                device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    label: Some("Texture compute pipeline layout"),
+                    push_constant_ranges: &[],
+                });
+            let compute_pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+                entry_point: params.entry_point,
+                label: Some("Texture compute pipeline"),
+                layout: Some(&compute_pipeline_layout),
+                module: params.program,
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                cache: None,
+            });
+            let in_view = params
+                .in_texture
+                .create_view(&TextureViewDescriptor::default());
+            let out_view = params
+                .out_texture
+                .create_view(&TextureViewDescriptor::default());
+            let bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Texture compute bind group"),
+                layout: &bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&in_view),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&out_view),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: meta_buf.as_entire_binding(),
+                    },
+                ],
+            });
+            // Never bound as everything is in group 0, but ShaderDispatch wants one
+            let empty_bind_group = device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Empty bind group"),
+                layout: &device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: Some("Empty bind group layout"),
+                    entries: &[],
+                }),
+                entries: &[],
+            });
+            (compute_pipeline, bind_group, empty_bind_group)
+        });
+    if let Some(err) = scope_err.await {
+        return Err(RunShaderError::PipelineCreation(err.to_string()));
+    }
+
+    let n_workgroups = (
+        width.div_ceil(params.workgroup_size.0),
+        height.div_ceil(params.workgroup_size.1),
+        1,
+    );
+    let chunks = tiles_3d(
+        n_workgroups,
+        device.limits().max_compute_workgroups_per_dimension,
+    )
+    .into_iter()
+    .map(|(origin, how_many)| (ChunkOffset::Origin(origin), how_many))
+    .collect();
+    ShaderDispatch {
+        device,
+        queue: params.queue,
+        compute_pipeline,
+        bind_group_index: 0,
+        bind_group,
+        empty_bind_group,
+        meta_buf,
+        chunks,
+    }
+    .submit_all()
+    .await;
+    Ok(())
+}
+
+// Copies a 2D texture (which needs COPY_SRC) out into tightly packed rows, e.g. 4 bytes per pixel for Rgba8Unorm,
+// the padding wgpu needs at the end of every row of the copy is stripped
+pub async fn read_back_texture(
+    device: &Device,
+    queue: &Queue,
+    texture: &wgpu::Texture,
+) -> Result<Vec<u8>, wgpu::BufferAsyncError> {
+    let bytes_per_pixel = texture
+        .format()
+        .block_copy_size(None)
+        .expect("Texture must have a single copyable aspect!");
+    let row_len = texture.width() * bytes_per_pixel;
+    let padded_row_len = row_len.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+    let transfer_buf = crate::create_readback_buffer(
+        device,
+        u64::from(padded_row_len) * u64::from(texture.height()),
+        BufferUsages::empty(),
+    );
+
+    let mut enc = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    enc.copy_texture_to_buffer(
+        texture.as_image_copy(),
+        wgpu::ImageCopyBuffer {
+            buffer: &transfer_buf,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_len),
+                rows_per_image: Some(texture.height()),
+            },
+        },
+        Extent3d {
+            width: texture.width(),
+            height: texture.height(),
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit([enc.finish()]);
+
+    let transfer_view =
+        crate::wgpu_map_helper(device, wgpu::MapMode::Read, &transfer_buf, ..).await?;
+    let res = transfer_view
+        .get_mapped_range()
+        .chunks_exact(usize::try_from(padded_row_len).unwrap())
+        .flat_map(|row| &row[..usize::try_from(row_len).unwrap()])
+        .copied()
+        .collect();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use wgpu::{util::DeviceExt, ShaderModuleDescriptor};
+
+    use super::*;
+    use crate::shader_bytes::ShaderBytes;
+
+    #[tokio::test]
+    async fn test_run_texture_shader_swizzles_channels() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var in_texture: texture_2d<f32>;
+                @group(0) @binding(1) var out_texture: texture_storage_2d<rgba32float, write>;
+                @group(0) @binding(2) var<uniform> origin: vec3<u32>;
+
+                @compute @workgroup_size(8, 8)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let pos = gid.xy + origin.xy * vec2<u32>(8u, 8u);
+                    if (any(pos >= textureDimensions(in_texture))) { return; }
+                    textureStore(out_texture, pos, textureLoad(in_texture, pos, 0).bgra);
+                }
+            "#,
+            )),
+        });
+
+        // Not a multiple of the workgroup size on either axis
+        let (width, height) = (37u32, 21u32);
+        let pixels = (0..width * height)
+            .flat_map(|i| [(i % 256) as u8, (i / 7 % 256) as u8, 200, 255])
+            .collect::<Vec<u8>>();
+        let in_texture = device.create_texture_with_data(
+            &queue,
+            &TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: TextureFormat::Rgba8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            &pixels,
+        );
+
+        let out_texture =
+            create_output_texture(&device, (width, height), TextureFormat::Rgba32Float);
+        run_texture_shader(RunTextureShaderParams {
+            device: &device,
+            queue: &queue,
+            in_texture: &in_texture,
+            out_texture: &out_texture,
+            workgroup_size: (8, 8),
+            program: &cs_module,
+            entry_point: "main",
+        })
+        .await
+        .unwrap();
+        let out_data = read_back_texture(&device, &queue, &out_texture)
+            .await
+            .unwrap();
+        assert_eq!(out_data.len(), pixels.len() * 4);
+        let expected = pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+            .map(|channel| f32::from(channel) / 255.0);
+        for (got, expected) in ShaderBytes::deserialise_to_iterator::<f32>(&out_data).zip(expected)
+        {
+            assert!((got - expected).abs() < 1e-6, "{got} != {expected}");
+        }
+
+        assert!(matches!(
+            run_texture_shader(RunTextureShaderParams {
+                device: &device,
+                queue: &queue,
+                in_texture: &in_texture,
+                out_texture: &create_output_texture(
+                    &device,
+                    (width, height),
+                    TextureFormat::Bgra8Unorm
+                ),
+                workgroup_size: (8, 8),
+                program: &cs_module,
+                entry_point: "main",
+            })
+            .await,
+            Err(RunShaderError::UnsupportedTextureFormat(
+                TextureFormat::Bgra8Unorm
+            ))
+        ));
+        assert_eq!(
+            run_texture_shader(RunTextureShaderParams {
+                device: &device,
+                queue: &queue,
+                in_texture: &in_texture,
+                out_texture: &create_output_texture(
+                    &device,
+                    (width - 1, height),
+                    TextureFormat::Rgba32Float
+                ),
+                workgroup_size: (8, 8),
+                program: &cs_module,
+                entry_point: "main",
+            })
+            .await,
+            Err(RunShaderError::TextureSizeMismatch {
+                in_size: (width, height),
+                out_size: (width - 1, height)
+            })
+        );
+    }
+}
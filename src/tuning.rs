@@ -0,0 +1,173 @@
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use wgpu::{Device, Queue, ShaderModuleDescriptor};
+
+use crate::{prepare_dispatch, DispatchParams, RunShaderError, ShaderBindings};
+
+// Name of the constant tune_workgroup_size defines in front of the shader, once per candidate,
+// e.g. const workgroup_len: u32 = 64u; so the shader has to use it without declaring it itself:
+// @compute @workgroup_size(workgroup_len) fn main(...) { ... }
+// NOTE: An override would be nicer than compiling a variant per candidate, but naga doesn't support them in @workgroup_size yet
+pub const WORKGROUP_LEN_CONST: &str = "workgroup_len";
+
+// Timed runs per candidate, the fastest one counts, after one untimed warm-up run
+const TUNING_RUNS_PER_SIZE: usize = 3;
+
+// One run of the kernel to be tuned, with the standard run_shader bindings
+pub struct TuneWorkgroupSizeParams<'a> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    pub in_buf: &'a wgpu::Buffer,
+    pub out_buf: &'a mut wgpu::Buffer,
+    // Stays the same for every candidate, n_workgroups is rounded up to cover it
+    pub n_invocations: usize,
+    pub wgsl_source: &'a str,
+    pub entry_point: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkgroupTuning {
+    pub best_workgroup_len: usize,
+    // (workgroup_len, fastest run) of every candidate, in the order they were given
+    pub timings: Vec<(usize, Duration)>,
+}
+
+// Runs the kernel with every candidate workgroup size and picks the fastest one on this device
+// NOTE: out_buf gets overwritten by every run, so afterwards it holds the output of the last candidate's last run
+pub async fn tune_workgroup_size(
+    params: TuneWorkgroupSizeParams<'_>,
+    candidates: &[usize],
+) -> Result<WorkgroupTuning, RunShaderError> {
+    let (device, queue) = (params.device, params.queue);
+    let out_buf: &wgpu::Buffer = params.out_buf;
+
+    let mut timings = Vec::with_capacity(candidates.len());
+    for &workgroup_len in candidates {
+        let (program, scope_err) =
+            crate::with_error_scope(device, wgpu::ErrorFilter::Validation, || {
+                device.create_shader_module(ShaderModuleDescriptor {
+                    label: Some("Workgroup size tuning variant"),
+                    source: wgpu::ShaderSource::Wgsl(Cow::from(format!(
+                        "const {WORKGROUP_LEN_CONST}: u32 = {workgroup_len}u;\n{}",
+                        params.wgsl_source
+                    ))),
+                })
+            });
+        if let Some(err) = scope_err.await {
+            return Err(RunShaderError::PipelineCreation(err.to_string()));
+        }
+        let bindings = ShaderBindings::default();
+        // Building the pipeline (shader compilation) isn't what's being measured
+        let dispatch = prepare_dispatch(DispatchParams {
+            device,
+            queue,
+            in_bufs: vec![params.in_buf],
+            out_bufs: vec![out_buf],
+            buffer_bindings: vec![
+                bindings.input_binding,
+                bindings.output_binding,
+                bindings.metadata_binding,
+            ],
+            bind_group_index: bindings.bind_group_index,
+            workgroup_len,
+            n_workgroups: params.n_invocations.div_ceil(workgroup_len.max(1)),
+            dispatch_dims: None,
+            program: &program,
+            entry_point: params.entry_point,
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            counter_buf: None,
+        })?;
+
+        let mut fastest = Duration::MAX;
+        for run in 0..=TUNING_RUNS_PER_SIZE {
+            let time_before = Instant::now();
+            dispatch.submit_all().await;
+            crate::wait_for_submitted_work(device, queue).await;
+            if run > 0 {
+                fastest = fastest.min(time_before.elapsed());
+            }
+        }
+        tracing::debug!("workgroup_len {workgroup_len} took {fastest:?}");
+        timings.push((workgroup_len, fastest));
+    }
+
+    let best_workgroup_len = timings
+        .iter()
+        .min_by_key(|(_, took)| *took)
+        .map(|(workgroup_len, _)| *workgroup_len)
+        .ok_or(RunShaderError::NoTuningCandidates)?;
+    Ok(WorkgroupTuning {
+        best_workgroup_len,
+        timings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::{
+        util::{BufferInitDescriptor, DeviceExt},
+        BufferUsages,
+    };
+
+    use super::*;
+    use crate::shader_bytes::ShaderBytes;
+
+    #[tokio::test]
+    async fn test_tune_workgroup_size() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let wgsl_source = r#"
+            @group(0) @binding(0) var<storage, read> v_in_data: array<u32>;
+            @group(0) @binding(1) var<storage, read_write> v_out_data: array<u32>;
+            @group(0) @binding(2) var<uniform> goff: u32;
+
+            @compute @workgroup_size(workgroup_len)
+            fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                let actual_id = gid.x + goff;
+                if (actual_id >= arrayLength(&v_out_data)) { return; }
+                v_out_data[actual_id] = v_in_data[actual_id] * 2u;
+            }
+        "#;
+
+        let input = (0..10_000u32).collect::<Vec<u32>>();
+        let in_buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: ShaderBytes::serialise_from_slice(&input).get_data(),
+            usage: BufferUsages::STORAGE,
+        });
+        let mut out_buf =
+            crate::create_output_buffer(&device, in_buf.size(), BufferUsages::empty());
+        let params = TuneWorkgroupSizeParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            n_invocations: input.len(),
+            wgsl_source,
+            entry_point: "main",
+        };
+        let tuning = tune_workgroup_size(params, &[32, 64, 128]).await.unwrap();
+
+        assert_eq!(
+            tuning
+                .timings
+                .iter()
+                .map(|(workgroup_len, _)| *workgroup_len)
+                .collect::<Vec<_>>(),
+            vec![32, 64, 128]
+        );
+        assert!(tuning.timings.contains(&(
+            tuning.best_workgroup_len,
+            tuning.timings.iter().map(|(_, took)| *took).min().unwrap()
+        )));
+        // Every candidate still computed the whole output
+        assert_eq!(
+            crate::read_back_as::<u32>(&device, &queue, &out_buf).await,
+            Ok(input.iter().map(|i| i * 2).collect::<Vec<u32>>())
+        );
+    }
+}
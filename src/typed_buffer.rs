@@ -0,0 +1,183 @@
+use std::marker::PhantomData;
+
+use wgpu::{
+    util::{BufferInitDescriptor, DeviceExt},
+    BufferUsages, Device, Queue, ShaderModule,
+};
+
+use crate::shader_bytes::{FromShaderBytes, IntoShaderBytes, ShaderBytes, ShaderBytesInfo};
+use crate::{RunShaderError, RunShaderParams, ShaderBindings};
+
+// A gpu buffer that remembers what type its elements are and how many of them it holds,
+// so the data can't accidentally be read back as some other type
+pub struct TypedBuffer<T: ShaderBytesInfo> {
+    buf: wgpu::Buffer,
+    len: usize,
+    elem_type: PhantomData<T>,
+}
+
+impl<T: ShaderBytesInfo> TypedBuffer<T> {
+    // Distance in bytes between consecutive elements in the buffer
+    pub fn stride() -> usize {
+        usize::next_multiple_of(T::shader_bytes_size(), T::shader_bytes_align())
+    }
+
+    // A storage buffer holding the data, usable as the input of run_shader_typed
+    pub fn from_slice(device: &Device, data: &[T], extra_usages: BufferUsages) -> Self
+    where
+        T: IntoShaderBytes,
+    {
+        let buf = device.create_buffer_init(&BufferInitDescriptor {
+            label: None,
+            contents: ShaderBytes::serialise_from_slice(data).get_data(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | extra_usages,
+        });
+        Self {
+            buf,
+            len: data.len(),
+            elem_type: PhantomData,
+        }
+    }
+
+    // An uninitialised buffer with room for len elements, usable as the output of run_shader_typed
+    pub fn new_output(device: &Device, len: usize, extra_usages: BufferUsages) -> Self {
+        let buf = crate::create_output_buffer(device, (len * Self::stride()) as u64, extra_usages);
+        Self {
+            buf,
+            len,
+            elem_type: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buf
+    }
+
+    pub fn into_inner(self) -> wgpu::Buffer {
+        self.buf
+    }
+
+    pub async fn read_back(
+        &self,
+        device: &Device,
+        queue: &Queue,
+    ) -> Result<Vec<T>, wgpu::BufferAsyncError>
+    where
+        T: FromShaderBytes,
+    {
+        crate::read_back_as(device, queue, &self.buf).await
+    }
+}
+
+pub struct TypedRunShaderParams<'a, In: ShaderBytesInfo, Out: ShaderBytesInfo> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    pub in_buf: &'a TypedBuffer<In>,
+    pub out_buf: &'a mut TypedBuffer<Out>,
+    pub workgroup_len: usize,
+    pub n_workgroups: usize,
+    pub program: &'a ShaderModule,
+    pub entry_point: &'a str,
+    pub debug_fill_output: bool,
+    pub strict_binding_sizes: bool,
+    pub params_buf: Option<&'a wgpu::Buffer>,
+    pub bindings: ShaderBindings,
+    pub dispatch_dims: Option<(u32, u32, u32)>,
+}
+
+// Same as run_shader, but the output is read back and deserialised as the out buffer's element type
+// NOTE: The shader still has to agree with In and Out, that part can't be checked here
+pub async fn run_shader_typed<In, Out>(
+    params: TypedRunShaderParams<'_, In, Out>,
+) -> Result<Vec<Out>, RunShaderError>
+where
+    In: IntoShaderBytes,
+    Out: FromShaderBytes,
+{
+    crate::run_shader(RunShaderParams {
+        device: params.device,
+        queue: params.queue,
+        in_buf: &params.in_buf.buf,
+        out_buf: &mut params.out_buf.buf,
+        workgroup_len: params.workgroup_len,
+        n_workgroups: params.n_workgroups,
+        program: params.program,
+        entry_point: params.entry_point,
+        debug_fill_output: params.debug_fill_output,
+        strict_binding_sizes: params.strict_binding_sizes,
+        params_buf: params.params_buf,
+        bindings: params.bindings,
+        dispatch_dims: params.dispatch_dims,
+    })
+    .await?;
+    params
+        .out_buf
+        .read_back(params.device, params.queue)
+        .await
+        .map_err(RunShaderError::ReadBack)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use wgpu::ShaderModuleDescriptor;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_typed_f32_round_trip() {
+        let (device, queue) = crate::test_support::default_device().await;
+        let cs_module = device.create_shader_module(ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(Cow::from(
+                r#"
+                @group(0) @binding(0) var<storage, read> v_in_data: array<f32>;
+                @group(0) @binding(1) var<storage, read_write> v_out_data: array<f32>;
+                @group(0) @binding(2) var<uniform> goff: u32;
+
+                @compute @workgroup_size(32)
+                fn main(@builtin(global_invocation_id) gid: vec3<u32>) {
+                    let actual_id = gid.x + goff;
+                    if (actual_id >= arrayLength(&v_out_data)) { return; }
+                    v_out_data[actual_id] = v_in_data[actual_id] * 2.0;
+                }
+            "#,
+            )),
+        });
+
+        let input_data = (0..1000).map(|i| i as f32 * 0.25).collect::<Vec<f32>>();
+        let in_buf = TypedBuffer::from_slice(&device, &input_data, BufferUsages::empty());
+        let mut out_buf = TypedBuffer::<f32>::new_output(&device, 1000, BufferUsages::empty());
+
+        let output = run_shader_typed(TypedRunShaderParams {
+            device: &device,
+            queue: &queue,
+            in_buf: &in_buf,
+            out_buf: &mut out_buf,
+            workgroup_len: 32,
+            n_workgroups: 1000usize.div_ceil(32),
+            program: &cs_module,
+            entry_point: "main",
+            debug_fill_output: false,
+            strict_binding_sizes: true,
+            params_buf: None,
+            bindings: ShaderBindings::default(),
+            dispatch_dims: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            output,
+            input_data.iter().map(|e| e * 2.0).collect::<Vec<f32>>()
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rand::{seq::index, Rng};
+
+use crate::serialisable_program::SerialisableProgram;
+
+// A cpu implementation of a kernel, used to spot check results that were computed on the gpu
+pub trait CpuReference: Send + Sync {
+    // How many bytes a single output element takes up
+    fn out_elem_nbytes(&self) -> usize;
+
+    // Computes the bytes of output element number elem_index, should be cheap compared to running the whole kernel
+    fn compute_elem(&self, program: &SerialisableProgram, elem_index: usize) -> Vec<u8>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpotCheckReport {
+    pub n_checked: usize,
+    pub mismatching_elems: Vec<usize>,
+}
+
+impl SpotCheckReport {
+    pub fn passed(&self) -> bool {
+        self.mismatching_elems.is_empty()
+    }
+}
+
+// Checks a random sample_fraction of the output elements against the cpu reference, at least one element is always checked
+pub fn spot_check(
+    reference: &dyn CpuReference,
+    program: &SerialisableProgram,
+    out_data: &[u8],
+    sample_fraction: f64,
+    rng: &mut impl Rng,
+) -> SpotCheckReport {
+    let elem_nbytes = reference.out_elem_nbytes();
+    let n_elems = out_data.len() / elem_nbytes;
+    if n_elems == 0 {
+        return SpotCheckReport {
+            n_checked: 0,
+            mismatching_elems: Vec::new(),
+        };
+    }
+    let n_checked = ((n_elems as f64 * sample_fraction).ceil() as usize).clamp(1, n_elems);
+
+    let mut mismatching_elems = index::sample(rng, n_elems, n_checked)
+        .into_iter()
+        .filter(|&elem_index| {
+            let gpu_elem = &out_data[elem_index * elem_nbytes..(elem_index + 1) * elem_nbytes];
+            reference.compute_elem(program, elem_index) != gpu_elem
+        })
+        .collect::<Vec<usize>>();
+    mismatching_elems.sort_unstable();
+
+    SpotCheckReport {
+        n_checked,
+        mismatching_elems,
+    }
+}
+
+// Knows the cpu equivalents of some kernels (by their source) and spot checks results of those kernels
+// Programs without a registered reference simply aren't checked
+pub struct ShadowChecker {
+    sample_fraction: f64,
+    references: HashMap<String, Arc<dyn CpuReference>>,
+}
+
+impl ShadowChecker {
+    pub fn new(sample_fraction: f64) -> Self {
+        Self {
+            sample_fraction,
+            references: HashMap::new(),
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        program_source: impl Into<String>,
+        reference: Arc<dyn CpuReference>,
+    ) {
+        self.references.insert(program_source.into(), reference);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sample_fraction > 0.0
+    }
+
+    // Returns None if checking is disabled or the program has no cpu reference
+    pub fn check(&self, program: &SerialisableProgram, out_data: &[u8]) -> Option<SpotCheckReport> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let reference = self.references.get(&program.program)?;
+        Some(spot_check(
+            reference.as_ref(),
+            program,
+            out_data,
+            self.sample_fraction,
+            &mut rand::thread_rng(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    struct Doubler;
+
+    impl CpuReference for Doubler {
+        fn out_elem_nbytes(&self) -> usize {
+            core::mem::size_of::<u32>()
+        }
+
+        fn compute_elem(&self, program: &SerialisableProgram, elem_index: usize) -> Vec<u8> {
+            let in_elem = u32::from_le_bytes(
+                program.in_data[elem_index * 4..(elem_index + 1) * 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            (in_elem * 2).to_le_bytes().to_vec()
+        }
+    }
+
+    #[test]
+    fn test_spot_check_finds_corruption() {
+        let program = crate::test_support::multiply_program(2, &(0..1000u32).collect::<Vec<u32>>());
+        let mut out_data = (0..1000u32)
+            .flat_map(|elem| (elem * 2).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let report = spot_check(&Doubler, &program, &out_data, 0.01, &mut rng);
+        assert_eq!(report.n_checked, 10);
+        assert!(report.passed());
+
+        // Corrupt an element and check everything, it has to be found
+        out_data[4 * 500] ^= 1;
+        let report = spot_check(&Doubler, &program, &out_data, 1.0, &mut rng);
+        assert_eq!(report.mismatching_elems, vec![500]);
+    }
+}